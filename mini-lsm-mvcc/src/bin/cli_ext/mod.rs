@@ -0,0 +1,152 @@
+//! The options of the CLI for the features that only some of the crates implement.
+
+use std::path::Path;
+
+use anyhow::Result;
+use clap::ValueEnum;
+
+use crate::wrapper::mini_lsm_wrapper::compact::{
+    CompactionOptions, CompactionPriority, FifoCompactionOptions, LazyLevelingCompactionOptions,
+    TimeWindowCompactionOptions,
+};
+use crate::wrapper::mini_lsm_wrapper::lsm_storage::LsmStorageOptions;
+use crate::wrapper::mini_lsm_wrapper::repair::repair_db;
+use crate::wrapper::mini_lsm_wrapper::wal::WalRecoveryMode;
+use crate::{base_compaction_options, Args, BaseCompactionStrategy};
+
+#[derive(Debug, Clone, ValueEnum)]
+pub enum CompactionStrategy {
+    Simple,
+    Leveled,
+    Tiered,
+    Fifo,
+    TimeWindow,
+    LazyLeveling,
+    None,
+}
+
+#[derive(Debug, Clone, ValueEnum)]
+enum Priority {
+    OldestSstId,
+    MinOverlappingBytes,
+    OldestMaxTs,
+    HighestTombstoneRatio,
+}
+
+#[derive(Debug, Clone, ValueEnum)]
+enum WalRecovery {
+    AbsoluteConsistency,
+    TolerateCorruptedTail,
+    PointInTime,
+    SkipCorrupted,
+}
+
+#[derive(clap::Args, Debug)]
+pub struct ExtArgs {
+    /// Which SST of a level the leveled compaction picks first
+    #[arg(long, default_value = "oldest-sst-id")]
+    compaction_priority: Priority,
+    #[arg(long, default_value = "tolerate-corrupted-tail")]
+    wal_recovery: WalRecovery,
+    #[arg(long)]
+    archive_wal: bool,
+    /// Number of threads a compaction is split across
+    #[arg(long, default_value_t = 1)]
+    max_subcompactions: usize,
+    /// Number of compaction workers
+    #[arg(long, default_value_t = 1)]
+    max_background_compactions: usize,
+    /// Compact an SST once the ratio of its entries that are tombstones exceeds this threshold
+    #[arg(long)]
+    tombstone_ratio_compaction_trigger: Option<f64>,
+    /// Compact an SST once it was built this many seconds ago, or never if 0
+    #[arg(long, default_value_t = 0)]
+    periodic_compaction_seconds: u64,
+    /// Cut a compaction output SST once it overlaps this many times the target SST size in the
+    /// level below, or never if 0
+    #[arg(long, default_value_t = 10)]
+    max_grandparent_overlap_factor: usize,
+}
+
+/// The commands that run instead of the REPL.
+#[derive(clap::Subcommand, Debug)]
+pub enum ExtCommand {
+    /// Rebuild the manifest from the SSTs and WALs, when the database fails to open
+    Repair,
+}
+
+fn compaction_options(strategy: &CompactionStrategy) -> CompactionOptions {
+    match strategy {
+        CompactionStrategy::Simple => base_compaction_options(BaseCompactionStrategy::Simple),
+        CompactionStrategy::Leveled => base_compaction_options(BaseCompactionStrategy::Leveled),
+        CompactionStrategy::Tiered => base_compaction_options(BaseCompactionStrategy::Tiered),
+        CompactionStrategy::None => base_compaction_options(BaseCompactionStrategy::None),
+        CompactionStrategy::Fifo => CompactionOptions::Fifo(FifoCompactionOptions {
+            max_table_files_size: 1 << 30, // 1GB
+            ttl_seconds: 0,
+        }),
+        CompactionStrategy::TimeWindow => {
+            CompactionOptions::TimeWindow(TimeWindowCompactionOptions {
+                window_seconds: 3600,
+                min_merge_width: 4,
+                size_ratio: 50,
+                ttl_seconds: 0,
+            })
+        }
+        CompactionStrategy::LazyLeveling => {
+            CompactionOptions::LazyLeveling(LazyLevelingCompactionOptions {
+                level0_file_num_compaction_trigger: 2,
+                size_ratio: 4,
+                max_levels: 4,
+            })
+        }
+    }
+}
+
+/// The options to open the database with.
+pub fn storage_options(args: &Args) -> LsmStorageOptions {
+    let ext = &args.ext;
+    LsmStorageOptions {
+        block_size: 4096,
+        target_sst_size: 2 << 20, // 2MB
+        num_memtable_limit: 3,
+        compaction_options: compaction_options(&args.compaction),
+        enable_wal: args.enable_wal,
+        serializable: args.serializable,
+        wal_recovery_mode: match ext.wal_recovery {
+            WalRecovery::AbsoluteConsistency => WalRecoveryMode::AbsoluteConsistency,
+            WalRecovery::TolerateCorruptedTail => WalRecoveryMode::TolerateCorruptedTailRecords,
+            WalRecovery::PointInTime => WalRecoveryMode::PointInTimeRecovery,
+            WalRecovery::SkipCorrupted => WalRecoveryMode::SkipAnyCorruptedRecords,
+        },
+        archive_wal: ext.archive_wal,
+        max_manifest_file_size: 64 << 20, // 64MB
+        max_subcompactions: ext.max_subcompactions,
+        max_background_compactions: ext.max_background_compactions,
+        compaction_priority: match ext.compaction_priority {
+            Priority::OldestSstId => CompactionPriority::OldestSstId,
+            Priority::MinOverlappingBytes => CompactionPriority::MinOverlappingBytes,
+            Priority::OldestMaxTs => CompactionPriority::OldestMaxTs,
+            Priority::HighestTombstoneRatio => CompactionPriority::HighestTombstoneRatio,
+        },
+        tombstone_ratio_compaction_trigger: ext.tombstone_ratio_compaction_trigger,
+        periodic_compaction_seconds: ext.periodic_compaction_seconds,
+        max_grandparent_overlap_factor: ext.max_grandparent_overlap_factor,
+    }
+}
+
+/// Run `command` on the database at `path`.
+pub fn run(command: ExtCommand, path: &Path, options: &LsmStorageOptions) -> Result<()> {
+    match command {
+        ExtCommand::Repair => {
+            let report = repair_db(path, options)?;
+            println!(
+                "repaired with {} SSTs, {} WAL records salvaged, {} files moved to lost",
+                report.ssts.len(),
+                report.salvaged_records,
+                report.lost_files.len()
+            );
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,286 @@
+//! The compaction strategies that only some of the crates implement.
+
+use std::sync::Arc;
+
+use crate::wrapper::mini_lsm_wrapper::compact::{
+    LazyLevelingCompactionController, LazyLevelingCompactionOptions,
+    TimeWindowCompactionController, TimeWindowCompactionOptions,
+};
+use crate::wrapper::mini_lsm_wrapper::table::{SsTable, SsTableStats};
+use crate::{generate_random_key_range, generate_random_split, MockStorage};
+
+#[derive(clap::Subcommand, Debug)]
+pub enum ExtArgs {
+    LazyLeveling {
+        #[clap(long)]
+        dump_real_id: bool,
+        #[clap(long, default_value = "2")]
+        level0_file_num_compaction_trigger: usize,
+        #[clap(long, default_value = "4")]
+        size_ratio: usize,
+        #[clap(long, default_value = "4")]
+        max_levels: usize,
+        #[clap(long, default_value = "50")]
+        iterations: usize,
+    },
+    TimeWindow {
+        #[clap(long)]
+        dump_real_id: bool,
+        #[clap(long, default_value = "3600")]
+        window_seconds: u64,
+        #[clap(long, default_value = "4")]
+        min_merge_width: usize,
+        #[clap(long, default_value = "50")]
+        size_ratio: usize,
+        #[clap(long, default_value = "0")]
+        ttl_seconds: u64,
+        /// Seconds between two flushes
+        #[clap(long, default_value = "300")]
+        flush_interval_seconds: u64,
+        #[clap(long, default_value = "50")]
+        iterations: usize,
+    },
+}
+
+pub fn run(args: ExtArgs) {
+    match args {
+        ExtArgs::LazyLeveling {
+            dump_real_id,
+            level0_file_num_compaction_trigger,
+            size_ratio,
+            max_levels,
+            iterations,
+        } => {
+            let controller = LazyLevelingCompactionController::new(LazyLevelingCompactionOptions {
+                level0_file_num_compaction_trigger,
+                size_ratio,
+                max_levels,
+            });
+            let mut storage = MockStorage::new();
+            // the upper levels have no sorted run until the first compactions
+            storage.snapshot.levels.push((max_levels, Vec::new()));
+            let mut max_space = 0;
+            for i in 0..iterations {
+                println!("=== Iteration {i} ===");
+                storage.flush_sst_to_l0();
+                println!("--- After Flush ---");
+                if dump_real_id {
+                    storage.dump_real_id(true, false);
+                } else {
+                    storage.dump_original_id(true, false);
+                }
+                let mut num_compactions = 0;
+                while let Some(task) = {
+                    println!("--- Compaction Task ---");
+                    controller.generate_compaction_task(&storage.snapshot)
+                } {
+                    let mut sst_ids = Vec::new();
+                    for file in task
+                        .upper_level_runs
+                        .iter()
+                        .flatten()
+                        .chain(task.lower_level_sst_ids.iter())
+                    {
+                        let new_sst_id = storage.generate_sst_id();
+                        sst_ids.push(new_sst_id);
+                        storage.file_list.insert(new_sst_id, *file);
+                        storage.total_writes += 1;
+                    }
+                    print!(
+                        "Upper L{} {:?} ",
+                        task.upper_level.unwrap_or_default(),
+                        task.upper_level_runs
+                    );
+                    print!(
+                        "Lower L{} {:?} ",
+                        task.lower_level, task.lower_level_sst_ids
+                    );
+                    println!("-> {:?}", sst_ids);
+                    max_space = max_space.max(storage.file_list.len());
+                    let (snapshot, del) =
+                        controller.apply_compaction_result(&storage.snapshot, &task, &sst_ids);
+                    storage.snapshot = snapshot;
+                    storage.remove(&del);
+                    println!("--- After Compaction ---");
+                    if dump_real_id {
+                        storage.dump_real_id(true, false);
+                    } else {
+                        storage.dump_original_id(true, false);
+                    }
+                    num_compactions += 1;
+                    if num_compactions >= max_levels * 2 {
+                        panic!("compaction does not converge?");
+                    }
+                }
+                if num_compactions == 0 {
+                    println!("no compaction triggered");
+                } else {
+                    println!("{num_compactions} compaction triggered in this iteration");
+                }
+                max_space = max_space.max(storage.file_list.len());
+                println!("--- Statistics ---");
+                println!(
+                    "Write Amplification: {}/{}={:.3}x",
+                    storage.total_writes,
+                    storage.total_flushes,
+                    storage.total_writes as f64 / storage.total_flushes as f64
+                );
+                println!(
+                    "Maximum Space Usage: {}/{}={:.3}x",
+                    max_space,
+                    storage.total_flushes,
+                    max_space as f64 / storage.total_flushes as f64
+                );
+                println!(
+                    "Read Amplification: {}x",
+                    storage.snapshot.l0_sstables.len()
+                        + storage
+                            .snapshot
+                            .levels
+                            .iter()
+                            .filter(|(_, f)| !f.is_empty())
+                            .count()
+                );
+                println!();
+            }
+        }
+        ExtArgs::TimeWindow {
+            dump_real_id,
+            window_seconds,
+            min_merge_width,
+            size_ratio,
+            ttl_seconds,
+            flush_interval_seconds,
+            iterations,
+        } => {
+            let controller = TimeWindowCompactionController::new(TimeWindowCompactionOptions {
+                window_seconds,
+                min_merge_width,
+                size_ratio,
+                ttl_seconds,
+            });
+            let mut storage = MockStorage::new();
+            let mut max_space = 0;
+            // the simulated clock, in seconds since the UNIX epoch
+            let mut now = window_seconds;
+            for i in 0..iterations {
+                println!("=== Iteration {i} (t={now}) ===");
+                let id = storage.flush_sst_to_new_tier();
+                let (first_key, last_key) = generate_random_key_range();
+                storage.snapshot.sstables.insert(
+                    id,
+                    Arc::new(
+                        SsTable::create_meta_only(id, 1, first_key, last_key).with_stats(
+                            SsTableStats {
+                                data_written_at: now,
+                                ..Default::default()
+                            },
+                        ),
+                    ),
+                );
+                println!("--- After Flush ---");
+                if dump_real_id {
+                    storage.dump_real_id(false, false);
+                } else {
+                    storage.dump_original_id(false, false);
+                }
+                let mut num_compactions = 0;
+                while let Some(task) = {
+                    println!("--- Compaction Task ---");
+                    controller.generate_compaction_task_at(&storage.snapshot, now)
+                } {
+                    let files = task
+                        .tiers
+                        .iter()
+                        .flat_map(|(_, files)| files)
+                        .copied()
+                        .collect::<Vec<_>>();
+                    let mut sst_ids = Vec::new();
+                    if !task.expired {
+                        let ssts = files
+                            .iter()
+                            .map(|id| storage.snapshot.sstables[id].clone())
+                            .collect::<Vec<_>>();
+                        let begin = ssts.iter().map(|x| x.first_key()).min().unwrap().clone();
+                        let end = ssts.iter().map(|x| x.last_key()).max().unwrap().clone();
+                        let stats = SsTableStats {
+                            data_written_at: ssts
+                                .iter()
+                                .map(|x| x.stats().data_written_at)
+                                .max()
+                                .unwrap(),
+                            ..Default::default()
+                        };
+                        let splits = generate_random_split(begin, end, files.len());
+                        for (file, (first_key, last_key)) in files.iter().zip(splits) {
+                            let new_sst_id = storage.generate_sst_id();
+                            sst_ids.push(new_sst_id);
+                            storage.file_list.insert(new_sst_id, *file);
+                            storage.total_writes += 1;
+                            storage.snapshot.sstables.insert(
+                                new_sst_id,
+                                Arc::new(
+                                    SsTable::create_meta_only(new_sst_id, 1, first_key, last_key)
+                                        .with_stats(stats),
+                                ),
+                            );
+                        }
+                    }
+                    for (tier_id, files) in &task.tiers {
+                        print!("L{} {:?} ", tier_id, files);
+                    }
+                    if task.expired {
+                        println!("expired in window {}", task.window);
+                    } else {
+                        println!("-> {:?} in window {}", sst_ids, task.window);
+                    }
+                    max_space = max_space.max(storage.file_list.len());
+                    let (snapshot, del) =
+                        controller.apply_compaction_result(&storage.snapshot, &task, &sst_ids);
+                    storage.snapshot = snapshot;
+                    storage.remove(&del);
+                    println!("--- After Compaction ---");
+                    if dump_real_id {
+                        storage.dump_real_id(false, false);
+                    } else {
+                        storage.dump_original_id(false, false);
+                    }
+                    num_compactions += 1;
+                    if num_compactions >= iterations {
+                        panic!("compaction does not converge?");
+                    }
+                }
+                if num_compactions == 0 {
+                    println!("no compaction triggered");
+                } else {
+                    println!("{num_compactions} compaction triggered in this iteration");
+                }
+                max_space = max_space.max(storage.file_list.len());
+                println!("--- Statistics ---");
+                println!(
+                    "Write Amplification: {}/{}={:.3}x",
+                    storage.total_writes,
+                    storage.total_flushes,
+                    storage.total_writes as f64 / storage.total_flushes as f64
+                );
+                println!(
+                    "Maximum Space Usage: {}/{}={:.3}x",
+                    max_space,
+                    storage.total_flushes,
+                    max_space as f64 / storage.total_flushes as f64
+                );
+                println!(
+                    "Read Amplification: {}x",
+                    storage
+                        .snapshot
+                        .levels
+                        .iter()
+                        .filter(|(_, f)| !f.is_empty())
+                        .count()
+                );
+                println!();
+                now += flush_interval_seconds;
+            }
+        }
+    }
+}
@@ -0,0 +1,331 @@
+use std::collections::VecDeque;
+use std::fs::File;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::{bail, Result};
+use bytes::Bytes;
+use crossbeam_channel::{Receiver, RecvTimeoutError, Sender, TrySendError};
+
+use crate::lsm_storage::{LsmStorageInner, WriteBatchRecord};
+use crate::wal::{Wal, WalBatch};
+
+/// Number of batches a subscriber may fall behind the writers before it is dropped.
+const MAX_PENDING_CHANGES: usize = 4096;
+
+/// A committed write batch, as seen by change data capture consumers.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ChangeBatch {
+    pub commit_ts: u64,
+    pub records: Vec<WriteBatchRecord<Bytes>>,
+}
+
+impl ChangeBatch {
+    fn from_wal_batch(batch: WalBatch) -> Self {
+        Self {
+            commit_ts: batch.commit_ts,
+            records: batch
+                .data
+                .into_iter()
+                .map(|(key, value)| {
+                    if value.is_empty() {
+                        WriteBatchRecord::Del(key)
+                    } else {
+                        WriteBatchRecord::Put(key, value)
+                    }
+                })
+                .collect(),
+        }
+    }
+}
+
+/// The sending end of a `ChangeStream`, held by the engine.
+pub(crate) struct ChangeSubscriber {
+    sender: Sender<ChangeBatch>,
+    /// Set when the subscriber is dropped for falling behind.
+    lagged: Arc<AtomicBool>,
+}
+
+/// A stream of committed batches in commit order, created by `MiniLsm::subscribe`. Batches
+/// retained in the WALs are returned first, followed by the batches committed after the stream was
+/// created. Iterating blocks until the next batch is committed, and ends when the engine is
+/// dropped, or once the stream falls more than `MAX_PENDING_CHANGES` batches behind the writers,
+/// after returning the batches received until then. Use `recv_timeout` to tell these apart.
+pub struct ChangeStream {
+    backlog: VecDeque<ChangeBatch>,
+    receiver: Receiver<ChangeBatch>,
+    lagged: Arc<AtomicBool>,
+    /// Batches below this ts have already been returned, or were not requested.
+    next_ts: u64,
+}
+
+impl ChangeStream {
+    /// Return the next batch if one is available without blocking.
+    pub fn try_next(&mut self) -> Option<ChangeBatch> {
+        if let Some(batch) = self.backlog.pop_front() {
+            return Some(batch);
+        }
+        while let Ok(batch) = self.receiver.try_recv() {
+            if let Some(batch) = self.accept(batch) {
+                return Some(batch);
+            }
+        }
+        None
+    }
+
+    /// Wait up to `timeout` for the next batch.
+    pub fn next_timeout(&mut self, timeout: Duration) -> Option<ChangeBatch> {
+        self.recv_timeout(timeout).ok().flatten()
+    }
+
+    /// Wait up to `timeout` for the next batch, returning an error if the engine has been dropped or
+    /// the stream fell too far behind.
+    pub fn recv_timeout(&mut self, timeout: Duration) -> Result<Option<ChangeBatch>> {
+        if let Some(batch) = self.backlog.pop_front() {
+            return Ok(Some(batch));
+        }
+        let deadline = std::time::Instant::now() + timeout;
+        loop {
+            match self.receiver.recv_deadline(deadline) {
+                Ok(batch) => {
+                    if let Some(batch) = self.accept(batch) {
+                        return Ok(Some(batch));
+                    }
+                }
+                Err(RecvTimeoutError::Timeout) => return Ok(None),
+                Err(RecvTimeoutError::Disconnected) if self.lagged.load(Ordering::SeqCst) => bail!(
+                    "the change stream fell more than {} batches behind and was dropped",
+                    MAX_PENDING_CHANGES
+                ),
+                Err(RecvTimeoutError::Disconnected) => bail!("the storage engine is closed"),
+            }
+        }
+    }
+
+    /// The live feed may repeat batches that were already read from the WALs.
+    fn accept(&mut self, batch: ChangeBatch) -> Option<ChangeBatch> {
+        if batch.commit_ts < self.next_ts {
+            return None;
+        }
+        self.next_ts = batch.commit_ts + 1;
+        Some(batch)
+    }
+}
+
+impl Iterator for ChangeStream {
+    type Item = ChangeBatch;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if let Some(batch) = self.backlog.pop_front() {
+            return Some(batch);
+        }
+        while let Ok(batch) = self.receiver.recv() {
+            if let Some(batch) = self.accept(batch) {
+                return Some(batch);
+            }
+        }
+        None
+    }
+}
+
+impl LsmStorageInner {
+    pub(crate) fn path_of_wal_archive_static(path: impl AsRef<Path>) -> PathBuf {
+        path.as_ref().join("archive")
+    }
+
+    pub(crate) fn path_of_archived_wal(&self, id: usize) -> PathBuf {
+        Self::path_of_wal_static(Self::path_of_wal_archive_static(&self.path), id)
+    }
+
+    /// Publish a batch to the subscribers. Must be called from `LsmMvccInner::publish_commit_ts`,
+    /// so that the batches are published in commit order.
+    pub(crate) fn publish_change<T: AsRef<[u8]>>(
+        &self,
+        commit_ts: u64,
+        batch: &[WriteBatchRecord<T>],
+    ) {
+        let mut subscribers = self.change_subscribers.lock();
+        if subscribers.is_empty() {
+            return;
+        }
+        let change = ChangeBatch {
+            commit_ts,
+            records: batch
+                .iter()
+                .map(|record| match record {
+                    WriteBatchRecord::Put(key, value) => WriteBatchRecord::Put(
+                        Bytes::copy_from_slice(key.as_ref()),
+                        Bytes::copy_from_slice(value.as_ref()),
+                    ),
+                    WriteBatchRecord::Del(key) => {
+                        WriteBatchRecord::Del(Bytes::copy_from_slice(key.as_ref()))
+                    }
+                })
+                .collect(),
+        };
+        // never block the writers on a slow subscriber, drop it instead
+        subscribers.retain(
+            |subscriber| match subscriber.sender.try_send(change.clone()) {
+                Ok(()) => true,
+                Err(TrySendError::Full(_)) => {
+                    subscriber.lagged.store(true, Ordering::SeqCst);
+                    false
+                }
+                Err(TrySendError::Disconnected(_)) => false,
+            },
+        );
+    }
+
+    /// Collect the ids of all retained WALs, in creation order, after writing out their buffers,
+    /// and the latest commit ts. If `subscriber` is given, it is registered at the same time, so
+    /// that every batch is either in the returned WALs or sent to the subscriber.
+    fn retained_wals(&self, subscriber: Option<ChangeSubscriber>) -> Result<(Vec<usize>, u64)> {
+        if !self.options.enable_wal {
+            bail!("change data capture requires the WAL to be enabled");
+        }
+        let mut ids = Vec::new();
+        // hold the state lock as well, so that no WAL is in the middle of being archived
+        let _lck = self.mvcc().write_lock.lock();
+        self.mvcc().wait_for_written_commits();
+        let _state_lock = self.state_lock.lock();
+        let snapshot = self.state.read().clone();
+        for memtable in snapshot
+            .imm_memtables
+            .iter()
+            .chain(std::iter::once(&snapshot.memtable))
+        {
+            memtable.flush_wal_buffer()?;
+            ids.push(memtable.id());
+        }
+        let archive = Self::path_of_wal_archive_static(&self.path);
+        if archive.exists() {
+            for entry in std::fs::read_dir(&archive)? {
+                let file_name = entry?.file_name();
+                let Some(id) = file_name
+                    .to_str()
+                    .and_then(|name| name.strip_suffix(".wal"))
+                    .and_then(|id| id.parse::<usize>().ok())
+                else {
+                    continue;
+                };
+                ids.push(id);
+            }
+        }
+        if let Some(subscriber) = subscriber {
+            self.change_subscribers.lock().push(subscriber);
+        }
+        ids.sort();
+        ids.dedup();
+        Ok((ids, self.mvcc().latest_commit_ts()))
+    }
+
+    /// Read the batches committed at or after `ts` from the given WALs, failing if some of them are
+    /// no longer retained. `latest_ts` is the latest commit ts when the WALs were listed.
+    fn read_changes(&self, ts: u64, wal_ids: &[usize], latest_ts: u64) -> Result<Vec<ChangeBatch>> {
+        let mut changes = Vec::new();
+        let mut first_retained_ts = None;
+        for id in wal_ids {
+            // a live WAL might have been archived after we listed it
+            let path = self.path_of_wal(*id);
+            let batches = if path.exists() {
+                Wal::read_batches(path)?
+            } else {
+                Wal::read_batches(self.path_of_archived_wal(*id))?
+            };
+            if first_retained_ts.is_none() {
+                first_retained_ts = batches.first().map(|batch| batch.commit_ts);
+            }
+            changes.extend(
+                batches
+                    .into_iter()
+                    .filter(|batch| batch.commit_ts >= ts)
+                    .map(ChangeBatch::from_wal_batch),
+            );
+        }
+        // every commit takes the next ts, so the batches before the first retained one were in
+        // WALs that have been removed, or written with `disable_wal`
+        let first_retained_ts = first_retained_ts.unwrap_or(latest_ts + 1);
+        if ts.max(1) < first_retained_ts {
+            bail!(
+                "the batches committed from ts {} to {} are no longer retained in the WALs",
+                ts.max(1),
+                first_retained_ts - 1
+            );
+        }
+        // and a gap after it means that the batches in between were written with `disable_wal`
+        let mut expected_ts = ts.max(1);
+        for change in &changes {
+            if change.commit_ts != expected_ts {
+                bail!(
+                    "expected the batch at ts {} in the WALs, found the one at ts {}",
+                    expected_ts,
+                    change.commit_ts
+                );
+            }
+            expected_ts += 1;
+        }
+        if expected_ts <= latest_ts {
+            bail!(
+                "the batches committed from ts {} to {} are missing from the WALs",
+                expected_ts,
+                latest_ts
+            );
+        }
+        Ok(changes)
+    }
+
+    pub(crate) fn get_updates_since(&self, ts: u64) -> Result<Vec<ChangeBatch>> {
+        let (wal_ids, latest_ts) = self.retained_wals(None)?;
+        self.read_changes(ts, &wal_ids, latest_ts)
+    }
+
+    pub(crate) fn subscribe(&self, from_ts: u64) -> Result<ChangeStream> {
+        let (sender, receiver) = crossbeam_channel::bounded(MAX_PENDING_CHANGES);
+        let lagged = Arc::new(AtomicBool::new(false));
+        let (wal_ids, latest_ts) = self.retained_wals(Some(ChangeSubscriber {
+            sender,
+            lagged: lagged.clone(),
+        }))?;
+        let backlog = self.read_changes(from_ts, &wal_ids, latest_ts)?;
+        let next_ts = backlog
+            .last()
+            .map(|batch| batch.commit_ts + 1)
+            .unwrap_or(from_ts);
+        Ok(ChangeStream {
+            backlog: backlog.into(),
+            receiver,
+            lagged,
+            next_ts,
+        })
+    }
+
+    /// Remove the archived WALs that only contain batches committed before `ts`. Returns the number
+    /// of WALs removed.
+    pub(crate) fn purge_wal_archive(&self, ts: u64) -> Result<usize> {
+        let archive = Self::path_of_wal_archive_static(&self.path);
+        if !archive.exists() {
+            return Ok(0);
+        }
+        let mut removed = 0;
+        for entry in std::fs::read_dir(&archive)? {
+            let path = entry?.path();
+            if path.extension().is_none_or(|ext| ext != "wal") {
+                continue;
+            }
+            // a corrupted archived WAL is purged like any other, as it cannot be read from anyway
+            let max_ts = Wal::salvage_batches(&path)?
+                .iter()
+                .map(|batch| batch.commit_ts)
+                .max()
+                .unwrap_or_default();
+            if max_ts < ts {
+                std::fs::remove_file(&path)?;
+                removed += 1;
+            }
+        }
+        File::open(&archive)?.sync_all()?;
+        Ok(removed)
+    }
+}
@@ -1,33 +1,53 @@
+mod fifo;
+mod lazy_leveling;
 mod leveled;
 mod simple_leveled;
 mod tiered;
+mod time_window;
 
 use std::collections::HashSet;
 use std::sync::Arc;
-use std::time::Duration;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
-use anyhow::Result;
-pub use leveled::{LeveledCompactionController, LeveledCompactionOptions, LeveledCompactionTask};
+use anyhow::{bail, Result};
+use bytes::Bytes;
+pub use fifo::{FifoCompactionController, FifoCompactionOptions, FifoCompactionTask};
+pub use lazy_leveling::{
+    LazyLevelingCompactionController, LazyLevelingCompactionOptions, LazyLevelingCompactionTask,
+};
+pub use leveled::{
+    CompactionPriority, LeveledCompactionController, LeveledCompactionOptions,
+    LeveledCompactionTask,
+};
 use serde::{Deserialize, Serialize};
 pub use simple_leveled::{
     SimpleLeveledCompactionController, SimpleLeveledCompactionOptions, SimpleLeveledCompactionTask,
 };
 pub use tiered::{TieredCompactionController, TieredCompactionOptions, TieredCompactionTask};
+pub use time_window::{
+    TimeWindowCompactionController, TimeWindowCompactionOptions, TimeWindowCompactionTask,
+};
 
 use crate::iterators::concat_iterator::SstConcatIterator;
 use crate::iterators::merge_iterator::MergeIterator;
 use crate::iterators::two_merge_iterator::TwoMergeIterator;
 use crate::iterators::StorageIterator;
-use crate::key::KeySlice;
-use crate::lsm_storage::{CompactionFilter, LsmStorageInner, LsmStorageState};
-use crate::manifest::ManifestRecord;
+use crate::key::{KeySlice, TS_RANGE_BEGIN};
+use crate::lsm_storage::{
+    CompactionFilter, CompactionFilterContext, CompactionFilterDecision, LsmStorageInner,
+    LsmStorageOptions, LsmStorageState,
+};
+use crate::manifest::{ManifestRecord, SstMetadata};
 use crate::table::{SsTable, SsTableBuilder, SsTableIterator};
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub enum CompactionTask {
     Leveled(LeveledCompactionTask),
     Tiered(TieredCompactionTask),
     Simple(SimpleLeveledCompactionTask),
+    Fifo(FifoCompactionTask),
+    TimeWindow(TimeWindowCompactionTask),
+    LazyLeveling(LazyLevelingCompactionTask),
     ForceFullCompaction {
         l0_sstables: Vec<usize>,
         l1_sstables: Vec<usize>,
@@ -35,35 +55,259 @@ pub enum CompactionTask {
 }
 
 impl CompactionTask {
+    /// The SSTs compacted by the task.
+    pub fn input_sst_ids(&self) -> Vec<usize> {
+        match self {
+            CompactionTask::ForceFullCompaction {
+                l0_sstables,
+                l1_sstables,
+            } => l0_sstables.iter().chain(l1_sstables).copied().collect(),
+            CompactionTask::Leveled(LeveledCompactionTask {
+                upper_level_sst_ids,
+                lower_level_sst_ids,
+                ..
+            })
+            | CompactionTask::Simple(SimpleLeveledCompactionTask {
+                upper_level_sst_ids,
+                lower_level_sst_ids,
+                ..
+            }) => upper_level_sst_ids
+                .iter()
+                .chain(lower_level_sst_ids)
+                .copied()
+                .collect(),
+            CompactionTask::Tiered(TieredCompactionTask { tiers, .. })
+            | CompactionTask::TimeWindow(TimeWindowCompactionTask { tiers, .. }) => {
+                tiers.iter().flat_map(|(_, ssts)| ssts).copied().collect()
+            }
+            CompactionTask::Fifo(FifoCompactionTask { ssts }) => ssts.clone(),
+            CompactionTask::LazyLeveling(LazyLevelingCompactionTask {
+                upper_level_runs,
+                lower_level_sst_ids,
+                ..
+            }) => upper_level_runs
+                .iter()
+                .flatten()
+                .chain(lower_level_sst_ids)
+                .copied()
+                .collect(),
+        }
+    }
+
     fn compact_to_bottom_level(&self) -> bool {
         match self {
             CompactionTask::ForceFullCompaction { .. } => true,
             CompactionTask::Leveled(task) => task.is_lower_level_bottom_level,
             CompactionTask::Simple(task) => task.is_lower_level_bottom_level,
             CompactionTask::Tiered(task) => task.bottom_tier_included,
+            CompactionTask::TimeWindow(task) => task.bottom_tier_included,
+            CompactionTask::LazyLeveling(task) => task.is_lower_level_bottom_level,
+            CompactionTask::Fifo(_) => false,
+        }
+    }
+
+    /// The SSTs below the level the task compacts to, where the outputs are cut.
+    fn grandparent_sst_ids(&self) -> &[usize] {
+        match self {
+            CompactionTask::Leveled(task) => &task.grandparent_sst_ids,
+            CompactionTask::Simple(task) => &task.grandparent_sst_ids,
+            _ => &[],
         }
     }
+
+    /// The level the task compacts to, or `None` if the levels are tiers.
+    fn output_level(&self) -> Option<usize> {
+        match self {
+            CompactionTask::ForceFullCompaction { .. } => Some(1),
+            CompactionTask::Leveled(task) => Some(task.lower_level),
+            CompactionTask::Simple(task) => Some(task.lower_level),
+            CompactionTask::LazyLeveling(task) => Some(task.lower_level),
+            CompactionTask::Tiered(_) | CompactionTask::TimeWindow(_) | CompactionTask::Fifo(_) => {
+                None
+            }
+        }
+    }
+}
+
+/// Compactions triggered by a single SST, alongside the size triggers of the compaction strategies.
+#[derive(Debug, Clone, Default)]
+pub struct SstCompactionTriggers {
+    /// Compact an SST once the ratio of its entries that are tombstones exceeds this threshold, so
+    /// that they move down to the bottom level, where they are dropped. The SSTs of the bottom level
+    /// are not checked, as their tombstones are kept only while a snapshot may read them.
+    pub tombstone_ratio: Option<f64>,
+    /// Compact an SST once it was built this many seconds ago, or never if 0. SSTs of the bottom
+    /// level are rewritten in place.
+    pub periodic_compaction_seconds: u64,
+}
+
+impl SstCompactionTriggers {
+    /// The triggers enabled in the storage options.
+    pub fn from_options(options: &LsmStorageOptions) -> Self {
+        Self {
+            tombstone_ratio: options.tombstone_ratio_compaction_trigger,
+            periodic_compaction_seconds: options.periodic_compaction_seconds,
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.tombstone_ratio.is_some() || self.periodic_compaction_seconds > 0
+    }
+
+    /// Returns why `sst` should be compacted, if it should, at `now` seconds since the UNIX epoch.
+    pub fn check(&self, sst: &SsTable, bottom_level: bool, now: u64) -> Option<String> {
+        let stats = sst.stats();
+        if let Some(threshold) = self.tombstone_ratio {
+            if !bottom_level && stats.tombstone_ratio() > threshold {
+                return Some(format!("tombstone ratio {:.3}", stats.tombstone_ratio()));
+            }
+        }
+        // the age of SSTs written before it was recorded is unknown
+        if self.periodic_compaction_seconds > 0
+            && stats.created_at > 0
+            && now.saturating_sub(stats.created_at) >= self.periodic_compaction_seconds
+        {
+            return Some(format!("age {}s", now - stats.created_at));
+        }
+        None
+    }
+
+    /// Returns the first of `sst_ids` that should be compacted, with why, skipping the `busy_ssts`.
+    pub(crate) fn find(
+        &self,
+        snapshot: &LsmStorageState,
+        sst_ids: &[usize],
+        bottom_level: bool,
+        busy_ssts: &HashSet<usize>,
+        now: u64,
+    ) -> Option<(usize, String)> {
+        sst_ids
+            .iter()
+            .filter(|id| !busy_ssts.contains(id))
+            .find_map(|id| {
+                self.check(&snapshot.sstables[id], bottom_level, now)
+                    .map(|reason| (*id, reason))
+            })
+    }
+}
+
+/// Seconds since the UNIX epoch, as recorded in `SsTableStats::created_at`.
+pub(crate) fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |elapsed| elapsed.as_secs())
+}
+
+/// The newest `SsTableStats::data_written_at` of the SSTs, which the SSTs compacted from them keep.
+fn newest_data_written_at(snapshot: &LsmStorageState, ids: &[usize]) -> u64 {
+    ids.iter()
+        .map(|id| snapshot.sstables[id].stats().data_written_at)
+        .max()
+        .unwrap_or_default()
 }
 
 pub(crate) enum CompactionController {
     Leveled(LeveledCompactionController),
     Tiered(TieredCompactionController),
     Simple(SimpleLeveledCompactionController),
+    Fifo(FifoCompactionController),
+    TimeWindow(TimeWindowCompactionController),
+    LazyLeveling(LazyLevelingCompactionController),
     NoCompaction,
 }
 
 impl CompactionController {
-    pub fn generate_compaction_task(&self, snapshot: &LsmStorageState) -> Option<CompactionTask> {
+    pub fn new(options: &CompactionOptions) -> Self {
+        match options {
+            CompactionOptions::Leveled(options) => {
+                Self::Leveled(LeveledCompactionController::new(options.clone()))
+            }
+            CompactionOptions::Tiered(options) => {
+                Self::Tiered(TieredCompactionController::new(options.clone()))
+            }
+            CompactionOptions::Simple(options) => {
+                Self::Simple(SimpleLeveledCompactionController::new(options.clone()))
+            }
+            CompactionOptions::Fifo(options) => {
+                Self::Fifo(FifoCompactionController::new(options.clone()))
+            }
+            CompactionOptions::TimeWindow(options) => {
+                Self::TimeWindow(TimeWindowCompactionController::new(options.clone()))
+            }
+            CompactionOptions::LazyLeveling(options) => {
+                Self::LazyLeveling(LazyLevelingCompactionController::new(options.clone()))
+            }
+            CompactionOptions::NoCompaction => Self::NoCompaction,
+        }
+    }
+
+    /// Apply the storage options that tune how tasks are generated, but are not part of the
+    /// compaction strategy. They are not needed to replay the compactions from the manifest.
+    pub fn with_storage_options(self, options: &LsmStorageOptions) -> Self {
+        let triggers = SstCompactionTriggers::from_options(options);
+        match self {
+            Self::Leveled(ctrl) => Self::Leveled(
+                ctrl.with_priority(options.compaction_priority)
+                    .with_triggers(triggers),
+            ),
+            Self::Tiered(ctrl) => Self::Tiered(ctrl.with_triggers(triggers)),
+            Self::Simple(ctrl) => Self::Simple(ctrl.with_triggers(triggers)),
+            // fifo compaction never rewrites SSTs, and time window compaction rewrites each window
+            // into one tier anyway
+            Self::Fifo(ctrl) => Self::Fifo(ctrl),
+            Self::TimeWindow(ctrl) => Self::TimeWindow(ctrl),
+            Self::LazyLeveling(ctrl) => Self::LazyLeveling(ctrl),
+            Self::NoCompaction => Self::NoCompaction,
+        }
+    }
+
+    /// The options the controller was created with.
+    pub fn options(&self) -> CompactionOptions {
+        match self {
+            Self::Leveled(ctrl) => CompactionOptions::Leveled(ctrl.options().clone()),
+            Self::Tiered(ctrl) => CompactionOptions::Tiered(ctrl.options().clone()),
+            Self::Simple(ctrl) => CompactionOptions::Simple(ctrl.options().clone()),
+            Self::Fifo(ctrl) => CompactionOptions::Fifo(ctrl.options().clone()),
+            Self::TimeWindow(ctrl) => CompactionOptions::TimeWindow(ctrl.options().clone()),
+            Self::LazyLeveling(ctrl) => CompactionOptions::LazyLeveling(ctrl.options().clone()),
+            Self::NoCompaction => CompactionOptions::NoCompaction,
+        }
+    }
+
+    /// Generate a task that does not use any of the `busy_ssts`, which are being compacted by the
+    /// running tasks.
+    pub fn generate_compaction_task(
+        &self,
+        snapshot: &LsmStorageState,
+        busy_ssts: &HashSet<usize>,
+    ) -> Option<CompactionTask> {
         match self {
             CompactionController::Leveled(ctrl) => ctrl
-                .generate_compaction_task(snapshot)
+                .generate_compaction_task_excluding(snapshot, busy_ssts)
                 .map(CompactionTask::Leveled),
             CompactionController::Simple(ctrl) => ctrl
-                .generate_compaction_task(snapshot)
+                .generate_compaction_task_excluding(snapshot, busy_ssts)
                 .map(CompactionTask::Simple),
+            // tiered compaction merges a prefix of the tiers, so one task runs at a time
+            CompactionController::Tiered(_) if !busy_ssts.is_empty() => None,
             CompactionController::Tiered(ctrl) => ctrl
                 .generate_compaction_task(snapshot)
                 .map(CompactionTask::Tiered),
+            // the oldest SSTs are dropped, so one task runs at a time
+            CompactionController::Fifo(_) if !busy_ssts.is_empty() => None,
+            CompactionController::Fifo(ctrl) => ctrl
+                .generate_compaction_task(snapshot)
+                .map(CompactionTask::Fifo),
+            // the tiers are found by their position, like in tiered compaction
+            CompactionController::TimeWindow(_) if !busy_ssts.is_empty() => None,
+            CompactionController::TimeWindow(ctrl) => ctrl
+                .generate_compaction_task(snapshot)
+                .map(CompactionTask::TimeWindow),
+            // the sorted runs of a level are merged together, so one task runs at a time
+            CompactionController::LazyLeveling(_) if !busy_ssts.is_empty() => None,
+            CompactionController::LazyLeveling(ctrl) => ctrl
+                .generate_compaction_task(snapshot)
+                .map(CompactionTask::LazyLeveling),
             CompactionController::NoCompaction => unreachable!(),
         }
     }
@@ -84,6 +328,32 @@ impl CompactionController {
             (CompactionController::Tiered(ctrl), CompactionTask::Tiered(task)) => {
                 ctrl.apply_compaction_result(snapshot, task, output)
             }
+            (CompactionController::Fifo(ctrl), CompactionTask::Fifo(task)) => {
+                ctrl.apply_compaction_result(snapshot, task, output)
+            }
+            (CompactionController::TimeWindow(ctrl), CompactionTask::TimeWindow(task)) => {
+                ctrl.apply_compaction_result(snapshot, task, output)
+            }
+            (CompactionController::LazyLeveling(ctrl), CompactionTask::LazyLeveling(task)) => {
+                ctrl.apply_compaction_result(snapshot, task, output)
+            }
+            // replayed from the manifest, see `force_full_compaction`
+            (
+                CompactionController::NoCompaction,
+                CompactionTask::ForceFullCompaction {
+                    l0_sstables,
+                    l1_sstables,
+                },
+            ) => {
+                let mut snapshot = snapshot.clone();
+                let mut l0_sstables_map = l0_sstables.iter().copied().collect::<HashSet<_>>();
+                snapshot
+                    .l0_sstables
+                    .retain(|sst| !l0_sstables_map.remove(sst));
+                snapshot.levels[0].1 = output.to_vec();
+                let files_to_remove = l0_sstables.iter().chain(l1_sstables).copied().collect();
+                (snapshot, files_to_remove)
+            }
             _ => unreachable!(),
         }
     }
@@ -93,12 +363,16 @@ impl CompactionController {
     pub fn flush_to_l0(&self) -> bool {
         matches!(
             self,
-            Self::Leveled(_) | Self::Simple(_) | Self::NoCompaction
+            Self::Leveled(_)
+                | Self::Simple(_)
+                | Self::Fifo(_)
+                | Self::LazyLeveling(_)
+                | Self::NoCompaction
         )
     }
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub enum CompactionOptions {
     /// Leveled compaction with partial compaction + dynamic level support (= RocksDB's Leveled
     /// Compaction)
@@ -107,25 +381,80 @@ pub enum CompactionOptions {
     Tiered(TieredCompactionOptions),
     /// Simple leveled compaction
     Simple(SimpleLeveledCompactionOptions),
+    /// Drop the oldest L0 SSTs, without ever rewriting them (= RocksDB's FIFO compaction)
+    Fifo(FifoCompactionOptions),
+    /// Tiered compaction within time windows, which expire whole (= Cassandra's TWCS)
+    TimeWindow(TimeWindowCompactionOptions),
+    /// Tiered upper levels over a leveled bottom level (= Dostoevsky's lazy leveling)
+    LazyLeveling(LazyLevelingCompactionOptions),
     /// In no compaction mode (week 1), always flush to L0
     NoCompaction,
 }
 
+impl CompactionOptions {
+    /// Check that a storage compacted with the `persisted` options can be opened with these ones.
+    /// The compaction strategy and the number of levels cannot change, as the LSM state in the
+    /// manifest is shaped by them. Use `MiniLsm::change_compaction_strategy` to migrate instead.
+    pub fn check_compatible_with(&self, persisted: &Self) -> Result<()> {
+        let compatible = match (persisted, self) {
+            (Self::Leveled(persisted), Self::Leveled(options)) => {
+                persisted.max_levels == options.max_levels
+            }
+            (Self::Simple(persisted), Self::Simple(options)) => {
+                persisted.max_levels == options.max_levels
+            }
+            (Self::LazyLeveling(persisted), Self::LazyLeveling(options)) => {
+                persisted.max_levels == options.max_levels
+            }
+            (Self::Tiered(_), Self::Tiered(_))
+            | (Self::Fifo(_), Self::Fifo(_))
+            | (Self::TimeWindow(_), Self::TimeWindow(_))
+            | (Self::NoCompaction, Self::NoCompaction) => true,
+            _ => false,
+        };
+        if !compatible {
+            bail!(
+                "incompatible compaction options: the storage is compacted with {:?}, but is opened with {:?}",
+                persisted,
+                self
+            );
+        }
+        Ok(())
+    }
+}
+
 impl LsmStorageInner {
     fn compact_generate_sst_from_iter(
         &self,
         mut iter: impl for<'a> StorageIterator<KeyType<'a> = KeySlice<'a>>,
-        compact_to_bottom_level: bool,
+        context: &CompactionFilterContext,
+        data_written_at: u64,
+        grandparents: &[Arc<SsTable>],
+        upper: Option<&[u8]>,
     ) -> Result<Vec<Arc<SsTable>>> {
-        let mut builder = None;
+        let mut builder: Option<SsTableBuilder> = None;
         let mut new_sst = Vec::new();
         let watermark = self.mvcc().watermark();
         let mut last_key = Vec::<u8>::new();
         let mut first_key_below_watermark = false;
+        let max_grandparent_overlap = self.options.target_sst_size as u64
+            * self.options.max_grandparent_overlap_factor as u64;
+        // the grandparents before `grandparent_idx` are behind the current key, and the output SST
+        // being built overlaps `grandparent_overlap` bytes of them
+        let mut grandparent_idx = 0;
+        let mut grandparent_overlap = 0;
         let compaction_filters = self.compaction_filters.lock().clone();
+        let mut custom_filters = Vec::new();
+        for filter in &compaction_filters {
+            if let CompactionFilter::Custom(factory) = filter {
+                custom_filters.push(factory.create_compaction_filter(context));
+            }
+        }
         'outer: while iter.is_valid() {
-            if builder.is_none() {
-                builder = Some(SsTableBuilder::new(self.options.block_size));
+            if let Some(upper) = upper {
+                if iter.key().key_ref() >= upper {
+                    break;
+                }
             }
 
             let same_as_last_key = iter.key().key_ref() == last_key;
@@ -133,7 +462,7 @@ impl LsmStorageInner {
                 first_key_below_watermark = true;
             }
 
-            if compact_to_bottom_level
+            if context.is_bottom_level
                 && !same_as_last_key
                 && iter.key().ts() <= watermark
                 && iter.value().is_empty()
@@ -145,6 +474,8 @@ impl LsmStorageInner {
                 continue;
             }
 
+            // the value rewritten by the custom filters
+            let mut new_value: Option<Bytes> = None;
             if iter.key().ts() <= watermark {
                 if same_as_last_key && !first_key_below_watermark {
                     iter.next()?;
@@ -162,14 +493,55 @@ impl LsmStorageInner {
                                     continue 'outer;
                                 }
                             }
+                            CompactionFilter::Custom(_) => {}
                         }
                     }
                 }
-            }
 
-            let builder_inner = builder.as_mut().unwrap();
+                for filter in &mut custom_filters {
+                    let value = match &new_value {
+                        Some(value) => &value[..],
+                        None => iter.value(),
+                    };
+                    if value.is_empty() {
+                        break;
+                    }
+                    match filter.filter(iter.key().key_ref(), iter.key().ts(), value) {
+                        CompactionFilterDecision::Keep => {}
+                        CompactionFilterDecision::Remove => new_value = Some(Bytes::new()),
+                        CompactionFilterDecision::ChangeValue(value) => new_value = Some(value),
+                    }
+                }
+
+                // the older versions are skipped as well, so that the filters never see them
+                let removed = matches!(&new_value, Some(value) if value.is_empty());
+                if context.is_bottom_level && removed {
+                    last_key.clear();
+                    last_key.extend(iter.key().key_ref());
+                    iter.next()?;
+                    continue;
+                }
+            }
 
-            if builder_inner.estimated_size() >= self.options.target_sst_size && !same_as_last_key {
+            let mut builder_full = match &builder {
+                Some(builder) => builder.estimated_size() >= self.options.target_sst_size,
+                None => false,
+            };
+            // also cut the output once it overlaps too many bytes of the grandparents
+            if max_grandparent_overlap > 0 && !same_as_last_key {
+                while let Some(sst) = grandparents.get(grandparent_idx) {
+                    if sst.last_key().key_ref() >= iter.key().key_ref() {
+                        break;
+                    }
+                    if builder.is_some() {
+                        grandparent_overlap += sst.table_size();
+                    }
+                    grandparent_idx += 1;
+                }
+                builder_full |= grandparent_overlap >= max_grandparent_overlap;
+            }
+            if builder_full && !same_as_last_key {
+                grandparent_overlap = 0;
                 let sst_id = self.next_sst_id();
                 let old_builder = builder.take().unwrap();
                 let sst = Arc::new(old_builder.build(
@@ -178,11 +550,19 @@ impl LsmStorageInner {
                     self.path_of_sst(sst_id),
                 )?);
                 new_sst.push(sst);
-                builder = Some(SsTableBuilder::new(self.options.block_size));
             }
 
+            // created on the first key kept, so that no empty SST is built when all keys are dropped
+            if builder.is_none() {
+                let mut new_builder = SsTableBuilder::new(self.options.block_size);
+                new_builder.set_data_written_at(data_written_at);
+                builder = Some(new_builder);
+            }
             let builder_inner = builder.as_mut().unwrap();
-            builder_inner.add(iter.key(), iter.value());
+            match &new_value {
+                Some(value) => builder_inner.add(iter.key(), value),
+                None => builder_inner.add(iter.key(), iter.value()),
+            }
 
             if !same_as_last_key {
                 last_key.clear();
@@ -204,30 +584,151 @@ impl LsmStorageInner {
     }
 
     fn compact(&self, task: &CompactionTask) -> Result<Vec<Arc<SsTable>>> {
+        // fifo compaction and expired time windows drop the input SSTs whole
+        if let CompactionTask::Fifo(_)
+        | CompactionTask::TimeWindow(TimeWindowCompactionTask { expired: true, .. }) = task
+        {
+            return Ok(Vec::new());
+        }
         let snapshot = {
             let state = self.state.read();
             state.clone()
         };
+        let boundaries = self.subcompaction_boundaries(&snapshot, task);
+        if boundaries.is_empty() {
+            return self.compact_range(&snapshot, task, None, None);
+        }
+        println!(
+            "splitting compaction into {} subcompactions at {:?}",
+            boundaries.len() + 1,
+            boundaries
+        );
+        // partition i covers the keys in [boundaries[i - 1], boundaries[i])
+        let lower_bounds = std::iter::once(None).chain(boundaries.iter().map(|key| Some(&key[..])));
+        let upper_bounds = boundaries
+            .iter()
+            .map(|key| Some(&key[..]))
+            .chain(std::iter::once(None));
+        std::thread::scope(|scope| {
+            let handles = lower_bounds
+                .zip(upper_bounds)
+                .map(|(lower, upper)| {
+                    let snapshot = &snapshot;
+                    scope.spawn(move || self.compact_range(snapshot, task, lower, upper))
+                })
+                .collect::<Vec<_>>();
+            let mut output = Vec::new();
+            for handle in handles {
+                let ssts = handle
+                    .join()
+                    .map_err(|e| anyhow::anyhow!("subcompaction panicked: {:?}", e))??;
+                output.extend(ssts);
+            }
+            Ok(output)
+        })
+    }
+
+    /// Pick the keys at which `task` is split into subcompactions, among the first keys of its input
+    /// SSTs. Each subcompaction gets at least `target_sst_size` bytes of input on average, and there
+    /// are at most `max_subcompactions` of them.
+    fn subcompaction_boundaries(
+        &self,
+        snapshot: &LsmStorageState,
+        task: &CompactionTask,
+    ) -> Vec<Bytes> {
+        if self.options.max_subcompactions <= 1 {
+            return Vec::new();
+        }
+        let ssts = task
+            .input_sst_ids()
+            .into_iter()
+            .map(|id| snapshot.sstables[&id].clone())
+            .collect::<Vec<_>>();
+        let input_size = ssts.iter().map(|sst| sst.table_size()).sum::<u64>();
+        let Some(min_key) = ssts.iter().map(|sst| sst.first_key().key_ref()).min() else {
+            return Vec::new();
+        };
+        let mut candidates = ssts
+            .iter()
+            .map(|sst| sst.first_key().key_ref())
+            .filter(|key| *key > min_key)
+            .collect::<Vec<_>>();
+        candidates.sort();
+        candidates.dedup();
+        let partitions = self
+            .options
+            .max_subcompactions
+            .min((input_size / self.options.target_sst_size as u64) as usize)
+            .min(candidates.len() + 1);
+        let mut boundaries = (1..partitions)
+            .map(|i| Bytes::copy_from_slice(candidates[i * candidates.len() / partitions]))
+            .collect::<Vec<_>>();
+        boundaries.dedup();
+        boundaries
+    }
+
+    /// Compact the keys of `task` in `[lower, upper)`, or all keys if the bounds are `None`. All
+    /// versions of a key are in the same range.
+    fn compact_range(
+        &self,
+        snapshot: &LsmStorageState,
+        task: &CompactionTask,
+        lower: Option<&[u8]>,
+        upper: Option<&[u8]>,
+    ) -> Result<Vec<Arc<SsTable>>> {
+        let sst_iter = |id: &usize| -> Result<Box<SsTableIterator>> {
+            let sst = snapshot.sstables.get(id).unwrap().clone();
+            let iter = match lower {
+                Some(key) => SsTableIterator::create_and_seek_to_key(
+                    sst,
+                    KeySlice::from_slice(key, TS_RANGE_BEGIN),
+                )?,
+                None => SsTableIterator::create_and_seek_to_first(sst)?,
+            };
+            Ok(Box::new(iter))
+        };
+        let concat_iter = |ids: &[usize]| -> Result<SstConcatIterator> {
+            let ssts = ids
+                .iter()
+                .map(|id| snapshot.sstables.get(id).unwrap().clone())
+                .collect::<Vec<_>>();
+            match lower {
+                Some(key) => SstConcatIterator::create_and_seek_to_key(
+                    ssts,
+                    KeySlice::from_slice(key, TS_RANGE_BEGIN),
+                ),
+                None => SstConcatIterator::create_and_seek_to_first(ssts),
+            }
+        };
+        let data_written_at = newest_data_written_at(snapshot, &task.input_sst_ids());
+        // in the key order, without the ones compacted by another task since the task was generated
+        let mut grandparents = task
+            .grandparent_sst_ids()
+            .iter()
+            .filter_map(|id| snapshot.sstables.get(id).cloned())
+            .collect::<Vec<_>>();
+        grandparents.sort_by(|a, b| a.first_key().cmp(b.first_key()));
+        let context = CompactionFilterContext {
+            is_bottom_level: task.compact_to_bottom_level(),
+            output_level: task.output_level(),
+        };
         match task {
             CompactionTask::ForceFullCompaction {
                 l0_sstables,
                 l1_sstables,
             } => {
-                let mut l0_iters = Vec::with_capacity(l0_sstables.len());
-                for id in l0_sstables.iter() {
-                    l0_iters.push(Box::new(SsTableIterator::create_and_seek_to_first(
-                        snapshot.sstables.get(id).unwrap().clone(),
-                    )?));
-                }
-                let mut l1_iters = Vec::with_capacity(l1_sstables.len());
-                for id in l1_sstables.iter() {
-                    l1_iters.push(snapshot.sstables.get(id).unwrap().clone());
-                }
+                let l0_iters = l0_sstables.iter().map(sst_iter).collect::<Result<_>>()?;
                 let iter = TwoMergeIterator::create(
                     MergeIterator::create(l0_iters),
-                    SstConcatIterator::create_and_seek_to_first(l1_iters)?,
+                    concat_iter(l1_sstables)?,
                 )?;
-                self.compact_generate_sst_from_iter(iter, task.compact_to_bottom_level())
+                self.compact_generate_sst_from_iter(
+                    iter,
+                    &context,
+                    data_written_at,
+                    &grandparents,
+                    upper,
+                )
             }
             CompactionTask::Simple(SimpleLeveledCompactionTask {
                 upper_level,
@@ -243,63 +744,77 @@ impl LsmStorageInner {
                 lower_level_sst_ids,
                 ..
             }) => match upper_level {
-                Some(_) => {
-                    let mut upper_ssts = Vec::with_capacity(upper_level_sst_ids.len());
-                    for id in upper_level_sst_ids.iter() {
-                        upper_ssts.push(snapshot.sstables.get(id).unwrap().clone());
-                    }
-                    let upper_iter = SstConcatIterator::create_and_seek_to_first(upper_ssts)?;
-                    let mut lower_ssts = Vec::with_capacity(upper_level_sst_ids.len());
-                    for id in lower_level_sst_ids.iter() {
-                        lower_ssts.push(snapshot.sstables.get(id).unwrap().clone());
-                    }
-                    let lower_iter = SstConcatIterator::create_and_seek_to_first(lower_ssts)?;
-                    self.compact_generate_sst_from_iter(
-                        TwoMergeIterator::create(upper_iter, lower_iter)?,
-                        task.compact_to_bottom_level(),
-                    )
-                }
+                Some(_) => self.compact_generate_sst_from_iter(
+                    TwoMergeIterator::create(
+                        concat_iter(upper_level_sst_ids)?,
+                        concat_iter(lower_level_sst_ids)?,
+                    )?,
+                    &context,
+                    data_written_at,
+                    &grandparents,
+                    upper,
+                ),
                 None => {
-                    let mut upper_iters = Vec::with_capacity(upper_level_sst_ids.len());
-                    for id in upper_level_sst_ids.iter() {
-                        upper_iters.push(Box::new(SsTableIterator::create_and_seek_to_first(
-                            snapshot.sstables.get(id).unwrap().clone(),
-                        )?));
-                    }
-                    let upper_iter = MergeIterator::create(upper_iters);
-                    let mut lower_ssts = Vec::with_capacity(upper_level_sst_ids.len());
-                    for id in lower_level_sst_ids.iter() {
-                        lower_ssts.push(snapshot.sstables.get(id).unwrap().clone());
-                    }
-                    let lower_iter = SstConcatIterator::create_and_seek_to_first(lower_ssts)?;
+                    let upper_iters = upper_level_sst_ids
+                        .iter()
+                        .map(sst_iter)
+                        .collect::<Result<_>>()?;
                     self.compact_generate_sst_from_iter(
-                        TwoMergeIterator::create(upper_iter, lower_iter)?,
-                        task.compact_to_bottom_level(),
+                        TwoMergeIterator::create(
+                            MergeIterator::create(upper_iters),
+                            concat_iter(lower_level_sst_ids)?,
+                        )?,
+                        &context,
+                        data_written_at,
+                        &grandparents,
+                        upper,
                     )
                 }
             },
-            CompactionTask::Tiered(TieredCompactionTask { tiers, .. }) => {
-                let mut iters = Vec::with_capacity(tiers.len());
-                for (_, tier_sst_ids) in tiers {
-                    let mut ssts = Vec::with_capacity(tier_sst_ids.len());
-                    for id in tier_sst_ids.iter() {
-                        ssts.push(snapshot.sstables.get(id).unwrap().clone());
-                    }
-                    iters.push(Box::new(SstConcatIterator::create_and_seek_to_first(ssts)?));
-                }
+            CompactionTask::LazyLeveling(LazyLevelingCompactionTask {
+                upper_level_runs,
+                lower_level_sst_ids,
+                ..
+            }) => {
+                let upper_iters = upper_level_runs
+                    .iter()
+                    .map(|run| Ok(Box::new(concat_iter(run)?)))
+                    .collect::<Result<_>>()?;
+                self.compact_generate_sst_from_iter(
+                    TwoMergeIterator::create(
+                        MergeIterator::create(upper_iters),
+                        concat_iter(lower_level_sst_ids)?,
+                    )?,
+                    &context,
+                    data_written_at,
+                    &grandparents,
+                    upper,
+                )
+            }
+            CompactionTask::Tiered(TieredCompactionTask { tiers, .. })
+            | CompactionTask::TimeWindow(TimeWindowCompactionTask { tiers, .. }) => {
+                let iters = tiers
+                    .iter()
+                    .map(|(_, tier_sst_ids)| Ok(Box::new(concat_iter(tier_sst_ids)?)))
+                    .collect::<Result<_>>()?;
                 self.compact_generate_sst_from_iter(
                     MergeIterator::create(iters),
-                    task.compact_to_bottom_level(),
+                    &context,
+                    data_written_at,
+                    &grandparents,
+                    upper,
                 )
             }
+            CompactionTask::Fifo(_) => unreachable!("fifo compaction does not write SSTs"),
         }
     }
 
     pub fn force_full_compaction(&self) -> Result<()> {
-        let CompactionOptions::NoCompaction = self.options.compaction_options else {
+        let CompactionController::NoCompaction = *self.compaction_controller() else {
             panic!("full compaction can only be called with compaction is not enabled")
         };
 
+        let _compaction_lock = self.compaction_lock.write();
         let snapshot = {
             let state = self.state.read();
             state.clone()
@@ -315,6 +830,10 @@ impl LsmStorageInner {
         println!("force full compaction: {:?}", compaction_task);
 
         let sstables = self.compact(&compaction_task)?;
+        let metadata = sstables
+            .iter()
+            .map(|sst| SstMetadata::from_sst(sst))
+            .collect::<Vec<_>>();
         let mut ids = Vec::with_capacity(sstables.len());
 
         {
@@ -341,9 +860,9 @@ impl LsmStorageInner {
             assert!(l0_sstables_map.is_empty());
             *self.state.write() = Arc::new(state);
             self.sync_dir()?;
-            self.manifest.as_ref().unwrap().add_record(
+            self.add_manifest_record(
                 &state_lock,
-                ManifestRecord::Compaction(compaction_task, ids.clone()),
+                ManifestRecord::Compaction(compaction_task, metadata),
             )?;
         }
         for sst in l0_sstables.iter().chain(l1_sstables.iter()) {
@@ -355,35 +874,269 @@ impl LsmStorageInner {
         Ok(())
     }
 
-    fn trigger_compaction(&self) -> Result<()> {
+    /// Compact all SSTs into a single sorted run, laid out in the shape the new compaction strategy
+    /// expects, and switch to the new strategy.
+    pub(crate) fn change_compaction_strategy(
+        &self,
+        compaction_options: CompactionOptions,
+    ) -> Result<()> {
+        let _compaction_lock = self.compaction_lock.write();
         let snapshot = {
             let state = self.state.read();
             state.clone()
         };
-        let task = self
-            .compaction_controller
-            .generate_compaction_task(&snapshot);
-        let Some(task) = task else {
-            return Ok(());
+        println!("changing compaction strategy to {:?}", compaction_options);
+
+        // from latest to earliest; each L0 SST is a sorted run on its own
+        let mut iters = Vec::new();
+        let mut inputs = Vec::new();
+        for sorted_run in snapshot
+            .l0_sstables
+            .iter()
+            .map(std::slice::from_ref)
+            .chain(snapshot.levels.iter().map(|(_, ssts)| ssts.as_slice()))
+        {
+            if sorted_run.is_empty() {
+                continue;
+            }
+            inputs.extend_from_slice(sorted_run);
+            let ssts = sorted_run
+                .iter()
+                .map(|id| snapshot.sstables[id].clone())
+                .collect();
+            iters.push(Box::new(SstConcatIterator::create_and_seek_to_first(ssts)?));
+        }
+        // the output is the only sorted run of the new strategy
+        let context = CompactionFilterContext {
+            is_bottom_level: true,
+            output_level: match &compaction_options {
+                CompactionOptions::Leveled(LeveledCompactionOptions { max_levels, .. })
+                | CompactionOptions::Simple(SimpleLeveledCompactionOptions {
+                    max_levels, ..
+                })
+                | CompactionOptions::LazyLeveling(LazyLevelingCompactionOptions {
+                    max_levels,
+                    ..
+                }) => Some(*max_levels),
+                CompactionOptions::Tiered(_) | CompactionOptions::TimeWindow(_) => None,
+                CompactionOptions::Fifo(_) => Some(0),
+                CompactionOptions::NoCompaction => Some(1),
+            },
         };
+        let sstables = self.compact_generate_sst_from_iter(
+            MergeIterator::create(iters),
+            &context,
+            newest_data_written_at(&snapshot, &inputs),
+            &[],
+            None,
+        )?;
+        let output = sstables.iter().map(|x| x.sst_id()).collect::<Vec<_>>();
+        let metadata = sstables
+            .iter()
+            .map(|sst| SstMetadata::from_sst(sst))
+            .collect::<Vec<_>>();
+
+        {
+            let state_lock = self.state_lock.lock();
+            let mut state = self.state.read().as_ref().clone();
+            let compacted = inputs.iter().copied().collect::<HashSet<_>>();
+            // memtables flushed while compacting, from latest to earliest
+            let flushed = state
+                .l0_sstables
+                .iter()
+                .chain(state.levels.iter().flat_map(|(_, ssts)| ssts))
+                .filter(|id| !compacted.contains(id))
+                .copied()
+                .collect::<Vec<_>>();
+            let (l0_sstables, levels) = match &compaction_options {
+                CompactionOptions::Leveled(LeveledCompactionOptions { max_levels, .. })
+                | CompactionOptions::Simple(SimpleLeveledCompactionOptions {
+                    max_levels, ..
+                }) => {
+                    let levels = (1..=*max_levels)
+                        .map(|level| {
+                            let ssts = if level == *max_levels {
+                                output.clone()
+                            } else {
+                                Vec::new()
+                            };
+                            (level, ssts)
+                        })
+                        .collect();
+                    (flushed, levels)
+                }
+                CompactionOptions::Tiered(_) | CompactionOptions::TimeWindow(_) => {
+                    let levels = flushed
+                        .iter()
+                        .map(|id| (*id, vec![*id]))
+                        .chain(output.first().map(|id| (*id, output.clone())))
+                        .collect();
+                    (Vec::new(), levels)
+                }
+                CompactionOptions::LazyLeveling(LazyLevelingCompactionOptions {
+                    max_levels,
+                    ..
+                }) => (flushed, vec![(*max_levels, output.clone())]),
+                CompactionOptions::Fifo(_) => (
+                    flushed.into_iter().chain(output.iter().copied()).collect(),
+                    Vec::new(),
+                ),
+                CompactionOptions::NoCompaction => (flushed, vec![(1, output.clone())]),
+            };
+            for id in &inputs {
+                let result = state.sstables.remove(id);
+                assert!(result.is_some());
+            }
+            for sst in sstables {
+                let result = state.sstables.insert(sst.sst_id(), sst);
+                assert!(result.is_none());
+            }
+            state.l0_sstables = l0_sstables.clone();
+            state.levels = levels.clone();
+            *self.compaction_controller.write() = Arc::new(
+                CompactionController::new(&compaction_options).with_storage_options(&self.options),
+            );
+            *self.state.write() = Arc::new(state);
+            self.sync_dir()?;
+            self.add_manifest_record(
+                &state_lock,
+                ManifestRecord::ChangeCompactionStrategy {
+                    compaction_options: compaction_options.clone(),
+                    l0_sstables,
+                    levels,
+                    output: metadata,
+                },
+            )?;
+            Self::write_options(
+                &self.path,
+                &LsmStorageOptions {
+                    compaction_options,
+                    ..self.options.as_ref().clone()
+                },
+            )?;
+        }
+        for id in &inputs {
+            std::fs::remove_file(self.path_of_sst(*id))?;
+        }
+        self.sync_dir()?;
+
+        println!("compaction strategy changed, new SSTs: {:?}", output);
+
+        Ok(())
+    }
+
+    /// Generate a task that does not conflict with the running ones, and mark its SSTs busy until
+    /// `run_compaction_task` completes it.
+    fn pick_compaction_task(&self) -> Option<CompactionTask> {
+        let compaction_controller = self.compaction_controller();
+        if let CompactionController::NoCompaction = *compaction_controller {
+            return None;
+        }
+        let mut running = self.running_compactions.lock();
+        let busy_ssts = running
+            .iter()
+            .flat_map(|task| task.input_sst_ids())
+            .collect::<HashSet<_>>();
+        let snapshot = {
+            let state = self.state.read();
+            state.clone()
+        };
+        let task = compaction_controller.generate_compaction_task(&snapshot, &busy_ssts)?;
+        running.push(task.clone());
+        Some(task)
+    }
+
+    /// Return the input SSTs in key order if `task` can be done by moving them to the lower level
+    /// without rewriting them, i.e., when the upper SSTs do not overlap each other nor any SST in the
+    /// lower level. Moved SSTs keep their deleted keys and old versions until they are compacted
+    /// again, so SSTs with tombstones are rewritten when compacted to the bottom level, as are the
+    /// SSTs that `SstCompactionTriggers` rewrites in place.
+    fn trivial_move_ssts(
+        snapshot: &LsmStorageState,
+        task: &CompactionTask,
+    ) -> Option<Vec<Arc<SsTable>>> {
+        let upper_level_sst_ids = match task {
+            CompactionTask::Leveled(LeveledCompactionTask {
+                upper_level,
+                upper_level_sst_ids,
+                lower_level,
+                lower_level_sst_ids,
+                ..
+            })
+            | CompactionTask::Simple(SimpleLeveledCompactionTask {
+                upper_level,
+                upper_level_sst_ids,
+                lower_level,
+                lower_level_sst_ids,
+                ..
+            }) if lower_level_sst_ids.is_empty() && *upper_level != Some(*lower_level) => {
+                upper_level_sst_ids
+            }
+            _ => return None,
+        };
+        let mut ssts = upper_level_sst_ids
+            .iter()
+            .map(|id| snapshot.sstables[id].clone())
+            .collect::<Vec<_>>();
+        if ssts.is_empty()
+            || task.compact_to_bottom_level()
+                && ssts.iter().any(|sst| sst.stats().num_tombstones > 0)
+        {
+            return None;
+        }
+        ssts.sort_by(|x, y| x.first_key().cmp(y.first_key()));
+        for pair in ssts.windows(2) {
+            if pair[0].last_key().key_ref() >= pair[1].first_key().key_ref() {
+                return None;
+            }
+        }
+        Some(ssts)
+    }
+
+    /// Run a task returned by `pick_compaction_task`, and apply its result to the current state,
+    /// which may have changed since the task was generated.
+    fn run_compaction_task(&self, task: &CompactionTask) -> Result<()> {
+        let result = self.compact_and_apply(task);
+        // the SSTs are released only once they are removed from the state, if the task succeeded
+        let input_sst_ids = task.input_sst_ids();
+        self.running_compactions
+            .lock()
+            .retain(|running| running.input_sst_ids() != input_sst_ids);
+        result
+    }
+
+    fn compact_and_apply(&self, task: &CompactionTask) -> Result<()> {
+        let compaction_controller = self.compaction_controller();
         self.dump_structure();
         println!("running compaction task: {:?}", task);
-        let sstables = self.compact(&task)?;
+        let snapshot = {
+            let state = self.state.read();
+            state.clone()
+        };
+        let (sstables, trivial_move) = match Self::trivial_move_ssts(&snapshot, task) {
+            Some(sstables) => {
+                println!("trivial move: {:?}", task.input_sst_ids());
+                (sstables, true)
+            }
+            None => (self.compact(task)?, false),
+        };
         let output = sstables.iter().map(|x| x.sst_id()).collect::<Vec<_>>();
+        let metadata = sstables
+            .iter()
+            .map(|sst| SstMetadata::from_sst(sst))
+            .collect::<Vec<_>>();
         let ssts_to_remove = {
             let state_lock = self.state_lock.lock();
             let mut snapshot = self.state.read().as_ref().clone();
-            let mut new_sst_ids = Vec::new();
             for file_to_add in sstables {
-                new_sst_ids.push(file_to_add.sst_id());
                 let result = snapshot.sstables.insert(file_to_add.sst_id(), file_to_add);
-                assert!(result.is_none());
+                assert!(result.is_none() || trivial_move);
             }
-            let (mut snapshot, files_to_remove) = self
-                .compaction_controller
-                .apply_compaction_result(&snapshot, &task, &output);
+            let (mut snapshot, files_to_remove) =
+                compaction_controller.apply_compaction_result(&snapshot, task, &output);
             let mut ssts_to_remove = Vec::with_capacity(files_to_remove.len());
-            for file_to_remove in &files_to_remove {
+            // the moved SSTs are both inputs and outputs
+            for file_to_remove in files_to_remove.iter().filter(|id| !output.contains(id)) {
                 let result = snapshot.sstables.remove(file_to_remove);
                 assert!(result.is_some(), "cannot remove {}.sst", file_to_remove);
                 ssts_to_remove.push(result.unwrap());
@@ -392,8 +1145,10 @@ impl LsmStorageInner {
             *state = Arc::new(snapshot);
             drop(state);
             self.sync_dir()?;
-            self.manifest()
-                .add_record(&state_lock, ManifestRecord::Compaction(task, new_sst_ids))?;
+            self.add_manifest_record(
+                &state_lock,
+                ManifestRecord::Compaction(task.clone(), metadata),
+            )?;
             ssts_to_remove
         };
         println!(
@@ -410,29 +1165,46 @@ impl LsmStorageInner {
         Ok(())
     }
 
+    pub(crate) fn trigger_compaction(&self) -> Result<()> {
+        // shared with the other workers, but not with the operations that need all compactions to
+        // be stopped
+        let _compaction_lock = self.compaction_lock.read();
+        let Some(task) = self.pick_compaction_task() else {
+            return Ok(());
+        };
+        self.run_compaction_task(&task)
+    }
+
     pub(crate) fn spawn_compaction_thread(
         self: &Arc<Self>,
         rx: crossbeam_channel::Receiver<()>,
     ) -> Result<Option<std::thread::JoinHandle<()>>> {
-        if let CompactionOptions::Leveled(_)
-        | CompactionOptions::Simple(_)
-        | CompactionOptions::Tiered(_) = self.options.compaction_options
-        {
-            let this = self.clone();
-            let handle = std::thread::spawn(move || {
-                let ticker = crossbeam_channel::tick(Duration::from_millis(50));
-                loop {
-                    crossbeam_channel::select! {
-                        recv(ticker) -> _ => if let Err(e) = this.trigger_compaction() {
-                            eprintln!("compaction failed: {}", e);
-                        },
-                        recv(rx) -> _ => return
-                    }
+        // spawned even without compaction, which `change_compaction_strategy` can enable later
+        let this = self.clone();
+        let handle = std::thread::spawn(move || {
+            // the workers stop when `stop_tx` is dropped
+            let (stop_tx, stop_rx) = crossbeam_channel::bounded::<()>(0);
+            std::thread::scope(|scope| {
+                for _ in 0..this.options.max_background_compactions.max(1) {
+                    let this = &this;
+                    let stop_rx = stop_rx.clone();
+                    scope.spawn(move || {
+                        let ticker = crossbeam_channel::tick(Duration::from_millis(50));
+                        loop {
+                            crossbeam_channel::select! {
+                                recv(ticker) -> _ => if let Err(e) = this.trigger_compaction() {
+                                    eprintln!("compaction failed: {}", e);
+                                },
+                                recv(stop_rx) -> _ => return
+                            }
+                        }
+                    });
                 }
+                rx.recv().ok();
+                drop(stop_tx);
             });
-            return Ok(Some(handle));
-        }
-        Ok(None)
+        });
+        Ok(Some(handle))
     }
 
     fn trigger_flush(&self) -> Result<()> {
@@ -441,7 +1213,12 @@ impl LsmStorageInner {
             state.imm_memtables.len() >= self.options.num_memtable_limit
         };
         if res {
-            self.force_flush_next_imm_memtable()?;
+            let state_lock = self.state_lock.lock();
+            // the memtables could have already been flushed by `force_flush_next_imm_memtable`,
+            // check again to ensure we really need to flush
+            if self.state.read().imm_memtables.len() >= self.options.num_memtable_limit {
+                self.flush_next_imm_memtable(&state_lock)?;
+            }
         }
 
         Ok(())
@@ -0,0 +1,85 @@
+use std::collections::HashSet;
+
+use serde::{Deserialize, Serialize};
+
+use super::unix_now;
+use crate::lsm_storage::LsmStorageState;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct FifoCompactionTask {
+    // the L0 SSTs to drop, from the oldest
+    pub ssts: Vec<usize>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct FifoCompactionOptions {
+    // drop the oldest SSTs once all SSTs take more than this many bytes
+    pub max_table_files_size: u64,
+    // drop the SSTs built this many seconds ago, or never if 0
+    pub ttl_seconds: u64,
+}
+
+/// Keeps all flushed SSTs in L0, and drops the oldest ones whole once they take too much space or
+/// are too old. The data is never rewritten, which suits data with bounded retention, e.g., logs.
+pub struct FifoCompactionController {
+    options: FifoCompactionOptions,
+}
+
+impl FifoCompactionController {
+    pub fn new(options: FifoCompactionOptions) -> Self {
+        Self { options }
+    }
+
+    pub fn options(&self) -> &FifoCompactionOptions {
+        &self.options
+    }
+
+    pub fn generate_compaction_task(
+        &self,
+        snapshot: &LsmStorageState,
+    ) -> Option<FifoCompactionTask> {
+        let now = unix_now();
+        let mut total_size = snapshot
+            .l0_sstables
+            .iter()
+            .map(|id| snapshot.sstables[id].table_size())
+            .sum::<u64>();
+        let mut ssts = Vec::new();
+        // L0 SSTs are ordered from the latest to the earliest flushed
+        for id in snapshot.l0_sstables.iter().rev() {
+            let sst = &snapshot.sstables[id];
+            // the age of SSTs written before it was recorded is unknown
+            let created_at = sst.stats().created_at;
+            let expired = self.options.ttl_seconds > 0
+                && created_at > 0
+                && now.saturating_sub(created_at) >= self.options.ttl_seconds;
+            if !expired && total_size <= self.options.max_table_files_size {
+                break;
+            }
+            ssts.push(*id);
+            total_size -= sst.table_size();
+        }
+        if ssts.is_empty() {
+            return None;
+        }
+        println!(
+            "compaction triggered by fifo: drop {:?}, {} bytes left",
+            ssts, total_size
+        );
+        Some(FifoCompactionTask { ssts })
+    }
+
+    pub fn apply_compaction_result(
+        &self,
+        snapshot: &LsmStorageState,
+        task: &FifoCompactionTask,
+        output: &[usize],
+    ) -> (LsmStorageState, Vec<usize>) {
+        assert!(output.is_empty(), "fifo compaction does not write SSTs");
+        let mut snapshot = snapshot.clone();
+        let mut ssts = task.ssts.iter().copied().collect::<HashSet<_>>();
+        snapshot.l0_sstables.retain(|id| !ssts.remove(id));
+        assert!(ssts.is_empty(), "sst mismatched");
+        (snapshot, task.ssts.clone())
+    }
+}
@@ -0,0 +1,158 @@
+use std::collections::HashSet;
+
+use serde::{Deserialize, Serialize};
+
+use crate::lsm_storage::LsmStorageState;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct LazyLevelingCompactionTask {
+    // if upper_level is `None`, then it is L0 compaction, where each SST is a sorted run
+    pub upper_level: Option<usize>,
+    // the sorted runs of the upper level, from the latest
+    pub upper_level_runs: Vec<Vec<usize>>,
+    pub lower_level: usize,
+    // empty unless the lower level is the bottom level, whose only sorted run is rewritten
+    pub lower_level_sst_ids: Vec<usize>,
+    pub is_lower_level_bottom_level: bool,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct LazyLevelingCompactionOptions {
+    pub level0_file_num_compaction_trigger: usize,
+    // each level is this many times as large as the one above, so an upper level merges its sorted
+    // runs into the next level once it has this many of them
+    pub size_ratio: usize,
+    pub max_levels: usize,
+}
+
+/// Lazy leveling: L1 to L(max_levels - 1) hold several sorted runs each, like the tiers of tiered
+/// compaction, and a full level merges its runs into a new run of the next level. The bottom level
+/// is a single sorted run, like in leveled compaction, which bounds the space amplification as most
+/// of the data is there, while the data is only rewritten once per upper level.
+///
+/// `levels` holds the sorted runs from the latest, tagged with their level, and always ends with
+/// the bottom level.
+pub struct LazyLevelingCompactionController {
+    options: LazyLevelingCompactionOptions,
+}
+
+impl LazyLevelingCompactionController {
+    pub fn new(options: LazyLevelingCompactionOptions) -> Self {
+        Self { options }
+    }
+
+    pub fn options(&self) -> &LazyLevelingCompactionOptions {
+        &self.options
+    }
+
+    fn bottom_level_sst_ids(snapshot: &LsmStorageState) -> &Vec<usize> {
+        let (level, ssts) = snapshot.levels.last().expect("no bottom level");
+        assert_eq!(
+            snapshot.levels.iter().filter(|(x, _)| x == level).count(),
+            1
+        );
+        ssts
+    }
+
+    fn task(
+        &self,
+        snapshot: &LsmStorageState,
+        upper_level: Option<usize>,
+        upper_level_runs: Vec<Vec<usize>>,
+    ) -> LazyLevelingCompactionTask {
+        let lower_level = upper_level.unwrap_or_default() + 1;
+        let is_lower_level_bottom_level = lower_level == self.options.max_levels;
+        LazyLevelingCompactionTask {
+            upper_level,
+            upper_level_runs,
+            lower_level,
+            lower_level_sst_ids: if is_lower_level_bottom_level {
+                Self::bottom_level_sst_ids(snapshot).clone()
+            } else {
+                Vec::new()
+            },
+            is_lower_level_bottom_level,
+        }
+    }
+
+    pub fn generate_compaction_task(
+        &self,
+        snapshot: &LsmStorageState,
+    ) -> Option<LazyLevelingCompactionTask> {
+        if snapshot.l0_sstables.len() >= self.options.level0_file_num_compaction_trigger {
+            println!(
+                "compaction triggered at level 0 with {} files",
+                snapshot.l0_sstables.len()
+            );
+            let runs = snapshot.l0_sstables.iter().map(|id| vec![*id]).collect();
+            return Some(self.task(snapshot, None, runs));
+        }
+        for level in 1..self.options.max_levels {
+            let runs = snapshot
+                .levels
+                .iter()
+                .filter(|(x, _)| *x == level)
+                .map(|(_, ssts)| ssts.clone())
+                .collect::<Vec<_>>();
+            if runs.len() >= self.options.size_ratio.max(2) {
+                println!(
+                    "compaction triggered at level {} with {} sorted runs",
+                    level,
+                    runs.len()
+                );
+                return Some(self.task(snapshot, Some(level), runs));
+            }
+        }
+        None
+    }
+
+    pub fn apply_compaction_result(
+        &self,
+        snapshot: &LsmStorageState,
+        task: &LazyLevelingCompactionTask,
+        output: &[usize],
+    ) -> (LsmStorageState, Vec<usize>) {
+        let mut snapshot = snapshot.clone();
+        let mut files_to_remove = Vec::new();
+        match task.upper_level {
+            Some(upper_level) => {
+                let mut runs_to_remove = task.upper_level_runs.iter().collect::<HashSet<_>>();
+                snapshot
+                    .levels
+                    .retain(|(level, ssts)| *level != upper_level || !runs_to_remove.remove(ssts));
+                assert!(runs_to_remove.is_empty(), "sorted runs mismatched");
+            }
+            None => {
+                let mut l0_ssts_compacted = task
+                    .upper_level_runs
+                    .iter()
+                    .flatten()
+                    .copied()
+                    .collect::<HashSet<_>>();
+                snapshot
+                    .l0_sstables
+                    .retain(|x| !l0_ssts_compacted.remove(x));
+                assert!(l0_ssts_compacted.is_empty());
+            }
+        }
+        files_to_remove.extend(task.upper_level_runs.iter().flatten().copied());
+        if task.is_lower_level_bottom_level {
+            let (level, ssts) = snapshot.levels.last_mut().unwrap();
+            assert_eq!(*level, task.lower_level, "bottom level mismatched");
+            assert_eq!(ssts, &task.lower_level_sst_ids, "sst mismatched");
+            files_to_remove.extend(ssts.iter().copied());
+            *ssts = output.to_vec();
+        } else if !output.is_empty() {
+            // the latest sorted run of the lower level, unless all keys were dropped
+            let idx = snapshot
+                .levels
+                .iter()
+                .position(|(level, _)| *level >= task.lower_level)
+                .unwrap();
+            snapshot
+                .levels
+                .insert(idx, (task.lower_level, output.to_vec()));
+        }
+        (snapshot, files_to_remove)
+    }
+}
@@ -2,9 +2,10 @@ use std::collections::HashSet;
 
 use serde::{Deserialize, Serialize};
 
+use super::{unix_now, SstCompactionTriggers};
 use crate::lsm_storage::LsmStorageState;
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct LeveledCompactionTask {
     // if upper_level is `None`, then it is L0 compaction
     pub upper_level: Option<usize>,
@@ -12,9 +13,28 @@ pub struct LeveledCompactionTask {
     pub lower_level: usize,
     pub lower_level_sst_ids: Vec<usize>,
     pub is_lower_level_bottom_level: bool,
+    // the SSTs of the level below the lower level that overlap the task, where the outputs are cut
+    // so that none of them overlaps too many of these
+    #[serde(default)]
+    pub grandparent_sst_ids: Vec<usize>,
 }
 
-#[derive(Debug, Clone)]
+/// Which SST of a level is compacted first, once the level exceeds its target size.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum CompactionPriority {
+    /// The oldest SST, i.e., the one with the smallest id.
+    #[default]
+    OldestSstId,
+    /// The SST overlapping the fewest bytes in the next level, which minimizes write amplification.
+    MinOverlappingBytes,
+    /// The SST whose newest entry is the oldest, so that cold data moves down first.
+    OldestMaxTs,
+    /// The SST with the highest ratio of tombstones, so that deletes reach the bottom level, where
+    /// they are dropped, sooner.
+    HighestTombstoneRatio,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct LeveledCompactionOptions {
     pub level_size_multiplier: usize,
     pub level0_file_num_compaction_trigger: usize,
@@ -24,11 +44,33 @@ pub struct LeveledCompactionOptions {
 
 pub struct LeveledCompactionController {
     options: LeveledCompactionOptions,
+    priority: CompactionPriority,
+    triggers: SstCompactionTriggers,
 }
 
 impl LeveledCompactionController {
     pub fn new(options: LeveledCompactionOptions) -> Self {
-        Self { options }
+        Self {
+            options,
+            priority: CompactionPriority::default(),
+            triggers: SstCompactionTriggers::default(),
+        }
+    }
+
+    /// Sets which SST of a level is compacted first.
+    pub fn with_priority(mut self, priority: CompactionPriority) -> Self {
+        self.priority = priority;
+        self
+    }
+
+    /// Sets the compactions triggered by the tombstones or the age of an SST.
+    pub fn with_triggers(mut self, triggers: SstCompactionTriggers) -> Self {
+        self.triggers = triggers;
+        self
+    }
+
+    pub fn options(&self) -> &LeveledCompactionOptions {
+        &self.options
     }
 
     fn find_overlapping_ssts(
@@ -61,9 +103,39 @@ impl LeveledCompactionController {
         overlap_ssts
     }
 
+    /// The SSTs of the level below `lower_level` that overlap the SSTs of a task.
+    fn find_grandparent_ssts(
+        &self,
+        snapshot: &LsmStorageState,
+        upper_level_sst_ids: &[usize],
+        lower_level_sst_ids: &[usize],
+        lower_level: usize,
+    ) -> Vec<usize> {
+        if lower_level >= self.options.max_levels {
+            return Vec::new();
+        }
+        let sst_ids = upper_level_sst_ids
+            .iter()
+            .chain(lower_level_sst_ids)
+            .copied()
+            .collect::<Vec<_>>();
+        self.find_overlapping_ssts(snapshot, &sst_ids, lower_level + 1)
+    }
+
     pub fn generate_compaction_task(
         &self,
         snapshot: &LsmStorageState,
+    ) -> Option<LeveledCompactionTask> {
+        self.generate_compaction_task_excluding(snapshot, &HashSet::new())
+    }
+
+    /// Generates a compaction task that does not use any of the `busy_ssts`, which are being
+    /// compacted by the running tasks. All L0 SSTs are compacted together, so only one L0 task runs
+    /// at a time, while the other levels may run several tasks on disjoint key ranges.
+    pub fn generate_compaction_task_excluding(
+        &self,
+        snapshot: &LsmStorageState,
+        busy_ssts: &HashSet<usize>,
     ) -> Option<LeveledCompactionTask> {
         // step 1: compute target level size
         let mut target_level_size = (0..self.options.max_levels).map(|_| 0).collect::<Vec<_>>(); // exclude level 0
@@ -96,18 +168,10 @@ impl LeveledCompactionController {
 
         // Flush L0 SST is the top priority
         if snapshot.l0_sstables.len() >= self.options.level0_file_num_compaction_trigger {
-            println!("flush L0 SST to base level {}", base_level);
-            return Some(LeveledCompactionTask {
-                upper_level: None,
-                upper_level_sst_ids: snapshot.l0_sstables.clone(),
-                lower_level: base_level,
-                lower_level_sst_ids: self.find_overlapping_ssts(
-                    snapshot,
-                    &snapshot.l0_sstables,
-                    base_level,
-                ),
-                is_lower_level_bottom_level: base_level == self.options.max_levels,
-            });
+            if let Some(task) = self.generate_l0_task(snapshot, base_level, busy_ssts) {
+                println!("flush L0 SST to base level {}", base_level);
+                return Some(task);
+            }
         }
 
         let mut priorities = Vec::with_capacity(self.options.max_levels);
@@ -119,8 +183,7 @@ impl LeveledCompactionController {
         }
         priorities.sort_by(|a, b| a.partial_cmp(b).unwrap().reverse());
 
-        let priority = priorities.first();
-        if let Some((_, level)) = priority {
+        if !priorities.is_empty() {
             println!(
                 "target level sizes: {:?}, real level sizes: {:?}, base_level: {}",
                 target_level_size
@@ -133,28 +196,177 @@ impl LeveledCompactionController {
                     .collect::<Vec<_>>(),
                 base_level,
             );
-
+        }
+        for (_, level) in &priorities {
             let level = *level;
-            let selected_sst = snapshot.levels[level - 1].1.iter().min().copied().unwrap(); // select the oldest sst to compact
-            println!(
-                "compaction triggered by priority: {level} out of {:?}, select {selected_sst} for compaction",
-                priorities
-            );
-            return Some(LeveledCompactionTask {
-                upper_level: Some(level),
-                upper_level_sst_ids: vec![selected_sst],
-                lower_level: level + 1,
-                lower_level_sst_ids: self.find_overlapping_ssts(
+            // select the sst to compact by priority, among the ones whose key range is not being
+            // compacted
+            let candidates = snapshot.levels[level - 1]
+                .1
+                .iter()
+                .filter(|id| !busy_ssts.contains(id))
+                .map(|id| {
+                    let lower_level_sst_ids =
+                        self.find_overlapping_ssts(snapshot, &[*id], level + 1);
+                    (*id, lower_level_sst_ids)
+                })
+                .filter(|(_, lower_level_sst_ids)| {
+                    lower_level_sst_ids.iter().all(|id| !busy_ssts.contains(id))
+                })
+                .collect::<Vec<_>>();
+            if let Some((selected_sst, lower_level_sst_ids)) =
+                self.select_by_priority(snapshot, candidates)
+            {
+                println!(
+                    "compaction triggered by priority: {level} out of {:?}, select {selected_sst} for compaction",
+                    priorities
+                );
+                let grandparent_sst_ids = self.find_grandparent_ssts(
                     snapshot,
                     &[selected_sst],
+                    &lower_level_sst_ids,
                     level + 1,
-                ),
-                is_lower_level_bottom_level: level + 1 == self.options.max_levels,
-            });
+                );
+                return Some(LeveledCompactionTask {
+                    upper_level: Some(level),
+                    upper_level_sst_ids: vec![selected_sst],
+                    lower_level: level + 1,
+                    lower_level_sst_ids,
+                    is_lower_level_bottom_level: level + 1 == self.options.max_levels,
+                    grandparent_sst_ids,
+                });
+            }
+        }
+
+        if self.triggers.is_enabled() {
+            return self.generate_triggered_task(snapshot, base_level, busy_ssts);
+        }
+        None
+    }
+
+    /// Compacts all L0 SSTs to the base level, unless any of the SSTs is busy.
+    fn generate_l0_task(
+        &self,
+        snapshot: &LsmStorageState,
+        base_level: usize,
+        busy_ssts: &HashSet<usize>,
+    ) -> Option<LeveledCompactionTask> {
+        let lower_level_sst_ids =
+            self.find_overlapping_ssts(snapshot, &snapshot.l0_sstables, base_level);
+        if snapshot
+            .l0_sstables
+            .iter()
+            .chain(&lower_level_sst_ids)
+            .any(|id| busy_ssts.contains(id))
+        {
+            return None;
+        }
+        let grandparent_sst_ids = self.find_grandparent_ssts(
+            snapshot,
+            &snapshot.l0_sstables,
+            &lower_level_sst_ids,
+            base_level,
+        );
+        Some(LeveledCompactionTask {
+            upper_level: None,
+            upper_level_sst_ids: snapshot.l0_sstables.clone(),
+            lower_level: base_level,
+            lower_level_sst_ids,
+            is_lower_level_bottom_level: base_level == self.options.max_levels,
+            grandparent_sst_ids,
+        })
+    }
+
+    /// Compacts the first SST, from the top level, that `SstCompactionTriggers` selects. An L0 SST
+    /// is compacted with all other L0 SSTs, and an SST of the bottom level is rewritten in place.
+    fn generate_triggered_task(
+        &self,
+        snapshot: &LsmStorageState,
+        base_level: usize,
+        busy_ssts: &HashSet<usize>,
+    ) -> Option<LeveledCompactionTask> {
+        let now = unix_now();
+        if let Some((sst, reason)) =
+            self.triggers
+                .find(snapshot, &snapshot.l0_sstables, false, busy_ssts, now)
+        {
+            if let Some(task) = self.generate_l0_task(snapshot, base_level, busy_ssts) {
+                println!("compaction triggered by {reason} of L0 SST {sst}");
+                return Some(task);
+            }
+        }
+        for level in 1..=self.options.max_levels {
+            let bottom_level = level == self.options.max_levels;
+            let mut busy_ssts = busy_ssts.clone();
+            while let Some((sst, reason)) = self.triggers.find(
+                snapshot,
+                &snapshot.levels[level - 1].1,
+                bottom_level,
+                &busy_ssts,
+                now,
+            ) {
+                let (lower_level, lower_level_sst_ids) = if bottom_level {
+                    (level, Vec::new())
+                } else {
+                    (
+                        level + 1,
+                        self.find_overlapping_ssts(snapshot, &[sst], level + 1),
+                    )
+                };
+                if lower_level_sst_ids.iter().any(|id| busy_ssts.contains(id)) {
+                    busy_ssts.insert(sst);
+                    continue;
+                }
+                println!("compaction triggered by {reason} of SST {sst} at level {level}");
+                let grandparent_sst_ids =
+                    self.find_grandparent_ssts(snapshot, &[sst], &lower_level_sst_ids, lower_level);
+                return Some(LeveledCompactionTask {
+                    upper_level: Some(level),
+                    upper_level_sst_ids: vec![sst],
+                    lower_level,
+                    lower_level_sst_ids,
+                    is_lower_level_bottom_level: lower_level == self.options.max_levels,
+                    grandparent_sst_ids,
+                });
+            }
         }
         None
     }
 
+    /// Selects the candidate, given with the SSTs it overlaps in the next level, to compact first
+    /// according to the priority. Ties are broken by the smallest SST id.
+    fn select_by_priority(
+        &self,
+        snapshot: &LsmStorageState,
+        candidates: Vec<(usize, Vec<usize>)>,
+    ) -> Option<(usize, Vec<usize>)> {
+        let sst = |id: &usize| &snapshot.sstables[id];
+        match self.priority {
+            CompactionPriority::OldestSstId => candidates.into_iter().min_by_key(|(id, _)| *id),
+            CompactionPriority::MinOverlappingBytes => {
+                candidates
+                    .into_iter()
+                    .min_by_key(|(id, lower_level_sst_ids)| {
+                        let overlapping_bytes = lower_level_sst_ids
+                            .iter()
+                            .map(|id| sst(id).table_size())
+                            .sum::<u64>();
+                        (overlapping_bytes, *id)
+                    })
+            }
+            CompactionPriority::OldestMaxTs => candidates
+                .into_iter()
+                .min_by_key(|(id, _)| (sst(id).max_ts(), *id)),
+            CompactionPriority::HighestTombstoneRatio => {
+                candidates.into_iter().min_by(|(a, _), (b, _)| {
+                    let ratio_a = sst(a).stats().tombstone_ratio();
+                    let ratio_b = sst(b).stats().tombstone_ratio();
+                    ratio_b.total_cmp(&ratio_a).then(a.cmp(b))
+                })
+            }
+        }
+    }
+
     pub fn apply_compaction_result(
         &self,
         snapshot: &LsmStorageState,
@@ -216,14 +428,21 @@ impl LeveledCompactionController {
             .collect::<Vec<_>>();
         assert!(lower_level_sst_ids_set.is_empty());
         new_lower_level_ssts.extend(output);
-        new_lower_level_ssts.sort_by(|x, y| {
-            snapshot
-                .sstables
-                .get(x)
-                .unwrap()
-                .first_key()
-                .cmp(snapshot.sstables.get(y).unwrap().first_key())
-        });
+        // the SSTs are not loaded yet when replaying the manifest, in which case the levels are sorted
+        // once they are, see `LsmStorageState::sort_levels`
+        if new_lower_level_ssts
+            .iter()
+            .all(|x| snapshot.sstables.contains_key(x))
+        {
+            new_lower_level_ssts.sort_by(|x, y| {
+                snapshot
+                    .sstables
+                    .get(x)
+                    .unwrap()
+                    .first_key()
+                    .cmp(snapshot.sstables.get(y).unwrap().first_key())
+            });
+        }
         snapshot.levels[task.lower_level - 1].1 = new_lower_level_ssts;
         (snapshot, files_to_remove)
     }
@@ -2,16 +2,17 @@ use std::collections::HashSet;
 
 use serde::{Deserialize, Serialize};
 
+use super::{unix_now, SstCompactionTriggers};
 use crate::lsm_storage::LsmStorageState;
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct SimpleLeveledCompactionOptions {
     pub size_ratio_percent: usize,
     pub level0_file_num_compaction_trigger: usize,
     pub max_levels: usize,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct SimpleLeveledCompactionTask {
     // if upper_level is `None`, then it is L0 compaction
     pub upper_level: Option<usize>,
@@ -19,15 +20,41 @@ pub struct SimpleLeveledCompactionTask {
     pub lower_level: usize,
     pub lower_level_sst_ids: Vec<usize>,
     pub is_lower_level_bottom_level: bool,
+    // the SSTs of the level below the lower level that overlap the task, where the outputs are cut
+    // so that none of them overlaps too many of these
+    #[serde(default)]
+    pub grandparent_sst_ids: Vec<usize>,
 }
 
 pub struct SimpleLeveledCompactionController {
     options: SimpleLeveledCompactionOptions,
+    triggers: SstCompactionTriggers,
 }
 
 impl SimpleLeveledCompactionController {
     pub fn new(options: SimpleLeveledCompactionOptions) -> Self {
-        Self { options }
+        Self {
+            options,
+            triggers: SstCompactionTriggers::default(),
+        }
+    }
+
+    /// Sets the compactions triggered by the tombstones or the age of an SST.
+    pub fn with_triggers(mut self, triggers: SstCompactionTriggers) -> Self {
+        self.triggers = triggers;
+        self
+    }
+
+    pub fn options(&self) -> &SimpleLeveledCompactionOptions {
+        &self.options
+    }
+
+    /// The whole level below `lower_level`, as a task compacts whole levels.
+    fn grandparent_sst_ids(&self, snapshot: &LsmStorageState, lower_level: usize) -> Vec<usize> {
+        if lower_level >= self.options.max_levels {
+            return Vec::new();
+        }
+        snapshot.levels[lower_level].1.clone()
     }
 
     /// Generates a compaction task.
@@ -36,6 +63,17 @@ impl SimpleLeveledCompactionController {
     pub fn generate_compaction_task(
         &self,
         snapshot: &LsmStorageState,
+    ) -> Option<SimpleLeveledCompactionTask> {
+        self.generate_compaction_task_excluding(snapshot, &HashSet::new())
+    }
+
+    /// Generates a compaction task that does not use any of the `busy_ssts`, which are being
+    /// compacted by the running tasks. Tasks compacting disjoint pairs of levels can run at the same
+    /// time.
+    pub fn generate_compaction_task_excluding(
+        &self,
+        snapshot: &LsmStorageState,
+        busy_ssts: &HashSet<usize>,
     ) -> Option<SimpleLeveledCompactionTask> {
         let mut level_sizes = Vec::new();
         level_sizes.push(snapshot.l0_sstables.len());
@@ -53,23 +91,83 @@ impl SimpleLeveledCompactionController {
             let lower_level = i + 1;
             let size_ratio = level_sizes[lower_level] as f64 / level_sizes[i] as f64;
             if size_ratio < self.options.size_ratio_percent as f64 / 100.0 {
+                let upper_level_sst_ids = if i == 0 {
+                    snapshot.l0_sstables.clone()
+                } else {
+                    snapshot.levels[i - 1].1.clone()
+                };
+                let lower_level_sst_ids = snapshot.levels[lower_level - 1].1.clone();
+                if upper_level_sst_ids
+                    .iter()
+                    .chain(&lower_level_sst_ids)
+                    .any(|id| busy_ssts.contains(id))
+                {
+                    continue;
+                }
                 println!(
                     "compaction triggered at level {} and {} with size ratio {}",
                     i, lower_level, size_ratio
                 );
                 return Some(SimpleLeveledCompactionTask {
                     upper_level: if i == 0 { None } else { Some(i) },
-                    upper_level_sst_ids: if i == 0 {
-                        snapshot.l0_sstables.clone()
-                    } else {
-                        snapshot.levels[i - 1].1.clone()
-                    },
+                    upper_level_sst_ids,
                     lower_level,
-                    lower_level_sst_ids: snapshot.levels[lower_level - 1].1.clone(),
+                    lower_level_sst_ids,
                     is_lower_level_bottom_level: lower_level == self.options.max_levels,
+                    grandparent_sst_ids: self.grandparent_sst_ids(snapshot, lower_level),
                 });
             }
         }
+
+        if self.triggers.is_enabled() {
+            return self.generate_triggered_task(snapshot, busy_ssts);
+        }
+        None
+    }
+
+    /// Compacts the level of the first SST, from the top level, that `SstCompactionTriggers`
+    /// selects, with the next level. The bottom level is rewritten in place.
+    fn generate_triggered_task(
+        &self,
+        snapshot: &LsmStorageState,
+        busy_ssts: &HashSet<usize>,
+    ) -> Option<SimpleLeveledCompactionTask> {
+        let now = unix_now();
+        for i in 0..=self.options.max_levels {
+            let bottom_level = i == self.options.max_levels;
+            let upper_level_sst_ids = if i == 0 {
+                &snapshot.l0_sstables
+            } else {
+                &snapshot.levels[i - 1].1
+            };
+            let Some((sst, reason)) =
+                self.triggers
+                    .find(snapshot, upper_level_sst_ids, bottom_level, busy_ssts, now)
+            else {
+                continue;
+            };
+            let (lower_level, lower_level_sst_ids) = if bottom_level {
+                (i, Vec::new())
+            } else {
+                (i + 1, snapshot.levels[i].1.clone())
+            };
+            if upper_level_sst_ids
+                .iter()
+                .chain(&lower_level_sst_ids)
+                .any(|id| busy_ssts.contains(id))
+            {
+                continue;
+            }
+            println!("compaction triggered by {reason} of SST {sst} at level {i}");
+            return Some(SimpleLeveledCompactionTask {
+                upper_level: if i == 0 { None } else { Some(i) },
+                upper_level_sst_ids: upper_level_sst_ids.clone(),
+                lower_level,
+                lower_level_sst_ids,
+                is_lower_level_bottom_level: lower_level == self.options.max_levels,
+                grandparent_sst_ids: self.grandparent_sst_ids(snapshot, lower_level),
+            });
+        }
         None
     }
 
@@ -77,9 +175,8 @@ impl SimpleLeveledCompactionController {
     ///
     /// The compactor will call this function with the compaction task and the list of SST ids generated. This function applies the
     /// result and generates a new LSM state. The functions should only change `l0_sstables` and `levels` without changing memtables
-    /// and `sstables` hash map. Though there should only be one thread running compaction jobs, you should think about the case
-    /// where an L0 SST gets flushed while the compactor generates new SSTs, and with that in mind, you should do some sanity checks
-    /// in your implementation.
+    /// and `sstables` hash map. The levels may have changed since the task was generated, as L0 SSTs get flushed and other tasks
+    /// complete while the compactor generates new SSTs, so only the SSTs of the task are replaced.
     pub fn apply_compaction_result(
         &self,
         snapshot: &LsmStorageState,
@@ -88,37 +185,31 @@ impl SimpleLeveledCompactionController {
     ) -> (LsmStorageState, Vec<usize>) {
         let mut snapshot = snapshot.clone();
         let mut files_to_remove = Vec::new();
-        if let Some(upper_level) = task.upper_level {
-            assert_eq!(
-                task.upper_level_sst_ids,
-                snapshot.levels[upper_level - 1].1,
-                "sst mismatched"
-            );
-            files_to_remove.extend(&snapshot.levels[upper_level - 1].1);
-            snapshot.levels[upper_level - 1].1.clear();
-        } else {
-            files_to_remove.extend(&task.upper_level_sst_ids);
-            let mut l0_ssts_compacted = task
-                .upper_level_sst_ids
-                .iter()
-                .copied()
-                .collect::<HashSet<_>>();
-            let new_l0_sstables = snapshot
-                .l0_sstables
-                .iter()
-                .copied()
-                .filter(|x| !l0_ssts_compacted.remove(x))
-                .collect::<Vec<_>>();
-            assert!(l0_ssts_compacted.is_empty());
-            snapshot.l0_sstables = new_l0_sstables;
-        }
-        assert_eq!(
-            task.lower_level_sst_ids,
-            snapshot.levels[task.lower_level - 1].1,
-            "sst mismatched"
-        );
-        files_to_remove.extend(&snapshot.levels[task.lower_level - 1].1);
-        snapshot.levels[task.lower_level - 1].1 = output.to_vec();
+        let mut upper_level_sst_ids_set = task
+            .upper_level_sst_ids
+            .iter()
+            .copied()
+            .collect::<HashSet<_>>();
+        let upper_level_ssts = match task.upper_level {
+            Some(upper_level) => &mut snapshot.levels[upper_level - 1].1,
+            None => &mut snapshot.l0_sstables,
+        };
+        upper_level_ssts.retain(|x| !upper_level_sst_ids_set.remove(x));
+        assert!(upper_level_sst_ids_set.is_empty(), "sst mismatched");
+        files_to_remove.extend(&task.upper_level_sst_ids);
+
+        let mut lower_level_sst_ids_set = task
+            .lower_level_sst_ids
+            .iter()
+            .copied()
+            .collect::<HashSet<_>>();
+        let lower_level_ssts = &mut snapshot.levels[task.lower_level - 1].1;
+        lower_level_ssts.retain(|x| !lower_level_sst_ids_set.remove(x));
+        assert!(lower_level_sst_ids_set.is_empty(), "sst mismatched");
+        // the whole lower level is compacted, and no other task writes to it meanwhile
+        assert!(lower_level_ssts.is_empty(), "sst mismatched");
+        files_to_remove.extend(&task.lower_level_sst_ids);
+        *lower_level_ssts = output.to_vec();
         (snapshot, files_to_remove)
     }
 }
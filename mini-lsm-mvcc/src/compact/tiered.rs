@@ -1,16 +1,17 @@
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 
 use serde::{Deserialize, Serialize};
 
+use super::{unix_now, SstCompactionTriggers};
 use crate::lsm_storage::LsmStorageState;
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct TieredCompactionTask {
     pub tiers: Vec<(usize, Vec<usize>)>,
     pub bottom_tier_included: bool,
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct TieredCompactionOptions {
     pub num_tiers: usize,
     pub max_size_amplification_percent: usize,
@@ -20,11 +21,25 @@ pub struct TieredCompactionOptions {
 
 pub struct TieredCompactionController {
     options: TieredCompactionOptions,
+    triggers: SstCompactionTriggers,
 }
 
 impl TieredCompactionController {
     pub fn new(options: TieredCompactionOptions) -> Self {
-        Self { options }
+        Self {
+            options,
+            triggers: SstCompactionTriggers::default(),
+        }
+    }
+
+    /// Sets the compactions triggered by the tombstones or the age of an SST.
+    pub fn with_triggers(mut self, triggers: SstCompactionTriggers) -> Self {
+        self.triggers = triggers;
+        self
+    }
+
+    pub fn options(&self) -> &TieredCompactionOptions {
+        &self.options
     }
 
     pub fn generate_compaction_task(
@@ -36,6 +51,9 @@ impl TieredCompactionController {
             "should not add l0 ssts in tiered compaction"
         );
         if snapshot.levels.len() < self.options.num_tiers {
+            if self.triggers.is_enabled() {
+                return self.generate_triggered_task(snapshot);
+            }
             return None;
         }
         // compaction triggered by space amplification ratio
@@ -92,6 +110,29 @@ impl TieredCompactionController {
         });
     }
 
+    /// Compacts the tier of the first SST, from the newest tier, that `SstCompactionTriggers`
+    /// selects, with the next tier. The last tier is rewritten alone.
+    fn generate_triggered_task(&self, snapshot: &LsmStorageState) -> Option<TieredCompactionTask> {
+        let now = unix_now();
+        let num_tiers = snapshot.levels.len();
+        for (i, (_, ssts)) in snapshot.levels.iter().enumerate() {
+            let bottom_tier = i + 1 == num_tiers;
+            let Some((sst, reason)) =
+                self.triggers
+                    .find(snapshot, ssts, bottom_tier, &HashSet::new(), now)
+            else {
+                continue;
+            };
+            println!("compaction triggered by {reason} of SST {sst} in tier {i}");
+            let last_tier = if bottom_tier { i } else { i + 1 };
+            return Some(TieredCompactionTask {
+                tiers: snapshot.levels[i..=last_tier].to_vec(),
+                bottom_tier_included: last_tier + 1 == num_tiers,
+            });
+        }
+        None
+    }
+
     pub fn apply_compaction_result(
         &self,
         snapshot: &LsmStorageState,
@@ -102,34 +143,44 @@ impl TieredCompactionController {
             snapshot.l0_sstables.is_empty(),
             "should not add l0 ssts in tiered compaction"
         );
-        let mut snapshot = snapshot.clone();
-        let mut tier_to_remove = task
-            .tiers
-            .iter()
-            .map(|(x, y)| (*x, y))
-            .collect::<HashMap<_, _>>();
-        let mut levels = Vec::new();
-        let mut new_tier_added = false;
-        let mut files_to_remove = Vec::new();
-        for (tier_id, files) in &snapshot.levels {
-            if let Some(ffiles) = tier_to_remove.remove(tier_id) {
-                // the tier should be removed
-                assert_eq!(ffiles, files, "file changed after issuing compaction task");
-                files_to_remove.extend(ffiles.iter().copied());
-            } else {
-                // retain the tier
-                levels.push((*tier_id, files.clone()));
-            }
-            if tier_to_remove.is_empty() && !new_tier_added {
-                // add the compacted tier to the LSM tree
-                new_tier_added = true;
-                levels.push((output[0], output.to_vec()));
-            }
+        replace_tiers(snapshot, &task.tiers, output)
+    }
+}
+
+/// Remove the compacted `tiers`, and add a tier of the `output` SSTs in place of the earliest one.
+pub(super) fn replace_tiers(
+    snapshot: &LsmStorageState,
+    tiers: &[(usize, Vec<usize>)],
+    output: &[usize],
+) -> (LsmStorageState, Vec<usize>) {
+    let mut snapshot = snapshot.clone();
+    let mut tier_to_remove = tiers
+        .iter()
+        .map(|(x, y)| (*x, y))
+        .collect::<HashMap<_, _>>();
+    let mut levels = Vec::new();
+    let mut new_tier_added = false;
+    let mut files_to_remove = Vec::new();
+    for (tier_id, files) in &snapshot.levels {
+        if let Some(ffiles) = tier_to_remove.remove(tier_id) {
+            // the tier should be removed
+            assert_eq!(ffiles, files, "file changed after issuing compaction task");
+            files_to_remove.extend(ffiles.iter().copied());
+        } else {
+            // retain the tier
+            levels.push((*tier_id, files.clone()));
         }
-        if !tier_to_remove.is_empty() {
-            unreachable!("some tiers not found??");
+        if tier_to_remove.is_empty() && !new_tier_added {
+            // add the compacted tier to the LSM tree, unless all keys were dropped
+            new_tier_added = true;
+            if let Some(tier_id) = output.first() {
+                levels.push((*tier_id, output.to_vec()));
+            }
         }
-        snapshot.levels = levels;
-        (snapshot, files_to_remove)
     }
+    if !tier_to_remove.is_empty() {
+        unreachable!("some tiers not found??");
+    }
+    snapshot.levels = levels;
+    (snapshot, files_to_remove)
 }
@@ -0,0 +1,181 @@
+use std::collections::BTreeMap;
+
+use serde::{Deserialize, Serialize};
+
+use super::tiered::replace_tiers;
+use super::unix_now;
+use crate::lsm_storage::LsmStorageState;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TimeWindowCompactionTask {
+    // the window of the tiers, in units of `window_seconds` since the UNIX epoch
+    pub window: u64,
+    pub tiers: Vec<(usize, Vec<usize>)>,
+    pub bottom_tier_included: bool,
+    // the tiers are dropped whole instead of being merged
+    pub expired: bool,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TimeWindowCompactionOptions {
+    // the length of a window, in seconds
+    pub window_seconds: u64,
+    // merge the tiers of the current window once this many of them have similar sizes
+    pub min_merge_width: usize,
+    // the tiers within this percentage of the smallest one have similar sizes
+    pub size_ratio: usize,
+    // drop the windows whose newest data was flushed this many seconds ago, or never if 0
+    pub ttl_seconds: u64,
+}
+
+/// Groups the tiers into windows by the time their data was flushed, as recorded in
+/// `SsTableStats::data_written_at`, and never merges tiers across windows. The adjacent tiers of
+/// the current window are size-tiered, and each earlier window is merged into a single tier, which
+/// is dropped whole once its data outlives the TTL.
+pub struct TimeWindowCompactionController {
+    options: TimeWindowCompactionOptions,
+}
+
+impl TimeWindowCompactionController {
+    pub fn new(options: TimeWindowCompactionOptions) -> Self {
+        Self { options }
+    }
+
+    pub fn options(&self) -> &TimeWindowCompactionOptions {
+        &self.options
+    }
+
+    /// The newest `data_written_at` of the SSTs in a tier.
+    fn data_written_at(snapshot: &LsmStorageState, ssts: &[usize]) -> u64 {
+        ssts.iter()
+            .map(|id| snapshot.sstables[id].stats().data_written_at)
+            .max()
+            .unwrap_or_default()
+    }
+
+    pub fn generate_compaction_task(
+        &self,
+        snapshot: &LsmStorageState,
+    ) -> Option<TimeWindowCompactionTask> {
+        self.generate_compaction_task_at(snapshot, unix_now())
+    }
+
+    /// Generate a task as if it were `now` seconds since the UNIX epoch.
+    pub fn generate_compaction_task_at(
+        &self,
+        snapshot: &LsmStorageState,
+        now: u64,
+    ) -> Option<TimeWindowCompactionTask> {
+        assert!(
+            snapshot.l0_sstables.is_empty(),
+            "should not add l0 ssts in time window compaction"
+        );
+        let window_seconds = self.options.window_seconds.max(1);
+        // the indices of the tiers in each window, from the latest tier, with the data flushed at
+        // an unknown time in window 0
+        let mut windows = BTreeMap::<u64, Vec<usize>>::new();
+        for (idx, (_, ssts)) in snapshot.levels.iter().enumerate() {
+            let window = Self::data_written_at(snapshot, ssts) / window_seconds;
+            windows.entry(window).or_default().push(idx);
+        }
+        // the tiers of a window are not always adjacent, and the tombstones of the merged tiers
+        // must be kept while a tier between them may hold an older version of their keys
+        let task = |window: u64, tiers: &[usize], expired: bool| TimeWindowCompactionTask {
+            window,
+            tiers: tiers
+                .iter()
+                .map(|idx| snapshot.levels[*idx].clone())
+                .collect(),
+            bottom_tier_included: tiers.last() == Some(&(snapshot.levels.len() - 1))
+                && tiers.windows(2).all(|pair| pair[0] + 1 == pair[1]),
+            expired,
+        };
+
+        // drop the earliest window whose data all expired
+        if self.options.ttl_seconds > 0 {
+            for (window, tiers) in &windows {
+                let data_written_at = tiers
+                    .iter()
+                    .map(|idx| Self::data_written_at(snapshot, &snapshot.levels[*idx].1))
+                    .max()
+                    .unwrap();
+                if data_written_at > 0
+                    && now.saturating_sub(data_written_at) >= self.options.ttl_seconds
+                {
+                    println!(
+                        "compaction triggered by ttl: window {} written at {}",
+                        window, data_written_at
+                    );
+                    return Some(task(*window, tiers, true));
+                }
+            }
+        }
+
+        // merge the tiers of a past window into one
+        let current_window = now / window_seconds;
+        for (window, tiers) in windows.range(..current_window).rev() {
+            if tiers.len() > 1 {
+                println!(
+                    "compaction triggered by past window {} with {} tiers",
+                    window,
+                    tiers.len()
+                );
+                return Some(task(*window, tiers, false));
+            }
+        }
+
+        // size-tier the current window, and the ones ahead of the clock
+        let size_ratio_trigger = (100.0 + self.options.size_ratio as f64) / 100.0;
+        let min_merge_width = self.options.min_merge_width.max(2);
+        for (window, tiers) in windows.range(current_window..) {
+            let size = |idx: usize| {
+                snapshot.levels[idx]
+                    .1
+                    .iter()
+                    .map(|id| snapshot.sstables[id].table_size())
+                    .sum::<u64>()
+            };
+            // a run of adjacent tiers, each within the size ratio of the smallest one
+            for start in 0..tiers.len() {
+                let (mut min_size, mut max_size) = (size(tiers[start]), size(tiers[start]));
+                let mut end = start + 1;
+                while end < tiers.len() && tiers[end] == tiers[end - 1] + 1 {
+                    let size = size(tiers[end]);
+                    if size.max(max_size) as f64
+                        > size.min(min_size).max(1) as f64 * size_ratio_trigger
+                    {
+                        break;
+                    }
+                    (min_size, max_size) = (size.min(min_size), size.max(max_size));
+                    end += 1;
+                }
+                if end - start >= min_merge_width {
+                    println!(
+                        "compaction triggered by {} similar tiers in window {}",
+                        end - start,
+                        window
+                    );
+                    return Some(task(*window, &tiers[start..end], false));
+                }
+            }
+        }
+        None
+    }
+
+    pub fn apply_compaction_result(
+        &self,
+        snapshot: &LsmStorageState,
+        task: &TimeWindowCompactionTask,
+        output: &[usize],
+    ) -> (LsmStorageState, Vec<usize>) {
+        assert!(
+            snapshot.l0_sstables.is_empty(),
+            "should not add l0 ssts in time window compaction"
+        );
+        assert!(
+            !task.expired || output.is_empty(),
+            "expired tiers are not compacted"
+        );
+        replace_tiers(snapshot, &task.tiers, output)
+    }
+}
@@ -1,4 +1,5 @@
 pub mod block;
+pub mod cdc;
 pub mod compact;
 pub mod debug;
 pub mod iterators;
@@ -8,7 +9,11 @@ pub mod lsm_storage;
 pub mod manifest;
 pub mod mem_table;
 pub mod mvcc;
+pub mod repair;
+pub mod replication;
+pub mod secondary;
 pub mod table;
+pub mod verify;
 pub mod wal;
 
 #[cfg(test)]
@@ -1,18 +1,21 @@
-use std::collections::{BTreeSet, HashMap};
-use std::fs::File;
+use std::collections::{BTreeSet, HashMap, HashSet};
+use std::fs::{File, TryLockError};
+use std::io::Write;
 use std::ops::Bound;
 use std::path::{Path, PathBuf};
 use std::sync::atomic::AtomicUsize;
 use std::sync::Arc;
 
-use anyhow::{Context, Result};
+use anyhow::{bail, Context, Result};
 use bytes::Bytes;
 use parking_lot::{Mutex, MutexGuard, RwLock};
+use serde::{Deserialize, Serialize};
 
 use crate::block::Block;
+use crate::cdc::{ChangeBatch, ChangeStream, ChangeSubscriber};
 use crate::compact::{
-    CompactionController, CompactionOptions, LeveledCompactionController, LeveledCompactionOptions,
-    SimpleLeveledCompactionController, SimpleLeveledCompactionOptions, TieredCompactionController,
+    CompactionController, CompactionOptions, CompactionPriority, CompactionTask,
+    LazyLevelingCompactionOptions, LeveledCompactionOptions, SimpleLeveledCompactionOptions,
 };
 use crate::iterators::concat_iterator::SstConcatIterator;
 use crate::iterators::merge_iterator::MergeIterator;
@@ -20,11 +23,15 @@ use crate::iterators::two_merge_iterator::TwoMergeIterator;
 use crate::iterators::StorageIterator;
 use crate::key::{self, KeySlice};
 use crate::lsm_iterator::{FusedIterator, LsmIterator};
-use crate::manifest::{Manifest, ManifestRecord};
+use crate::manifest::{Manifest, ManifestRecord, SstInfo, SstMetadata};
 use crate::mem_table::{map_bound, map_key_bound_plus_ts, MemTable};
 use crate::mvcc::txn::{Transaction, TxnIterator};
 use crate::mvcc::LsmMvccInner;
+use crate::replication::{ReplicationTransport, WalShipper};
+use crate::secondary::SecondaryState;
 use crate::table::{FileObject, SsTable, SsTableBuilder, SsTableIterator};
+use crate::verify::VerifyReport;
+use crate::wal::{WalRecoveryMode, WalRecoveryReport};
 
 pub type BlockCache = moka::sync::Cache<(usize, usize), Arc<Block>>;
 
@@ -44,20 +51,53 @@ pub struct LsmStorageState {
     pub sstables: HashMap<usize, Arc<SsTable>>,
 }
 
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub enum WriteBatchRecord<T: AsRef<[u8]>> {
     Put(T, T),
     Del(T),
 }
 
+/// Per-write durability options.
+#[derive(Debug, Clone, Default)]
+pub struct WriteOptions {
+    /// Return only after the write is durable in the WAL. Concurrent synced writers share a single
+    /// `fsync`. The write is not visible to readers or shipped to followers before it is durable,
+    /// and neither are the writes after it, which keeps the commit order.
+    pub sync: bool,
+    /// Skip the WAL for this write. The write is lost if the process crashes before the memtable
+    /// is flushed.
+    pub disable_wal: bool,
+}
+
 impl LsmStorageState {
-    fn create(options: &LsmStorageOptions) -> Self {
+    /// Sort the SSTs of each level by their first key. Compaction results replayed from the manifest
+    /// are not sorted, as the SSTs are only loaded afterwards.
+    pub(crate) fn sort_levels(&mut self) {
+        for (_, ssts) in &mut self.levels {
+            ssts.sort_by(|x, y| {
+                self.sstables[x]
+                    .first_key()
+                    .cmp(self.sstables[y].first_key())
+            });
+        }
+    }
+
+    pub(crate) fn create(options: &LsmStorageOptions) -> Self {
         let levels = match &options.compaction_options {
             CompactionOptions::Leveled(LeveledCompactionOptions { max_levels, .. })
             | CompactionOptions::Simple(SimpleLeveledCompactionOptions { max_levels, .. }) => (1
                 ..=*max_levels)
                 .map(|level| (level, Vec::new()))
                 .collect::<Vec<_>>(),
-            CompactionOptions::Tiered(_) => Vec::new(),
+            CompactionOptions::Tiered(_)
+            | CompactionOptions::Fifo(_)
+            | CompactionOptions::TimeWindow(_) => Vec::new(),
+            // the upper levels have no sorted run yet
+            CompactionOptions::LazyLeveling(LazyLevelingCompactionOptions {
+                max_levels, ..
+            }) => {
+                vec![(*max_levels, Vec::new())]
+            }
             CompactionOptions::NoCompaction => vec![(1, Vec::new())],
         };
         Self {
@@ -70,7 +110,7 @@ impl LsmStorageState {
     }
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct LsmStorageOptions {
     // Block size in bytes
     pub block_size: usize,
@@ -81,9 +121,49 @@ pub struct LsmStorageOptions {
     pub compaction_options: CompactionOptions,
     pub enable_wal: bool,
     pub serializable: bool,
+    // How to handle incomplete or corrupted WAL records when opening the engine
+    pub wal_recovery_mode: WalRecoveryMode,
+    // Move the WALs of flushed memtables to the archive directory instead of deleting them, so
+    // that change data capture can serve older batches. Without it, `get_updates_since` and
+    // `subscribe` only serve the batches of memtables not flushed yet, and fail for older ones
+    pub archive_wal: bool,
+    // Switch to a new manifest file starting with a snapshot of the LSM state once the current one
+    // grows over this size in bytes
+    pub max_manifest_file_size: u64,
+    // Maximum number of threads a compaction is split across, each compacting a disjoint key range
+    #[serde(default = "LsmStorageOptions::default_max_subcompactions")]
+    pub max_subcompactions: usize,
+    // Number of compaction workers, which run non-conflicting compaction tasks at the same time
+    #[serde(default = "LsmStorageOptions::default_max_background_compactions")]
+    pub max_background_compactions: usize,
+    // Which SST of a level the leveled compaction picks first
+    #[serde(default)]
+    pub compaction_priority: CompactionPriority,
+    // Compact an SST once the ratio of its entries that are tombstones exceeds this threshold
+    #[serde(default)]
+    pub tombstone_ratio_compaction_trigger: Option<f64>,
+    // Compact an SST once it was built this many seconds ago, or never if 0
+    #[serde(default)]
+    pub periodic_compaction_seconds: u64,
+    // Cut a compaction output SST once it overlaps this many times `target_sst_size` bytes of the
+    // level below its own, so that compacting it later stays cheap, or never if 0
+    #[serde(default = "LsmStorageOptions::default_max_grandparent_overlap_factor")]
+    pub max_grandparent_overlap_factor: usize,
 }
 
 impl LsmStorageOptions {
+    fn default_max_subcompactions() -> usize {
+        1
+    }
+
+    fn default_max_background_compactions() -> usize {
+        1
+    }
+
+    fn default_max_grandparent_overlap_factor() -> usize {
+        10
+    }
+
     pub fn default_for_week1_test() -> Self {
         Self {
             block_size: 4096,
@@ -92,6 +172,15 @@ impl LsmStorageOptions {
             enable_wal: false,
             num_memtable_limit: 50,
             serializable: false,
+            wal_recovery_mode: WalRecoveryMode::default(),
+            archive_wal: false,
+            max_manifest_file_size: 64 << 20,
+            max_subcompactions: 1,
+            max_background_compactions: 1,
+            compaction_priority: CompactionPriority::default(),
+            tombstone_ratio_compaction_trigger: None,
+            periodic_compaction_seconds: 0,
+            max_grandparent_overlap_factor: 10,
         }
     }
 
@@ -103,6 +192,15 @@ impl LsmStorageOptions {
             enable_wal: false,
             num_memtable_limit: 2,
             serializable: false,
+            wal_recovery_mode: WalRecoveryMode::default(),
+            archive_wal: false,
+            max_manifest_file_size: 64 << 20,
+            max_subcompactions: 1,
+            max_background_compactions: 1,
+            compaction_priority: CompactionPriority::default(),
+            tombstone_ratio_compaction_trigger: None,
+            periodic_compaction_seconds: 0,
+            max_grandparent_overlap_factor: 10,
         }
     }
 
@@ -114,6 +212,15 @@ impl LsmStorageOptions {
             enable_wal: false,
             num_memtable_limit: 2,
             serializable: false,
+            wal_recovery_mode: WalRecoveryMode::default(),
+            archive_wal: false,
+            max_manifest_file_size: 64 << 20,
+            max_subcompactions: 1,
+            max_background_compactions: 1,
+            compaction_priority: CompactionPriority::default(),
+            tombstone_ratio_compaction_trigger: None,
+            periodic_compaction_seconds: 0,
+            max_grandparent_overlap_factor: 10,
         }
     }
 }
@@ -149,23 +256,101 @@ fn key_within(user_key: &[u8], table_begin: KeySlice, table_end: KeySlice) -> bo
     table_begin.key_ref() <= user_key && user_key <= table_end.key_ref()
 }
 
-#[derive(Clone, Debug)]
+#[derive(Clone)]
 pub enum CompactionFilter {
     Prefix(Bytes),
+    /// A user-defined filter, created for each compaction by the factory.
+    Custom(Arc<dyn CompactionFilterFactory>),
+}
+
+impl std::fmt::Debug for CompactionFilter {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Prefix(prefix) => f.debug_tuple("Prefix").field(prefix).finish(),
+            Self::Custom(_) => f.write_str("Custom(..)"),
+        }
+    }
+}
+
+/// What a `CompactionFilterTrait` does with a version of a key.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum CompactionFilterDecision {
+    Keep,
+    /// Replace the version with a deletion, so that the older versions in the levels below do not
+    /// show up again. The deletion is dropped right away in a bottom-level compaction.
+    Remove,
+    /// Rewrite the value of the version, where an empty value is a deletion.
+    ChangeValue(Bytes),
+}
+
+/// The compaction that a `CompactionFilterTrait` is created for.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct CompactionFilterContext {
+    /// Whether the output is in the bottom level, below which there is no older version of a key.
+    pub is_bottom_level: bool,
+    /// The level of the output, or `None` in tiered compaction, whose tiers have no level number.
+    pub output_level: Option<usize>,
+}
+
+/// A user-defined compaction filter. It sees the latest version of each key at or below the
+/// watermark, in the key order, while the newer versions, which only newer snapshots read, are
+/// kept as they are. It is not called on deletions.
+pub trait CompactionFilterTrait {
+    fn filter(&mut self, key: &[u8], ts: u64, value: &[u8]) -> CompactionFilterDecision;
+}
+
+/// Creates a `CompactionFilterTrait` for each compaction, or for each of its subcompactions, so
+/// that a filter can keep state across the keys of a compaction.
+pub trait CompactionFilterFactory: Send + Sync {
+    fn create_compaction_filter(
+        &self,
+        context: &CompactionFilterContext,
+    ) -> Box<dyn CompactionFilterTrait>;
+}
+
+/// How the storage engine is opened.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum OpenMode {
+    ReadWrite,
+    /// Only applies the batches shipped from a leader, see `MiniLsm::open_follower`.
+    Follower,
+    /// Never modifies the directory, see `MiniLsm::open_read_only`.
+    ReadOnly,
+    /// Follows a primary that has the directory open, see `MiniLsm::open_as_secondary`.
+    Secondary,
 }
 
 /// The storage interface of the LSM tree.
 pub(crate) struct LsmStorageInner {
     pub(crate) state: Arc<RwLock<Arc<LsmStorageState>>>,
     pub(crate) state_lock: Mutex<()>,
-    path: PathBuf,
+    /// Held shared by the compaction workers while compacting, so that the outputs are not mistaken
+    /// for obsolete files, and exclusively by the operations that need all compactions stopped.
+    pub(crate) compaction_lock: RwLock<()>,
+    /// The tasks run by the compaction workers. Their SSTs are busy, and are not picked by other
+    /// tasks.
+    pub(crate) running_compactions: Mutex<Vec<CompactionTask>>,
+    pub(crate) path: PathBuf,
     pub(crate) block_cache: Arc<BlockCache>,
     next_sst_id: AtomicUsize,
     pub(crate) options: Arc<LsmStorageOptions>,
-    pub(crate) compaction_controller: CompactionController,
+    /// Replaced by `change_compaction_strategy`, which makes `options.compaction_options` stale.
+    pub(crate) compaction_controller: RwLock<Arc<CompactionController>>,
     pub(crate) manifest: Option<Manifest>,
     pub(crate) mvcc: Option<LsmMvccInner>,
     pub(crate) compaction_filters: Arc<Mutex<Vec<CompactionFilter>>>,
+    /// What was dropped when recovering the WALs.
+    pub(crate) wal_recovery_report: WalRecoveryReport,
+    /// Change data capture subscribers, see `MiniLsm::subscribe`.
+    pub(crate) change_subscribers: Mutex<Vec<ChangeSubscriber>>,
+    /// The error that stopped the replication thread of a follower, see `MiniLsm::check_replication`.
+    pub(crate) replication_error: Mutex<Option<String>>,
+    pub(crate) open_mode: OpenMode,
+    /// The lock on the LOCK file, held for the lifetime of the storage, see `lock_dir`. A secondary
+    /// does not take it, as the primary holds it.
+    _dir_lock: Option<File>,
+    /// What a secondary has caught up with, see `try_catch_up_with_primary`.
+    pub(crate) secondary: Option<Mutex<SecondaryState>>,
 }
 
 /// A thin wrapper for `LsmStorageInner` and the user interface for MiniLSM.
@@ -179,18 +364,44 @@ pub struct MiniLsm {
     compaction_notifier: crossbeam_channel::Sender<()>,
     /// The handle for the compaction thread. (In week 2)
     compaction_thread: Mutex<Option<std::thread::JoinHandle<()>>>,
+    /// Notifies the replication thread of a follower to stop working.
+    replication_notifier: crossbeam_channel::Sender<()>,
+    /// The handle for the replication thread of a follower.
+    replication_thread: Mutex<Option<std::thread::JoinHandle<()>>>,
 }
 
 impl Drop for MiniLsm {
     fn drop(&mut self) {
         self.compaction_notifier.send(()).ok();
         self.flush_notifier.send(()).ok();
+        self.replication_notifier.send(()).ok();
+        // wait for the background threads to release the storage, so that the directory can be
+        // opened again once this is dropped
+        for thread in [
+            &self.replication_thread,
+            &self.compaction_thread,
+            &self.flush_thread,
+        ] {
+            if let Some(thread) = thread.lock().take() {
+                thread.join().ok();
+            }
+        }
     }
 }
 
 impl MiniLsm {
     pub fn close(&self) -> Result<()> {
+        if self.inner.is_read_only() {
+            return Ok(());
+        }
         self.inner.sync_dir()?;
+        self.replication_notifier.send(()).ok();
+        let mut replication_thread = self.replication_thread.lock();
+        if let Some(replication_thread) = replication_thread.take() {
+            replication_thread
+                .join()
+                .map_err(|e| anyhow::anyhow!("{:?}", e))?;
+        }
         self.compaction_notifier.send(()).ok();
         self.flush_notifier.send(()).ok();
 
@@ -235,17 +446,89 @@ impl MiniLsm {
     /// Start the storage engine by either loading an existing directory or creating a new one if the directory does
     /// not exist.
     pub fn open(path: impl AsRef<Path>, options: LsmStorageOptions) -> Result<Arc<Self>> {
-        let inner = Arc::new(LsmStorageInner::open(path, options)?);
+        Self::start(LsmStorageInner::open(path, options)?, None)
+    }
+
+    /// Open a read-only follower, usually from a checkpoint of the leader created by
+    /// `create_checkpoint`. The follower applies the batches received from `transport` at their
+    /// leader commit ts, and serves reads at the latest applied batch. Writes are rejected.
+    pub fn open_follower(
+        path: impl AsRef<Path>,
+        options: LsmStorageOptions,
+        transport: Arc<dyn ReplicationTransport>,
+    ) -> Result<Arc<Self>> {
+        if !options.enable_wal {
+            bail!("followers require the WAL to be enabled");
+        }
+        Self::start(
+            LsmStorageInner::open_with_mode(path, options, OpenMode::Follower)?,
+            Some(transport),
+        )
+    }
+
+    /// Open the storage in `path` without modifying it, e.g., to inspect a copy of a database. The
+    /// state is recovered from the manifest and the WALs, which are left as they are, and no flush
+    /// or compaction happens. Writes are rejected. The storage may be opened read-only by several
+    /// processes at the same time, but not while it is opened for writing.
+    pub fn open_read_only(path: impl AsRef<Path>, options: LsmStorageOptions) -> Result<Arc<Self>> {
+        Self::start(
+            LsmStorageInner::open_with_mode(path, options, OpenMode::ReadOnly)?,
+            None,
+        )
+    }
+
+    /// Open a secondary of the storage in `path`, which may be opened for writing by another process
+    /// at the same time, the primary. The secondary serves reads like a read-only storage, from the
+    /// state recovered when it is opened; call `try_catch_up_with_primary` to see what the primary
+    /// has written since. Writes are seen once they reach the WAL files of the primary, e.g., after
+    /// `sync`. The SSTs that the primary removes after a compaction remain readable until the
+    /// secondary catches up.
+    pub fn open_as_secondary(
+        path: impl AsRef<Path>,
+        options: LsmStorageOptions,
+    ) -> Result<Arc<Self>> {
+        Self::start(
+            LsmStorageInner::open_with_mode(path, options, OpenMode::Secondary)?,
+            None,
+        )
+    }
+
+    /// Catch up with the writes, flushes and compactions of the primary, by reading the manifest
+    /// records and the WAL records it wrote since the last time. Only for a secondary, see
+    /// `open_as_secondary`.
+    pub fn try_catch_up_with_primary(&self) -> Result<()> {
+        self.inner.try_catch_up_with_primary()
+    }
+
+    /// Spawn the background threads of an opened storage engine.
+    fn start(
+        inner: LsmStorageInner,
+        transport: Option<Arc<dyn ReplicationTransport>>,
+    ) -> Result<Arc<Self>> {
+        let inner = Arc::new(inner);
+        let read_only = inner.is_read_only();
         let (tx1, rx) = crossbeam_channel::unbounded();
-        let compaction_thread = inner.spawn_compaction_thread(rx)?;
+        let compaction_thread = if read_only {
+            None
+        } else {
+            inner.spawn_compaction_thread(rx)?
+        };
         let (tx2, rx) = crossbeam_channel::unbounded();
-        let flush_thread = inner.spawn_flush_thread(rx)?;
+        let flush_thread = if read_only {
+            None
+        } else {
+            inner.spawn_flush_thread(rx)?
+        };
+        let (tx3, rx) = crossbeam_channel::unbounded();
+        let replication_thread = inner.spawn_replication_thread(rx, transport)?;
         Ok(Arc::new(Self {
             inner,
             flush_notifier: tx2,
             flush_thread: Mutex::new(flush_thread),
             compaction_notifier: tx1,
             compaction_thread: Mutex::new(compaction_thread),
+            replication_notifier: tx3,
+            replication_thread: Mutex::new(replication_thread),
         }))
     }
 
@@ -261,14 +544,30 @@ impl MiniLsm {
         self.inner.write_batch(batch)
     }
 
+    pub fn write_batch_with_options<T: AsRef<[u8]>>(
+        &self,
+        batch: &[WriteBatchRecord<T>],
+        options: &WriteOptions,
+    ) -> Result<()> {
+        self.inner.write_batch_with_options(batch, options)
+    }
+
     pub fn put(&self, key: &[u8], value: &[u8]) -> Result<()> {
         self.inner.put(key, value)
     }
 
+    pub fn put_with_options(&self, key: &[u8], value: &[u8], options: &WriteOptions) -> Result<()> {
+        self.inner.put_with_options(key, value, options)
+    }
+
     pub fn delete(&self, key: &[u8]) -> Result<()> {
         self.inner.delete(key)
     }
 
+    pub fn delete_with_options(&self, key: &[u8], options: &WriteOptions) -> Result<()> {
+        self.inner.delete_with_options(key, options)
+    }
+
     pub fn sync(&self) -> Result<()> {
         self.inner.sync()
     }
@@ -283,6 +582,7 @@ impl MiniLsm {
 
     /// Only call this in test cases due to race conditions
     pub fn force_flush(&self) -> Result<()> {
+        self.inner.check_writable()?;
         if !self.inner.state.read().memtable.is_empty() {
             self.inner
                 .force_freeze_memtable(&self.inner.state_lock.lock())?;
@@ -294,8 +594,93 @@ impl MiniLsm {
     }
 
     pub fn force_full_compaction(&self) -> Result<()> {
+        self.inner.check_writable()?;
         self.inner.force_full_compaction()
     }
+
+    /// Return the batches committed at or after `ts` in commit order. Fails if some of them are no
+    /// longer retained in the WALs, i.e., their WALs were deleted on flush because `archive_wal` is
+    /// disabled, or purged from the archive. Batches written with `disable_wal` are not included,
+    /// and are taken for dropped ones if no WAL retains a batch before them.
+    pub fn get_updates_since(&self, ts: u64) -> Result<Vec<ChangeBatch>> {
+        self.inner.get_updates_since(ts)
+    }
+
+    /// Subscribe to the batches committed at or after `from_ts`, starting with the ones retained in
+    /// the WALs, which must still retain all of them as in `get_updates_since`. Batches may be
+    /// delivered before they are durable. A subscriber that falls too far behind the writers is
+    /// dropped, see `ChangeStream`.
+    pub fn subscribe(&self, from_ts: u64) -> Result<ChangeStream> {
+        self.inner.subscribe(from_ts)
+    }
+
+    /// Remove the archived WALs whose batches were all committed before `ts`, returning the number of
+    /// WALs removed.
+    pub fn purge_wal_archive(&self, ts: u64) -> Result<usize> {
+        self.inner.check_writable()?;
+        self.inner.purge_wal_archive(ts)
+    }
+
+    /// Switch to another compaction strategy, after compacting all SSTs into a layout it accepts.
+    /// The storage must be opened with the new compaction options from now on.
+    pub fn change_compaction_strategy(&self, compaction_options: CompactionOptions) -> Result<()> {
+        self.inner.check_writable()?;
+        self.inner.change_compaction_strategy(compaction_options)
+    }
+
+    /// Remove the files in the storage directory that are no longer referenced, returning the number
+    /// of bytes reclaimed. This also happens when the storage is opened.
+    pub fn purge_obsolete_files(&self) -> Result<u64> {
+        self.inner.check_writable()?;
+        self.inner.purge_obsolete_files()
+    }
+
+    /// Create a checkpoint of the storage in `path`, which must not exist yet. Returns the commit ts
+    /// of the last batch in the checkpoint. A follower opened from the checkpoint catches up with the
+    /// batches shipped from the next ts; enable `archive_wal` if memtables may be flushed before
+    /// shipping starts, so that these batches are still retained.
+    pub fn create_checkpoint(&self, path: impl AsRef<Path>) -> Result<u64> {
+        // a read-only storage has no WAL for its memtable
+        self.inner.check_writable()?;
+        self.inner.create_checkpoint(path)
+    }
+
+    /// Check the integrity of the storage, and report all problems found with their location: every
+    /// SST in the state must exist and pass the checksums of its block meta and bloom filter, the
+    /// keys in each SST must be sorted, the SSTs within each level must be ordered and must not
+    /// overlap, and the WALs must replay cleanly. In deep mode, all blocks are read to check their
+    /// checksums and the order of all keys. Compactions wait while SSTs are checked.
+    pub fn verify(&self, deep: bool) -> Result<VerifyReport> {
+        self.inner.verify(deep)
+    }
+
+    /// Ship the batches committed at or after `from_ts` to a follower through `transport`.
+    pub fn start_wal_shipping(
+        &self,
+        from_ts: u64,
+        transport: Arc<dyn ReplicationTransport>,
+    ) -> Result<WalShipper> {
+        self.inner.spawn_wal_shipping_thread(from_ts, transport)
+    }
+
+    /// The commit ts of the latest batch, i.e., the applied ts on a follower.
+    pub fn latest_commit_ts(&self) -> u64 {
+        self.inner.mvcc().latest_commit_ts()
+    }
+
+    /// Return the error that stopped a follower from applying the batches shipped from its leader,
+    /// after which it no longer catches up. The follower must be reopened to resume replication.
+    pub fn check_replication(&self) -> Result<()> {
+        if let Some(e) = self.inner.replication_error.lock().as_ref() {
+            bail!("replication failed: {}", e);
+        }
+        Ok(())
+    }
+
+    /// What was dropped when recovering the WALs in `open`.
+    pub fn wal_recovery_report(&self) -> &WalRecoveryReport {
+        &self.inner.wal_recovery_report
+    }
 }
 
 impl LsmStorageInner {
@@ -308,6 +693,23 @@ impl LsmStorageInner {
         self.mvcc.as_ref().unwrap()
     }
 
+    /// Whether the storage never modifies its directory.
+    pub(crate) fn is_read_only(&self) -> bool {
+        matches!(self.open_mode, OpenMode::ReadOnly | OpenMode::Secondary)
+    }
+
+    /// Reject the operations that modify the directory of a read-only storage.
+    pub(crate) fn check_writable(&self) -> Result<()> {
+        if self.is_read_only() {
+            bail!("cannot modify a storage opened read-only");
+        }
+        Ok(())
+    }
+
+    pub(crate) fn compaction_controller(&self) -> Arc<CompactionController> {
+        self.compaction_controller.read().clone()
+    }
+
     pub(crate) fn manifest(&self) -> &Manifest {
         self.manifest.as_ref().unwrap()
     }
@@ -315,88 +717,141 @@ impl LsmStorageInner {
     /// Start the storage engine by either loading an existing directory or creating a new one if the directory does
     /// not exist.
     pub(crate) fn open(path: impl AsRef<Path>, options: LsmStorageOptions) -> Result<Self> {
+        Self::open_with_mode(path, options, OpenMode::ReadWrite)
+    }
+
+    pub(crate) fn open_with_mode(
+        path: impl AsRef<Path>,
+        options: LsmStorageOptions,
+        open_mode: OpenMode,
+    ) -> Result<Self> {
         let mut state = LsmStorageState::create(&options);
         let path = path.as_ref();
         let mut next_sst_id = 1;
         let block_cache = Arc::new(BlockCache::new(1 << 20)); // 4GB block cache,
-        let manifest;
+        let mut manifest = None;
 
-        let compaction_controller = match &options.compaction_options {
-            CompactionOptions::Leveled(options) => {
-                CompactionController::Leveled(LeveledCompactionController::new(options.clone()))
-            }
-            CompactionOptions::Tiered(options) => {
-                CompactionController::Tiered(TieredCompactionController::new(options.clone()))
-            }
-            CompactionOptions::Simple(options) => CompactionController::Simple(
-                SimpleLeveledCompactionController::new(options.clone()),
-            ),
-            CompactionOptions::NoCompaction => CompactionController::NoCompaction,
-        };
+        let compaction_controller =
+            CompactionController::new(&options.compaction_options).with_storage_options(&options);
 
+        let secondary = open_mode == OpenMode::Secondary;
+        let read_only = matches!(open_mode, OpenMode::ReadOnly | OpenMode::Secondary);
+        if read_only && !Manifest::exists(path) {
+            bail!("no storage to open read-only in {}", path.display());
+        }
         if !path.exists() {
             std::fs::create_dir_all(path).context("failed to create DB dir")?;
         }
-        let manifest_path = path.join("MANIFEST");
+        let dir_lock = if secondary {
+            None
+        } else {
+            Self::lock_dir(path, read_only)?
+        };
         let mut last_commit_ts = 0;
-        if !manifest_path.exists() {
+        let mut wal_recovery_report = WalRecoveryReport::default();
+        // set if recovery dropped memtables that the manifest still refers to
+        let mut manifest_outdated = false;
+        let manifest_created = !secondary && !Manifest::exists(path);
+        if secondary {
+            // recovered by catching up with the primary once opened
+        } else if manifest_created {
+            Self::check_no_orphaned_files(path)?;
             if options.enable_wal {
                 state.memtable = Arc::new(MemTable::create_with_wal(
                     state.memtable.id(),
                     Self::path_of_wal_static(path, state.memtable.id()),
                 )?);
             }
-            manifest = Manifest::create(&manifest_path).context("failed to create manifest")?;
-            manifest.add_record_when_init(ManifestRecord::NewMemtable(state.memtable.id()))?;
+            let m = Manifest::create(path).context("failed to create manifest")?;
+            // start with a snapshot, so that the manifest always records the compaction options
+            m.add_record_when_init(ManifestRecord::Snapshot {
+                memtables: vec![state.memtable.id()],
+                l0_sstables: Vec::new(),
+                levels: state.levels.clone(),
+                sstables: Vec::new(),
+                max_sst_id: state.memtable.id(),
+                compaction_options: options.compaction_options.clone(),
+            })?;
+            manifest = Some(m);
         } else {
-            let (m, records) = Manifest::recover(&manifest_path)?;
+            let records = if read_only {
+                Manifest::read(path)?
+            } else {
+                let (m, records) = Manifest::recover(path)?;
+                manifest = Some(m);
+                records
+            };
+            // the compaction strategy might have been changed since the OPTIONS file was written,
+            // in which case the manifest knows better
+            let persisted_compaction_options = match records
+                .iter()
+                .filter_map(|record| record.compaction_options())
+                .last()
+            {
+                Some(compaction_options) => Some(compaction_options.clone()),
+                None => Self::read_options(path)?.map(|options| options.compaction_options),
+            };
+            if let Some(persisted) = &persisted_compaction_options {
+                options
+                    .compaction_options
+                    .check_compatible_with(persisted)?;
+            }
             let mut memtables = BTreeSet::new();
+            let mut sst_info = HashMap::new();
+            let mut replay_controller = CompactionController::new(&options.compaction_options);
             for record in records {
-                match record {
-                    ManifestRecord::Flush(sst_id) => {
-                        let res = memtables.remove(&sst_id);
-                        assert!(res, "memtable not exist?");
-                        if compaction_controller.flush_to_l0() {
-                            state.l0_sstables.insert(0, sst_id);
-                        } else {
-                            state.levels.insert(0, (sst_id, vec![sst_id]));
-                        }
-                        next_sst_id = next_sst_id.max(sst_id);
-                    }
-                    ManifestRecord::NewMemtable(x) => {
-                        next_sst_id = next_sst_id.max(x);
-                        memtables.insert(x);
-                    }
-                    ManifestRecord::Compaction(task, output) => {
-                        let (new_state, _) =
-                            compaction_controller.apply_compaction_result(&state, &task, &output);
-                        // TODO: apply remove again
-                        state = new_state;
-                        next_sst_id =
-                            next_sst_id.max(output.iter().max().copied().unwrap_or_default());
-                    }
-                }
+                next_sst_id = next_sst_id.max(Self::apply_manifest_record(
+                    &mut state,
+                    &mut memtables,
+                    &mut sst_info,
+                    &mut replay_controller,
+                    record,
+                ));
             }
 
             let mut sst_cnt = 0;
-            // recover SSTs
+            let mut lazy_sst_cnt = 0;
+            // recover SSTs. The ones whose metadata is in the manifest are not read until they are
+            // used, while the ones recorded in a JSON manifest are read to learn their key ranges.
             for table_id in state
                 .l0_sstables
                 .iter()
                 .chain(state.levels.iter().flat_map(|(_, files)| files))
             {
                 let table_id = *table_id;
-                let sst = SsTable::open(
-                    table_id,
-                    Some(block_cache.clone()),
-                    FileObject::open(&Self::path_of_sst_static(path, table_id))
-                        .context("failed to open SST")?,
-                )?;
+                let file = FileObject::open(&Self::path_of_sst_static(path, table_id))
+                    .context("failed to open SST")?;
+                let sst = match sst_info.get(&table_id) {
+                    Some(info) => {
+                        if file.size() != info.size {
+                            bail!(
+                                "{}.sst has size {}, but the manifest recorded {}",
+                                table_id,
+                                file.size(),
+                                info.size
+                            );
+                        }
+                        lazy_sst_cnt += 1;
+                        SsTable::open_lazy(
+                            table_id,
+                            Some(block_cache.clone()),
+                            file,
+                            info.first_key.clone(),
+                            info.last_key.clone(),
+                            info.max_ts,
+                        )
+                    }
+                    None => SsTable::open(table_id, Some(block_cache.clone()), file)?,
+                };
                 last_commit_ts = last_commit_ts.max(sst.max_ts());
                 state.sstables.insert(table_id, Arc::new(sst));
                 sst_cnt += 1;
             }
-            println!("{} SSTs opened", sst_cnt);
+            println!(
+                "{} SSTs opened, {} of them from the manifest",
+                sst_cnt, lazy_sst_cnt
+            );
+            state.sort_levels();
 
             next_sst_id += 1;
 
@@ -404,8 +859,22 @@ impl LsmStorageInner {
             if options.enable_wal {
                 let mut wal_cnt = 0;
                 for id in memtables.iter() {
-                    let memtable =
-                        MemTable::recover_from_wal(*id, Self::path_of_wal_static(path, *id))?;
+                    let wal_path = Self::path_of_wal_static(path, *id);
+                    let memtable = if read_only {
+                        MemTable::replay_from_wal_with_mode(
+                            *id,
+                            wal_path,
+                            options.wal_recovery_mode,
+                            &mut wal_recovery_report,
+                        )?
+                    } else {
+                        MemTable::recover_from_wal_with_mode(
+                            *id,
+                            wal_path,
+                            options.wal_recovery_mode,
+                            &mut wal_recovery_report,
+                        )?
+                    };
                     let max_ts = memtable
                         .map
                         .iter()
@@ -416,9 +885,21 @@ impl LsmStorageInner {
                     if !memtable.is_empty() {
                         state.imm_memtables.insert(0, Arc::new(memtable));
                         wal_cnt += 1;
+                    } else {
+                        manifest_outdated = true;
                     }
                 }
                 println!("{} WALs recovered", wal_cnt);
+                if wal_recovery_report != WalRecoveryReport::default() {
+                    println!("WAL recovery: {:?}", wal_recovery_report);
+                }
+            }
+            if read_only {
+                // serve the latest recovered memtable as the current one, which is never written to
+                if !state.imm_memtables.is_empty() {
+                    state.memtable = state.imm_memtables.remove(0);
+                }
+            } else if options.enable_wal {
                 state.memtable = Arc::new(MemTable::create_with_wal(
                     next_sst_id,
                     Self::path_of_wal_static(path, next_sst_id),
@@ -426,28 +907,266 @@ impl LsmStorageInner {
             } else {
                 state.memtable = Arc::new(MemTable::create(next_sst_id));
             }
-            m.add_record_when_init(ManifestRecord::NewMemtable(state.memtable.id()))?;
-            next_sst_id += 1;
-            manifest = m;
+            if let Some(m) = &manifest {
+                m.add_record_when_init(ManifestRecord::NewMemtable(state.memtable.id()))?;
+                next_sst_id += 1;
+            }
         };
 
+        if !read_only {
+            Self::write_options(path, &options)?;
+        }
+
         let storage = Self {
             state: Arc::new(RwLock::new(Arc::new(state))),
             state_lock: Mutex::new(()),
+            compaction_lock: RwLock::new(()),
+            running_compactions: Mutex::new(Vec::new()),
             path: path.to_path_buf(),
             block_cache,
             next_sst_id: AtomicUsize::new(next_sst_id),
-            compaction_controller,
-            manifest: Some(manifest),
+            compaction_controller: RwLock::new(Arc::new(compaction_controller)),
+            manifest,
             options: options.into(),
             mvcc: Some(LsmMvccInner::new(last_commit_ts)),
             compaction_filters: Arc::new(Mutex::new(Vec::new())),
+            wal_recovery_report,
+            change_subscribers: Mutex::new(Vec::new()),
+            replication_error: Mutex::new(None),
+            open_mode,
+            _dir_lock: dir_lock,
+            secondary: secondary.then(|| Mutex::new(SecondaryState::new(path))),
         };
+        if secondary {
+            storage.try_catch_up_with_primary()?;
+        }
+        if read_only {
+            return Ok(storage);
+        }
         storage.sync_dir()?;
+        {
+            let state_lock = storage.state_lock.lock();
+            if manifest_outdated {
+                storage.rotate_manifest(&state_lock)?;
+            } else {
+                storage.try_rotate_manifest(&state_lock)?;
+            }
+        }
+        // clean up after a crash in the middle of a flush or a compaction, which never happens to a
+        // storage just created
+        if !manifest_created {
+            storage.purge_obsolete_files()?;
+        }
 
         Ok(storage)
     }
 
+    /// Replay a manifest record on `state`, tracking the memtables not flushed yet in `memtables`,
+    /// the SST metadata recorded in the manifest in `sst_info`, and the compaction strategy in
+    /// effect in `compaction_controller`. Returns the largest SST id in the record.
+    pub(crate) fn apply_manifest_record(
+        state: &mut LsmStorageState,
+        memtables: &mut BTreeSet<usize>,
+        sst_info: &mut HashMap<usize, SstInfo>,
+        compaction_controller: &mut CompactionController,
+        record: ManifestRecord,
+    ) -> usize {
+        let mut record_info = |sst: SstMetadata| {
+            if let Some(info) = sst.info {
+                sst_info.insert(sst.id, info);
+            }
+            sst.id
+        };
+        match record {
+            ManifestRecord::Flush(sst) => {
+                let sst_id = record_info(sst);
+                let res = memtables.remove(&sst_id);
+                assert!(res, "memtable not exist?");
+                if compaction_controller.flush_to_l0() {
+                    state.l0_sstables.insert(0, sst_id);
+                } else {
+                    state.levels.insert(0, (sst_id, vec![sst_id]));
+                }
+                sst_id
+            }
+            ManifestRecord::NewMemtable(x) => {
+                memtables.insert(x);
+                x
+            }
+            ManifestRecord::Compaction(task, output) => {
+                let output = output.into_iter().map(record_info).collect::<Vec<_>>();
+                let (new_state, _) =
+                    compaction_controller.apply_compaction_result(state, &task, &output);
+                *state = new_state;
+                output.iter().max().copied().unwrap_or_default()
+            }
+            ManifestRecord::Snapshot {
+                memtables: snapshot_memtables,
+                l0_sstables,
+                levels,
+                sstables,
+                max_sst_id,
+                compaction_options,
+            } => {
+                for sst in sstables {
+                    record_info(sst);
+                }
+                *compaction_controller = CompactionController::new(&compaction_options);
+                *memtables = snapshot_memtables.into_iter().collect();
+                state.l0_sstables = l0_sstables;
+                state.levels = levels;
+                max_sst_id
+            }
+            ManifestRecord::ChangeCompactionStrategy {
+                compaction_options,
+                l0_sstables,
+                levels,
+                output,
+            } => {
+                let output = output.into_iter().map(record_info).collect::<Vec<_>>();
+                *compaction_controller = CompactionController::new(&compaction_options);
+                state.l0_sstables = l0_sstables;
+                state.levels = levels;
+                output.iter().max().copied().unwrap_or_default()
+            }
+        }
+    }
+
+    /// A snapshot record of the current state for the manifest.
+    pub(crate) fn manifest_snapshot(&self, snapshot: &LsmStorageState) -> ManifestRecord {
+        ManifestRecord::Snapshot {
+            memtables: snapshot
+                .imm_memtables
+                .iter()
+                .rev()
+                .chain(std::iter::once(&snapshot.memtable))
+                .map(|memtable| memtable.id())
+                .collect(),
+            l0_sstables: snapshot.l0_sstables.clone(),
+            levels: snapshot.levels.clone(),
+            sstables: snapshot
+                .l0_sstables
+                .iter()
+                .chain(snapshot.levels.iter().flat_map(|(_, ssts)| ssts))
+                .map(|id| SstMetadata::from_sst(&snapshot.sstables[id]))
+                .collect(),
+            max_sst_id: self
+                .next_sst_id
+                .load(std::sync::atomic::Ordering::SeqCst)
+                .saturating_sub(1),
+            compaction_options: self.compaction_controller().options(),
+        }
+    }
+
+    /// Add a record to the manifest, and switch to a new manifest file starting with a snapshot once
+    /// the current one grows over `max_manifest_file_size`.
+    pub(crate) fn add_manifest_record(
+        &self,
+        state_lock_observer: &MutexGuard<'_, ()>,
+        record: ManifestRecord,
+    ) -> Result<()> {
+        self.manifest().add_record(state_lock_observer, record)?;
+        self.try_rotate_manifest(state_lock_observer)
+    }
+
+    fn try_rotate_manifest(&self, state_lock_observer: &MutexGuard<'_, ()>) -> Result<()> {
+        // manifests in the JSON format are rewritten as soon as the storage is opened
+        if self.manifest().size() >= self.options.max_manifest_file_size
+            || self.manifest().is_json()
+        {
+            self.rotate_manifest(state_lock_observer)?;
+        }
+        Ok(())
+    }
+
+    fn rotate_manifest(&self, state_lock_observer: &MutexGuard<'_, ()>) -> Result<()> {
+        let snapshot = self.state.read().clone();
+        self.manifest()
+            .rotate(state_lock_observer, self.manifest_snapshot(&snapshot))
+    }
+
+    /// Remove the SSTs, WALs and manifest files in the storage directory that are not referenced
+    /// by the current state, e.g., the inputs of a compaction that crashed before removing them, or
+    /// the output of one that never reached the manifest. Returns the number of bytes reclaimed.
+    pub(crate) fn purge_obsolete_files(&self) -> Result<u64> {
+        // running compactions write SSTs that are not in the state yet
+        let _compaction_lock = self.compaction_lock.write();
+        let _state_lock = self.state_lock.lock();
+        let snapshot = self.state.read().clone();
+        let memtables = snapshot
+            .imm_memtables
+            .iter()
+            .chain(std::iter::once(&snapshot.memtable))
+            .map(|memtable| memtable.id())
+            .collect::<HashSet<_>>();
+        let mut obsolete = self.manifest().obsolete_files()?;
+        for entry in std::fs::read_dir(&self.path)? {
+            let path = entry?.path();
+            let Some((id, ext)) = path
+                .file_name()
+                .and_then(|name| name.to_str())
+                .and_then(|name| name.split_once('.'))
+                .and_then(|(id, ext)| Some((id.parse::<usize>().ok()?, ext)))
+            else {
+                continue;
+            };
+            let referenced = match ext {
+                "sst" => snapshot.sstables.contains_key(&id),
+                // without the WAL, leave the WALs written by earlier runs alone
+                "wal" => !self.options.enable_wal || memtables.contains(&id),
+                _ => true,
+            };
+            if !referenced {
+                obsolete.push(path);
+            }
+        }
+        let mut reclaimed = 0;
+        for path in &obsolete {
+            reclaimed += std::fs::metadata(path)?.len();
+            std::fs::remove_file(path)?;
+            println!("removed obsolete file {}", path.display());
+        }
+        if !obsolete.is_empty() {
+            self.sync_dir()?;
+        }
+        Ok(reclaimed)
+    }
+
+    /// Refuse to create a manifest in `path` when the directory holds the files of a storage whose
+    /// manifest is lost, as they would be unreachable, and soon removed as obsolete. `repair_db`
+    /// rebuilds the manifest from them instead.
+    fn check_no_orphaned_files(path: &Path) -> Result<()> {
+        let mut orphaned = Manifest::files(path)?;
+        for entry in std::fs::read_dir(path)? {
+            let file = entry?.path();
+            let Some((_, ext)) = file
+                .file_name()
+                .and_then(|name| name.to_str())
+                .and_then(|name| name.split_once('.'))
+                .and_then(|(id, ext)| Some((id.parse::<usize>().ok()?, ext)))
+            else {
+                continue;
+            };
+            if ext == "sst" || ext == "wal" {
+                orphaned.push(file);
+            }
+        }
+        let archive = Self::path_of_wal_archive_static(path);
+        if archive.is_dir() && std::fs::read_dir(&archive)?.next().is_some() {
+            orphaned.push(archive);
+        }
+        if let Some(file) = orphaned.first() {
+            bail!(
+                "{} has no manifest, but holds {} files of a storage, e.g., {}; run repair_db to \
+                 rebuild the manifest",
+                path.display(),
+                orphaned.len(),
+                file.display()
+            );
+        }
+        Ok(())
+    }
+
     pub fn add_compaction_filter(&self, compaction_filter: CompactionFilter) {
         let mut compaction_filters = self.compaction_filters.lock();
         compaction_filters.push(compaction_filter);
@@ -484,26 +1203,27 @@ impl LsmStorageInner {
 
         let mut l0_iters = Vec::with_capacity(snapshot.l0_sstables.len());
 
-        let keep_table = |key: &[u8], table: &SsTable| {
+        let keep_table = |key: &[u8], table: &Arc<SsTable>| -> Result<bool> {
             if key_within(
                 key,
                 table.first_key().as_key_slice(),
                 table.last_key().as_key_slice(),
             ) {
-                if let Some(bloom) = &table.bloom {
+                // the bloom filter of an SST opened lazily is only read here
+                if let Some(bloom) = &table.opened()?.bloom {
                     if bloom.may_contain(farmhash::fingerprint32(key)) {
-                        return true;
+                        return Ok(true);
                     }
                 } else {
-                    return true;
+                    return Ok(true);
                 }
             }
-            false
+            Ok(false)
         };
 
         for table in snapshot.l0_sstables.iter() {
             let table = snapshot.sstables[table].clone();
-            if keep_table(key, &table) {
+            if keep_table(key, &table)? {
                 l0_iters.push(Box::new(SsTableIterator::create_and_seek_to_key(
                     table,
                     KeySlice::from_slice(key, key::TS_RANGE_BEGIN),
@@ -516,7 +1236,7 @@ impl LsmStorageInner {
             let mut level_ssts = Vec::with_capacity(snapshot.levels[0].1.len());
             for table in level_sst_ids {
                 let table = snapshot.sstables[table].clone();
-                if keep_table(key, &table) {
+                if keep_table(key, &table)? {
                     level_ssts.push(table);
                 }
             }
@@ -542,47 +1262,115 @@ impl LsmStorageInner {
         Ok(None)
     }
 
-    pub fn write_batch_inner<T: AsRef<[u8]>>(&self, batch: &[WriteBatchRecord<T>]) -> Result<u64> {
+    /// Write a batch with a single commit timestamp. With `options.sync`, the WAL is synced after
+    /// releasing the write lock, so that writers arriving during an `fsync` form the next group.
+    pub fn write_batch_inner<T: AsRef<[u8]>>(
+        &self,
+        batch: &[WriteBatchRecord<T>],
+        options: &WriteOptions,
+    ) -> Result<u64> {
+        let (ts, memtable, wal_offset) = self.write_batch_unpublished(batch, options)?;
+        self.publish_batch(batch, ts, &memtable, wal_offset, options)?;
+        Ok(ts)
+    }
+
+    /// Write a batch at the next commit timestamp without making it visible, and return the ts,
+    /// the memtable and the WAL offset to pass to `publish_batch`.
+    pub(crate) fn write_batch_unpublished<T: AsRef<[u8]>>(
+        &self,
+        batch: &[WriteBatchRecord<T>],
+        options: &WriteOptions,
+    ) -> Result<(u64, Arc<MemTable>, u64)> {
+        if self.open_mode == OpenMode::Follower {
+            bail!("cannot write to a follower");
+        }
+        self.check_writable()?;
         let _lck = self.mvcc().write_lock.lock();
-        let ts = self.mvcc().latest_commit_ts() + 1;
+        let ts = self.mvcc().written_commit_ts() + 1;
+        let (memtable, wal_offset) = self.write_batch_at_ts(batch, ts, options)?;
+        Ok((ts, memtable, wal_offset))
+    }
+
+    /// Make a batch written by `write_batch_unpublished` visible to readers and change
+    /// subscribers. With `options.sync`, this waits for the WAL sync first, so that nothing is
+    /// read or shipped before it is durable.
+    pub(crate) fn publish_batch<T: AsRef<[u8]>>(
+        &self,
+        batch: &[WriteBatchRecord<T>],
+        ts: u64,
+        memtable: &MemTable,
+        wal_offset: u64,
+        options: &WriteOptions,
+    ) -> Result<()> {
+        let synced = if options.sync && !options.disable_wal {
+            // the memtable might have been frozen in the meantime, in which case its WAL is
+            // already synced and this returns immediately
+            memtable.sync_wal_to(wal_offset)
+        } else {
+            Ok(())
+        };
+        // publish even if the sync failed, the batches after this one wait for it
+        self.mvcc().publish_commit_ts(ts, || {
+            if !options.disable_wal {
+                self.publish_change(ts, batch);
+            }
+        });
+        synced
+    }
+
+    /// Write a batch into the current memtable at `ts`, and return the memtable and the WAL offset
+    /// to sync. The batch is not visible until its ts is published. Must be called with the write
+    /// lock held.
+    pub(crate) fn write_batch_at_ts<T: AsRef<[u8]>>(
+        &self,
+        batch: &[WriteBatchRecord<T>],
+        ts: u64,
+        options: &WriteOptions,
+    ) -> Result<(Arc<MemTable>, u64)> {
+        let mut data = Vec::with_capacity(batch.len());
         for record in batch {
             match record {
                 WriteBatchRecord::Del(key) => {
                     let key = key.as_ref();
                     assert!(!key.is_empty(), "key cannot be empty");
-                    let size;
-                    {
-                        let guard = self.state.read();
-                        guard.memtable.put(KeySlice::from_slice(key, ts), b"")?;
-                        size = guard.memtable.approximate_size();
-                    }
-                    self.try_freeze(size)?;
+                    data.push((KeySlice::from_slice(key, ts), &b""[..]));
                 }
                 WriteBatchRecord::Put(key, value) => {
                     let key = key.as_ref();
                     let value = value.as_ref();
                     assert!(!key.is_empty(), "key cannot be empty");
                     assert!(!value.is_empty(), "value cannot be empty");
-                    let size;
-                    {
-                        let guard = self.state.read();
-                        guard.memtable.put(KeySlice::from_slice(key, ts), value)?;
-                        size = guard.memtable.approximate_size();
-                    }
-                    self.try_freeze(size)?;
+                    data.push((KeySlice::from_slice(key, ts), value));
                 }
             }
         }
-        self.mvcc().update_commit_ts(ts);
-        Ok(ts)
+        let (memtable, wal_offset) = {
+            let guard = self.state.read();
+            let wal_offset = guard.memtable.put_batch(&data, !options.disable_wal)?;
+            (guard.memtable.clone(), wal_offset)
+        };
+        self.try_freeze(memtable.approximate_size())?;
+        self.mvcc().update_written_ts(ts);
+        Ok((memtable, wal_offset))
     }
 
+    /// Write a batch with the default `WriteOptions`. The plain write methods keep the signatures
+    /// of the other mini-lsm crates, as the tests and the CLI shared with them call them.
     pub fn write_batch<T: AsRef<[u8]>>(
         self: &Arc<Self>,
         batch: &[WriteBatchRecord<T>],
+    ) -> Result<()> {
+        self.write_batch_with_options(batch, &WriteOptions::default())
+    }
+
+    /// Write a batch atomically, as a single commit, with the given `WriteOptions`.
+    pub fn write_batch_with_options<T: AsRef<[u8]>>(
+        self: &Arc<Self>,
+        batch: &[WriteBatchRecord<T>],
+        options: &WriteOptions,
     ) -> Result<()> {
         if !self.options.serializable {
-            self.write_batch_inner(batch)?;
+            self.write_batch_inner(batch, options)?;
         } else {
             let txn = self.mvcc().new_txn(self.clone(), self.options.serializable);
             for record in batch {
@@ -595,31 +1383,46 @@ impl LsmStorageInner {
                     }
                 }
             }
-            txn.commit()?;
+            txn.commit_with_options(options)?;
         }
         Ok(())
     }
 
     /// Put a key-value pair into the storage by writing into the current memtable.
     pub fn put(self: &Arc<Self>, key: &[u8], value: &[u8]) -> Result<()> {
+        self.put_with_options(key, value, &WriteOptions::default())
+    }
+
+    /// Put a key-value pair into the storage with the given `WriteOptions`.
+    pub fn put_with_options(
+        self: &Arc<Self>,
+        key: &[u8],
+        value: &[u8],
+        options: &WriteOptions,
+    ) -> Result<()> {
         if !self.options.serializable {
-            self.write_batch_inner(&[WriteBatchRecord::Put(key, value)])?;
+            self.write_batch_inner(&[WriteBatchRecord::Put(key, value)], options)?;
         } else {
             let txn = self.mvcc().new_txn(self.clone(), self.options.serializable);
             txn.put(key, value);
-            txn.commit()?;
+            txn.commit_with_options(options)?;
         }
         Ok(())
     }
 
     /// Remove a key from the storage by writing an empty value.
     pub fn delete(self: &Arc<Self>, key: &[u8]) -> Result<()> {
+        self.delete_with_options(key, &WriteOptions::default())
+    }
+
+    /// Remove a key from the storage with the given `WriteOptions`.
+    pub fn delete_with_options(self: &Arc<Self>, key: &[u8], options: &WriteOptions) -> Result<()> {
         if !self.options.serializable {
-            self.write_batch_inner(&[WriteBatchRecord::Del(key)])?;
+            self.write_batch_inner(&[WriteBatchRecord::Del(key)], options)?;
         } else {
             let txn = self.mvcc().new_txn(self.clone(), self.options.serializable);
             txn.delete(key);
-            txn.commit()?;
+            txn.commit_with_options(options)?;
         }
         Ok(())
     }
@@ -653,6 +1456,73 @@ impl LsmStorageInner {
         Self::path_of_wal_static(&self.path, id)
     }
 
+    pub(crate) fn path_of_options_static(path: impl AsRef<Path>) -> PathBuf {
+        path.as_ref().join("OPTIONS")
+    }
+
+    pub(crate) fn path_of_lock_static(path: impl AsRef<Path>) -> PathBuf {
+        path.as_ref().join("LOCK")
+    }
+
+    /// Lock the storage in `path` through its LOCK file, so that it is not opened by another
+    /// process (or twice by this one) at the same time. Readers that never modify the directory
+    /// take a `shared` lock, which only excludes writers. They open LOCK read-only, and skip the
+    /// lock if there is no LOCK file, so that they can read a read-only directory. The lock is
+    /// released when the returned file is closed.
+    pub(crate) fn lock_dir(path: &Path, shared: bool) -> Result<Option<File>> {
+        let file = if shared {
+            match File::open(Self::path_of_lock_static(path)) {
+                Ok(file) => file,
+                // no writer ever opened the storage since LOCK was introduced
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+                Err(e) => return Err(e).context("failed to open LOCK"),
+            }
+        } else {
+            File::options()
+                .read(true)
+                .write(true)
+                .create(true)
+                .truncate(false)
+                .open(Self::path_of_lock_static(path))
+                .context("failed to open LOCK")?
+        };
+        let result = if shared {
+            file.try_lock_shared()
+        } else {
+            file.try_lock()
+        };
+        match result {
+            Ok(()) => Ok(Some(file)),
+            Err(TryLockError::WouldBlock) => {
+                bail!("database {} is already in use", path.display())
+            }
+            Err(TryLockError::Error(e)) => Err(e).context("failed to lock LOCK"),
+        }
+    }
+
+    /// Read the OPTIONS file of the storage in `path`, if there is one.
+    pub(crate) fn read_options(path: &Path) -> Result<Option<LsmStorageOptions>> {
+        match std::fs::read(Self::path_of_options_static(path)) {
+            Ok(data) => Ok(Some(
+                serde_json::from_slice(&data).context("failed to parse OPTIONS")?,
+            )),
+            // created before the OPTIONS file was introduced
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e).context("failed to read OPTIONS"),
+        }
+    }
+
+    /// Atomically replace the OPTIONS file of the storage in `path`.
+    pub(crate) fn write_options(path: &Path, options: &LsmStorageOptions) -> Result<()> {
+        let tmp = path.join("OPTIONS.tmp");
+        let mut file = File::create(&tmp)?;
+        file.write_all(&serde_json::to_vec_pretty(options)?)?;
+        file.sync_all()?;
+        std::fs::rename(&tmp, Self::path_of_options_static(path))?;
+        File::open(path)?.sync_all()?;
+        Ok(())
+    }
+
     pub(super) fn sync_dir(&self) -> Result<()> {
         File::open(&self.path)?.sync_all()?;
         Ok(())
@@ -688,7 +1558,7 @@ impl LsmStorageInner {
 
         self.freeze_memtable_with_memtable(memtable)?;
 
-        self.manifest().add_record(
+        self.add_manifest_record(
             state_lock_observer,
             ManifestRecord::NewMemtable(memtable_id),
         )?;
@@ -699,8 +1569,13 @@ impl LsmStorageInner {
 
     /// Force flush the earliest-created immutable memtable to disk
     pub fn force_flush_next_imm_memtable(&self) -> Result<()> {
-        let state_lock = self.state_lock.lock();
+        self.flush_next_imm_memtable(&self.state_lock.lock())
+    }
 
+    pub(crate) fn flush_next_imm_memtable(
+        &self,
+        state_lock_observer: &MutexGuard<'_, ()>,
+    ) -> Result<()> {
         let flush_memtable;
 
         {
@@ -729,7 +1604,7 @@ impl LsmStorageInner {
             let mem = snapshot.imm_memtables.pop().unwrap();
             assert_eq!(mem.id(), sst_id);
             // Add L0 table
-            if self.compaction_controller.flush_to_l0() {
+            if self.compaction_controller().flush_to_l0() {
                 // In leveled compaction or no compaction, simply flush to L0
                 snapshot.l0_sstables.insert(0, sst_id);
             } else {
@@ -737,18 +1612,25 @@ impl LsmStorageInner {
                 snapshot.levels.insert(0, (sst_id, vec![sst_id]));
             }
             println!("flushed {}.sst with size={}", sst_id, sst.table_size());
-            snapshot.sstables.insert(sst_id, sst);
+            snapshot.sstables.insert(sst_id, sst.clone());
             // Update the snapshot.
             *guard = Arc::new(snapshot);
         }
 
+        self.add_manifest_record(
+            state_lock_observer,
+            ManifestRecord::Flush(SstMetadata::from_sst(&sst)),
+        )?;
+
         if self.options.enable_wal {
-            std::fs::remove_file(self.path_of_wal(sst_id))?;
+            if self.options.archive_wal {
+                std::fs::create_dir_all(Self::path_of_wal_archive_static(&self.path))?;
+                std::fs::rename(self.path_of_wal(sst_id), self.path_of_archived_wal(sst_id))?;
+            } else {
+                std::fs::remove_file(self.path_of_wal(sst_id))?;
+            }
         }
 
-        self.manifest()
-            .add_record(&state_lock, ManifestRecord::Flush(sst_id))?;
-
         self.sync_dir()?;
 
         Ok(())
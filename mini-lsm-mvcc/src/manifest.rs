@@ -1,69 +1,444 @@
 use std::fs::{File, OpenOptions};
-use std::io::{Read, Write};
-use std::path::Path;
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
 use std::sync::Arc;
 
 use anyhow::{bail, Context, Result};
-use bytes::{Buf, BufMut};
+use bytes::{Buf, BufMut, Bytes};
 use parking_lot::{Mutex, MutexGuard};
 use serde::{Deserialize, Serialize};
 
-use crate::compact::CompactionTask;
+use crate::compact::{
+    CompactionOptions, CompactionTask, FifoCompactionTask, LazyLevelingCompactionTask,
+    LeveledCompactionTask, SimpleLeveledCompactionTask, TieredCompactionTask,
+    TimeWindowCompactionTask,
+};
+use crate::key::KeyBytes;
+use crate::table::SsTable;
 
+/// The manifest is stored in `MANIFEST-<id>` files, and `CURRENT` holds the name of the one in use.
+/// Databases created before manifest rotation have a single `MANIFEST` file and no `CURRENT`.
+///
+/// A manifest file starts with a magic number and a format version, followed by the records:
+///
+/// ```text
+/// | magic (u32) | version (u16) | record_len (u32) | record | checksum (u32) | ... |
+/// ```
+///
+/// Files written before the binary format have no header and hold JSON records framed as
+/// `| record_len (u64) | json | checksum (u32) |`. They are still readable, and are replaced by a
+/// binary file when the storage is opened.
 pub struct Manifest {
-    file: Arc<Mutex<File>>,
+    dir: PathBuf,
+    file: Arc<Mutex<ManifestFile>>,
 }
 
-#[derive(Serialize, Deserialize)]
+struct ManifestFile {
+    file: File,
+    /// The id of the manifest file, 0 for the legacy `MANIFEST` file.
+    id: usize,
+    /// The size of the manifest file in bytes.
+    size: u64,
+    /// Whether the file holds JSON records.
+    json: bool,
+}
+
+/// What the manifest knows about an SST.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SstMetadata {
+    pub id: usize,
+    /// `None` for SSTs recorded in a JSON manifest, which only has their ids.
+    pub info: Option<SstInfo>,
+}
+
+/// The metadata of an SST, known from the manifest without reading the SST. The storage is opened
+/// with these, and only reads the block meta and bloom filter of an SST when it is first used, see
+/// `SsTable::open_lazy`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SstInfo {
+    pub first_key: KeyBytes,
+    pub last_key: KeyBytes,
+    /// The size of the SST file in bytes.
+    pub size: u64,
+    pub max_ts: u64,
+}
+
+impl SstMetadata {
+    pub fn from_sst(sst: &SsTable) -> Self {
+        Self {
+            id: sst.sst_id(),
+            info: Some(SstInfo {
+                first_key: sst.first_key().clone(),
+                last_key: sst.last_key().clone(),
+                size: sst.table_size(),
+                max_ts: sst.max_ts(),
+            }),
+        }
+    }
+
+    fn without_info(id: usize) -> Self {
+        Self { id, info: None }
+    }
+}
+
+#[derive(Debug)]
 pub enum ManifestRecord {
+    /// A memtable is flushed to the SST with the same id.
+    Flush(SstMetadata),
+    NewMemtable(usize),
+    /// A compaction task is applied, producing the given SSTs.
+    Compaction(CompactionTask, Vec<SstMetadata>),
+    /// The full state of the LSM tree, written at the beginning of each manifest file. Replaying
+    /// the manifest starts from the latest snapshot.
+    Snapshot {
+        /// Memtables not flushed yet, from earliest to latest.
+        memtables: Vec<usize>,
+        l0_sstables: Vec<usize>,
+        levels: Vec<(usize, Vec<usize>)>,
+        /// The metadata of all SSTs in `l0_sstables` and `levels`.
+        sstables: Vec<SstMetadata>,
+        /// The largest SST id that might have been allocated.
+        max_sst_id: usize,
+        /// The compaction options in effect.
+        compaction_options: CompactionOptions,
+    },
+    /// The compaction strategy is changed, and all SSTs are compacted into a layout the new
+    /// strategy accepts.
+    ChangeCompactionStrategy {
+        compaction_options: CompactionOptions,
+        l0_sstables: Vec<usize>,
+        levels: Vec<(usize, Vec<usize>)>,
+        /// The SSTs produced by compacting the old layout.
+        output: Vec<SstMetadata>,
+    },
+}
+
+impl ManifestRecord {
+    /// The compaction options in effect after this record, if it records them.
+    pub fn compaction_options(&self) -> Option<&CompactionOptions> {
+        match self {
+            Self::Snapshot {
+                compaction_options, ..
+            }
+            | Self::ChangeCompactionStrategy {
+                compaction_options, ..
+            } => Some(compaction_options),
+            _ => None,
+        }
+    }
+}
+
+/// The records of JSON manifest files.
+#[derive(Serialize, Deserialize)]
+enum LegacyManifestRecord {
     Flush(usize),
     NewMemtable(usize),
     Compaction(CompactionTask, Vec<usize>),
 }
 
+impl From<LegacyManifestRecord> for ManifestRecord {
+    fn from(record: LegacyManifestRecord) -> Self {
+        match record {
+            LegacyManifestRecord::Flush(id) => Self::Flush(SstMetadata::without_info(id)),
+            LegacyManifestRecord::NewMemtable(id) => Self::NewMemtable(id),
+            LegacyManifestRecord::Compaction(task, output) => Self::Compaction(
+                task,
+                output.into_iter().map(SstMetadata::without_info).collect(),
+            ),
+        }
+    }
+}
+
+impl TryFrom<&ManifestRecord> for LegacyManifestRecord {
+    type Error = anyhow::Error;
+
+    fn try_from(record: &ManifestRecord) -> Result<Self> {
+        Ok(match record {
+            ManifestRecord::Flush(sst) => Self::Flush(sst.id),
+            ManifestRecord::NewMemtable(id) => Self::NewMemtable(*id),
+            ManifestRecord::Compaction(task, output) => {
+                Self::Compaction(task.clone(), output.iter().map(|sst| sst.id).collect())
+            }
+            ManifestRecord::Snapshot { .. } | ManifestRecord::ChangeCompactionStrategy { .. } => {
+                bail!("cannot write {:?} to a JSON manifest", record)
+            }
+        })
+    }
+}
+
+/// Follows the manifest of a storage that another process is writing to, see
+/// `MiniLsm::open_as_secondary`.
+#[derive(Clone)]
+pub struct ManifestReader {
+    dir: PathBuf,
+    /// The id of the manifest file being read, `None` before the first read.
+    id: Option<usize>,
+    /// The offset of the first record not read yet.
+    offset: usize,
+    json: bool,
+}
+
+impl ManifestReader {
+    pub fn new(dir: impl AsRef<Path>) -> Self {
+        Self {
+            dir: dir.as_ref().to_path_buf(),
+            id: None,
+            offset: 0,
+            json: false,
+        }
+    }
+
+    /// Read the records appended since the last read. When the manifest has been rotated, the
+    /// records of the new file are returned instead, starting with its snapshot. A record that is
+    /// still being written is left for the next read.
+    pub fn read_new_records(&mut self) -> Result<Vec<ManifestRecord>> {
+        loop {
+            let id = Manifest::current_id(&self.dir)?;
+            let mut file = match File::open(self.dir.join(Manifest::file_name(id))) {
+                Ok(file) => file,
+                // rotated again since `CURRENT` was read
+                Err(e)
+                    if e.kind() == std::io::ErrorKind::NotFound
+                        && Manifest::current_id(&self.dir)? != id =>
+                {
+                    continue
+                }
+                Err(e) => return Err(e).context("failed to read manifest"),
+            };
+            let mut buf = Vec::new();
+            if self.id == Some(id) {
+                file.seek(SeekFrom::Start(self.offset as u64))?;
+                file.read_to_end(&mut buf)?;
+            } else {
+                // the new file starts with a snapshot of the state when the manifest was rotated,
+                // and the old file is not appended to afterwards
+                file.read_to_end(&mut buf)?;
+                let (json, header_size) = Manifest::decode_header(&buf)?;
+                buf.drain(..header_size);
+                self.id = Some(id);
+                self.offset = header_size;
+                self.json = json;
+            }
+            let (records, len) = Manifest::decode_body(&buf, self.json)?;
+            self.offset += len;
+            return Ok(records);
+        }
+    }
+}
+
+const CURRENT: &str = "CURRENT";
+const LEGACY_MANIFEST: &str = "MANIFEST";
+/// "MNFT". JSON files start with the u64 length of the first record, whose upper bytes are zero.
+const MANIFEST_MAGIC: u32 = 0x4d4e_4654;
+const MANIFEST_VERSION: u16 = 1;
+const HEADER_SIZE: u64 = (std::mem::size_of::<u32>() + std::mem::size_of::<u16>()) as u64;
+
 impl Manifest {
-    pub fn create(path: impl AsRef<Path>) -> Result<Self> {
+    fn file_name(id: usize) -> String {
+        if id == 0 {
+            LEGACY_MANIFEST.to_string()
+        } else {
+            format!("MANIFEST-{:06}", id)
+        }
+    }
+
+    /// Whether the directory contains a manifest.
+    pub fn exists(dir: impl AsRef<Path>) -> bool {
+        let dir = dir.as_ref();
+        dir.join(CURRENT).exists() || dir.join(LEGACY_MANIFEST).exists()
+    }
+
+    /// Atomically point `CURRENT` to the manifest file with the given id.
+    fn set_current(dir: &Path, id: usize) -> Result<()> {
+        let tmp = dir.join(format!("{}.tmp", CURRENT));
+        let mut file = File::create(&tmp)?;
+        file.write_all(format!("{}\n", Self::file_name(id)).as_bytes())?;
+        file.sync_all()?;
+        std::fs::rename(&tmp, dir.join(CURRENT))?;
+        File::open(dir)?.sync_all()?;
+        Ok(())
+    }
+
+    /// Create a manifest file with the given id, holding only the header.
+    fn create_file(dir: &Path, id: usize) -> Result<ManifestFile> {
+        // a crash in a previous rotation might have left a file with the same id behind
+        let mut file = OpenOptions::new()
+            .read(true)
+            .create(true)
+            .truncate(true)
+            .write(true)
+            .open(dir.join(Self::file_name(id)))
+            .context("failed to create manifest")?;
+        let mut buf = Vec::with_capacity(HEADER_SIZE as usize);
+        buf.put_u32(MANIFEST_MAGIC);
+        buf.put_u16(MANIFEST_VERSION);
+        file.write_all(&buf)?;
+        file.sync_all()?;
+        Ok(ManifestFile {
+            file,
+            id,
+            size: HEADER_SIZE,
+            json: false,
+        })
+    }
+
+    /// Create a new manifest in `dir`.
+    pub fn create(dir: impl AsRef<Path>) -> Result<Self> {
+        let dir = dir.as_ref();
+        if dir.join(Self::file_name(1)).exists() {
+            bail!("manifest already exists in {}", dir.display());
+        }
+        let file = Self::create_file(dir, 1)?;
+        Self::set_current(dir, 1)?;
         Ok(Self {
-            file: Arc::new(Mutex::new(
-                OpenOptions::new()
-                    .read(true)
-                    .create_new(true)
-                    .write(true)
-                    .open(path)
-                    .context("failed to create manifest")?,
-            )),
+            dir: dir.to_path_buf(),
+            file: Arc::new(Mutex::new(file)),
         })
     }
 
-    pub fn recover(path: impl AsRef<Path>) -> Result<(Self, Vec<ManifestRecord>)> {
+    /// Start a new manifest in `dir` with `snapshot`, replacing the one in use, if any, by switching
+    /// `CURRENT` to it atomically. The new file gets an id above the ids of all manifest files in
+    /// `dir`, so the manifest in use is left intact until the switch. Its files are then obsolete.
+    pub fn replace(dir: impl AsRef<Path>, snapshot: ManifestRecord) -> Result<Self> {
+        assert!(matches!(snapshot, ManifestRecord::Snapshot { .. }));
+        let dir = dir.as_ref();
+        let mut id = 1;
+        for file in Self::files(dir)? {
+            let name = file.file_name().and_then(|name| name.to_str()).unwrap();
+            if let Some(Ok(file_id)) = name.strip_prefix("MANIFEST-").map(str::parse::<usize>) {
+                id = id.max(file_id + 1);
+            }
+        }
+        let mut file = Self::create_file(dir, id)?;
+        file.size += Self::write_record(&mut file.file, &snapshot, false)?;
+        Self::set_current(dir, id)?;
+        Ok(Self {
+            dir: dir.to_path_buf(),
+            file: Arc::new(Mutex::new(file)),
+        })
+    }
+
+    /// The id of the manifest file in use in `dir`, which is 0 for a legacy manifest.
+    fn current_id(dir: &Path) -> Result<usize> {
+        Ok(match std::fs::read_to_string(dir.join(CURRENT)) {
+            Ok(current) => {
+                let current = current.trim_end();
+                match current.strip_prefix("MANIFEST-").map(str::parse::<usize>) {
+                    Some(Ok(id)) if id > 0 => id,
+                    _ => bail!("invalid CURRENT file: {:?}", current),
+                }
+            }
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => 0,
+            Err(e) => return Err(e).context("failed to read CURRENT"),
+        })
+    }
+
+    /// Recover the manifest in `dir`, returning the records starting from the latest snapshot.
+    pub fn recover(dir: impl AsRef<Path>) -> Result<(Self, Vec<ManifestRecord>)> {
+        let dir = dir.as_ref();
+        let id = Self::current_id(dir)?;
         let mut file = OpenOptions::new()
             .read(true)
             .append(true)
-            .open(path)
+            .open(dir.join(Self::file_name(id)))
             .context("failed to recover manifest")?;
         let mut buf = Vec::new();
         file.read_to_end(&mut buf)?;
-        let mut buf_ptr = buf.as_slice();
-        let mut records = Vec::new();
-        while buf_ptr.has_remaining() {
-            let len = buf_ptr.get_u64();
-            let slice = &buf_ptr[..len as usize];
-            let json = serde_json::from_slice::<ManifestRecord>(slice)?;
-            buf_ptr.advance(len as usize);
-            let checksum = buf_ptr.get_u32();
-            if checksum != crc32fast::hash(slice) {
-                bail!("checksum mismatched!");
-            }
-            records.push(json);
-        }
+        let (json, records) = Self::decode_records(&buf)?;
         Ok((
             Self {
-                file: Arc::new(Mutex::new(file)),
+                dir: dir.to_path_buf(),
+                file: Arc::new(Mutex::new(ManifestFile {
+                    file,
+                    id,
+                    size: buf.len() as u64,
+                    json,
+                })),
             },
             records,
         ))
     }
 
+    /// Read the records of the manifest in `dir` starting from the latest snapshot, without opening
+    /// it for writing.
+    pub fn read(dir: impl AsRef<Path>) -> Result<Vec<ManifestRecord>> {
+        let dir = dir.as_ref();
+        let buf = std::fs::read(dir.join(Self::file_name(Self::current_id(dir)?)))
+            .context("failed to read manifest")?;
+        Ok(Self::decode_records(&buf)?.1)
+    }
+
+    /// Decode the content of a manifest file, returning whether it is in the JSON format and the
+    /// records starting from the latest snapshot.
+    fn decode_records(buf: &[u8]) -> Result<(bool, Vec<ManifestRecord>)> {
+        let (json, header_size) = Self::decode_header(buf)?;
+        let (records, len) = Self::decode_body(&buf[header_size..], json)?;
+        if header_size + len < buf.len() {
+            bail!(
+                "incomplete record at offset {} of the manifest",
+                header_size + len
+            );
+        }
+        Ok((json, records))
+    }
+
+    /// Decode the header of a manifest file, returning whether it is in the JSON format and the size
+    /// of the header.
+    fn decode_header(buf: &[u8]) -> Result<(bool, usize)> {
+        let json = buf.len() < 4 || (&buf[..4]).get_u32() != MANIFEST_MAGIC;
+        if json {
+            return Ok((true, 0));
+        }
+        if buf.len() < HEADER_SIZE as usize {
+            bail!("incomplete manifest header");
+        }
+        let version = (&buf[4..]).get_u16();
+        if version != MANIFEST_VERSION {
+            bail!("unsupported manifest version {}", version);
+        }
+        Ok((false, HEADER_SIZE as usize))
+    }
+
+    /// Decode the records in `buf`, which follows the header of a manifest file, starting from the
+    /// latest snapshot. Decoding stops at a record that is not completely written, and the number of
+    /// bytes decoded is returned along with the records.
+    fn decode_body(buf: &[u8], json: bool) -> Result<(Vec<ManifestRecord>, usize)> {
+        let len_size = if json {
+            std::mem::size_of::<u64>()
+        } else {
+            std::mem::size_of::<u32>()
+        };
+        let mut buf_ptr = buf;
+        let mut records = Vec::new();
+        while buf_ptr.remaining() >= len_size {
+            let len = if json {
+                (&buf_ptr[..]).get_u64() as usize
+            } else {
+                (&buf_ptr[..]).get_u32() as usize
+            };
+            if buf_ptr.remaining() < len_size + len + 4 {
+                break;
+            }
+            buf_ptr.advance(len_size);
+            let slice = &buf_ptr[..len];
+            let checksum = (&buf_ptr[len..]).get_u32();
+            if checksum != crc32fast::hash(slice) {
+                bail!("checksum mismatched!");
+            }
+            let record = if json {
+                serde_json::from_slice::<LegacyManifestRecord>(slice)?.into()
+            } else {
+                decode_record(slice)?
+            };
+            buf_ptr.advance(len + 4);
+            if let ManifestRecord::Snapshot { .. } = record {
+                records.clear();
+            }
+            records.push(record);
+        }
+        Ok((records, buf.len() - buf_ptr.len()))
+    }
+
     pub fn add_record(
         &self,
         _state_lock_observer: &MutexGuard<()>,
@@ -74,12 +449,482 @@ impl Manifest {
 
     pub fn add_record_when_init(&self, record: ManifestRecord) -> Result<()> {
         let mut file = self.file.lock();
-        let mut buf = serde_json::to_vec(&record)?;
-        let hash = crc32fast::hash(&buf);
-        file.write_all(&(buf.len() as u64).to_be_bytes())?;
-        buf.put_u32(hash);
+        let json = file.json;
+        let written = Self::write_record(&mut file.file, &record, json)?;
+        file.size += written;
+        Ok(())
+    }
+
+    fn write_record(file: &mut File, record: &ManifestRecord, json: bool) -> Result<u64> {
+        let mut buf = Vec::new();
+        let payload = if json {
+            serde_json::to_vec(&LegacyManifestRecord::try_from(record)?)?
+        } else {
+            let mut payload = Vec::new();
+            encode_record(record, &mut payload);
+            payload
+        };
+        if json {
+            buf.put_u64(payload.len() as u64);
+        } else {
+            buf.put_u32(payload.len() as u32);
+        }
+        buf.put_slice(&payload);
+        buf.put_u32(crc32fast::hash(&payload));
         file.write_all(&buf)?;
         file.sync_all()?;
+        Ok(buf.len() as u64)
+    }
+
+    /// The size of the manifest file in use.
+    pub fn size(&self) -> u64 {
+        self.file.lock().size
+    }
+
+    /// Whether the manifest file in use holds JSON records, and should be rewritten in the binary
+    /// format.
+    pub fn is_json(&self) -> bool {
+        self.file.lock().json
+    }
+
+    /// Whether `name` is a manifest file or a temporary `CURRENT` file, excluding `CURRENT` itself.
+    fn is_manifest_file(name: &str) -> bool {
+        name == LEGACY_MANIFEST
+            || name.starts_with("MANIFEST-")
+            || name == format!("{}.tmp", CURRENT)
+    }
+
+    /// All manifest files in `dir`, including `CURRENT`.
+    pub fn files(dir: impl AsRef<Path>) -> Result<Vec<PathBuf>> {
+        let mut files = Vec::new();
+        for entry in std::fs::read_dir(dir)? {
+            let entry = entry?;
+            let file_name = entry.file_name();
+            let Some(name) = file_name.to_str() else {
+                continue;
+            };
+            if Self::is_manifest_file(name) || name == CURRENT {
+                files.push(entry.path());
+            }
+        }
+        Ok(files)
+    }
+
+    /// The manifest files in the directory other than the one in use, e.g., left behind by a crash
+    /// in the middle of a rotation.
+    pub fn obsolete_files(&self) -> Result<Vec<PathBuf>> {
+        let in_use = Self::file_name(self.file.lock().id);
+        let mut files = Vec::new();
+        for entry in std::fs::read_dir(&self.dir)? {
+            let entry = entry?;
+            let file_name = entry.file_name();
+            let Some(name) = file_name.to_str() else {
+                continue;
+            };
+            if Self::is_manifest_file(name) && name != in_use {
+                files.push(entry.path());
+            }
+        }
+        Ok(files)
+    }
+
+    /// Switch to a new manifest file starting with `snapshot`, which must reflect all the records
+    /// added so far, and remove the old file.
+    pub fn rotate(
+        &self,
+        _state_lock_observer: &MutexGuard<()>,
+        snapshot: ManifestRecord,
+    ) -> Result<()> {
+        assert!(matches!(snapshot, ManifestRecord::Snapshot { .. }));
+        let mut file = self.file.lock();
+        let id = file.id + 1;
+        let mut new_file = Self::create_file(&self.dir, id)?;
+        new_file.size += Self::write_record(&mut new_file.file, &snapshot, false)?;
+        let size = new_file.size;
+        Self::set_current(&self.dir, id)?;
+        let old_id = std::mem::replace(&mut *file, new_file).id;
+        std::fs::remove_file(self.dir.join(Self::file_name(old_id)))?;
+        println!(
+            "manifest rotated to {} with size={}",
+            Self::file_name(id),
+            size
+        );
         Ok(())
     }
 }
+
+const RECORD_FLUSH: u8 = 0;
+const RECORD_NEW_MEMTABLE: u8 = 1;
+const RECORD_COMPACTION: u8 = 2;
+const RECORD_SNAPSHOT: u8 = 3;
+const RECORD_CHANGE_COMPACTION_STRATEGY: u8 = 4;
+
+const TASK_LEVELED: u8 = 0;
+const TASK_TIERED: u8 = 1;
+const TASK_SIMPLE: u8 = 2;
+const TASK_FORCE_FULL: u8 = 3;
+const TASK_FIFO: u8 = 4;
+const TASK_TIME_WINDOW: u8 = 5;
+const TASK_LAZY_LEVELING: u8 = 6;
+
+fn encode_record(record: &ManifestRecord, buf: &mut Vec<u8>) {
+    match record {
+        ManifestRecord::Flush(sst) => {
+            buf.put_u8(RECORD_FLUSH);
+            encode_sst(sst, buf);
+        }
+        ManifestRecord::NewMemtable(id) => {
+            buf.put_u8(RECORD_NEW_MEMTABLE);
+            buf.put_u64(*id as u64);
+        }
+        ManifestRecord::Compaction(task, output) => {
+            buf.put_u8(RECORD_COMPACTION);
+            encode_task(task, buf);
+            buf.put_u32(output.len() as u32);
+            for sst in output {
+                encode_sst(sst, buf);
+            }
+        }
+        ManifestRecord::Snapshot {
+            memtables,
+            l0_sstables,
+            levels,
+            sstables,
+            max_sst_id,
+            compaction_options,
+        } => {
+            buf.put_u8(RECORD_SNAPSHOT);
+            encode_ids(memtables, buf);
+            encode_ids(l0_sstables, buf);
+            encode_levels(levels, buf);
+            buf.put_u32(sstables.len() as u32);
+            for sst in sstables {
+                encode_sst(sst, buf);
+            }
+            buf.put_u64(*max_sst_id as u64);
+            encode_compaction_options(compaction_options, buf);
+        }
+        ManifestRecord::ChangeCompactionStrategy {
+            compaction_options,
+            l0_sstables,
+            levels,
+            output,
+        } => {
+            buf.put_u8(RECORD_CHANGE_COMPACTION_STRATEGY);
+            encode_compaction_options(compaction_options, buf);
+            encode_ids(l0_sstables, buf);
+            encode_levels(levels, buf);
+            buf.put_u32(output.len() as u32);
+            for sst in output {
+                encode_sst(sst, buf);
+            }
+        }
+    }
+}
+
+fn decode_record(mut buf: &[u8]) -> Result<ManifestRecord> {
+    let record = match get_u8(&mut buf)? {
+        RECORD_FLUSH => ManifestRecord::Flush(decode_sst(&mut buf)?),
+        RECORD_NEW_MEMTABLE => ManifestRecord::NewMemtable(get_u64(&mut buf)? as usize),
+        RECORD_COMPACTION => {
+            let task = decode_task(&mut buf)?;
+            let output = (0..get_u32(&mut buf)?)
+                .map(|_| decode_sst(&mut buf))
+                .collect::<Result<_>>()?;
+            ManifestRecord::Compaction(task, output)
+        }
+        RECORD_SNAPSHOT => {
+            let memtables = decode_ids(&mut buf)?;
+            let l0_sstables = decode_ids(&mut buf)?;
+            let levels = decode_levels(&mut buf)?;
+            let sstables = (0..get_u32(&mut buf)?)
+                .map(|_| decode_sst(&mut buf))
+                .collect::<Result<_>>()?;
+            let max_sst_id = get_u64(&mut buf)? as usize;
+            let compaction_options = decode_compaction_options(&mut buf)?;
+            ManifestRecord::Snapshot {
+                memtables,
+                l0_sstables,
+                levels,
+                sstables,
+                max_sst_id,
+                compaction_options,
+            }
+        }
+        RECORD_CHANGE_COMPACTION_STRATEGY => {
+            let compaction_options = decode_compaction_options(&mut buf)?;
+            let l0_sstables = decode_ids(&mut buf)?;
+            let levels = decode_levels(&mut buf)?;
+            let output = (0..get_u32(&mut buf)?)
+                .map(|_| decode_sst(&mut buf))
+                .collect::<Result<_>>()?;
+            ManifestRecord::ChangeCompactionStrategy {
+                compaction_options,
+                l0_sstables,
+                levels,
+                output,
+            }
+        }
+        tag => bail!("unknown manifest record type {}", tag),
+    };
+    if buf.has_remaining() {
+        bail!("trailing bytes in manifest record");
+    }
+    Ok(record)
+}
+
+fn encode_task(task: &CompactionTask, buf: &mut Vec<u8>) {
+    match task {
+        CompactionTask::Leveled(task) => {
+            buf.put_u8(TASK_LEVELED);
+            encode_level_task(
+                task.upper_level,
+                &task.upper_level_sst_ids,
+                task.lower_level,
+                &task.lower_level_sst_ids,
+                task.is_lower_level_bottom_level,
+                buf,
+            );
+        }
+        CompactionTask::Tiered(task) => {
+            buf.put_u8(TASK_TIERED);
+            encode_levels(&task.tiers, buf);
+            buf.put_u8(task.bottom_tier_included as u8);
+        }
+        CompactionTask::Simple(task) => {
+            buf.put_u8(TASK_SIMPLE);
+            encode_level_task(
+                task.upper_level,
+                &task.upper_level_sst_ids,
+                task.lower_level,
+                &task.lower_level_sst_ids,
+                task.is_lower_level_bottom_level,
+                buf,
+            );
+        }
+        CompactionTask::Fifo(task) => {
+            buf.put_u8(TASK_FIFO);
+            encode_ids(&task.ssts, buf);
+        }
+        CompactionTask::TimeWindow(task) => {
+            buf.put_u8(TASK_TIME_WINDOW);
+            buf.put_u64(task.window);
+            encode_levels(&task.tiers, buf);
+            buf.put_u8(task.bottom_tier_included as u8);
+            buf.put_u8(task.expired as u8);
+        }
+        CompactionTask::LazyLeveling(task) => {
+            buf.put_u8(TASK_LAZY_LEVELING);
+            buf.put_u64(task.upper_level.unwrap_or_default() as u64);
+            buf.put_u32(task.upper_level_runs.len() as u32);
+            for run in &task.upper_level_runs {
+                encode_ids(run, buf);
+            }
+            buf.put_u64(task.lower_level as u64);
+            encode_ids(&task.lower_level_sst_ids, buf);
+            buf.put_u8(task.is_lower_level_bottom_level as u8);
+        }
+        CompactionTask::ForceFullCompaction {
+            l0_sstables,
+            l1_sstables,
+        } => {
+            buf.put_u8(TASK_FORCE_FULL);
+            encode_ids(l0_sstables, buf);
+            encode_ids(l1_sstables, buf);
+        }
+    }
+}
+
+fn encode_level_task(
+    upper_level: Option<usize>,
+    upper_level_sst_ids: &[usize],
+    lower_level: usize,
+    lower_level_sst_ids: &[usize],
+    is_lower_level_bottom_level: bool,
+    buf: &mut Vec<u8>,
+) {
+    // level 0 is never a lower level, so it stands for L0 as the upper level
+    buf.put_u64(upper_level.unwrap_or_default() as u64);
+    encode_ids(upper_level_sst_ids, buf);
+    buf.put_u64(lower_level as u64);
+    encode_ids(lower_level_sst_ids, buf);
+    buf.put_u8(is_lower_level_bottom_level as u8);
+}
+
+fn decode_task(buf: &mut &[u8]) -> Result<CompactionTask> {
+    let task = match get_u8(buf)? {
+        tag @ (TASK_LEVELED | TASK_SIMPLE) => {
+            let upper_level = Some(get_u64(buf)? as usize).filter(|level| *level != 0);
+            let upper_level_sst_ids = decode_ids(buf)?;
+            let lower_level = get_u64(buf)? as usize;
+            let lower_level_sst_ids = decode_ids(buf)?;
+            let is_lower_level_bottom_level = get_u8(buf)? != 0;
+            // the grandparents only decide where the outputs are cut, and are not needed to apply
+            // the result
+            let grandparent_sst_ids = Vec::new();
+            if tag == TASK_LEVELED {
+                CompactionTask::Leveled(LeveledCompactionTask {
+                    upper_level,
+                    upper_level_sst_ids,
+                    lower_level,
+                    lower_level_sst_ids,
+                    is_lower_level_bottom_level,
+                    grandparent_sst_ids,
+                })
+            } else {
+                CompactionTask::Simple(SimpleLeveledCompactionTask {
+                    upper_level,
+                    upper_level_sst_ids,
+                    lower_level,
+                    lower_level_sst_ids,
+                    is_lower_level_bottom_level,
+                    grandparent_sst_ids,
+                })
+            }
+        }
+        TASK_TIERED => {
+            let tiers = decode_levels(buf)?;
+            CompactionTask::Tiered(TieredCompactionTask {
+                tiers,
+                bottom_tier_included: get_u8(buf)? != 0,
+            })
+        }
+        TASK_FIFO => CompactionTask::Fifo(FifoCompactionTask {
+            ssts: decode_ids(buf)?,
+        }),
+        TASK_TIME_WINDOW => CompactionTask::TimeWindow(TimeWindowCompactionTask {
+            window: get_u64(buf)?,
+            tiers: decode_levels(buf)?,
+            bottom_tier_included: get_u8(buf)? != 0,
+            expired: get_u8(buf)? != 0,
+        }),
+        TASK_LAZY_LEVELING => CompactionTask::LazyLeveling(LazyLevelingCompactionTask {
+            upper_level: Some(get_u64(buf)? as usize).filter(|level| *level != 0),
+            upper_level_runs: (0..get_u32(buf)?)
+                .map(|_| decode_ids(buf))
+                .collect::<Result<_>>()?,
+            lower_level: get_u64(buf)? as usize,
+            lower_level_sst_ids: decode_ids(buf)?,
+            is_lower_level_bottom_level: get_u8(buf)? != 0,
+        }),
+        TASK_FORCE_FULL => CompactionTask::ForceFullCompaction {
+            l0_sstables: decode_ids(buf)?,
+            l1_sstables: decode_ids(buf)?,
+        },
+        tag => bail!("unknown compaction task type {}", tag),
+    };
+    Ok(task)
+}
+
+fn encode_ids(ids: &[usize], buf: &mut Vec<u8>) {
+    buf.put_u32(ids.len() as u32);
+    for id in ids {
+        buf.put_u64(*id as u64);
+    }
+}
+
+fn decode_ids(buf: &mut &[u8]) -> Result<Vec<usize>> {
+    (0..get_u32(buf)?)
+        .map(|_| Ok(get_u64(buf)? as usize))
+        .collect()
+}
+
+/// Encode levels or tiers, each of which is an id and a list of SSTs.
+fn encode_levels(levels: &[(usize, Vec<usize>)], buf: &mut Vec<u8>) {
+    buf.put_u32(levels.len() as u32);
+    for (id, ssts) in levels {
+        buf.put_u64(*id as u64);
+        encode_ids(ssts, buf);
+    }
+}
+
+fn decode_levels(buf: &mut &[u8]) -> Result<Vec<(usize, Vec<usize>)>> {
+    (0..get_u32(buf)?)
+        .map(|_| Ok((get_u64(buf)? as usize, decode_ids(buf)?)))
+        .collect()
+}
+
+/// Compaction options are stored as JSON, like in the OPTIONS file, as they are rarely written.
+fn encode_compaction_options(options: &CompactionOptions, buf: &mut Vec<u8>) {
+    let json = serde_json::to_vec(options).expect("failed to serialize compaction options");
+    buf.put_u32(json.len() as u32);
+    buf.put_slice(&json);
+}
+
+fn decode_compaction_options(buf: &mut &[u8]) -> Result<CompactionOptions> {
+    let len = get_u32(buf)? as usize;
+    check_remaining(buf, len)?;
+    let options = serde_json::from_slice(&buf[..len])?;
+    buf.advance(len);
+    Ok(options)
+}
+
+fn encode_sst(sst: &SstMetadata, buf: &mut Vec<u8>) {
+    buf.put_u64(sst.id as u64);
+    let Some(info) = &sst.info else {
+        buf.put_u8(0);
+        return;
+    };
+    buf.put_u8(1);
+    encode_key(&info.first_key, buf);
+    encode_key(&info.last_key, buf);
+    buf.put_u64(info.size);
+    buf.put_u64(info.max_ts);
+}
+
+fn decode_sst(buf: &mut &[u8]) -> Result<SstMetadata> {
+    let id = get_u64(buf)? as usize;
+    let info = if get_u8(buf)? != 0 {
+        Some(SstInfo {
+            first_key: decode_key(buf)?,
+            last_key: decode_key(buf)?,
+            size: get_u64(buf)?,
+            max_ts: get_u64(buf)?,
+        })
+    } else {
+        None
+    };
+    Ok(SstMetadata { id, info })
+}
+
+fn encode_key(key: &KeyBytes, buf: &mut Vec<u8>) {
+    buf.put_u16(key.key_len() as u16);
+    buf.put_slice(key.key_ref());
+    buf.put_u64(key.ts());
+}
+
+fn decode_key(buf: &mut &[u8]) -> Result<KeyBytes> {
+    let len = get_u16(buf)? as usize;
+    check_remaining(buf, len)?;
+    let key: Bytes = buf.copy_to_bytes(len);
+    Ok(KeyBytes::from_bytes_with_ts(key, get_u64(buf)?))
+}
+
+/// Fail when a record has less than `len` bytes left, e.g., as it is malformed or was written in
+/// another version of the format, instead of reading past its end.
+fn check_remaining(buf: &[u8], len: usize) -> Result<()> {
+    if buf.remaining() < len {
+        bail!("manifest record is too short");
+    }
+    Ok(())
+}
+
+fn get_u8(buf: &mut &[u8]) -> Result<u8> {
+    check_remaining(buf, 1)?;
+    Ok(buf.get_u8())
+}
+
+fn get_u16(buf: &mut &[u8]) -> Result<u16> {
+    check_remaining(buf, 2)?;
+    Ok(buf.get_u16())
+}
+
+fn get_u32(buf: &mut &[u8]) -> Result<u32> {
+    check_remaining(buf, 4)?;
+    Ok(buf.get_u32())
+}
+
+fn get_u64(buf: &mut &[u8]) -> Result<u64> {
+    check_remaining(buf, 8)?;
+    Ok(buf.get_u64())
+}
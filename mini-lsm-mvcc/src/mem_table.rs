@@ -12,7 +12,7 @@ use ouroboros::self_referencing;
 use crate::iterators::StorageIterator;
 use crate::key::{KeyBytes, KeySlice, TS_DEFAULT};
 use crate::table::SsTableBuilder;
-use crate::wal::Wal;
+use crate::wal::{Wal, WalRecoveryMode, WalRecoveryReport};
 
 /// A basic mem-table based on crossbeam-skiplist.
 ///
@@ -90,6 +90,46 @@ impl MemTable {
         })
     }
 
+    /// Create a memtable from WAL, handling bad records as specified by `mode`.
+    pub fn recover_from_wal_with_mode(
+        id: usize,
+        path: impl AsRef<Path>,
+        mode: WalRecoveryMode,
+        report: &mut WalRecoveryReport,
+    ) -> Result<Self> {
+        let map = Arc::new(SkipMap::new());
+        Ok(Self {
+            id,
+            wal: Some(Wal::recover_with_mode(path.as_ref(), &map, mode, report)?),
+            map,
+            approximate_size: Arc::new(AtomicUsize::new(0)),
+        })
+    }
+
+    /// Create a memtable from WAL like `recover_from_wal_with_mode`, but leave the WAL untouched and
+    /// do not attach it to the memtable. Used by read-only storages.
+    pub fn replay_from_wal_with_mode(
+        id: usize,
+        path: impl AsRef<Path>,
+        mode: WalRecoveryMode,
+        report: &mut WalRecoveryReport,
+    ) -> Result<Self> {
+        let map = Arc::new(SkipMap::new());
+        Wal::replay_with_mode(path.as_ref(), &map, mode, report)?;
+        Ok(Self {
+            id,
+            map,
+            wal: None,
+            approximate_size: Arc::new(AtomicUsize::new(0)),
+        })
+    }
+
+    /// Apply the records after `offset` in the WAL in `path` to this memtable, see
+    /// `Wal::replay_from`.
+    pub fn replay_wal_from(&self, path: impl AsRef<Path>, offset: u64) -> Result<(u64, u64)> {
+        Wal::replay_from(path, offset, &self.map)
+    }
+
     /// Get a value by key. Should not be used in week 3.
     pub fn get(&self, key: KeySlice) -> Option<Bytes> {
         let key_bytes = KeyBytes::from_bytes_with_ts(
@@ -123,17 +163,27 @@ impl MemTable {
     /// In week 1, day 1, simply put the key-value pair into the skipmap.
     /// In week 2, day 6, also flush the data to WAL.
     pub fn put(&self, key: KeySlice, value: &[u8]) -> Result<()> {
-        let estimated_size = key.raw_len() + value.len();
-        self.map.insert(
-            key.to_key_vec().into_key_bytes(),
-            Bytes::copy_from_slice(value),
-        );
+        self.put_batch(&[(key, value)], true)?;
+        Ok(())
+    }
+
+    /// Put a batch of key-value pairs into the mem-table, and append them to the WAL if
+    /// `write_wal` is set. Returns the WAL offset to pass to `sync_wal_to`.
+    pub fn put_batch(&self, data: &[(KeySlice, &[u8])], write_wal: bool) -> Result<u64> {
+        let mut estimated_size = 0;
+        for (key, value) in data {
+            estimated_size += key.raw_len() + value.len();
+            self.map.insert(
+                key.to_key_vec().into_key_bytes(),
+                Bytes::copy_from_slice(value),
+            );
+        }
         self.approximate_size
             .fetch_add(estimated_size, std::sync::atomic::Ordering::Relaxed);
-        if let Some(ref wal) = self.wal {
-            wal.put(key, value)?;
+        if let (Some(wal), true) = (&self.wal, write_wal) {
+            return wal.put_batch(data);
         }
-        Ok(())
+        Ok(0)
     }
 
     pub fn sync_wal(&self) -> Result<()> {
@@ -143,6 +193,22 @@ impl MemTable {
         Ok(())
     }
 
+    /// Write the buffered WAL records to the file without syncing it.
+    pub fn flush_wal_buffer(&self) -> Result<()> {
+        if let Some(ref wal) = self.wal {
+            wal.flush_buffer()?;
+        }
+        Ok(())
+    }
+
+    /// Wait until the WAL is durable up to `offset`, sharing the `fsync` with concurrent writers.
+    pub fn sync_wal_to(&self, offset: u64) -> Result<()> {
+        if let Some(ref wal) = self.wal {
+            wal.sync_to(offset)?;
+        }
+        Ok(())
+    }
+
     /// Get an iterator over a range of keys.
     pub fn scan(&self, lower: Bound<KeySlice>, upper: Bound<KeySlice>) -> MemTableIterator {
         let (lower, upper) = (map_key_bound(lower), map_key_bound(upper));
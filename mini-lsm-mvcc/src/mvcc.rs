@@ -6,11 +6,14 @@ pub mod watermark;
 
 use std::{
     collections::{BTreeMap, HashSet},
-    sync::{atomic::AtomicBool, Arc},
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc,
+    },
 };
 
 use crossbeam_skiplist::SkipMap;
-use parking_lot::Mutex;
+use parking_lot::{Condvar, Mutex};
 
 use crate::lsm_storage::LsmStorageInner;
 
@@ -28,6 +31,10 @@ pub(crate) struct LsmMvccInner {
     pub(crate) write_lock: Mutex<()>,
     pub(crate) commit_lock: Mutex<()>,
     pub(crate) ts: Arc<Mutex<(u64, Watermark)>>,
+    /// The commit ts of the latest batch written to the memtable. It runs ahead of the visible
+    /// commit ts in `ts` while the batches in between wait for their WAL sync.
+    written_ts: AtomicU64,
+    commit_published: Condvar,
     pub(crate) committed_txns: Arc<Mutex<BTreeMap<u64, CommittedTxnData>>>,
 }
 
@@ -37,6 +44,8 @@ impl LsmMvccInner {
             write_lock: Mutex::new(()),
             commit_lock: Mutex::new(()),
             ts: Arc::new(Mutex::new((initial_ts, Watermark::new()))),
+            written_ts: AtomicU64::new(initial_ts),
+            commit_published: Condvar::new(),
             committed_txns: Arc::new(Mutex::new(BTreeMap::new())),
         }
     }
@@ -46,7 +55,43 @@ impl LsmMvccInner {
     }
 
     pub fn update_commit_ts(&self, ts: u64) {
-        self.ts.lock().0 = ts;
+        let mut guard = self.ts.lock();
+        guard.0 = ts;
+        self.written_ts.store(ts, Ordering::SeqCst);
+    }
+
+    /// The commit ts of the latest batch written to the memtable, which might not be visible yet.
+    /// Must be called with the write lock held.
+    pub(crate) fn written_commit_ts(&self) -> u64 {
+        self.written_ts.load(Ordering::SeqCst)
+    }
+
+    /// Record that the batch at `ts` has been written to the memtable. Must be called with the
+    /// write lock held.
+    pub(crate) fn update_written_ts(&self, ts: u64) {
+        self.written_ts.store(ts, Ordering::SeqCst);
+    }
+
+    /// Make the batch at `ts` visible once all the batches before it are, calling `publish` right
+    /// before that so that the changes are published in commit order.
+    pub(crate) fn publish_commit_ts(&self, ts: u64, publish: impl FnOnce()) {
+        let mut guard = self.ts.lock();
+        while guard.0 + 1 < ts {
+            self.commit_published.wait(&mut guard);
+        }
+        assert_eq!(guard.0 + 1, ts, "commit ts published out of order");
+        publish();
+        guard.0 = ts;
+        self.commit_published.notify_all();
+    }
+
+    /// Wait until every batch written to the memtable is visible. Called with the write lock held,
+    /// this gives a point where the memtables and the WALs hold exactly the visible batches.
+    pub(crate) fn wait_for_written_commits(&self) {
+        let mut guard = self.ts.lock();
+        while guard.0 < self.written_ts.load(Ordering::SeqCst) {
+            self.commit_published.wait(&mut guard);
+        }
     }
 
     /// All ts (strictly) below this ts can be garbage collected.
@@ -0,0 +1,175 @@
+use std::collections::BTreeSet;
+use std::path::{Path, PathBuf};
+
+use anyhow::{bail, Result};
+
+use crate::compact::CompactionController;
+use crate::key::KeySlice;
+use crate::lsm_storage::{LsmStorageInner, LsmStorageOptions, LsmStorageState};
+use crate::manifest::{Manifest, ManifestRecord, SstMetadata};
+use crate::mem_table::MemTable;
+use crate::table::{FileObject, SsTable, SsTableBuilder};
+use crate::wal::Wal;
+
+/// Where `repair_db` moves the files it does not keep.
+const LOST_DIR: &str = "lost";
+
+/// What `repair_db` did to a storage directory.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct RepairReport {
+    /// The SSTs in the rebuilt manifest, including the ones built from WALs.
+    pub ssts: Vec<usize>,
+    /// The WALs whose records were salvaged into new SSTs.
+    pub salvaged_wals: Vec<usize>,
+    /// Number of records (batches) salvaged from the WALs.
+    pub salvaged_records: usize,
+    /// The files moved to the `lost` directory: the old manifest, the SSTs that failed validation,
+    /// and the WALs once salvaged.
+    pub lost_files: Vec<PathBuf>,
+}
+
+/// Rebuild the manifest of the storage in `path` from the SSTs and WALs in the directory, when the
+/// manifest is corrupted and the storage fails to open. Every SST is validated, and the readable
+/// records of the WALs are written to new SSTs. All SSTs are placed in L0, or in tiers for tiered
+/// compaction, ordered by their `max_ts`. The compaction strategy and the block size are taken from
+/// the OPTIONS file, or from `options` if there is none, which should then be the options the
+/// storage is opened with. The old manifest and the salvaged WALs are only moved away once the new
+/// manifest is in use, so a repair that fails halfway can be run again.
+pub fn repair_db(path: impl AsRef<Path>, options: &LsmStorageOptions) -> Result<RepairReport> {
+    LsmStorageInner::repair(path.as_ref(), options)
+}
+
+/// Move `file` to the `lost` directory of the storage in `path`.
+fn move_to_lost(path: &Path, file: &Path, report: &mut RepairReport) -> Result<()> {
+    let lost_dir = path.join(LOST_DIR);
+    std::fs::create_dir_all(&lost_dir)?;
+    let target = lost_dir.join(file.file_name().unwrap());
+    std::fs::rename(file, &target)?;
+    println!("moved {} to {}", file.display(), target.display());
+    report.lost_files.push(target);
+    Ok(())
+}
+
+impl LsmStorageInner {
+    fn repair(path: &Path, options: &LsmStorageOptions) -> Result<RepairReport> {
+        if !path.is_dir() {
+            bail!("{} is not a directory", path.display());
+        }
+        let _dir_lock = Self::lock_dir(path, false)?;
+        let options = Self::read_options(path)?.unwrap_or_else(|| options.clone());
+        let mut report = RepairReport::default();
+
+        let mut sst_ids = BTreeSet::new();
+        let mut wal_ids = BTreeSet::new();
+        for entry in std::fs::read_dir(path)? {
+            let file = entry?.path();
+            let Some((id, ext)) = file
+                .file_name()
+                .and_then(|name| name.to_str())
+                .and_then(|name| name.split_once('.'))
+                .and_then(|(id, ext)| Some((id.parse::<usize>().ok()?, ext)))
+            else {
+                continue;
+            };
+            match ext {
+                "sst" => sst_ids.insert(id),
+                "wal" => wal_ids.insert(id),
+                _ => continue,
+            };
+        }
+        // never reuse the id of a file, even a lost one
+        let max_sst_id = sst_ids
+            .iter()
+            .chain(wal_ids.iter())
+            .max()
+            .copied()
+            .unwrap_or_default();
+
+        let mut sstables = Vec::new();
+        for id in sst_ids {
+            let file = Self::path_of_sst_static(path, id);
+            match Self::open_and_validate_sst(&file, id) {
+                Ok(sst) => sstables.push(sst),
+                Err(e) => {
+                    println!("{} is corrupted: {}", file.display(), e);
+                    move_to_lost(path, &file, &mut report)?;
+                }
+            }
+        }
+
+        // moved to the `lost` directory only once the new manifest is in use, so that they are not
+        // lost should the repair fail before that
+        let mut salvaged_wal_files = Vec::new();
+        for id in wal_ids {
+            let file = Self::path_of_wal_static(path, id);
+            // the memtable was flushed, but the WAL was not removed yet
+            if sstables.iter().any(|sst| sst.sst_id() == id) {
+                salvaged_wal_files.push(file);
+                continue;
+            }
+            let memtable = MemTable::create(id);
+            let batches = Wal::salvage_batches(&file)?;
+            for batch in &batches {
+                let data = batch
+                    .data
+                    .iter()
+                    .map(|(key, value)| (KeySlice::from_slice(key, batch.commit_ts), &value[..]))
+                    .collect::<Vec<_>>();
+                memtable.put_batch(&data, false)?;
+            }
+            if !memtable.is_empty() {
+                let mut builder = SsTableBuilder::new(options.block_size);
+                memtable.flush(&mut builder)?;
+                sstables.push(builder.build(id, None, Self::path_of_sst_static(path, id))?);
+                report.salvaged_wals.push(id);
+                report.salvaged_records += batches.len();
+            }
+            salvaged_wal_files.push(file);
+        }
+
+        // newest first, as if the SSTs were flushed in the order of their commit ts
+        sstables.sort_by_key(|sst| std::cmp::Reverse((sst.max_ts(), sst.sst_id())));
+        let mut state = LsmStorageState::create(&options);
+        let ids = sstables.iter().map(|sst| sst.sst_id()).collect::<Vec<_>>();
+        if CompactionController::new(&options.compaction_options).flush_to_l0() {
+            state.l0_sstables = ids.clone();
+        } else {
+            state.levels = ids.iter().map(|id| (*id, vec![*id])).collect();
+        }
+
+        // the SSTs built from the WALs are synced when written, and so are their directory entries
+        // before the new manifest refers to them
+        std::fs::File::open(path)?.sync_all()?;
+        let manifest = Manifest::replace(
+            path,
+            ManifestRecord::Snapshot {
+                memtables: Vec::new(),
+                l0_sstables: state.l0_sstables,
+                levels: state.levels,
+                sstables: sstables.iter().map(SstMetadata::from_sst).collect(),
+                max_sst_id,
+                compaction_options: options.compaction_options,
+            },
+        )?;
+        for file in manifest
+            .obsolete_files()?
+            .into_iter()
+            .chain(salvaged_wal_files)
+        {
+            move_to_lost(path, &file, &mut report)?;
+        }
+        std::fs::File::open(path)?.sync_all()?;
+        report.ssts = ids;
+        println!("repaired {}: {:?}", path.display(), report);
+        Ok(report)
+    }
+
+    /// Open the SST in `file`, and read all its blocks to verify their checksums.
+    fn open_and_validate_sst(file: &Path, id: usize) -> Result<SsTable> {
+        let sst = SsTable::open(id, None, FileObject::open(file)?)?;
+        for block_idx in 0..sst.num_of_blocks() {
+            sst.read_block(block_idx)?;
+        }
+        Ok(sst)
+    }
+}
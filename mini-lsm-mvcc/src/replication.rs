@@ -0,0 +1,194 @@
+use std::path::Path;
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::{bail, Result};
+use crossbeam_channel::{Receiver, RecvTimeoutError, Sender, TryRecvError};
+
+use crate::cdc::ChangeBatch;
+use crate::lsm_storage::{LsmStorageInner, WriteOptions};
+use crate::manifest::Manifest;
+
+/// Carries the batches shipped from a leader to a follower.
+pub trait ReplicationTransport: Send + Sync {
+    /// Send a batch to the follower.
+    fn send(&self, batch: ChangeBatch) -> Result<()>;
+
+    /// Wait up to `timeout` for the next batch. Returns `None` if no batch arrived in time.
+    fn recv_timeout(&self, timeout: Duration) -> Result<Option<ChangeBatch>>;
+}
+
+/// An in-process transport, for a leader and a follower in the same process.
+#[derive(Clone)]
+pub struct ChannelTransport {
+    tx: Sender<ChangeBatch>,
+    rx: Receiver<ChangeBatch>,
+}
+
+impl ChannelTransport {
+    pub fn new() -> Self {
+        let (tx, rx) = crossbeam_channel::unbounded();
+        Self { tx, rx }
+    }
+}
+
+impl Default for ChannelTransport {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl ReplicationTransport for ChannelTransport {
+    fn send(&self, batch: ChangeBatch) -> Result<()> {
+        self.tx.send(batch)?;
+        Ok(())
+    }
+
+    fn recv_timeout(&self, timeout: Duration) -> Result<Option<ChangeBatch>> {
+        match self.rx.recv_timeout(timeout) {
+            Ok(batch) => Ok(Some(batch)),
+            Err(RecvTimeoutError::Timeout) => Ok(None),
+            Err(RecvTimeoutError::Disconnected) => bail!("transport disconnected"),
+        }
+    }
+}
+
+/// Ships the batches committed on a leader to a transport, created by `MiniLsm::start_wal_shipping`.
+/// Shipping stops when this is dropped.
+pub struct WalShipper {
+    stop_notifier: Sender<()>,
+    thread: Option<std::thread::JoinHandle<Result<()>>>,
+}
+
+impl WalShipper {
+    /// Stop shipping, and return the error that stopped it early, if any.
+    pub fn stop(mut self) -> Result<()> {
+        self.stop_notifier.send(()).ok();
+        self.thread
+            .take()
+            .unwrap()
+            .join()
+            .map_err(|e| anyhow::anyhow!("{:?}", e))?
+    }
+}
+
+impl Drop for WalShipper {
+    fn drop(&mut self) {
+        self.stop_notifier.send(()).ok();
+    }
+}
+
+impl LsmStorageInner {
+    /// Create a copy of the storage in `path`, from which a follower can be opened. SSTs are hard
+    /// linked when possible. Returns the commit ts of the last batch in the checkpoint.
+    pub(crate) fn create_checkpoint(&self, path: impl AsRef<Path>) -> Result<u64> {
+        let path = path.as_ref();
+        if !self.options.enable_wal {
+            bail!("checkpoints require the WAL to be enabled");
+        }
+        if path.exists() {
+            bail!("checkpoint directory {} already exists", path.display());
+        }
+        std::fs::create_dir_all(path)?;
+
+        // block writes, flushes and compactions while copying, so that the SSTs, WALs and manifest
+        // are consistent with each other
+        let _lck = self.mvcc().write_lock.lock();
+        self.mvcc().wait_for_written_commits();
+        let _state_lock = self.state_lock.lock();
+        let snapshot = self.state.read().clone();
+        for id in snapshot.sstables.keys() {
+            let target = Self::path_of_sst_static(path, *id);
+            if std::fs::hard_link(self.path_of_sst(*id), &target).is_err() {
+                std::fs::copy(self.path_of_sst(*id), &target)?;
+            }
+        }
+        for memtable in snapshot
+            .imm_memtables
+            .iter()
+            .chain(std::iter::once(&snapshot.memtable))
+        {
+            memtable.flush_wal_buffer()?;
+            std::fs::copy(
+                self.path_of_wal(memtable.id()),
+                Self::path_of_wal_static(path, memtable.id()),
+            )?;
+        }
+        Manifest::create(path)?.add_record_when_init(self.manifest_snapshot(&snapshot))?;
+        Self::write_options(path, &self.options)?;
+        std::fs::File::open(path)?.sync_all()?;
+        Ok(self.mvcc().latest_commit_ts())
+    }
+
+    /// Apply a batch shipped from the leader at its commit ts. Batches that are already applied are
+    /// ignored. As every commit on the leader takes the next ts, a batch that does not follow the
+    /// latest one applied means that some batches were lost, e.g., written with `disable_wal`.
+    pub(crate) fn apply_replicated_batch(&self, batch: &ChangeBatch) -> Result<()> {
+        let _lck = self.mvcc().write_lock.lock();
+        let latest_ts = self.mvcc().written_commit_ts();
+        if batch.commit_ts <= latest_ts {
+            return Ok(());
+        }
+        if batch.commit_ts != latest_ts + 1 {
+            bail!(
+                "received the batch at ts {} while the batches after ts {} are missing",
+                batch.commit_ts,
+                latest_ts
+            );
+        }
+        self.write_batch_at_ts(&batch.records, batch.commit_ts, &WriteOptions::default())?;
+        self.mvcc().publish_commit_ts(batch.commit_ts, || {
+            self.publish_change(batch.commit_ts, &batch.records)
+        });
+        Ok(())
+    }
+
+    pub(crate) fn spawn_wal_shipping_thread(
+        &self,
+        from_ts: u64,
+        transport: Arc<dyn ReplicationTransport>,
+    ) -> Result<WalShipper> {
+        let mut stream = self.subscribe(from_ts)?;
+        let (stop_notifier, rx) = crossbeam_channel::unbounded::<()>();
+        let thread = std::thread::spawn(move || loop {
+            if !matches!(rx.try_recv(), Err(TryRecvError::Empty)) {
+                return Ok(());
+            }
+            if let Some(batch) = stream.recv_timeout(Duration::from_millis(50))? {
+                transport.send(batch)?;
+            }
+        });
+        Ok(WalShipper {
+            stop_notifier,
+            thread: Some(thread),
+        })
+    }
+
+    pub(crate) fn spawn_replication_thread(
+        self: &Arc<Self>,
+        rx: Receiver<()>,
+        transport: Option<Arc<dyn ReplicationTransport>>,
+    ) -> Result<Option<std::thread::JoinHandle<()>>> {
+        let Some(transport) = transport else {
+            return Ok(None);
+        };
+        let this = self.clone();
+        let handle = std::thread::spawn(move || loop {
+            if !matches!(rx.try_recv(), Err(TryRecvError::Empty)) {
+                return;
+            }
+            let result = transport
+                .recv_timeout(Duration::from_millis(50))
+                .and_then(|batch| match batch {
+                    Some(batch) => this.apply_replicated_batch(&batch),
+                    None => Ok(()),
+                });
+            if let Err(e) = result {
+                eprintln!("replication failed: {}", e);
+                *this.replication_error.lock() = Some(format!("{:#}", e));
+                return;
+            }
+        });
+        Ok(Some(handle))
+    }
+}
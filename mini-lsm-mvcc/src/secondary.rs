@@ -0,0 +1,152 @@
+use std::collections::{BTreeSet, HashMap};
+use std::path::Path;
+use std::sync::Arc;
+
+use anyhow::{bail, Result};
+
+use crate::compact::CompactionController;
+use crate::lsm_storage::{LsmStorageInner, LsmStorageState};
+use crate::manifest::ManifestReader;
+use crate::mem_table::MemTable;
+use crate::table::{FileObject, SsTable};
+
+/// How many times catching up is retried while the primary keeps removing the files it refers to.
+const CATCH_UP_ATTEMPTS: usize = 10;
+
+/// What a secondary has caught up with, see `MiniLsm::open_as_secondary`.
+pub(crate) struct SecondaryState {
+    manifest: ManifestReader,
+    /// The memtables not flushed yet according to the manifest.
+    memtables: BTreeSet<usize>,
+    /// How far the WAL of each memtable has been applied.
+    wal_offsets: HashMap<usize, u64>,
+}
+
+impl SecondaryState {
+    pub(crate) fn new(path: &Path) -> Self {
+        Self {
+            manifest: ManifestReader::new(path),
+            memtables: BTreeSet::new(),
+            wal_offsets: HashMap::new(),
+        }
+    }
+}
+
+fn is_not_found(e: &anyhow::Error) -> bool {
+    e.downcast_ref::<std::io::Error>()
+        .is_some_and(|e| e.kind() == std::io::ErrorKind::NotFound)
+}
+
+impl LsmStorageInner {
+    /// Apply the manifest records and the WAL records that the primary wrote since the last time.
+    /// A file that the primary removed in the meantime has been replaced by a later manifest
+    /// record, so the records written since are applied as well before trying again.
+    pub(crate) fn try_catch_up_with_primary(&self) -> Result<()> {
+        let Some(secondary) = &self.secondary else {
+            bail!("only a secondary can catch up with the primary");
+        };
+        let mut secondary = secondary.lock();
+        // nothing is applied to the secondary until all files are opened
+        let mut manifest = secondary.manifest.clone();
+        let mut memtables = secondary.memtables.clone();
+        let mut state = self.state.read().as_ref().clone();
+        let mut compaction_controller =
+            CompactionController::new(&self.compaction_controller().options())
+                .with_storage_options(&self.options);
+        for _ in 0..CATCH_UP_ATTEMPTS {
+            for record in manifest.read_new_records()? {
+                Self::apply_manifest_record(
+                    &mut state,
+                    &mut memtables,
+                    &mut HashMap::new(),
+                    &mut compaction_controller,
+                    record,
+                );
+            }
+            let mut new_state = state.clone();
+            let mut wal_offsets = secondary.wal_offsets.clone();
+            let max_ts = match self.open_primary_files(&mut new_state, &memtables, &mut wal_offsets)
+            {
+                Ok(max_ts) => max_ts,
+                Err(e) if is_not_found(&e) => continue,
+                Err(e) => return Err(e),
+            };
+            *self.state.write() = Arc::new(new_state);
+            *self.compaction_controller.write() = Arc::new(compaction_controller);
+            if max_ts > self.mvcc().latest_commit_ts() {
+                self.mvcc().update_commit_ts(max_ts);
+            }
+            secondary.manifest = manifest;
+            secondary.memtables = memtables;
+            secondary.wal_offsets = wal_offsets;
+            return Ok(());
+        }
+        bail!(
+            "failed to catch up after {} attempts, the primary keeps removing files",
+            CATCH_UP_ATTEMPTS
+        )
+    }
+
+    /// Open the SSTs in the layout of `state`, and apply the WALs of `memtables` from the offsets in
+    /// `wal_offsets`. The SSTs and memtables that `state` already holds are reused. Returns the
+    /// largest commit ts found in the files opened.
+    fn open_primary_files(
+        &self,
+        state: &mut LsmStorageState,
+        memtables: &BTreeSet<usize>,
+        wal_offsets: &mut HashMap<usize, u64>,
+    ) -> Result<u64> {
+        let mut max_ts = 0;
+        let mut sstables = HashMap::new();
+        for id in state
+            .l0_sstables
+            .iter()
+            .chain(state.levels.iter().flat_map(|(_, ssts)| ssts))
+        {
+            let sst = match state.sstables.get(id) {
+                // still readable after the primary removes it, as the file stays open
+                Some(sst) => sst.clone(),
+                None => {
+                    let sst = SsTable::open(
+                        *id,
+                        Some(self.block_cache.clone()),
+                        FileObject::open(&self.path_of_sst(*id))?,
+                    )?;
+                    max_ts = max_ts.max(sst.max_ts());
+                    Arc::new(sst)
+                }
+            };
+            sstables.insert(*id, sst);
+        }
+        state.sstables = sstables;
+        state.sort_levels();
+
+        let mut opened = state
+            .imm_memtables
+            .iter()
+            .chain(std::iter::once(&state.memtable))
+            .map(|memtable| (memtable.id(), memtable.clone()))
+            .collect::<HashMap<_, _>>();
+        let mut recovered = Vec::new();
+        for id in memtables {
+            let memtable = opened
+                .remove(id)
+                .unwrap_or_else(|| Arc::new(MemTable::create(*id)));
+            if self.options.enable_wal {
+                let offset = wal_offsets.get(id).copied().unwrap_or_default();
+                let (offset, ts) = memtable.replay_wal_from(self.path_of_wal(*id), offset)?;
+                wal_offsets.insert(*id, offset);
+                max_ts = max_ts.max(ts);
+            }
+            recovered.push(memtable);
+        }
+        wal_offsets.retain(|id, _| memtables.contains(id));
+        // the latest memtable is the one the primary writes to
+        state.memtable = recovered
+            .pop()
+            .unwrap_or_else(|| Arc::new(MemTable::create(0)));
+        recovered.reverse();
+        state.imm_memtables = recovered;
+        Ok(max_ts)
+    }
+}
@@ -4,7 +4,7 @@ mod iterator;
 
 use std::fs::File;
 use std::path::Path;
-use std::sync::Arc;
+use std::sync::{Arc, OnceLock};
 
 use anyhow::{anyhow, bail, Result};
 pub use builder::SsTableBuilder;
@@ -27,9 +27,38 @@ pub struct BlockMeta {
     pub last_key: KeyBytes,
 }
 
+/// Statistics of an SST, recorded when it is built.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct SsTableStats {
+    /// Number of entries, counting every version of a key.
+    pub num_entries: u64,
+    /// Number of entries that are tombstones, i.e., with an empty value.
+    pub num_tombstones: u64,
+    /// When the SST was built, in seconds since the UNIX epoch, or 0 if unknown.
+    pub created_at: u64,
+    /// When the newest data of the SST was flushed, in seconds since the UNIX epoch, or 0 if
+    /// unknown. Unlike `created_at`, it is kept when the data is compacted.
+    pub data_written_at: u64,
+}
+
+impl SsTableStats {
+    /// The fraction of the entries that are tombstones.
+    pub fn tombstone_ratio(&self) -> f64 {
+        if self.num_entries == 0 {
+            return 0.0;
+        }
+        self.num_tombstones as f64 / self.num_entries as f64
+    }
+}
+
 impl BlockMeta {
     /// Encode block meta to a buffer.
-    pub fn encode_block_meta(block_meta: &[BlockMeta], max_ts: u64, buf: &mut Vec<u8>) {
+    pub fn encode_block_meta(
+        block_meta: &[BlockMeta],
+        max_ts: u64,
+        stats: SsTableStats,
+        buf: &mut Vec<u8>,
+    ) {
         let mut estimated_size = std::mem::size_of::<u32>(); // number of blocks
         for meta in block_meta {
             // The size of offset
@@ -44,6 +73,7 @@ impl BlockMeta {
             estimated_size += meta.last_key.raw_len();
         }
         estimated_size += std::mem::size_of::<u64>(); // max timestamp
+        estimated_size += std::mem::size_of::<u64>() * 4; // stats
         estimated_size += std::mem::size_of::<u32>(); // checksum
 
         // Reserve the space to improve performance, especially when the size of incoming data is
@@ -61,16 +91,29 @@ impl BlockMeta {
             buf.put_u64(meta.last_key.ts());
         }
         buf.put_u64(max_ts);
+        buf.put_u64(stats.num_entries);
+        buf.put_u64(stats.num_tombstones);
+        buf.put_u64(stats.created_at);
+        buf.put_u64(stats.data_written_at);
         buf.put_u32(crc32fast::hash(&buf[original_len + 4..]));
         assert_eq!(estimated_size, buf.len() - original_len);
     }
 
-    /// Decode block meta from a buffer.
-    pub fn decode_block_meta(mut buf: &[u8]) -> Result<(Vec<BlockMeta>, u64)> {
+    /// Decode block meta from a buffer. The stats were appended to the block meta over time, and the
+    /// ones missing from older SSTs are left empty.
+    pub fn decode_block_meta(mut buf: &[u8]) -> Result<(Vec<BlockMeta>, u64, SsTableStats)> {
+        // verify the checksum first, so that garbage is never decoded
+        if buf.remaining() < 4 + 8 + 4 {
+            bail!("meta block too short");
+        }
+        let checksum = (&buf[buf.remaining() - 4..]).get_u32();
+        if checksum != crc32fast::hash(&buf[4..buf.remaining() - 4]) {
+            bail!("meta checksum mismatched");
+        }
         let mut block_meta = Vec::new();
         let num = buf.get_u32() as usize;
-        let checksum = crc32fast::hash(&buf[..buf.remaining() - 4]);
-        for _ in 0..num {
+        // the number of blocks is not covered by the checksum
+        while block_meta.len() < num && buf.remaining() > 8 + 4 {
             let offset = buf.get_u32() as usize;
             let first_key_len = buf.get_u16() as usize;
             let first_key =
@@ -84,12 +127,34 @@ impl BlockMeta {
                 last_key,
             });
         }
+        if block_meta.len() != num {
+            bail!(
+                "found {} blocks in the meta block, expected {}",
+                block_meta.len(),
+                num
+            );
+        }
         let max_ts = buf.get_u64();
-        if buf.get_u32() != checksum {
-            bail!("meta checksum mismatched");
+        let mut stats = SsTableStats::default();
+        for field in [
+            &mut stats.num_entries,
+            &mut stats.num_tombstones,
+            &mut stats.created_at,
+            &mut stats.data_written_at,
+        ] {
+            if buf.remaining() < 8 + 4 {
+                break;
+            }
+            *field = buf.get_u64();
+        }
+        if buf.remaining() != 4 {
+            bail!(
+                "unexpected {} bytes after the block meta",
+                buf.remaining() - 4
+            );
         }
 
-        Ok((block_meta, max_ts))
+        Ok((block_meta, max_ts, stats))
     }
 }
 
@@ -142,6 +207,9 @@ pub struct SsTable {
     last_key: KeyBytes,
     pub(crate) bloom: Option<Bloom>,
     max_ts: u64,
+    stats: SsTableStats,
+    /// For an SST created by `open_lazy`, the SST opened from the same file on first use.
+    lazy: Option<OnceLock<Arc<SsTable>>>,
 }
 impl SsTable {
     #[cfg(test)]
@@ -152,14 +220,27 @@ impl SsTable {
     /// Open SSTable from a file.
     pub fn open(id: usize, block_cache: Option<Arc<BlockCache>>, file: FileObject) -> Result<Self> {
         let len = file.size();
+        // the offsets are checked, so that a truncated or garbage file fails to open
+        if len < 4 {
+            bail!("SST too short");
+        }
         let raw_bloom_offset = file.read(len - 4, 4)?;
         let bloom_offset = (&raw_bloom_offset[..]).get_u32() as u64;
+        if bloom_offset < 4 || bloom_offset + 5 + 4 > len - 4 {
+            bail!("invalid bloom filter offset {}", bloom_offset);
+        }
         let raw_bloom = file.read(bloom_offset, len - 4 - bloom_offset)?;
         let bloom_filter = Bloom::decode(&raw_bloom)?;
         let raw_meta_offset = file.read(bloom_offset - 4, 4)?;
         let block_meta_offset = (&raw_meta_offset[..]).get_u32() as u64;
+        if block_meta_offset > bloom_offset - 4 {
+            bail!("invalid block meta offset {}", block_meta_offset);
+        }
         let raw_meta = file.read(block_meta_offset, bloom_offset - 4 - block_meta_offset)?;
-        let (block_meta, max_ts) = BlockMeta::decode_block_meta(&raw_meta[..])?;
+        let (block_meta, max_ts, stats) = BlockMeta::decode_block_meta(&raw_meta[..])?;
+        if block_meta.is_empty() {
+            bail!("SST has no blocks");
+        }
         Ok(Self {
             file,
             first_key: block_meta.first().unwrap().first_key.clone(),
@@ -170,9 +251,80 @@ impl SsTable {
             block_cache,
             bloom: Some(bloom_filter),
             max_ts,
+            stats,
+            lazy: None,
         })
     }
 
+    /// Create an SST from the metadata recorded in the manifest, without reading the file. Its block
+    /// meta, bloom filter and stats are only read when it is first used, see `opened`.
+    pub fn open_lazy(
+        id: usize,
+        block_cache: Option<Arc<BlockCache>>,
+        file: FileObject,
+        first_key: KeyBytes,
+        last_key: KeyBytes,
+        max_ts: u64,
+    ) -> Self {
+        Self {
+            file,
+            block_meta: vec![],
+            block_meta_offset: 0,
+            id,
+            block_cache,
+            first_key,
+            last_key,
+            bloom: None,
+            max_ts,
+            stats: SsTableStats::default(),
+            lazy: Some(OnceLock::new()),
+        }
+    }
+
+    /// The SST with its block meta and bloom filter loaded, which is the SST itself unless it was
+    /// created by `open_lazy`. Fails if the file does not match the metadata it was created with.
+    pub fn opened(self: &Arc<Self>) -> Result<Arc<SsTable>> {
+        match self.lazy {
+            Some(_) => Ok(self.lazy_table()?.clone()),
+            None => Ok(self.clone()),
+        }
+    }
+
+    fn lazy_table(&self) -> Result<&Arc<SsTable>> {
+        let lazy = self.lazy.as_ref().expect("not a lazily opened SST");
+        if let Some(table) = lazy.get() {
+            return Ok(table);
+        }
+        // the file was opened with the storage, so it is still readable if the SST has been
+        // removed by a compaction since
+        let file = FileObject(
+            Some(self.file.0.as_ref().unwrap().try_clone()?),
+            self.file.1,
+        );
+        let table = SsTable::open(self.id, self.block_cache.clone(), file)?;
+        if table.first_key != self.first_key
+            || table.last_key != self.last_key
+            || table.max_ts != self.max_ts
+        {
+            bail!(
+                "{}.sst does not match the metadata recorded in the manifest",
+                self.id
+            );
+        }
+        Ok(lazy.get_or_init(|| Arc::new(table)))
+    }
+
+    /// The SST holding the block meta, for the methods that cannot fail. An SST created by
+    /// `open_lazy` must be read through `opened` first.
+    fn loaded(&self) -> &SsTable {
+        match &self.lazy {
+            Some(lazy) => lazy
+                .get()
+                .unwrap_or_else(|| panic!("{}.sst used before being opened", self.id)),
+            None => self,
+        }
+    }
+
     /// Create a mock SST with only first key + last key metadata
     pub fn create_meta_only(
         id: usize,
@@ -190,11 +342,23 @@ impl SsTable {
             last_key,
             bloom: None,
             max_ts: 0,
+            stats: SsTableStats::default(),
+            lazy: None,
         }
     }
 
+    /// Set the stats of a mock SST created by `create_meta_only`.
+    pub fn with_stats(mut self, stats: SsTableStats) -> Self {
+        assert!(self.file.0.is_none(), "not a mock SST");
+        self.stats = stats;
+        self
+    }
+
     /// Read a block from the disk.
     pub fn read_block(&self, block_idx: usize) -> Result<Arc<Block>> {
+        if self.lazy.is_some() {
+            return self.lazy_table()?.read_block(block_idx);
+        }
         let offset = self.block_meta[block_idx].offset;
         let offset_end = self
             .block_meta
@@ -226,14 +390,15 @@ impl SsTable {
 
     /// Find the block that may contain `key`.
     pub fn find_block_idx(&self, key: KeySlice) -> usize {
-        self.block_meta
+        self.loaded()
+            .block_meta
             .partition_point(|meta| meta.first_key.as_key_slice() <= key)
             .saturating_sub(1)
     }
 
     /// Get number of data blocks.
     pub fn num_of_blocks(&self) -> usize {
-        self.block_meta.len()
+        self.loaded().block_meta.len()
     }
 
     pub fn first_key(&self) -> &KeyBytes {
@@ -255,4 +420,16 @@ impl SsTable {
     pub fn max_ts(&self) -> u64 {
         self.max_ts
     }
+
+    /// The stats of the SST, which are read from the file of an SST created by `open_lazy`. If that
+    /// fails, the stats are empty, and reading the SST fails as well.
+    pub fn stats(&self) -> SsTableStats {
+        match self.lazy {
+            Some(_) => self
+                .lazy_table()
+                .map(|table| table.stats)
+                .unwrap_or_default(),
+            None => self.stats,
+        }
+    }
 }
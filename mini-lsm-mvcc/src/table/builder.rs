@@ -1,11 +1,12 @@
 use std::path::Path;
 use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
 
 use anyhow::Result;
 use bytes::BufMut;
 
 use super::bloom::Bloom;
-use super::{BlockMeta, FileObject, SsTable};
+use super::{BlockMeta, FileObject, SsTable, SsTableStats};
 use crate::block::BlockBuilder;
 use crate::key::{KeySlice, KeyVec};
 use crate::lsm_storage::BlockCache;
@@ -20,6 +21,7 @@ pub struct SsTableBuilder {
     block_size: usize,
     key_hashes: Vec<u32>,
     max_ts: u64,
+    stats: SsTableStats,
 }
 
 impl SsTableBuilder {
@@ -34,6 +36,7 @@ impl SsTableBuilder {
             builder: BlockBuilder::new(block_size),
             key_hashes: Vec::new(),
             max_ts: 0,
+            stats: SsTableStats::default(),
         }
     }
 
@@ -47,6 +50,10 @@ impl SsTableBuilder {
             self.max_ts = key.ts();
         }
         self.key_hashes.push(farmhash::fingerprint32(key.key_ref()));
+        self.stats.num_entries += 1;
+        if value.is_empty() {
+            self.stats.num_tombstones += 1;
+        }
 
         if self.builder.add(key, value) {
             self.last_key.set_from_slice(key);
@@ -62,6 +69,12 @@ impl SsTableBuilder {
         self.last_key.set_from_slice(key);
     }
 
+    /// Sets when the newest data was flushed, for an SST built by a compaction. It defaults to when
+    /// the SST is built.
+    pub fn set_data_written_at(&mut self, data_written_at: u64) {
+        self.stats.data_written_at = data_written_at;
+    }
+
     /// Get the estimated size of the SSTable.
     pub fn estimated_size(&self) -> usize {
         self.data.len()
@@ -88,9 +101,15 @@ impl SsTableBuilder {
         path: impl AsRef<Path>,
     ) -> Result<SsTable> {
         self.finish_block();
+        self.stats.created_at = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |elapsed| elapsed.as_secs());
+        if self.stats.data_written_at == 0 {
+            self.stats.data_written_at = self.stats.created_at;
+        }
         let mut buf = self.data;
         let meta_offset = buf.len();
-        BlockMeta::encode_block_meta(&self.meta, self.max_ts, &mut buf);
+        BlockMeta::encode_block_meta(&self.meta, self.max_ts, self.stats, &mut buf);
         buf.put_u32(meta_offset as u32);
         let bloom = Bloom::build_from_key_hashes(
             &self.key_hashes,
@@ -110,6 +129,8 @@ impl SsTableBuilder {
             block_cache,
             bloom: Some(bloom),
             max_ts: self.max_ts,
+            stats: self.stats,
+            lazy: None,
         })
     }
 
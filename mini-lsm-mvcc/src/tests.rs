@@ -1,4 +1,29 @@
+mod change_data_capture;
+mod compaction_filter;
+mod compaction_priority;
+mod compaction_strategy_change;
+mod compaction_triggers;
+mod concurrent_compaction;
+mod dir_lock;
+mod fifo_compaction;
+mod grandparent_overlap;
 mod harness;
+mod lazy_leveling_compaction;
+mod manifest_format;
+mod manifest_rotation;
+mod obsolete_files;
+mod options_file;
+mod read_only;
+mod repair;
+mod replication;
+mod secondary;
+mod subcompaction;
+mod time_window_compaction;
+mod trivial_move;
+mod verify;
+mod wal_atomic_batch;
+mod wal_group_commit;
+mod wal_recovery_mode;
 mod week1_day1;
 mod week1_day2;
 mod week1_day3;
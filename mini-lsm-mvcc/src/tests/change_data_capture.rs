@@ -0,0 +1,232 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
+
+use bytes::Bytes;
+use tempfile::tempdir;
+
+use crate::{
+    cdc::ChangeBatch,
+    compact::CompactionOptions,
+    lsm_storage::{LsmStorageOptions, MiniLsm, WriteBatchRecord, WriteOptions},
+};
+
+use super::harness::wal_options;
+
+fn options() -> LsmStorageOptions {
+    LsmStorageOptions {
+        archive_wal: true,
+        ..wal_options()
+    }
+}
+
+fn put(key: &str, value: &str) -> WriteBatchRecord<Bytes> {
+    WriteBatchRecord::Put(
+        Bytes::copy_from_slice(key.as_bytes()),
+        Bytes::copy_from_slice(value.as_bytes()),
+    )
+}
+
+fn del(key: &str) -> WriteBatchRecord<Bytes> {
+    WriteBatchRecord::Del(Bytes::copy_from_slice(key.as_bytes()))
+}
+
+#[test]
+fn test_get_updates_since() {
+    let dir = tempdir().unwrap();
+    let storage = MiniLsm::open(&dir, options()).unwrap();
+    storage.put(b"a", b"1").unwrap();
+    storage
+        .write_batch(&[put("b", "2"), put("c", "3"), del("a")])
+        .unwrap();
+    storage.force_flush().unwrap();
+    storage
+        .put_with_options(
+            b"x",
+            b"not logged",
+            &WriteOptions {
+                disable_wal: true,
+                ..Default::default()
+            },
+        )
+        .unwrap();
+    storage.delete(b"b").unwrap();
+
+    let expected = vec![
+        ChangeBatch {
+            commit_ts: 1,
+            records: vec![put("a", "1")],
+        },
+        ChangeBatch {
+            commit_ts: 2,
+            records: vec![put("b", "2"), put("c", "3"), del("a")],
+        },
+        ChangeBatch {
+            commit_ts: 4,
+            records: vec![del("b")],
+        },
+    ];
+    // the batch at ts 3 is not in the WALs, so reading across it fails
+    assert!(storage.get_updates_since(0).is_err());
+    assert!(storage.get_updates_since(3).is_err());
+    assert!(storage.subscribe(2).is_err());
+    assert_eq!(storage.get_updates_since(4).unwrap(), expected[2..]);
+    assert_eq!(storage.get_updates_since(5).unwrap(), vec![]);
+
+    // the archived WAL survives a restart
+    storage.close().unwrap();
+    drop(storage);
+    let storage = MiniLsm::open(&dir, options()).unwrap();
+    assert_eq!(storage.get_updates_since(4).unwrap(), expected[2..]);
+
+    // only the archived WAL with batches 1 and 2 can be purged, after which asking for them fails
+    assert_eq!(storage.purge_wal_archive(3).unwrap(), 1);
+    assert!(storage.get_updates_since(0).is_err());
+    assert!(storage.subscribe(2).is_err());
+    assert_eq!(storage.get_updates_since(4).unwrap(), expected[2..]);
+}
+
+#[test]
+fn test_get_updates_since_corrupted_wal() {
+    let dir = tempdir().unwrap();
+    let storage = MiniLsm::open(&dir, options()).unwrap();
+    storage.put(b"a", b"1").unwrap();
+    storage.put(b"b", b"2").unwrap();
+    storage.force_flush().unwrap();
+    storage.put(b"c", b"3").unwrap();
+    let archived = std::fs::read_dir(dir.path().join("archive"))
+        .unwrap()
+        .next()
+        .unwrap()
+        .unwrap()
+        .path();
+    let mut data = std::fs::read(&archived).unwrap();
+    let last = data.len() - 1;
+    data[last] ^= 0xff;
+    std::fs::write(&archived, data).unwrap();
+    // the corrupted batch is not skipped over
+    assert!(storage.get_updates_since(0).is_err());
+    assert!(storage.get_updates_since(3).is_err());
+    assert!(storage.subscribe(1).is_err());
+    // until the corrupted WAL is purged
+    assert_eq!(storage.purge_wal_archive(3).unwrap(), 1);
+    assert_eq!(storage.get_updates_since(3).unwrap().len(), 1);
+}
+
+#[test]
+fn test_get_updates_since_without_archive() {
+    let dir = tempdir().unwrap();
+    let mut options = options();
+    options.archive_wal = false;
+    let storage = MiniLsm::open(&dir, options).unwrap();
+    storage.put(b"a", b"1").unwrap();
+    storage.force_flush().unwrap();
+    storage.put(b"b", b"2").unwrap();
+    // the WAL with the first batch was deleted when its memtable was flushed
+    assert!(storage.get_updates_since(0).is_err());
+    assert!(storage.get_updates_since(1).is_err());
+    assert_eq!(
+        storage.get_updates_since(2).unwrap(),
+        vec![ChangeBatch {
+            commit_ts: 2,
+            records: vec![put("b", "2")],
+        }]
+    );
+    storage.force_flush().unwrap();
+    assert!(storage.get_updates_since(2).is_err());
+    assert_eq!(storage.get_updates_since(3).unwrap(), vec![]);
+}
+
+#[test]
+fn test_subscribe() {
+    let dir = tempdir().unwrap();
+    let mut options = options();
+    // freeze memtables while the writers are running
+    options.target_sst_size = 1024;
+    let storage = MiniLsm::open(&dir, options).unwrap();
+    for i in 0..10 {
+        storage
+            .write_batch(&[
+                put(&format!("{i}_a"), "v"),
+                put(&format!("{i}_b"), "v"),
+                put(&format!("{i}_c"), "v"),
+            ])
+            .unwrap();
+    }
+    storage.force_flush().unwrap();
+    let mut stream = storage.subscribe(5).unwrap();
+    let done = AtomicBool::new(false);
+    std::thread::scope(|s| {
+        for t in 0..4 {
+            let storage = storage.clone();
+            s.spawn(move || {
+                for i in 0..50 {
+                    storage
+                        .write_batch(&[
+                            put(&format!("{t}_{i}_a"), "v"),
+                            put(&format!("{t}_{i}_b"), "v"),
+                            del(&format!("{t}_{i}_c")),
+                        ])
+                        .unwrap();
+                }
+            });
+        }
+        // archive WALs while the stream is being consumed
+        s.spawn(|| {
+            while !done.load(Ordering::SeqCst) {
+                if !storage.inner.state.read().imm_memtables.is_empty() {
+                    storage.inner.force_flush_next_imm_memtable().unwrap();
+                }
+                std::thread::sleep(Duration::from_millis(1));
+            }
+        });
+        // every batch is received exactly once and in commit order
+        for ts in 5..=210 {
+            let batch = stream.next_timeout(Duration::from_secs(10)).unwrap();
+            assert_eq!(batch.commit_ts, ts);
+            assert_eq!(batch.records.len(), 3);
+        }
+        done.store(true, Ordering::SeqCst);
+    });
+    assert_eq!(stream.try_next(), None);
+    storage.put(b"live", b"1").unwrap();
+    assert_eq!(
+        stream.next(),
+        Some(ChangeBatch {
+            commit_ts: 211,
+            records: vec![put("live", "1")],
+        })
+    );
+}
+
+#[test]
+fn test_subscribe_lagging_consumer_is_dropped() {
+    let dir = tempdir().unwrap();
+    let storage = MiniLsm::open(&dir, options()).unwrap();
+    let mut stream = storage.subscribe(0).unwrap();
+    // nobody reads the stream, so the writers must not wait for it
+    for i in 0..5000 {
+        storage.put(format!("{i}").as_bytes(), b"v").unwrap();
+    }
+    // the batches received before the stream fell behind are still returned
+    for ts in 1..=4096 {
+        let batch = stream
+            .recv_timeout(Duration::from_secs(1))
+            .unwrap()
+            .unwrap();
+        assert_eq!(batch.commit_ts, ts);
+    }
+    assert!(stream.recv_timeout(Duration::from_secs(1)).is_err());
+    assert_eq!(storage.inner.change_subscribers.lock().len(), 0);
+}
+
+#[test]
+fn test_subscribe_requires_wal() {
+    let dir = tempdir().unwrap();
+    let storage = MiniLsm::open(
+        &dir,
+        LsmStorageOptions::default_for_week2_test(CompactionOptions::NoCompaction),
+    )
+    .unwrap();
+    assert!(storage.subscribe(0).is_err());
+    assert!(storage.get_updates_since(0).is_err());
+}
@@ -0,0 +1,195 @@
+use std::sync::Arc;
+
+use bytes::Bytes;
+use parking_lot::Mutex;
+use tempfile::tempdir;
+
+use crate::{
+    compact::{CompactionOptions, SimpleLeveledCompactionOptions},
+    lsm_storage::{
+        CompactionFilter, CompactionFilterContext, CompactionFilterDecision,
+        CompactionFilterFactory, CompactionFilterTrait, LsmStorageInner, LsmStorageOptions,
+        MiniLsm,
+    },
+};
+
+use super::harness::{check_iter_result_by_key, construct_merge_iterator_over_storage, sync};
+
+/// Records the context of each compaction, and creates a filter that applies `decide` to each
+/// value.
+struct TestFilterFactory {
+    contexts: Arc<Mutex<Vec<CompactionFilterContext>>>,
+    decide: fn(&[u8]) -> CompactionFilterDecision,
+}
+
+struct TestFilter {
+    decide: fn(&[u8]) -> CompactionFilterDecision,
+    last_key: Option<Vec<u8>>,
+}
+
+impl CompactionFilterTrait for TestFilter {
+    fn filter(&mut self, key: &[u8], _ts: u64, value: &[u8]) -> CompactionFilterDecision {
+        // a filter sees a single version of each key, in the key order
+        if let Some(last_key) = &self.last_key {
+            assert!(last_key.as_slice() < key);
+        }
+        self.last_key = Some(key.to_vec());
+        (self.decide)(value)
+    }
+}
+
+impl CompactionFilterFactory for TestFilterFactory {
+    fn create_compaction_filter(
+        &self,
+        context: &CompactionFilterContext,
+    ) -> Box<dyn CompactionFilterTrait> {
+        self.contexts.lock().push(context.clone());
+        Box::new(TestFilter {
+            decide: self.decide,
+            last_key: None,
+        })
+    }
+}
+
+fn add_test_filter(
+    storage: &LsmStorageInner,
+    decide: fn(&[u8]) -> CompactionFilterDecision,
+) -> Arc<Mutex<Vec<CompactionFilterContext>>> {
+    let contexts = Arc::new(Mutex::new(Vec::new()));
+    storage.add_compaction_filter(CompactionFilter::Custom(Arc::new(TestFilterFactory {
+        contexts: contexts.clone(),
+        decide,
+    })));
+    contexts
+}
+
+/// Migrates the values from the `v1:` format to the `v2:` one.
+fn migrate_v1_to_v2(value: &[u8]) -> CompactionFilterDecision {
+    match value.strip_prefix(b"v1:") {
+        Some(rest) => CompactionFilterDecision::ChangeValue(Bytes::from([b"v2:", rest].concat())),
+        None => CompactionFilterDecision::Keep,
+    }
+}
+
+#[test]
+fn test_compaction_filter_change_value() {
+    let dir = tempdir().unwrap();
+    let options = LsmStorageOptions::default_for_week2_test(CompactionOptions::NoCompaction);
+    let storage = MiniLsm::open(&dir, options).unwrap();
+    for i in 0..10 {
+        storage
+            .put(
+                format!("key_{}", i).as_bytes(),
+                format!("v1:{}", i).as_bytes(),
+            )
+            .unwrap();
+    }
+    storage.force_flush().unwrap();
+    let snapshot = storage.new_txn().unwrap();
+    storage.put(b"key_0", b"v1:new").unwrap();
+    storage.force_flush().unwrap();
+    let contexts = add_test_filter(&storage.inner, migrate_v1_to_v2);
+    storage.force_full_compaction().unwrap();
+
+    // the version above the watermark is kept as it is
+    let mut expected = vec![
+        (Bytes::from("key_0"), Bytes::from("v1:new")),
+        (Bytes::from("key_0"), Bytes::from("v2:0")),
+    ];
+    for i in 1..10 {
+        expected.push((
+            Bytes::from(format!("key_{}", i)),
+            Bytes::from(format!("v2:{}", i)),
+        ));
+    }
+    let mut iter = construct_merge_iterator_over_storage(&storage.inner.state.read());
+    check_iter_result_by_key(&mut iter, expected);
+    assert_eq!(&snapshot.get(b"key_0").unwrap().unwrap()[..], b"v2:0");
+
+    // each compaction gets a filter of its own
+    drop(snapshot);
+    storage.force_full_compaction().unwrap();
+    assert_eq!(&storage.get(b"key_0").unwrap().unwrap()[..], b"v2:new");
+    assert_eq!(&storage.get(b"key_1").unwrap().unwrap()[..], b"v2:1");
+    let context = CompactionFilterContext {
+        is_bottom_level: true,
+        output_level: Some(1),
+    };
+    assert_eq!(*contexts.lock(), vec![context.clone(), context]);
+}
+
+/// Removes the values marked as expired.
+fn remove_expired(value: &[u8]) -> CompactionFilterDecision {
+    if value == b"expired" {
+        CompactionFilterDecision::Remove
+    } else {
+        CompactionFilterDecision::Keep
+    }
+}
+
+#[test]
+fn test_compaction_filter_remove_above_bottom_level() {
+    let dir = tempdir().unwrap();
+    let options = LsmStorageOptions::default_for_week2_test(CompactionOptions::Simple(
+        SimpleLeveledCompactionOptions {
+            size_ratio_percent: 200,
+            level0_file_num_compaction_trigger: 2,
+            max_levels: 2,
+        },
+    ));
+    let storage = Arc::new(LsmStorageInner::open(&dir, options).unwrap());
+    let put_and_flush = |key_values: &[(&str, &str)]| {
+        for _ in 0..2 {
+            for (key, value) in key_values {
+                storage.put(key.as_bytes(), value.as_bytes()).unwrap();
+            }
+            sync(&storage);
+        }
+    };
+    // the old values are compacted to L2, the bottom level
+    put_and_flush(&[("key_a", "old"), ("key_b", "old")]);
+    storage.trigger_compaction().unwrap();
+    storage.trigger_compaction().unwrap();
+    {
+        let state = storage.state.read();
+        assert!(state.l0_sstables.is_empty());
+        assert!(state.levels[0].1.is_empty());
+        assert!(!state.levels[1].1.is_empty());
+    }
+
+    // the expired value in L1 is replaced with a deletion, so that the old value in L2 is not read
+    let contexts = add_test_filter(&storage, remove_expired);
+    put_and_flush(&[("key_b", "expired"), ("key_c", "new")]);
+    storage.trigger_compaction().unwrap();
+    assert!(!storage.state.read().levels[0].1.is_empty());
+    assert_eq!(
+        contexts.lock().last().unwrap(),
+        &CompactionFilterContext {
+            is_bottom_level: false,
+            output_level: Some(1),
+        }
+    );
+    assert_eq!(&storage.get(b"key_a").unwrap().unwrap()[..], b"old");
+    assert_eq!(storage.get(b"key_b").unwrap(), None);
+    assert_eq!(&storage.get(b"key_c").unwrap().unwrap()[..], b"new");
+
+    // the deletion is dropped along with the old value in a bottom-level compaction
+    storage.trigger_compaction().unwrap();
+    let state = storage.state.read();
+    assert!(state.levels[0].1.is_empty());
+    assert_eq!(
+        contexts.lock().last().unwrap(),
+        &CompactionFilterContext {
+            is_bottom_level: true,
+            output_level: Some(2),
+        }
+    );
+    let mut iter = construct_merge_iterator_over_storage(&state);
+    check_iter_result_by_key(
+        &mut iter,
+        vec![
+            (Bytes::from("key_a"), Bytes::from("old")),
+            (Bytes::from("key_c"), Bytes::from("new")),
+        ],
+    );
+}
@@ -0,0 +1,96 @@
+use bytes::BufMut;
+use tempfile::tempdir;
+
+use crate::{
+    compact::{CompactionPriority, LeveledCompactionController, LeveledCompactionOptions},
+    table::{BlockMeta, FileObject, SsTable, SsTableStats},
+};
+
+use super::harness::{build_sst, mock_state};
+
+#[test]
+fn test_sst_stats() {
+    let dir = tempdir().unwrap();
+    let sst = build_sst(dir.path(), 1, "key", 100, 5, Some(4));
+    let expected = sst.stats();
+    assert_eq!(expected.num_entries, 100);
+    assert_eq!(expected.num_tombstones, 25);
+    assert!(expected.created_at > 0);
+    assert_eq!(expected.data_written_at, expected.created_at);
+    assert_eq!(sst.stats().tombstone_ratio(), 0.25);
+
+    // the stats are stored in the block meta
+    let file = FileObject::open(&dir.path().join("00001.sst")).unwrap();
+    let sst = SsTable::open(1, None, file).unwrap();
+    assert_eq!(sst.stats(), expected);
+}
+
+#[test]
+fn test_block_meta_without_stats() {
+    let dir = tempdir().unwrap();
+    let sst = build_sst(dir.path(), 1, "key", 100, 5, Some(4));
+    let mut buf = Vec::new();
+    BlockMeta::encode_block_meta(&sst.block_meta, 5, SsTableStats::default(), &mut buf);
+
+    // the block meta written before the stats were recorded ends with the max timestamp
+    buf.truncate(buf.len() - 8 * 4 - 4);
+    let checksum = crc32fast::hash(&buf[4..]);
+    buf.put_u32(checksum);
+    let (block_meta, max_ts, stats) = BlockMeta::decode_block_meta(&buf).unwrap();
+    assert_eq!(block_meta, sst.block_meta);
+    assert_eq!(max_ts, 5);
+    assert_eq!(stats, SsTableStats::default());
+}
+
+#[test]
+fn test_leveled_compaction_priority() {
+    let dir = tempdir().unwrap();
+    let l1 = [
+        // the oldest SST
+        build_sst(dir.path(), 1, "a", 100, 40, None),
+        // the SST with the oldest data
+        build_sst(dir.path(), 2, "b", 100, 10, None),
+        // the SST not overlapping L2
+        build_sst(dir.path(), 3, "c", 100, 30, None),
+        // the SST with the most tombstones
+        build_sst(dir.path(), 4, "d", 100, 20, Some(2)),
+    ];
+    let l2 = [
+        build_sst(dir.path(), 5, "a", 1000, 1, None),
+        build_sst(dir.path(), 6, "b", 1000, 1, None),
+        build_sst(dir.path(), 7, "d", 1000, 1, None),
+    ];
+    let state = mock_state(
+        Vec::new(),
+        vec![(1, vec![1, 2, 3, 4]), (2, vec![5, 6, 7])],
+        l1.into_iter().chain(l2).collect(),
+    );
+
+    for (priority, selected_sst, lower_level_sst_ids) in [
+        (CompactionPriority::OldestSstId, 1, vec![5]),
+        (CompactionPriority::MinOverlappingBytes, 3, vec![]),
+        (CompactionPriority::OldestMaxTs, 2, vec![6]),
+        (CompactionPriority::HighestTombstoneRatio, 4, vec![7]),
+    ] {
+        let controller = LeveledCompactionController::new(LeveledCompactionOptions {
+            level_size_multiplier: 2,
+            level0_file_num_compaction_trigger: 2,
+            max_levels: 2,
+            base_level_size_mb: 1,
+        })
+        .with_priority(priority);
+        let task = controller.generate_compaction_task(&state).unwrap();
+        assert_eq!(task.upper_level, Some(1), "{:?}", priority);
+        assert_eq!(
+            task.upper_level_sst_ids,
+            vec![selected_sst],
+            "{:?}",
+            priority
+        );
+        assert_eq!(
+            task.lower_level_sst_ids, lower_level_sst_ids,
+            "{:?}",
+            priority
+        );
+    }
+}
@@ -0,0 +1,115 @@
+use std::path::Path;
+use std::sync::Arc;
+use std::time::Duration;
+
+use tempfile::tempdir;
+
+use crate::{compact::CompactionOptions, lsm_storage::MiniLsm};
+
+use super::harness::{leveled_options, simple_options, tiered_options, wal_options_with};
+
+/// Overwrite the keys, flushing after each round, and return the expected value of each key.
+fn write_rounds(storage: &MiniLsm, rounds: std::ops::Range<usize>) -> Vec<String> {
+    let mut expected = vec![String::new(); 10];
+    for round in rounds {
+        for (i, value) in expected.iter_mut().enumerate() {
+            *value = format!("value_{}_{}", i, round);
+            storage
+                .put(format!("key_{}", i).as_bytes(), value.as_bytes())
+                .unwrap();
+        }
+        storage.delete(b"key_0").unwrap();
+        storage.force_flush().unwrap();
+    }
+    expected
+}
+
+fn check(storage: &MiniLsm, expected: &[String]) {
+    assert_eq!(storage.get(b"key_0").unwrap(), None);
+    for (i, value) in expected.iter().enumerate().skip(1) {
+        assert_eq!(
+            &storage
+                .get(format!("key_{}", i).as_bytes())
+                .unwrap()
+                .unwrap()[..],
+            value.as_bytes()
+        );
+    }
+}
+
+fn test_change_compaction_strategy(
+    dir: &Path,
+    from: CompactionOptions,
+    to: CompactionOptions,
+) -> Arc<MiniLsm> {
+    let storage = MiniLsm::open(dir, wal_options_with(from.clone())).unwrap();
+    write_rounds(&storage, 0..10);
+    std::thread::sleep(Duration::from_millis(200));
+    storage.change_compaction_strategy(to.clone()).unwrap();
+    {
+        let state = storage.inner.state.read();
+        assert!(state.l0_sstables.is_empty());
+        match &to {
+            CompactionOptions::Tiered(_) => assert_eq!(state.levels.len(), 1),
+            _ => {
+                let (bottom, upper) = state.levels.split_last().unwrap();
+                assert!(upper.iter().all(|(_, ssts)| ssts.is_empty()));
+                assert!(!bottom.1.is_empty());
+            }
+        }
+    }
+    // the new strategy compacts the SSTs flushed from now on
+    let expected = write_rounds(&storage, 10..20);
+    std::thread::sleep(Duration::from_millis(200));
+    check(&storage, &expected);
+    storage.close().unwrap();
+    drop(storage);
+
+    // the old options are rejected, and recovery replays the switch
+    assert!(MiniLsm::open(dir, wal_options_with(from)).is_err());
+    let storage = MiniLsm::open(dir, wal_options_with(to)).unwrap();
+    check(&storage, &expected);
+    storage
+}
+
+#[test]
+fn test_change_tiered_to_leveled() {
+    let dir = tempdir().unwrap();
+    test_change_compaction_strategy(dir.path(), tiered_options(), leveled_options(4));
+}
+
+#[test]
+fn test_change_leveled_to_tiered() {
+    let dir = tempdir().unwrap();
+    test_change_compaction_strategy(dir.path(), leveled_options(4), tiered_options());
+}
+
+#[test]
+fn test_change_no_compaction_to_simple() {
+    let dir = tempdir().unwrap();
+    let storage = test_change_compaction_strategy(
+        dir.path(),
+        CompactionOptions::NoCompaction,
+        simple_options(),
+    );
+    // compaction was enabled without reopening the storage
+    assert!(storage.inner.state.read().l0_sstables.len() < 10);
+}
+
+#[test]
+fn test_change_compaction_strategy_stale_options_file() {
+    let dir = tempdir().unwrap();
+    let storage = MiniLsm::open(&dir, wal_options_with(tiered_options())).unwrap();
+    let expected = write_rounds(&storage, 0..3);
+    let options_file = std::fs::read(dir.path().join("OPTIONS")).unwrap();
+    storage
+        .change_compaction_strategy(leveled_options(4))
+        .unwrap();
+    storage.close().unwrap();
+    drop(storage);
+    // a crash after recording the switch in the manifest, but before updating the OPTIONS file
+    std::fs::write(dir.path().join("OPTIONS"), options_file).unwrap();
+    assert!(MiniLsm::open(&dir, wal_options_with(tiered_options())).is_err());
+    let storage = MiniLsm::open(&dir, wal_options_with(leveled_options(4))).unwrap();
+    check(&storage, &expected);
+}
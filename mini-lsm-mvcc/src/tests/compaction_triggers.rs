@@ -0,0 +1,195 @@
+use std::collections::HashSet;
+use std::sync::Arc;
+use std::time::Duration;
+
+use tempfile::tempdir;
+
+use crate::{
+    compact::{
+        CompactionOptions, LeveledCompactionController, LeveledCompactionOptions,
+        SimpleLeveledCompactionOptions, SstCompactionTriggers, TieredCompactionController,
+        TieredCompactionOptions,
+    },
+    lsm_storage::{LsmStorageInner, LsmStorageOptions},
+};
+
+use super::harness::{build_sst, mock_state, sync, tiered_options};
+
+#[test]
+fn test_sst_compaction_triggers_check() {
+    let dir = tempdir().unwrap();
+    let sst = build_sst(dir.path(), 1, "key", 100, 1, Some(2));
+    let created_at = sst.stats().created_at;
+    let triggers = SstCompactionTriggers {
+        tombstone_ratio: Some(0.4),
+        periodic_compaction_seconds: 0,
+    };
+    assert!(triggers.check(&sst, false, created_at).is_some());
+    // tombstones are not compacted in the bottom level
+    assert!(triggers.check(&sst, true, created_at).is_none());
+
+    let triggers = SstCompactionTriggers {
+        tombstone_ratio: Some(0.5),
+        periodic_compaction_seconds: 60,
+    };
+    assert!(triggers.check(&sst, false, created_at).is_none());
+    assert!(triggers.check(&sst, false, created_at + 59).is_none());
+    assert!(triggers.check(&sst, false, created_at + 60).is_some());
+    assert!(triggers.check(&sst, true, created_at + 60).is_some());
+}
+
+#[test]
+fn test_tombstone_triggered_compaction_leveled() {
+    let dir = tempdir().unwrap();
+    // L2 is within its target size, which is half of L3
+    let state = mock_state(
+        Vec::new(),
+        vec![(1, vec![]), (2, vec![1, 2]), (3, vec![3, 4])],
+        vec![
+            build_sst(dir.path(), 1, "a", 100, 1, None),
+            build_sst(dir.path(), 2, "b", 100, 1, Some(2)),
+            build_sst(dir.path(), 3, "a", 1000, 1, None),
+            build_sst(dir.path(), 4, "b", 1000, 1, Some(2)),
+        ],
+    );
+    let controller = || {
+        LeveledCompactionController::new(LeveledCompactionOptions {
+            level_size_multiplier: 2,
+            level0_file_num_compaction_trigger: 2,
+            max_levels: 3,
+            base_level_size_mb: 0,
+        })
+    };
+    assert!(controller().generate_compaction_task(&state).is_none());
+
+    let controller = controller().with_triggers(SstCompactionTriggers {
+        tombstone_ratio: Some(0.3),
+        periodic_compaction_seconds: 0,
+    });
+    let task = controller.generate_compaction_task(&state).unwrap();
+    assert_eq!(task.upper_level, Some(2));
+    assert_eq!(task.upper_level_sst_ids, vec![2]);
+    assert_eq!(task.lower_level, 3);
+    assert_eq!(task.lower_level_sst_ids, vec![4]);
+    assert!(task.is_lower_level_bottom_level);
+
+    // the tombstones of the bottom level do not trigger a compaction
+    assert!(controller
+        .generate_compaction_task_excluding(&state, &HashSet::from([2]))
+        .is_none());
+}
+
+#[test]
+fn test_tombstone_triggered_compaction_tiered() {
+    let dir = tempdir().unwrap();
+    let state = mock_state(
+        Vec::new(),
+        vec![(2, vec![2]), (1, vec![1])],
+        vec![
+            build_sst(dir.path(), 1, "a", 100, 1, None),
+            build_sst(dir.path(), 2, "a", 100, 1, Some(2)),
+        ],
+    );
+    let controller = || {
+        TieredCompactionController::new(TieredCompactionOptions {
+            num_tiers: 3,
+            max_size_amplification_percent: 200,
+            size_ratio: 1,
+            min_merge_width: 2,
+        })
+    };
+    assert!(controller().generate_compaction_task(&state).is_none());
+
+    let controller = controller().with_triggers(SstCompactionTriggers {
+        tombstone_ratio: Some(0.3),
+        periodic_compaction_seconds: 0,
+    });
+    let task = controller.generate_compaction_task(&state).unwrap();
+    assert_eq!(task.tiers, state.levels);
+    assert!(task.bottom_tier_included);
+}
+
+#[test]
+fn test_periodic_compaction_simple() {
+    let dir = tempdir().unwrap();
+    let mut options = LsmStorageOptions::default_for_week2_test(CompactionOptions::Simple(
+        SimpleLeveledCompactionOptions {
+            size_ratio_percent: 200,
+            level0_file_num_compaction_trigger: 2,
+            max_levels: 1,
+        },
+    ));
+    options.periodic_compaction_seconds = 1;
+    let storage = Arc::new(LsmStorageInner::open(&dir, options).unwrap());
+    for i in 0..100 {
+        storage
+            .put(format!("key_{:03}", i).as_bytes(), b"value")
+            .unwrap();
+    }
+    sync(&storage);
+    let l0_sstables = storage.state.read().l0_sstables.clone();
+    assert_eq!(l0_sstables.len(), 1);
+    storage.trigger_compaction().unwrap();
+    assert_eq!(storage.state.read().l0_sstables, l0_sstables);
+
+    // the L0 SST is moved to L1 once it is old enough, and then rewritten in place, as L1 is the
+    // bottom level
+    std::thread::sleep(Duration::from_secs(1));
+    storage.trigger_compaction().unwrap();
+    {
+        let state = storage.state.read();
+        assert!(state.l0_sstables.is_empty());
+        assert_eq!(state.levels[0].1, l0_sstables);
+    }
+    storage.trigger_compaction().unwrap();
+    let l1_sstables = storage.state.read().levels[0].1.clone();
+    assert_eq!(l1_sstables.len(), 1);
+    assert_ne!(l1_sstables, l0_sstables);
+    storage.trigger_compaction().unwrap();
+    assert_eq!(storage.state.read().levels[0].1, l1_sstables);
+    for i in 0..100 {
+        assert_eq!(
+            &storage
+                .get(format!("key_{:03}", i).as_bytes())
+                .unwrap()
+                .unwrap()[..],
+            b"value"
+        );
+    }
+}
+
+#[test]
+fn test_periodic_compaction_tiered() {
+    let dir = tempdir().unwrap();
+    let mut options = LsmStorageOptions::default_for_week2_test(tiered_options());
+    options.periodic_compaction_seconds = 1;
+    let storage = Arc::new(LsmStorageInner::open(&dir, options).unwrap());
+    for i in 0..100 {
+        storage
+            .put(format!("key_{:03}", i).as_bytes(), b"value")
+            .unwrap();
+    }
+    sync(&storage);
+    let tier = storage.state.read().levels[0].1.clone();
+    storage.trigger_compaction().unwrap();
+    assert_eq!(storage.state.read().levels[0].1, tier);
+
+    // the only tier is rewritten once it is old enough
+    std::thread::sleep(Duration::from_secs(1));
+    storage.trigger_compaction().unwrap();
+    {
+        let state = storage.state.read();
+        assert_eq!(state.levels.len(), 1);
+        assert_eq!(state.levels[0].1.len(), 1);
+        assert_ne!(state.levels[0].1, tier);
+    }
+    for i in 0..100 {
+        assert_eq!(
+            &storage
+                .get(format!("key_{:03}", i).as_bytes())
+                .unwrap()
+                .unwrap()[..],
+            b"value"
+        );
+    }
+}
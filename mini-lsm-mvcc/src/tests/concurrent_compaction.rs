@@ -0,0 +1,127 @@
+use std::collections::HashSet;
+use std::sync::Arc;
+
+use bytes::Bytes;
+use tempfile::tempdir;
+
+use crate::{
+    compact::{
+        CompactionOptions, LeveledCompactionOptions, SimpleLeveledCompactionController,
+        SimpleLeveledCompactionOptions,
+    },
+    key::KeyBytes,
+    lsm_storage::{LsmStorageOptions, LsmStorageState, MiniLsm},
+    table::SsTable,
+};
+
+use super::harness::{check_compaction_ratio, compaction_bench, mock_state};
+
+fn key(key: &str) -> KeyBytes {
+    KeyBytes::from_bytes_with_ts(Bytes::copy_from_slice(key.as_bytes()), 0)
+}
+
+/// A state with SSTs covering the whole key space, `l0` SSTs in L0 and `levels[i]` SSTs in L(i+1).
+fn full_range_state(l0: usize, levels: &[usize]) -> LsmStorageState {
+    let num_ssts = l0 + levels.iter().sum::<usize>();
+    let ssts = (1..=num_ssts)
+        .map(|id| Arc::new(SsTable::create_meta_only(id, 1 << 20, key("a"), key("z"))))
+        .collect();
+    let mut next_id = l0 + 1;
+    let levels = levels
+        .iter()
+        .enumerate()
+        .map(|(level, num)| {
+            let ids = (next_id..next_id + num).collect();
+            next_id += num;
+            (level + 1, ids)
+        })
+        .collect();
+    mock_state((1..=l0).rev().collect(), levels, ssts)
+}
+
+#[test]
+fn test_simple_leveled_non_conflicting_tasks() {
+    let controller = SimpleLeveledCompactionController::new(SimpleLeveledCompactionOptions {
+        size_ratio_percent: 200,
+        level0_file_num_compaction_trigger: 2,
+        max_levels: 3,
+    });
+    let state = full_range_state(2, &[0, 2, 1]);
+    let l0_task = controller.generate_compaction_task(&state).unwrap();
+    assert_eq!(l0_task.upper_level, None);
+
+    // the L0 task is skipped while its SSTs are busy, and the L2 task does not conflict with it
+    let mut busy_ssts = l0_task
+        .upper_level_sst_ids
+        .iter()
+        .chain(&l0_task.lower_level_sst_ids)
+        .copied()
+        .collect::<HashSet<_>>();
+    let l2_task = controller
+        .generate_compaction_task_excluding(&state, &busy_ssts)
+        .unwrap();
+    assert_eq!(l2_task.upper_level, Some(2));
+    busy_ssts.extend(
+        l2_task
+            .upper_level_sst_ids
+            .iter()
+            .chain(&l2_task.lower_level_sst_ids),
+    );
+    assert!(controller
+        .generate_compaction_task_excluding(&state, &busy_ssts)
+        .is_none());
+
+    // the tasks complete in any order, while new SSTs are flushed to L0
+    let mut state = state;
+    state.l0_sstables.insert(0, 100);
+    let (state, removed) = controller.apply_compaction_result(&state, &l2_task, &[101]);
+    assert_eq!(removed.len(), 3);
+    let (state, removed) = controller.apply_compaction_result(&state, &l0_task, &[102, 103]);
+    assert_eq!(removed.len(), 2);
+    assert_eq!(state.l0_sstables, vec![100]);
+    assert_eq!(
+        state.levels,
+        vec![(1, vec![102, 103]), (2, vec![]), (3, vec![101])]
+    );
+}
+
+fn test_concurrent_compaction_integration(compaction_options: CompactionOptions) {
+    let dir = tempdir().unwrap();
+    let mut options = LsmStorageOptions::default_for_week2_test(compaction_options);
+    options.max_background_compactions = 4;
+    let storage = MiniLsm::open(&dir, options.clone()).unwrap();
+
+    compaction_bench(storage.clone());
+    check_compaction_ratio(storage.clone());
+    let report = storage.verify(false).unwrap();
+    assert!(report.is_ok(), "{:?}", report.errors);
+    assert!(storage.inner.running_compactions.lock().is_empty());
+    storage.close().unwrap();
+    drop(storage);
+
+    // the levels are sorted again after replaying the compactions
+    let storage = MiniLsm::open(&dir, options).unwrap();
+    let report = storage.verify(false).unwrap();
+    assert!(report.is_ok(), "{:?}", report.errors);
+}
+
+#[test]
+fn test_concurrent_compaction_leveled() {
+    test_concurrent_compaction_integration(CompactionOptions::Leveled(LeveledCompactionOptions {
+        level0_file_num_compaction_trigger: 2,
+        level_size_multiplier: 2,
+        base_level_size_mb: 1,
+        max_levels: 4,
+    }));
+}
+
+#[test]
+fn test_concurrent_compaction_simple() {
+    test_concurrent_compaction_integration(CompactionOptions::Simple(
+        SimpleLeveledCompactionOptions {
+            size_ratio_percent: 200,
+            level0_file_num_compaction_trigger: 2,
+            max_levels: 3,
+        },
+    ));
+}
@@ -0,0 +1,34 @@
+use tempfile::tempdir;
+
+use crate::lsm_storage::MiniLsm;
+
+use super::harness::wal_options;
+
+#[test]
+fn test_dir_lock_rejects_second_open() {
+    let dir = tempdir().unwrap();
+    let storage = MiniLsm::open(&dir, wal_options()).unwrap();
+    storage.put(b"key", b"value").unwrap();
+    let err = MiniLsm::open(&dir, wal_options()).err().unwrap();
+    assert!(err.to_string().contains("already in use"), "{}", err);
+    // the failed open leaves the storage alone
+    storage.put(b"key2", b"value2").unwrap();
+    assert_eq!(&storage.get(b"key").unwrap().unwrap()[..], b"value");
+    assert!(dir.path().join("LOCK").exists());
+}
+
+#[test]
+fn test_dir_lock_released_on_drop() {
+    let dir = tempdir().unwrap();
+    let storage = MiniLsm::open(&dir, wal_options()).unwrap();
+    storage.put(b"key", b"value").unwrap();
+    // dropped without closing, while the background threads are running
+    drop(storage);
+    let storage = MiniLsm::open(&dir, wal_options()).unwrap();
+    assert_eq!(&storage.get(b"key").unwrap().unwrap()[..], b"value");
+    storage.close().unwrap();
+    drop(storage);
+    // the LOCK file left behind does not prevent opening the storage
+    let storage = MiniLsm::open(&dir, wal_options()).unwrap();
+    assert_eq!(&storage.get(b"key").unwrap().unwrap()[..], b"value");
+}
@@ -0,0 +1,76 @@
+use std::sync::Arc;
+use std::time::Duration;
+
+use tempfile::tempdir;
+
+use crate::{
+    compact::{CompactionOptions, FifoCompactionController, FifoCompactionOptions},
+    lsm_storage::{LsmStorageInner, LsmStorageOptions},
+};
+
+use super::harness::{check_keys, flush_ranges};
+
+fn options(max_table_files_size: u64, ttl_seconds: u64) -> LsmStorageOptions {
+    LsmStorageOptions::default_for_week2_test(CompactionOptions::Fifo(FifoCompactionOptions {
+        max_table_files_size,
+        ttl_seconds,
+    }))
+}
+
+#[test]
+fn test_fifo_compaction_by_size() {
+    let dir = tempdir().unwrap();
+    let storage = Arc::new(LsmStorageInner::open(&dir, options(u64::MAX, 0)).unwrap());
+    let ssts = flush_ranges(&storage, &[0..100, 100..200, 200..300, 300..400]);
+    storage.trigger_compaction().unwrap();
+    assert_eq!(storage.state.read().l0_sstables.len(), 4);
+
+    // only the two latest SSTs fit in the limit
+    let snapshot = storage.state.read().as_ref().clone();
+    let size = |id: usize| snapshot.sstables[&id].table_size();
+    let controller = FifoCompactionController::new(FifoCompactionOptions {
+        max_table_files_size: size(ssts[2]) + size(ssts[3]),
+        ttl_seconds: 0,
+    });
+    let task = controller.generate_compaction_task(&snapshot).unwrap();
+    assert_eq!(task.ssts, ssts[..2]);
+    let (snapshot, files_to_remove) = controller.apply_compaction_result(&snapshot, &task, &[]);
+    assert_eq!(files_to_remove, ssts[..2]);
+    assert_eq!(snapshot.l0_sstables, vec![ssts[3], ssts[2]]);
+    assert!(controller.generate_compaction_task(&snapshot).is_none());
+}
+
+#[test]
+fn test_fifo_compaction_by_age() {
+    let dir = tempdir().unwrap();
+    let storage = Arc::new(LsmStorageInner::open(&dir, options(u64::MAX, 2)).unwrap());
+    let ssts = flush_ranges(&storage, &[0..100, 100..200]);
+    storage.trigger_compaction().unwrap();
+    assert_eq!(storage.state.read().l0_sstables.len(), 2);
+
+    // the SSTs flushed before the sleep are dropped whole, without writing any SST
+    std::thread::sleep(Duration::from_secs(2));
+    let new_ssts = flush_ranges(&storage, &[200..250, 250..300]);
+    storage.trigger_compaction().unwrap();
+    {
+        let state = storage.state.read();
+        assert_eq!(state.l0_sstables, vec![new_ssts[3], new_ssts[2]]);
+        assert!(state.levels.is_empty());
+        assert_eq!(state.sstables.len(), 2);
+    }
+    for id in ssts {
+        assert!(!storage.path_of_sst(id).exists());
+    }
+    check_keys(&storage, 0..200, false);
+    check_keys(&storage, 200..300, true);
+
+    // the dropped SSTs are recorded in the manifest
+    drop(storage);
+    let storage = Arc::new(LsmStorageInner::open(&dir, options(u64::MAX, 2)).unwrap());
+    assert_eq!(
+        storage.state.read().l0_sstables,
+        vec![new_ssts[3], new_ssts[2]]
+    );
+    check_keys(&storage, 0..200, false);
+    check_keys(&storage, 200..300, true);
+}
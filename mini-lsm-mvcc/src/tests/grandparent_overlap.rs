@@ -0,0 +1,148 @@
+use std::sync::Arc;
+
+use tempfile::tempdir;
+
+use crate::{
+    compact::{
+        CompactionOptions, LeveledCompactionController, LeveledCompactionOptions,
+        SimpleLeveledCompactionController, SimpleLeveledCompactionOptions,
+    },
+    key::KeyBytes,
+    lsm_storage::{LsmStorageInner, LsmStorageOptions, LsmStorageState},
+    table::SsTable,
+};
+
+use super::harness::{mock_state, sync};
+
+/// Build a state with a level for each of `levels`, given as the SSTs of the level, each with its
+/// id, size in MB, and the range of its keys.
+fn levels_state(levels: &[&[(usize, u64, u32, u32)]]) -> LsmStorageState {
+    let key = |x: &u32| KeyBytes::for_testing_from_bytes_no_ts(format!("{:03}", x).into());
+    let ssts = levels
+        .iter()
+        .flat_map(|ssts| ssts.iter())
+        .map(|(id, size_mb, first_key, last_key)| {
+            let sst = SsTable::create_meta_only(*id, size_mb << 20, key(first_key), key(last_key));
+            Arc::new(sst)
+        })
+        .collect();
+    let levels = levels
+        .iter()
+        .enumerate()
+        .map(|(level, ssts)| (level + 1, ssts.iter().map(|(id, ..)| *id).collect()))
+        .collect();
+    mock_state(Vec::new(), levels, ssts)
+}
+
+#[test]
+fn test_leveled_task_grandparents() {
+    let controller = LeveledCompactionController::new(LeveledCompactionOptions {
+        level_size_multiplier: 2,
+        level0_file_num_compaction_trigger: 2,
+        max_levels: 3,
+        base_level_size_mb: 1,
+    });
+    // L1 exceeds its 1MB target, and its SST is compacted with the first one of L2
+    let state = levels_state(&[
+        &[(1, 2, 100, 200)],
+        &[(2, 0, 50, 150), (3, 0, 300, 400)],
+        &[
+            (4, 1, 0, 90),
+            (5, 1, 100, 160),
+            (6, 1, 170, 260),
+            (7, 1, 300, 400),
+        ],
+    ]);
+    let task = controller.generate_compaction_task(&state).unwrap();
+    assert_eq!(task.upper_level_sst_ids, vec![1]);
+    assert_eq!(task.lower_level_sst_ids, vec![2]);
+    assert_eq!(task.grandparent_sst_ids, vec![4, 5, 6]);
+}
+
+#[test]
+fn test_simple_leveled_task_grandparents() {
+    let controller = SimpleLeveledCompactionController::new(SimpleLeveledCompactionOptions {
+        size_ratio_percent: 200,
+        level0_file_num_compaction_trigger: 2,
+        max_levels: 3,
+    });
+    let mut state = levels_state(&[&[], &[(1, 1, 0, 100), (2, 1, 200, 300)], &[]]);
+    state.l0_sstables = vec![4, 3];
+    let task = controller.generate_compaction_task(&state).unwrap();
+    assert_eq!(task.lower_level, 1);
+    assert_eq!(task.grandparent_sst_ids, vec![1, 2]);
+
+    // there are no grandparents below the bottom level
+    state.l0_sstables.clear();
+    let task = controller.generate_compaction_task(&state).unwrap();
+    assert_eq!(task.lower_level, 3);
+    assert!(task.is_lower_level_bottom_level);
+    assert!(task.grandparent_sst_ids.is_empty());
+}
+
+#[test]
+fn test_compaction_output_cut_at_grandparents() {
+    for max_grandparent_overlap_factor in [0, 1] {
+        let dir = tempdir().unwrap();
+        let mut options = LsmStorageOptions::default_for_week2_test(CompactionOptions::Simple(
+            SimpleLeveledCompactionOptions {
+                size_ratio_percent: 200,
+                level0_file_num_compaction_trigger: 2,
+                max_levels: 2,
+            },
+        ));
+        options.max_grandparent_overlap_factor = max_grandparent_overlap_factor;
+        let storage = Arc::new(LsmStorageInner::open(&dir, options).unwrap());
+        // the keys are moved to L2, the bottom level, as SSTs of about the target size
+        let value = [b'x'; 1000];
+        for i in 0..5000 {
+            storage
+                .put(format!("key_{:05}", i).as_bytes(), &value)
+                .unwrap();
+        }
+        sync(&storage);
+        while !storage.state.read().imm_memtables.is_empty() {
+            storage.force_flush_next_imm_memtable().unwrap();
+        }
+        for _ in 0..3 {
+            storage.trigger_compaction().unwrap();
+        }
+        let grandparents = {
+            let state = storage.state.read();
+            assert!(state.l0_sstables.is_empty());
+            assert!(state.levels[0].1.is_empty());
+            state.levels[1].1.clone()
+        };
+        assert!(grandparents.len() >= 4);
+
+        // a few keys spanning all of L2 are compacted from L0 to L1
+        for _ in 0..2 {
+            for i in (0..5000).step_by(1000) {
+                storage
+                    .put(format!("key_{:05}", i + 500).as_bytes(), b"new")
+                    .unwrap();
+            }
+            sync(&storage);
+        }
+        storage.trigger_compaction().unwrap();
+        let state = storage.state.read();
+        assert!(state.l0_sstables.is_empty());
+        assert_eq!(state.levels[1].1, grandparents);
+        let l1_sstables = &state.levels[0].1;
+        if max_grandparent_overlap_factor == 0 {
+            assert_eq!(l1_sstables.len(), 1);
+        } else {
+            // each output overlaps a single SST of L2
+            assert_eq!(l1_sstables.len(), 5);
+            for id in l1_sstables {
+                let sst = &state.sstables[id];
+                let overlapping = grandparents
+                    .iter()
+                    .map(|id| &state.sstables[id])
+                    .filter(|x| x.first_key() <= sst.last_key() && sst.first_key() <= x.last_key())
+                    .count();
+                assert_eq!(overlapping, 1);
+            }
+        }
+    }
+}
@@ -0,0 +1,133 @@
+use std::sync::Arc;
+
+use tempfile::tempdir;
+
+use crate::{
+    compact::{CompactionOptions, LazyLevelingCompactionController, LazyLevelingCompactionOptions},
+    lsm_storage::{LsmStorageInner, LsmStorageOptions},
+};
+
+use super::harness::{mock_state, sync};
+
+fn lazy_leveling_options() -> LazyLevelingCompactionOptions {
+    LazyLevelingCompactionOptions {
+        level0_file_num_compaction_trigger: 2,
+        size_ratio: 3,
+        max_levels: 3,
+    }
+}
+
+#[test]
+fn test_lazy_leveling_tiered_upper_levels() {
+    let controller = LazyLevelingCompactionController::new(lazy_leveling_options());
+    let state = mock_state(
+        vec![4],
+        vec![(1, vec![3]), (1, vec![2]), (3, vec![1])],
+        Vec::new(),
+    );
+    assert!(controller.generate_compaction_task(&state).is_none());
+
+    // the L0 SSTs become the latest sorted run of L1, without rewriting the others
+    let state = mock_state(
+        vec![5, 4],
+        vec![(1, vec![3]), (1, vec![2]), (3, vec![1])],
+        Vec::new(),
+    );
+    let task = controller.generate_compaction_task(&state).unwrap();
+    assert_eq!(task.upper_level, None);
+    assert_eq!(task.upper_level_runs, vec![vec![5], vec![4]]);
+    assert_eq!(task.lower_level, 1);
+    assert!(task.lower_level_sst_ids.is_empty());
+    assert!(!task.is_lower_level_bottom_level);
+    let (state, files_to_remove) = controller.apply_compaction_result(&state, &task, &[6]);
+    assert_eq!(files_to_remove, vec![5, 4]);
+    assert!(state.l0_sstables.is_empty());
+    assert_eq!(
+        state.levels,
+        vec![(1, vec![6]), (1, vec![3]), (1, vec![2]), (3, vec![1])]
+    );
+
+    // a full level is merged into a new sorted run of the next level
+    let task = controller.generate_compaction_task(&state).unwrap();
+    assert_eq!(task.upper_level, Some(1));
+    assert_eq!(task.upper_level_runs, vec![vec![6], vec![3], vec![2]]);
+    assert_eq!(task.lower_level, 2);
+    assert!(task.lower_level_sst_ids.is_empty());
+    let (state, _) = controller.apply_compaction_result(&state, &task, &[7, 8]);
+    assert_eq!(state.levels, vec![(2, vec![7, 8]), (3, vec![1])]);
+    assert!(controller.generate_compaction_task(&state).is_none());
+}
+
+#[test]
+fn test_lazy_leveling_leveled_bottom_level() {
+    let controller = LazyLevelingCompactionController::new(lazy_leveling_options());
+    let state = mock_state(
+        vec![],
+        vec![
+            (1, vec![9]),
+            (2, vec![7, 8]),
+            (2, vec![5, 6]),
+            (2, vec![3, 4]),
+            (3, vec![1, 2]),
+        ],
+        Vec::new(),
+    );
+    // the runs of the last upper level are merged with the only run of the bottom level
+    let task = controller.generate_compaction_task(&state).unwrap();
+    assert_eq!(task.upper_level, Some(2));
+    assert_eq!(
+        task.upper_level_runs,
+        vec![vec![7, 8], vec![5, 6], vec![3, 4]]
+    );
+    assert_eq!(task.lower_level, 3);
+    assert_eq!(task.lower_level_sst_ids, vec![1, 2]);
+    assert!(task.is_lower_level_bottom_level);
+    let (state, mut files_to_remove) =
+        controller.apply_compaction_result(&state, &task, &[10, 11, 12]);
+    files_to_remove.sort();
+    assert_eq!(files_to_remove, (1..=8).collect::<Vec<_>>());
+    assert_eq!(state.levels, vec![(1, vec![9]), (3, vec![10, 11, 12])]);
+}
+
+#[test]
+fn test_lazy_leveling_compaction_integration() {
+    let dir = tempdir().unwrap();
+    let options = LsmStorageOptions::default_for_week2_test(CompactionOptions::LazyLeveling(
+        lazy_leveling_options(),
+    ));
+    let storage = Arc::new(LsmStorageInner::open(&dir, options.clone()).unwrap());
+    for round in 0..18 {
+        for i in 0..100 {
+            let key = format!("key_{:05}", i * 18 + round);
+            storage.put(key.as_bytes(), b"value").unwrap();
+        }
+        if round > 0 {
+            let key = format!("key_{:05}", round - 1);
+            storage.delete(key.as_bytes()).unwrap();
+        }
+        sync(&storage);
+        for _ in 0..3 {
+            storage.trigger_compaction().unwrap();
+        }
+    }
+    let levels = {
+        let state = storage.state.read();
+        // 18 SSTs flushed as 9 L1 runs, merged into 3 L2 runs, merged into the bottom level
+        assert!(state.l0_sstables.is_empty());
+        assert_eq!(state.levels.len(), 1);
+        assert_eq!(state.levels[0].0, 3);
+        state.levels.clone()
+    };
+    let check = |storage: &Arc<LsmStorageInner>| {
+        for i in 0..1800 {
+            let value = storage.get(format!("key_{:05}", i).as_bytes()).unwrap();
+            assert_eq!(value.is_none(), i < 17, "key_{:05}", i);
+        }
+    };
+    check(&storage);
+    drop(storage);
+
+    let storage = Arc::new(LsmStorageInner::open(&dir, options).unwrap());
+    assert_eq!(storage.state.read().levels, levels);
+    check(&storage);
+}
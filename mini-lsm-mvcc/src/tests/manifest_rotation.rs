@@ -0,0 +1,136 @@
+use std::path::Path;
+
+use tempfile::tempdir;
+
+use crate::{
+    compact::{
+        CompactionOptions, LeveledCompactionOptions, SimpleLeveledCompactionOptions,
+        TieredCompactionOptions,
+    },
+    lsm_storage::{LsmStorageOptions, MiniLsm},
+};
+
+fn manifest_files(dir: &Path) -> Vec<String> {
+    let mut files = std::fs::read_dir(dir)
+        .unwrap()
+        .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+        .filter(|name| name.starts_with("MANIFEST"))
+        .collect::<Vec<_>>();
+    files.sort();
+    files
+}
+
+fn options(compaction_options: CompactionOptions) -> LsmStorageOptions {
+    let mut options = LsmStorageOptions::default_for_week2_test(compaction_options);
+    options.max_manifest_file_size = 1024;
+    options
+}
+
+#[test]
+fn test_manifest_rotation_leveled() {
+    test_manifest_rotation(CompactionOptions::Leveled(LeveledCompactionOptions {
+        level_size_multiplier: 2,
+        level0_file_num_compaction_trigger: 2,
+        max_levels: 3,
+        base_level_size_mb: 1,
+    }))
+}
+
+#[test]
+fn test_manifest_rotation_tiered() {
+    test_manifest_rotation(CompactionOptions::Tiered(TieredCompactionOptions {
+        num_tiers: 3,
+        max_size_amplification_percent: 200,
+        size_ratio: 1,
+        min_merge_width: 3,
+    }))
+}
+
+#[test]
+fn test_manifest_rotation_simple() {
+    test_manifest_rotation(CompactionOptions::Simple(SimpleLeveledCompactionOptions {
+        size_ratio_percent: 200,
+        level0_file_num_compaction_trigger: 2,
+        max_levels: 3,
+    }));
+}
+
+fn test_manifest_rotation(compaction_options: CompactionOptions) {
+    let dir = tempdir().unwrap();
+    let storage = MiniLsm::open(&dir, options(compaction_options.clone())).unwrap();
+    for i in 0..100 {
+        storage
+            .put(
+                format!("key_{}", i % 10).as_bytes(),
+                format!("v{}", i).as_bytes(),
+            )
+            .unwrap();
+        storage.force_flush().unwrap();
+        if i % 10 == 0 {
+            // let the compaction thread add some compaction records
+            std::thread::sleep(std::time::Duration::from_millis(60));
+        }
+    }
+    storage.close().unwrap();
+    let state = storage.inner.state.read().clone();
+    drop(storage);
+
+    let files = manifest_files(dir.path());
+    assert_eq!(files.len(), 1, "{:?}", files);
+    assert_ne!(files[0], "MANIFEST-000001");
+    let current = std::fs::read_to_string(dir.path().join("CURRENT")).unwrap();
+    assert_eq!(current.trim_end(), files[0]);
+
+    let storage = MiniLsm::open(&dir, options(compaction_options)).unwrap();
+    {
+        let recovered = storage.inner.state.read();
+        assert_eq!(recovered.l0_sstables, state.l0_sstables);
+        assert_eq!(recovered.levels, state.levels);
+    }
+    for i in 90..100 {
+        assert_eq!(
+            &storage
+                .get(format!("key_{}", i % 10).as_bytes())
+                .unwrap()
+                .unwrap()[..],
+            format!("v{}", i).as_bytes()
+        );
+    }
+}
+
+#[test]
+fn test_manifest_legacy_file() {
+    let dir = tempdir().unwrap();
+    let options = LsmStorageOptions::default_for_week2_test(CompactionOptions::NoCompaction);
+    let storage = MiniLsm::open(&dir, options.clone()).unwrap();
+    storage.put(b"key", b"value").unwrap();
+    storage.close().unwrap();
+    drop(storage);
+    // turn the manifest into one written before manifest rotation was introduced
+    std::fs::rename(
+        dir.path().join("MANIFEST-000001"),
+        dir.path().join("MANIFEST"),
+    )
+    .unwrap();
+    std::fs::remove_file(dir.path().join("CURRENT")).unwrap();
+
+    let storage = MiniLsm::open(&dir, options.clone()).unwrap();
+    assert_eq!(&storage.get(b"key").unwrap().unwrap()[..], b"value");
+    storage.put(b"key2", b"value2").unwrap();
+    storage.close().unwrap();
+    drop(storage);
+    assert_eq!(manifest_files(dir.path()), vec!["MANIFEST".to_string()]);
+
+    // the legacy manifest is replaced once it is rotated
+    let mut options = options;
+    options.max_manifest_file_size = 0;
+    let storage = MiniLsm::open(&dir, options.clone()).unwrap();
+    drop(storage);
+    assert_eq!(
+        manifest_files(dir.path()),
+        vec!["MANIFEST-000001".to_string()]
+    );
+    let storage = MiniLsm::open(&dir, options).unwrap();
+    assert_eq!(&storage.get(b"key").unwrap().unwrap()[..], b"value");
+    assert_eq!(&storage.get(b"key2").unwrap().unwrap()[..], b"value2");
+}
@@ -0,0 +1,131 @@
+use std::path::Path;
+
+use tempfile::tempdir;
+
+use crate::{
+    compact::CompactionOptions,
+    lsm_storage::{LsmStorageOptions, MiniLsm},
+    repair::repair_db,
+};
+
+fn files_with_extension(dir: &Path, ext: &str) -> Vec<String> {
+    let mut files = std::fs::read_dir(dir)
+        .unwrap()
+        .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+        .filter(|name| name.ends_with(ext))
+        .collect::<Vec<_>>();
+    files.sort();
+    files
+}
+
+#[test]
+fn test_purge_obsolete_files_on_open() {
+    let dir = tempdir().unwrap();
+    let mut options = LsmStorageOptions::default_for_week2_test(CompactionOptions::NoCompaction);
+    options.enable_wal = true;
+    let storage = MiniLsm::open(&dir, options.clone()).unwrap();
+    storage.put(b"key", b"value").unwrap();
+    storage.force_flush().unwrap();
+    storage.put(b"key2", b"value2").unwrap();
+    storage.close().unwrap();
+    drop(storage);
+    let ssts = files_with_extension(dir.path(), ".sst");
+    let wals = files_with_extension(dir.path(), ".wal");
+
+    // files left behind by a crash: a compaction output that never reached the manifest, a WAL
+    // removed from the manifest, and an unfinished manifest rotation
+    std::fs::write(dir.path().join("01000.sst"), vec![0; 100]).unwrap();
+    std::fs::write(dir.path().join("01001.wal"), vec![0; 10]).unwrap();
+    std::fs::write(dir.path().join("MANIFEST-000002"), vec![0; 1]).unwrap();
+    std::fs::write(dir.path().join("other.txt"), b"not ours").unwrap();
+
+    let storage = MiniLsm::open(&dir, options).unwrap();
+    assert_eq!(files_with_extension(dir.path(), ".sst"), ssts);
+    assert!(!dir.path().join("01001.wal").exists());
+    assert!(!dir.path().join("MANIFEST-000002").exists());
+    assert!(dir.path().join("other.txt").exists());
+    // the recovered WALs are still there
+    for wal in wals {
+        assert!(dir.path().join(wal).exists());
+    }
+    assert_eq!(&storage.get(b"key").unwrap().unwrap()[..], b"value");
+    assert_eq!(&storage.get(b"key2").unwrap().unwrap()[..], b"value2");
+    assert_eq!(storage.purge_obsolete_files().unwrap(), 0);
+}
+
+#[test]
+fn test_purge_obsolete_files_reports_bytes() {
+    let dir = tempdir().unwrap();
+    let storage = MiniLsm::open(
+        &dir,
+        LsmStorageOptions::default_for_week2_test(CompactionOptions::NoCompaction),
+    )
+    .unwrap();
+    for i in 0..3 {
+        storage
+            .put(format!("key_{}", i).as_bytes(), b"value")
+            .unwrap();
+        storage.force_flush().unwrap();
+    }
+    std::fs::write(dir.path().join("01000.sst"), vec![0; 100]).unwrap();
+    std::fs::write(dir.path().join("01001.sst"), vec![0; 23]).unwrap();
+    assert_eq!(storage.purge_obsolete_files().unwrap(), 123);
+    assert_eq!(storage.purge_obsolete_files().unwrap(), 0);
+    assert_eq!(files_with_extension(dir.path(), ".sst").len(), 3);
+    for i in 0..3 {
+        assert_eq!(
+            &storage
+                .get(format!("key_{}", i).as_bytes())
+                .unwrap()
+                .unwrap()[..],
+            b"value"
+        );
+    }
+}
+
+#[test]
+fn test_purge_empty_wals() {
+    let dir = tempdir().unwrap();
+    let mut options = LsmStorageOptions::default_for_week2_test(CompactionOptions::NoCompaction);
+    options.enable_wal = true;
+    let storage = MiniLsm::open(&dir, options.clone()).unwrap();
+    storage.put(b"key", b"value").unwrap();
+    storage.close().unwrap();
+    drop(storage);
+    // the WALs of memtables that are empty after recovery do not pile up
+    for _ in 0..5 {
+        let storage = MiniLsm::open(&dir, options.clone()).unwrap();
+        storage.close().unwrap();
+    }
+    assert_eq!(files_with_extension(dir.path(), ".wal").len(), 2);
+    let storage = MiniLsm::open(&dir, options).unwrap();
+    assert_eq!(&storage.get(b"key").unwrap().unwrap()[..], b"value");
+}
+
+#[test]
+fn test_open_without_current() {
+    let dir = tempdir().unwrap();
+    let mut options = LsmStorageOptions::default_for_week2_test(CompactionOptions::NoCompaction);
+    options.enable_wal = true;
+    let storage = MiniLsm::open(&dir, options.clone()).unwrap();
+    storage.put(b"key", b"value").unwrap();
+    storage.force_flush().unwrap();
+    storage.put(b"key2", b"value2").unwrap();
+    storage.close().unwrap();
+    drop(storage);
+    let ssts = files_with_extension(dir.path(), ".sst");
+    let wals = files_with_extension(dir.path(), ".wal");
+
+    // the files are not mistaken for the obsolete files of a new storage
+    std::fs::remove_file(dir.path().join("CURRENT")).unwrap();
+    let err = MiniLsm::open(&dir, options.clone()).err().unwrap();
+    assert!(err.to_string().contains("repair_db"), "{}", err);
+    assert_eq!(files_with_extension(dir.path(), ".sst"), ssts);
+    assert_eq!(files_with_extension(dir.path(), ".wal"), wals);
+    assert!(!dir.path().join("CURRENT").exists());
+
+    repair_db(&dir, &options).unwrap();
+    let storage = MiniLsm::open(&dir, options).unwrap();
+    assert_eq!(&storage.get(b"key").unwrap().unwrap()[..], b"value");
+    assert_eq!(&storage.get(b"key2").unwrap().unwrap()[..], b"value2");
+}
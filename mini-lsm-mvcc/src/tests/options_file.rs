@@ -0,0 +1,129 @@
+use tempfile::tempdir;
+
+use crate::{
+    compact::{CompactionOptions, LeveledCompactionOptions},
+    lsm_storage::{LsmStorageOptions, MiniLsm},
+};
+
+use super::harness::{leveled_options, tiered_options};
+
+fn persisted_options(dir: &std::path::Path) -> LsmStorageOptions {
+    serde_json::from_slice(&std::fs::read(dir.join("OPTIONS")).unwrap()).unwrap()
+}
+
+#[test]
+fn test_options_incompatible_compaction() {
+    let dir = tempdir().unwrap();
+    let storage = MiniLsm::open(
+        &dir,
+        LsmStorageOptions::default_for_week2_test(tiered_options()),
+    )
+    .unwrap();
+    storage.put(b"key", b"value").unwrap();
+    storage.close().unwrap();
+    drop(storage);
+    assert!(matches!(
+        persisted_options(dir.path()).compaction_options,
+        CompactionOptions::Tiered(_)
+    ));
+
+    for compaction_options in [leveled_options(3), CompactionOptions::NoCompaction] {
+        let err = MiniLsm::open(
+            &dir,
+            LsmStorageOptions::default_for_week2_test(compaction_options),
+        )
+        .err()
+        .unwrap();
+        assert!(
+            err.to_string().contains("incompatible compaction options"),
+            "{}",
+            err
+        );
+    }
+    // the rejected options are not persisted
+    let storage = MiniLsm::open(
+        &dir,
+        LsmStorageOptions::default_for_week2_test(tiered_options()),
+    )
+    .unwrap();
+    assert_eq!(&storage.get(b"key").unwrap().unwrap()[..], b"value");
+}
+
+#[test]
+fn test_options_incompatible_levels() {
+    let dir = tempdir().unwrap();
+    let storage = MiniLsm::open(
+        &dir,
+        LsmStorageOptions::default_for_week2_test(leveled_options(3)),
+    )
+    .unwrap();
+    storage.close().unwrap();
+    drop(storage);
+    assert!(MiniLsm::open(
+        &dir,
+        LsmStorageOptions::default_for_week2_test(leveled_options(4))
+    )
+    .is_err());
+}
+
+#[test]
+fn test_options_compatible_changes() {
+    let dir = tempdir().unwrap();
+    let storage = MiniLsm::open(
+        &dir,
+        LsmStorageOptions::default_for_week2_test(leveled_options(3)),
+    )
+    .unwrap();
+    storage.put(b"key", b"value").unwrap();
+    storage.close().unwrap();
+    drop(storage);
+
+    let mut options = LsmStorageOptions::default_for_week2_test(CompactionOptions::Leveled(
+        LeveledCompactionOptions {
+            level_size_multiplier: 4,
+            level0_file_num_compaction_trigger: 4,
+            max_levels: 3,
+            base_level_size_mb: 2,
+        },
+    ));
+    options.block_size = 1024;
+    options.num_memtable_limit = 5;
+    let storage = MiniLsm::open(&dir, options).unwrap();
+    assert_eq!(&storage.get(b"key").unwrap().unwrap()[..], b"value");
+    drop(storage);
+
+    let persisted = persisted_options(dir.path());
+    assert_eq!(persisted.block_size, 1024);
+    assert_eq!(persisted.num_memtable_limit, 5);
+    let CompactionOptions::Leveled(leveled) = persisted.compaction_options else {
+        panic!("unexpected compaction options");
+    };
+    assert_eq!(leveled.level0_file_num_compaction_trigger, 4);
+}
+
+#[test]
+fn test_options_file_created_for_existing_storage() {
+    let dir = tempdir().unwrap();
+    let storage = MiniLsm::open(
+        &dir,
+        LsmStorageOptions::default_for_week2_test(tiered_options()),
+    )
+    .unwrap();
+    storage.close().unwrap();
+    drop(storage);
+    // a storage created before the OPTIONS file was introduced
+    std::fs::remove_file(dir.path().join("OPTIONS")).unwrap();
+    let storage = MiniLsm::open(
+        &dir,
+        LsmStorageOptions::default_for_week2_test(tiered_options()),
+    )
+    .unwrap();
+    storage.close().unwrap();
+    drop(storage);
+    assert!(dir.path().join("OPTIONS").exists());
+    assert!(MiniLsm::open(
+        &dir,
+        LsmStorageOptions::default_for_week2_test(leveled_options(3))
+    )
+    .is_err());
+}
@@ -0,0 +1,183 @@
+use std::collections::BTreeMap;
+use std::ops::Bound;
+use std::path::Path;
+
+use tempfile::tempdir;
+
+use crate::{
+    compact::CompactionOptions,
+    iterators::StorageIterator,
+    lsm_storage::{MiniLsm, WriteBatchRecord},
+    wal::WalRecoveryReport,
+};
+
+use super::harness::wal_options;
+
+/// The name and content of every file in `dir`.
+fn dir_content(dir: &Path) -> BTreeMap<String, Vec<u8>> {
+    std::fs::read_dir(dir)
+        .unwrap()
+        .map(|entry| {
+            let entry = entry.unwrap();
+            (
+                entry.file_name().into_string().unwrap(),
+                std::fs::read(entry.path()).unwrap(),
+            )
+        })
+        .collect()
+}
+
+/// Write `key_0`..`key_9` into an SST, overwrite the odd keys in an immutable memtable and delete
+/// `key_0` in the memtable, leaving the storage as a crash would.
+fn populate(dir: &Path) {
+    let storage = MiniLsm::open(dir, wal_options()).unwrap();
+    for i in 0..10 {
+        storage.put(format!("key_{}", i).as_bytes(), b"v1").unwrap();
+    }
+    storage.force_flush().unwrap();
+    for i in (1..10).step_by(2) {
+        storage.put(format!("key_{}", i).as_bytes(), b"v2").unwrap();
+    }
+    storage
+        .inner
+        .force_freeze_memtable(&storage.inner.state_lock.lock())
+        .unwrap();
+    storage.delete(b"key_0").unwrap();
+    storage.sync().unwrap();
+}
+
+fn check(storage: &MiniLsm) {
+    assert_eq!(storage.get(b"key_0").unwrap(), None);
+    let mut iter = storage.scan(Bound::Unbounded, Bound::Unbounded).unwrap();
+    for i in 1..10 {
+        assert!(iter.is_valid());
+        assert_eq!(iter.key(), format!("key_{}", i).as_bytes());
+        assert_eq!(iter.value(), if i % 2 == 1 { b"v2" } else { b"v1" });
+        iter.next().unwrap();
+    }
+    assert!(!iter.is_valid());
+}
+
+#[test]
+fn test_read_only_leaves_directory_untouched() {
+    let dir = tempdir().unwrap();
+    populate(dir.path());
+    let content = dir_content(dir.path());
+
+    let storage = MiniLsm::open_read_only(&dir, wal_options()).unwrap();
+    check(&storage);
+    assert!(storage.inner.manifest.is_none());
+    assert!(storage.put(b"key_0", b"v3").is_err());
+    assert!(storage.delete(b"key_1").is_err());
+    assert!(storage
+        .write_batch(&[WriteBatchRecord::Put(b"key_0".as_slice(), b"v3".as_slice())])
+        .is_err());
+    let txn = storage.new_txn().unwrap();
+    txn.put(b"key_0", b"v3");
+    assert!(txn.commit().is_err());
+    drop(txn);
+    assert!(storage.force_flush().is_err());
+    assert!(storage.force_full_compaction().is_err());
+    assert!(storage.purge_obsolete_files().is_err());
+    assert!(storage
+        .change_compaction_strategy(CompactionOptions::NoCompaction)
+        .is_err());
+    check(&storage);
+    storage.close().unwrap();
+    drop(storage);
+    assert_eq!(dir_content(dir.path()), content);
+
+    // the storage is still writable afterwards
+    let storage = MiniLsm::open(&dir, wal_options()).unwrap();
+    check(&storage);
+    storage.put(b"key_0", b"v3").unwrap();
+}
+
+#[test]
+fn test_read_only_torn_wal_tail() {
+    let dir = tempdir().unwrap();
+    populate(dir.path());
+    // a record torn by a crash at the end of the latest WAL
+    let wal = dir_content(dir.path())
+        .into_keys()
+        .rfind(|name| name.ends_with(".wal"))
+        .unwrap();
+    let mut file = std::fs::OpenOptions::new()
+        .append(true)
+        .open(dir.path().join(&wal))
+        .unwrap();
+    std::io::Write::write_all(&mut file, &[0, 0, 1]).unwrap();
+    drop(file);
+    let content = dir_content(dir.path());
+
+    let storage = MiniLsm::open_read_only(&dir, wal_options()).unwrap();
+    check(&storage);
+    assert_eq!(
+        storage.wal_recovery_report(),
+        &WalRecoveryReport {
+            dropped_records: 1,
+            dropped_bytes: 3,
+            stopped_at: None,
+        }
+    );
+    drop(storage);
+    // the torn record is not truncated
+    assert_eq!(dir_content(dir.path()), content);
+}
+
+#[test]
+fn test_read_only_shared_lock() {
+    let dir = tempdir().unwrap();
+    populate(dir.path());
+    let storage1 = MiniLsm::open_read_only(&dir, wal_options()).unwrap();
+    let storage2 = MiniLsm::open_read_only(&dir, wal_options()).unwrap();
+    check(&storage1);
+    check(&storage2);
+    let err = MiniLsm::open(&dir, wal_options()).err().unwrap();
+    assert!(err.to_string().contains("already in use"), "{}", err);
+    drop(storage1);
+    drop(storage2);
+
+    let storage = MiniLsm::open(&dir, wal_options()).unwrap();
+    let err = MiniLsm::open_read_only(&dir, wal_options()).err().unwrap();
+    assert!(err.to_string().contains("already in use"), "{}", err);
+    drop(storage);
+}
+
+#[test]
+fn test_read_only_missing_storage() {
+    let dir = tempdir().unwrap();
+    let path = dir.path().join("missing");
+    assert!(MiniLsm::open_read_only(&path, wal_options()).is_err());
+    assert!(!path.exists());
+}
+
+#[test]
+fn test_read_only_directory() {
+    use std::os::unix::fs::PermissionsExt;
+
+    let dir = tempdir().unwrap();
+    populate(dir.path());
+    let set_mode = |file_mode, dir_mode| {
+        for name in dir_content(dir.path()).into_keys() {
+            let permissions = std::fs::Permissions::from_mode(file_mode);
+            std::fs::set_permissions(dir.path().join(name), permissions).unwrap();
+        }
+        std::fs::set_permissions(dir.path(), std::fs::Permissions::from_mode(dir_mode)).unwrap();
+    };
+    set_mode(0o444, 0o555);
+    let storage = MiniLsm::open_read_only(&dir, wal_options()).unwrap();
+    check(&storage);
+    drop(storage);
+
+    // without a LOCK file, the storage is read without locking it
+    set_mode(0o644, 0o755);
+    std::fs::remove_file(dir.path().join("LOCK")).unwrap();
+    let content = dir_content(dir.path());
+    set_mode(0o444, 0o555);
+    let storage = MiniLsm::open_read_only(&dir, wal_options()).unwrap();
+    check(&storage);
+    drop(storage);
+    set_mode(0o644, 0o755);
+    assert_eq!(dir_content(dir.path()), content);
+}
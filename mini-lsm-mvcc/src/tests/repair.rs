@@ -0,0 +1,168 @@
+use std::path::Path;
+
+use bytes::Bytes;
+use tempfile::tempdir;
+
+use crate::{
+    compact::{CompactionOptions, TieredCompactionOptions},
+    lsm_storage::{LsmStorageOptions, MiniLsm},
+    repair::repair_db,
+};
+
+use super::harness::{scan_all, wal_options, wal_options_with};
+
+fn tiered() -> CompactionOptions {
+    CompactionOptions::Tiered(TieredCompactionOptions {
+        num_tiers: 100,
+        max_size_amplification_percent: 10000,
+        size_ratio: 100,
+        min_merge_width: 100,
+    })
+}
+
+/// Write a few SSTs and leave some writes in the WALs, then corrupt the manifest. Returns what the
+/// storage held.
+fn populate_and_corrupt_manifest(dir: &Path, options: &LsmStorageOptions) -> Vec<(Bytes, Bytes)> {
+    let storage = MiniLsm::open(dir, options.clone()).unwrap();
+    for round in 0..3 {
+        for i in round * 10..round * 10 + 20 {
+            storage
+                .put(
+                    format!("key_{:03}", i).as_bytes(),
+                    format!("v{}", round).as_bytes(),
+                )
+                .unwrap();
+        }
+        storage
+            .delete(format!("key_{:03}", round).as_bytes())
+            .unwrap();
+        storage.force_flush().unwrap();
+    }
+    storage.put(b"key_100", b"in the wal").unwrap();
+    storage.delete(b"key_010").unwrap();
+    storage.sync().unwrap();
+    let expected = scan_all(&storage);
+    drop(storage);
+
+    let current = std::fs::read_to_string(dir.join("CURRENT")).unwrap();
+    let manifest = dir.join(current.trim_end());
+    let mut data = std::fs::read(&manifest).unwrap();
+    let len = data.len();
+    data[len - 10] ^= 0xff;
+    std::fs::write(&manifest, data).unwrap();
+    assert!(MiniLsm::open(dir, options.clone()).is_err());
+    expected
+}
+
+#[test]
+fn test_repair_rebuilds_manifest() {
+    let dir = tempdir().unwrap();
+    let options = wal_options();
+    let expected = populate_and_corrupt_manifest(dir.path(), &options);
+    let old_manifest = std::fs::read_to_string(dir.path().join("CURRENT")).unwrap();
+
+    let report = repair_db(&dir, &options).unwrap();
+    assert_eq!(report.ssts.len(), 4);
+    assert_eq!(report.salvaged_wals.len(), 1);
+    assert_eq!(report.salvaged_records, 2);
+    // the old manifest and the salvaged WAL are moved once `CURRENT` points to the new manifest
+    let lost = dir.path().join("lost");
+    assert!(lost.join(old_manifest.trim_end()).exists());
+    assert!(lost
+        .join(format!("{:05}.wal", report.salvaged_wals[0]))
+        .exists());
+    let new_manifest = std::fs::read_to_string(dir.path().join("CURRENT")).unwrap();
+    assert!(new_manifest > old_manifest);
+
+    let storage = MiniLsm::open(&dir, options.clone()).unwrap();
+    assert_eq!(scan_all(&storage), expected);
+    {
+        let state = storage.inner.state.read();
+        assert_eq!(state.l0_sstables, report.ssts);
+        // newest first
+        let max_ts = state
+            .l0_sstables
+            .iter()
+            .map(|id| state.sstables[id].max_ts())
+            .collect::<Vec<_>>();
+        assert!(max_ts.windows(2).all(|w| w[0] >= w[1]));
+    }
+    // the repaired storage keeps working
+    storage.put(b"key_101", b"v").unwrap();
+    storage.force_flush().unwrap();
+    storage.close().unwrap();
+    drop(storage);
+    let storage = MiniLsm::open(&dir, options).unwrap();
+    assert_eq!(
+        storage.get(b"key_101").unwrap(),
+        Some(Bytes::from_static(b"v"))
+    );
+    assert_eq!(
+        storage.get(b"key_100").unwrap(),
+        Some(Bytes::from_static(b"in the wal"))
+    );
+}
+
+#[test]
+fn test_repair_drops_corrupted_sst() {
+    let dir = tempdir().unwrap();
+    let options = wal_options_with(tiered());
+    populate_and_corrupt_manifest(dir.path(), &options);
+    let mut ssts = std::fs::read_dir(dir.path())
+        .unwrap()
+        .map(|entry| entry.unwrap().path())
+        .filter(|path| path.extension().is_some_and(|ext| ext == "sst"))
+        .collect::<Vec<_>>();
+    ssts.sort();
+    let mut data = std::fs::read(&ssts[0]).unwrap();
+    data[10] ^= 0xff;
+    std::fs::write(&ssts[0], data).unwrap();
+    std::fs::write(dir.path().join("00999.sst"), b"garbage").unwrap();
+
+    let report = repair_db(&dir, &options).unwrap();
+    assert_eq!(report.ssts.len(), 3);
+    let lost = dir.path().join("lost");
+    assert!(lost.join(ssts[0].file_name().unwrap()).exists());
+    assert!(lost.join("00999.sst").exists());
+
+    let storage = MiniLsm::open(&dir, options).unwrap();
+    {
+        // a tier for each SST
+        let state = storage.inner.state.read();
+        assert!(state.l0_sstables.is_empty());
+        assert_eq!(
+            state.levels,
+            report
+                .ssts
+                .iter()
+                .map(|id| (*id, vec![*id]))
+                .collect::<Vec<_>>()
+        );
+    }
+    // only the keys of the first round were in the corrupted SST
+    assert_eq!(storage.get(b"key_005").unwrap(), None);
+    assert_eq!(
+        storage.get(b"key_015").unwrap(),
+        Some(Bytes::from_static(b"v1"))
+    );
+    assert_eq!(
+        storage.get(b"key_100").unwrap(),
+        Some(Bytes::from_static(b"in the wal"))
+    );
+}
+
+#[test]
+fn test_repair_without_options_file() {
+    let dir = tempdir().unwrap();
+    let expected = populate_and_corrupt_manifest(dir.path(), &wal_options_with(tiered()));
+    std::fs::remove_file(dir.path().join("OPTIONS")).unwrap();
+    // the storage is repaired for the given compaction strategy
+    let report = repair_db(&dir, &wal_options_with(tiered())).unwrap();
+    let storage = MiniLsm::open(&dir, wal_options_with(tiered())).unwrap();
+    assert_eq!(scan_all(&storage), expected);
+    {
+        let state = storage.inner.state.read();
+        assert!(state.l0_sstables.is_empty());
+        assert_eq!(state.levels.len(), report.ssts.len());
+    }
+}
@@ -0,0 +1,153 @@
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use bytes::Bytes;
+use tempfile::tempdir;
+
+use crate::{
+    cdc::ChangeBatch,
+    lsm_storage::{LsmStorageOptions, MiniLsm, WriteBatchRecord},
+    replication::{ChannelTransport, ReplicationTransport},
+};
+
+use super::harness::{scan_all, wal_options};
+
+fn options() -> LsmStorageOptions {
+    LsmStorageOptions {
+        archive_wal: true,
+        ..wal_options()
+    }
+}
+
+fn wait_for_ts(follower: &MiniLsm, ts: u64) {
+    let start = Instant::now();
+    while follower.latest_commit_ts() < ts {
+        assert!(start.elapsed() < Duration::from_secs(10), "follower lags");
+        std::thread::sleep(Duration::from_millis(10));
+    }
+}
+
+#[test]
+fn test_follower_catch_up_and_tail() {
+    let leader_dir = tempdir().unwrap();
+    let follower_dir = tempdir().unwrap();
+    let checkpoint = follower_dir.path().join("follower");
+    let leader = MiniLsm::open(&leader_dir, options()).unwrap();
+    for i in 0..100 {
+        leader
+            .put(format!("key_{:03}", i).as_bytes(), b"v1")
+            .unwrap();
+    }
+    leader.force_flush().unwrap();
+    for i in 50..150 {
+        leader
+            .put(format!("key_{:03}", i).as_bytes(), b"v2")
+            .unwrap();
+    }
+    let checkpoint_ts = leader.create_checkpoint(&checkpoint).unwrap();
+    assert_eq!(checkpoint_ts, 200);
+    // these batches are committed before shipping starts, and are served from the WALs
+    for i in 0..10 {
+        leader.delete(format!("key_{:03}", i).as_bytes()).unwrap();
+    }
+
+    let transport = Arc::new(ChannelTransport::new());
+    let shipper = leader
+        .start_wal_shipping(checkpoint_ts + 1, transport.clone())
+        .unwrap();
+    let follower = MiniLsm::open_follower(&checkpoint, options(), transport).unwrap();
+    assert!(follower.put(b"key_000", b"v3").is_err());
+    assert!(follower.delete(b"key_000").is_err());
+    let txn = follower.new_txn().unwrap();
+    txn.put(b"key_000", b"v3");
+    assert!(txn.commit().is_err());
+    // the transaction keeps the follower open, see the restart below
+    drop(txn);
+
+    leader
+        .write_batch(&[
+            WriteBatchRecord::Put(b"key_200".as_slice(), b"v3".as_slice()),
+            WriteBatchRecord::Del(b"key_100".as_slice()),
+        ])
+        .unwrap();
+    wait_for_ts(&follower, leader.latest_commit_ts());
+    assert_eq!(follower.get(b"key_005").unwrap(), None);
+    assert_eq!(
+        follower.get(b"key_020").unwrap(),
+        Some(Bytes::from_static(b"v1"))
+    );
+    assert_eq!(
+        follower.get(b"key_120").unwrap(),
+        Some(Bytes::from_static(b"v2"))
+    );
+    assert_eq!(follower.get(b"key_100").unwrap(), None);
+    assert_eq!(scan_all(&follower), scan_all(&leader));
+
+    // the follower persists what it applied, and resumes tailing after a restart
+    follower.close().unwrap();
+    drop(follower);
+    shipper.stop().unwrap();
+    let transport = Arc::new(ChannelTransport::new());
+    let follower = MiniLsm::open_follower(&checkpoint, options(), transport.clone()).unwrap();
+    let applied_ts = follower.latest_commit_ts();
+    assert_eq!(applied_ts, leader.latest_commit_ts());
+    let shipper = leader
+        .start_wal_shipping(applied_ts + 1, transport)
+        .unwrap();
+    leader.put(b"key_300", b"v4").unwrap();
+    wait_for_ts(&follower, leader.latest_commit_ts());
+    assert_eq!(
+        follower.get(b"key_300").unwrap(),
+        Some(Bytes::from_static(b"v4"))
+    );
+    assert_eq!(scan_all(&follower), scan_all(&leader));
+    follower.check_replication().unwrap();
+    shipper.stop().unwrap();
+}
+
+#[test]
+fn test_follower_rejects_gap() {
+    let leader_dir = tempdir().unwrap();
+    let follower_dir = tempdir().unwrap();
+    let checkpoint = follower_dir.path().join("follower");
+    let leader = MiniLsm::open(&leader_dir, options()).unwrap();
+    leader.put(b"a", b"1").unwrap();
+    let checkpoint_ts = leader.create_checkpoint(&checkpoint).unwrap();
+    let transport = Arc::new(ChannelTransport::new());
+    let follower = MiniLsm::open_follower(&checkpoint, options(), transport.clone()).unwrap();
+    let batch = |ts: u64| ChangeBatch {
+        commit_ts: ts,
+        records: vec![WriteBatchRecord::Put(
+            Bytes::from(format!("key_{ts}")),
+            Bytes::from_static(b"v"),
+        )],
+    };
+    transport.send(batch(checkpoint_ts + 1)).unwrap();
+    wait_for_ts(&follower, checkpoint_ts + 1);
+    follower.check_replication().unwrap();
+    // the batch at `checkpoint_ts + 2` never arrives
+    transport.send(batch(checkpoint_ts + 3)).unwrap();
+    let start = Instant::now();
+    while follower.check_replication().is_ok() {
+        assert!(
+            start.elapsed() < Duration::from_secs(10),
+            "gap not detected"
+        );
+        std::thread::sleep(Duration::from_millis(10));
+    }
+    assert_eq!(follower.latest_commit_ts(), checkpoint_ts + 1);
+    assert_eq!(
+        follower
+            .get(format!("key_{}", checkpoint_ts + 3).as_bytes())
+            .unwrap(),
+        None
+    );
+}
+
+#[test]
+fn test_follower_requires_wal() {
+    let dir = tempdir().unwrap();
+    let mut options = options();
+    options.enable_wal = false;
+    assert!(MiniLsm::open_follower(&dir, options, Arc::new(ChannelTransport::new())).is_err());
+}
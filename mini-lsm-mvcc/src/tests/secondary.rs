@@ -0,0 +1,154 @@
+use std::path::Path;
+
+use bytes::Bytes;
+use tempfile::tempdir;
+
+use crate::lsm_storage::MiniLsm;
+
+use super::harness::{scan_all, wal_options};
+
+fn put_range(storage: &MiniLsm, keys: std::ops::Range<usize>, value: &str) {
+    for i in keys {
+        storage
+            .put(format!("key_{:03}", i).as_bytes(), value.as_bytes())
+            .unwrap();
+    }
+}
+
+fn sst_files(dir: &Path) -> Vec<String> {
+    std::fs::read_dir(dir)
+        .unwrap()
+        .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+        .filter(|name| name.ends_with(".sst"))
+        .collect()
+}
+
+#[test]
+fn test_secondary_catch_up_with_writes() {
+    let dir = tempdir().unwrap();
+    let primary = MiniLsm::open(&dir, wal_options()).unwrap();
+    put_range(&primary, 0..50, "v1");
+    primary.force_flush().unwrap();
+    put_range(&primary, 25..75, "v2");
+    // the secondary reads what the primary has written to its WALs
+    primary.sync().unwrap();
+
+    // the primary keeps the directory open
+    let secondary = MiniLsm::open_as_secondary(&dir, wal_options()).unwrap();
+    assert_eq!(scan_all(&secondary), scan_all(&primary));
+    assert_eq!(secondary.latest_commit_ts(), primary.latest_commit_ts());
+    assert!(secondary.put(b"key_000", b"v3").is_err());
+    assert!(secondary.force_flush().is_err());
+    let txn = secondary.new_txn().unwrap();
+    txn.put(b"key_000", b"v3");
+    assert!(txn.commit().is_err());
+    drop(txn);
+
+    // writes to the current memtable, a new memtable and a new SST are seen after catching up
+    let before = scan_all(&secondary);
+    primary.delete(b"key_000").unwrap();
+    put_range(&primary, 50..100, "v3");
+    primary.force_flush().unwrap();
+    put_range(&primary, 100..120, "v4");
+    primary.sync().unwrap();
+    assert_eq!(scan_all(&secondary), before);
+    secondary.try_catch_up_with_primary().unwrap();
+    assert_eq!(scan_all(&secondary), scan_all(&primary));
+    assert_eq!(secondary.get(b"key_000").unwrap(), None);
+    assert_eq!(
+        secondary.get(b"key_110").unwrap(),
+        Some(Bytes::from_static(b"v4"))
+    );
+    // catching up again without new writes changes nothing
+    secondary.try_catch_up_with_primary().unwrap();
+    assert_eq!(scan_all(&secondary), scan_all(&primary));
+
+    // the primary is not affected by the secondary
+    drop(secondary);
+    primary.close().unwrap();
+    drop(primary);
+    let primary = MiniLsm::open(&dir, wal_options()).unwrap();
+    assert_eq!(
+        primary.get(b"key_110").unwrap(),
+        Some(Bytes::from_static(b"v4"))
+    );
+}
+
+#[test]
+fn test_secondary_tolerates_removed_ssts() {
+    let dir = tempdir().unwrap();
+    let primary = MiniLsm::open(&dir, wal_options()).unwrap();
+    for round in 0..4 {
+        put_range(
+            &primary,
+            round * 10..round * 10 + 20,
+            &format!("v{}", round),
+        );
+        primary.force_flush().unwrap();
+    }
+    let secondary = MiniLsm::open_as_secondary(&dir, wal_options()).unwrap();
+    let expected = scan_all(&primary);
+    assert_eq!(scan_all(&secondary), expected);
+    let old_ssts = sst_files(dir.path());
+
+    // the compaction removes the SSTs the secondary reads from
+    primary.force_full_compaction().unwrap();
+    put_range(&primary, 0..5, "v5");
+    primary.sync().unwrap();
+    assert!(old_ssts.iter().all(|sst| !dir.path().join(sst).exists()));
+    assert_eq!(scan_all(&secondary), expected);
+    assert_eq!(
+        secondary.get(b"key_015").unwrap(),
+        Some(Bytes::from_static(b"v1"))
+    );
+
+    secondary.try_catch_up_with_primary().unwrap();
+    assert_eq!(scan_all(&secondary), scan_all(&primary));
+    {
+        let state = secondary.inner.state.read();
+        assert!(state.l0_sstables.is_empty());
+        assert_eq!(state.sstables.len(), sst_files(dir.path()).len());
+    }
+
+    // the primary replays the full compaction when reopened
+    let expected = scan_all(&primary);
+    drop(secondary);
+    primary.close().unwrap();
+    drop(primary);
+    let primary = MiniLsm::open(&dir, wal_options()).unwrap();
+    assert_eq!(scan_all(&primary), expected);
+}
+
+#[test]
+fn test_secondary_follows_manifest_rotation() {
+    let dir = tempdir().unwrap();
+    let mut options = wal_options();
+    options.max_manifest_file_size = 0;
+    let primary = MiniLsm::open(&dir, options.clone()).unwrap();
+    put_range(&primary, 0..10, "v1");
+    primary.sync().unwrap();
+    let secondary = MiniLsm::open_as_secondary(&dir, options).unwrap();
+    for round in 0..3 {
+        // every record starts a new manifest file
+        put_range(
+            &primary,
+            round * 10..round * 10 + 20,
+            &format!("v{}", round + 2),
+        );
+        primary.force_flush().unwrap();
+        primary.sync().unwrap();
+        secondary.try_catch_up_with_primary().unwrap();
+        assert_eq!(scan_all(&secondary), scan_all(&primary));
+    }
+}
+
+#[test]
+fn test_catch_up_requires_secondary() {
+    let dir = tempdir().unwrap();
+    let storage = MiniLsm::open(&dir, wal_options()).unwrap();
+    assert!(storage.try_catch_up_with_primary().is_err());
+    drop(storage);
+    let storage = MiniLsm::open_read_only(&dir, wal_options()).unwrap();
+    assert!(storage.try_catch_up_with_primary().is_err());
+    assert!(MiniLsm::open_as_secondary(dir.path().join("missing"), wal_options()).is_err());
+}
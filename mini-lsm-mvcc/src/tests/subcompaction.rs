@@ -0,0 +1,90 @@
+use tempfile::tempdir;
+
+use crate::{
+    compact::{CompactionOptions, TieredCompactionOptions},
+    lsm_storage::{LsmStorageOptions, MiniLsm},
+};
+
+use super::harness::{check_compaction_ratio, compaction_bench};
+
+#[test]
+fn test_subcompaction_split_at_sst_boundaries() {
+    let dir = tempdir().unwrap();
+    let mut options = LsmStorageOptions::default_for_week2_test(CompactionOptions::NoCompaction);
+    options.target_sst_size = 4096;
+    options.max_subcompactions = 4;
+    let storage = MiniLsm::open(&dir, options).unwrap();
+    // four L0 SSTs with disjoint key ranges, and one deleting some keys of each range
+    for i in 0..4 {
+        for j in 0..150 {
+            storage
+                .put(
+                    format!("key_{}_{:03}", i, j).as_bytes(),
+                    format!("value_{}_{}", i, j).as_bytes(),
+                )
+                .unwrap();
+        }
+        storage.force_flush().unwrap();
+    }
+    for i in 0..4 {
+        storage
+            .delete(format!("key_{}_{:03}", i, 0).as_bytes())
+            .unwrap();
+    }
+    storage.force_flush().unwrap();
+    assert_eq!(storage.inner.state.read().l0_sstables.len(), 5);
+    storage.force_full_compaction().unwrap();
+
+    {
+        let state = storage.inner.state.read();
+        assert!(state.l0_sstables.is_empty());
+        let first_keys = state.levels[0]
+            .1
+            .iter()
+            .map(|id| state.sstables[id].first_key().key_ref().to_vec())
+            .collect::<Vec<_>>();
+        // each range is compacted on its own, so that an output SST starts at each boundary, right
+        // after the deleted key
+        let expected = (0..4)
+            .map(|i| format!("key_{}_{:03}", i, 1).into_bytes())
+            .collect::<Vec<_>>();
+        assert_eq!(first_keys, expected);
+    }
+    assert!(storage.verify(true).unwrap().is_ok());
+    for i in 0..4 {
+        assert_eq!(
+            storage
+                .get(format!("key_{}_{:03}", i, 0).as_bytes())
+                .unwrap(),
+            None
+        );
+        for j in 1..150 {
+            assert_eq!(
+                &storage
+                    .get(format!("key_{}_{:03}", i, j).as_bytes())
+                    .unwrap()
+                    .unwrap()[..],
+                format!("value_{}_{}", i, j).as_bytes()
+            );
+        }
+    }
+}
+
+#[test]
+fn test_subcompaction_integration() {
+    let dir = tempdir().unwrap();
+    let mut options = LsmStorageOptions::default_for_week2_test(CompactionOptions::Tiered(
+        TieredCompactionOptions {
+            num_tiers: 3,
+            max_size_amplification_percent: 200,
+            size_ratio: 1,
+            min_merge_width: 2,
+        },
+    ));
+    options.max_subcompactions = 4;
+    let storage = MiniLsm::open(&dir, options).unwrap();
+
+    compaction_bench(storage.clone());
+    check_compaction_ratio(storage.clone());
+    assert!(storage.verify(false).unwrap().is_ok());
+}
@@ -0,0 +1,227 @@
+use std::sync::Arc;
+
+use tempfile::tempdir;
+
+use crate::{
+    compact::{
+        CompactionOptions, TimeWindowCompactionController, TimeWindowCompactionOptions,
+        TimeWindowCompactionTask,
+    },
+    key::KeyBytes,
+    lsm_storage::{LsmStorageInner, LsmStorageOptions, LsmStorageState},
+    table::{SsTable, SsTableStats},
+};
+
+use super::harness::{mock_state, sync};
+
+const WINDOW: u64 = 3600;
+
+fn controller(ttl_seconds: u64) -> TimeWindowCompactionController {
+    TimeWindowCompactionController::new(TimeWindowCompactionOptions {
+        window_seconds: WINDOW,
+        min_merge_width: 3,
+        size_ratio: 50,
+        ttl_seconds,
+    })
+}
+
+/// Build a state with a tier for each of `tiers`, from the latest, given as the size and the flush
+/// time of its only SST.
+fn tiers_state(tiers: &[(u64, u64)]) -> LsmStorageState {
+    let ids = (1..=tiers.len()).rev();
+    let ssts = ids
+        .clone()
+        .zip(tiers)
+        .map(|(id, (size, data_written_at))| {
+            let key = KeyBytes::for_testing_from_bytes_no_ts(format!("key_{:03}", id).into());
+            let sst = SsTable::create_meta_only(id, *size, key.clone(), key);
+            Arc::new(sst.with_stats(SsTableStats {
+                data_written_at: *data_written_at,
+                ..Default::default()
+            }))
+        })
+        .collect();
+    mock_state(Vec::new(), ids.map(|id| (id, vec![id])).collect(), ssts)
+}
+
+fn tier_ids(task: &TimeWindowCompactionTask) -> Vec<usize> {
+    task.tiers.iter().map(|(id, _)| *id).collect()
+}
+
+#[test]
+fn test_time_window_size_tiered_within_window() {
+    let now = 10 * WINDOW + 100;
+    let controller = controller(0);
+    // only two tiers of similar size in the current window
+    let state = tiers_state(&[(100, now), (120, now), (1000, now), (100, 9 * WINDOW)]);
+    assert!(controller
+        .generate_compaction_task_at(&state, now)
+        .is_none());
+
+    // similar tiers are merged only if they are adjacent
+    let state = tiers_state(&[
+        (100, now),
+        (120, now),
+        (1000, now),
+        (140, now),
+        (100, 9 * WINDOW),
+    ]);
+    assert!(controller
+        .generate_compaction_task_at(&state, now)
+        .is_none());
+
+    // the three small tiers of the current window are merged, without the big one
+    let state = tiers_state(&[
+        (100, now),
+        (120, now),
+        (140, now),
+        (1000, now),
+        (100, 9 * WINDOW),
+    ]);
+    let task = controller.generate_compaction_task_at(&state, now).unwrap();
+    assert_eq!(task.window, 10);
+    assert_eq!(tier_ids(&task), vec![5, 4, 3]);
+    assert!(!task.bottom_tier_included);
+    assert!(!task.expired);
+
+    // the output takes the place of the earliest tier
+    let (state, files_to_remove) = controller.apply_compaction_result(&state, &task, &[6, 7]);
+    assert_eq!(files_to_remove, vec![5, 4, 3]);
+    assert_eq!(
+        state.levels,
+        vec![(6, vec![6, 7]), (2, vec![2]), (1, vec![1])]
+    );
+}
+
+#[test]
+fn test_time_window_merge_past_window() {
+    let now = 10 * WINDOW + 100;
+    let controller = controller(0);
+    // the tiers of different sizes in window 8 are merged into one, but never with window 9
+    let state = tiers_state(&[
+        (100, now),
+        (100, 9 * WINDOW + 10),
+        (100, 8 * WINDOW + 20),
+        (1000, 8 * WINDOW + 10),
+        (100, 7 * WINDOW),
+    ]);
+    let task = controller.generate_compaction_task_at(&state, now).unwrap();
+    assert_eq!(task.window, 8);
+    assert_eq!(tier_ids(&task), vec![3, 2]);
+    assert!(!task.bottom_tier_included);
+
+    let state = tiers_state(&[(100, now), (100, 9 * WINDOW + 10), (100, 9 * WINDOW)]);
+    let task = controller.generate_compaction_task_at(&state, now).unwrap();
+    assert_eq!(task.window, 9);
+    assert_eq!(tier_ids(&task), vec![2, 1]);
+    assert!(task.bottom_tier_included);
+}
+
+#[test]
+fn test_time_window_merge_keeps_tombstones_over_skipped_tier() {
+    let now = 10 * WINDOW + 100;
+    let controller = controller(0);
+    // the newest tier and the bottom one are in window 9, around a tier from window 8: a tombstone
+    // of the newest tier must be kept, as it may hide a version in the skipped tier
+    let state = tiers_state(&[(100, 9 * WINDOW + 10), (100, 8 * WINDOW), (100, 9 * WINDOW)]);
+    let task = controller.generate_compaction_task_at(&state, now).unwrap();
+    assert_eq!(task.window, 9);
+    assert_eq!(tier_ids(&task), vec![3, 1]);
+    assert!(!task.bottom_tier_included);
+
+    // the merged tier takes the place of the bottom one
+    let (state, _) = controller.apply_compaction_result(&state, &task, &[4]);
+    assert_eq!(state.levels, vec![(2, vec![2]), (4, vec![4])]);
+}
+
+#[test]
+fn test_time_window_ttl() {
+    let now = 10 * WINDOW + 100;
+    let controller = controller(2 * WINDOW);
+    let state = tiers_state(&[
+        (100, now),
+        (100, 8 * WINDOW + 200),
+        (100, 8 * WINDOW + 100),
+        (100, 7 * WINDOW),
+        (100, 0),
+    ]);
+    // the data of an unknown age is kept
+    let task = controller.generate_compaction_task_at(&state, now).unwrap();
+    assert_eq!(task.window, 7);
+    assert_eq!(tier_ids(&task), vec![2]);
+    assert!(task.expired);
+    let (state, files_to_remove) = controller.apply_compaction_result(&state, &task, &[]);
+    assert_eq!(files_to_remove, vec![2]);
+
+    // window 8 is dropped whole once its newest data expires
+    let task = controller.generate_compaction_task_at(&state, now).unwrap();
+    assert!(!task.expired);
+    assert_eq!(tier_ids(&task), vec![4, 3]);
+    let task = controller
+        .generate_compaction_task_at(&state, 10 * WINDOW + 200)
+        .unwrap();
+    assert!(task.expired);
+    assert_eq!(task.window, 8);
+    assert_eq!(tier_ids(&task), vec![4, 3]);
+}
+
+#[test]
+fn test_time_window_compaction_integration() {
+    let dir = tempdir().unwrap();
+    let options = LsmStorageOptions::default_for_week2_test(CompactionOptions::TimeWindow(
+        TimeWindowCompactionOptions {
+            window_seconds: WINDOW,
+            min_merge_width: 2,
+            size_ratio: 50,
+            ttl_seconds: 0,
+        },
+    ));
+    let storage = Arc::new(LsmStorageInner::open(&dir, options.clone()).unwrap());
+    for range in [0..100, 100..200] {
+        for i in range {
+            storage
+                .put(format!("key_{:05}", i).as_bytes(), b"value")
+                .unwrap();
+        }
+        sync(&storage);
+    }
+    let (flushed_at, tiers) = {
+        let state = storage.state.read();
+        assert!(state.l0_sstables.is_empty());
+        assert_eq!(state.levels.len(), 2);
+        let flushed_at = state
+            .sstables
+            .values()
+            .map(|sst| sst.stats().data_written_at)
+            .max()
+            .unwrap();
+        (flushed_at, state.levels.clone())
+    };
+
+    // the merged tier keeps the time its data was flushed, unless the window has just changed
+    storage.trigger_compaction().unwrap();
+    let levels = {
+        let state = storage.state.read();
+        if state.levels == tiers {
+            return;
+        }
+        assert_eq!(state.levels.len(), 1);
+        for id in &state.levels[0].1 {
+            assert_eq!(state.sstables[id].stats().data_written_at, flushed_at);
+        }
+        state.levels.clone()
+    };
+    drop(storage);
+
+    let storage = Arc::new(LsmStorageInner::open(&dir, options).unwrap());
+    assert_eq!(storage.state.read().levels, levels);
+    for i in 0..200 {
+        assert_eq!(
+            &storage
+                .get(format!("key_{:05}", i).as_bytes())
+                .unwrap()
+                .unwrap()[..],
+            b"value"
+        );
+    }
+}
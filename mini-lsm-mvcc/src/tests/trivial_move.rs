@@ -0,0 +1,78 @@
+use std::path::Path;
+use std::sync::Arc;
+
+use tempfile::tempdir;
+
+use crate::lsm_storage::{LsmStorageInner, LsmStorageOptions};
+
+use super::harness::{check_keys, flush_ranges, simple_options};
+
+fn num_ssts(dir: &Path) -> usize {
+    std::fs::read_dir(dir)
+        .unwrap()
+        .filter(|entry| {
+            entry
+                .as_ref()
+                .unwrap()
+                .file_name()
+                .to_str()
+                .unwrap()
+                .ends_with(".sst")
+        })
+        .count()
+}
+
+#[test]
+fn test_trivial_move_sequential_inserts() {
+    let dir = tempdir().unwrap();
+    let storage = Arc::new(
+        LsmStorageInner::open(
+            &dir,
+            LsmStorageOptions::default_for_week2_test(simple_options()),
+        )
+        .unwrap(),
+    );
+    let ssts = flush_ranges(&storage, &[0..100, 100..200]);
+    storage.trigger_compaction().unwrap();
+    {
+        let state = storage.state.read();
+        assert!(state.l0_sstables.is_empty());
+        // moved in key order, without writing any SST
+        assert_eq!(state.levels[0].1, ssts);
+    }
+    assert_eq!(num_ssts(dir.path()), 2);
+    check_keys(&storage, 0..200, true);
+    drop(storage);
+
+    // the move is replayed from the manifest
+    let storage = Arc::new(
+        LsmStorageInner::open(
+            &dir,
+            LsmStorageOptions::default_for_week2_test(simple_options()),
+        )
+        .unwrap(),
+    );
+    assert_eq!(storage.state.read().levels[0].1, ssts);
+    check_keys(&storage, 0..200, true);
+}
+
+#[test]
+fn test_no_trivial_move_for_overlapping_ssts() {
+    let dir = tempdir().unwrap();
+    let storage = Arc::new(
+        LsmStorageInner::open(
+            &dir,
+            LsmStorageOptions::default_for_week2_test(simple_options()),
+        )
+        .unwrap(),
+    );
+    let ssts = flush_ranges(&storage, &[0..100, 50..150]);
+    storage.trigger_compaction().unwrap();
+    {
+        let state = storage.state.read();
+        assert!(state.l0_sstables.is_empty());
+        assert!(state.levels[0].1.iter().all(|id| !ssts.contains(id)));
+    }
+    assert_eq!(num_ssts(dir.path()), 1);
+    check_keys(&storage, 0..150, true);
+}
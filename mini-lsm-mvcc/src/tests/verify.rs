@@ -0,0 +1,120 @@
+use std::io::Write;
+use std::sync::Arc;
+
+use tempfile::tempdir;
+
+use crate::{lsm_storage::MiniLsm, verify::VerifyLocation};
+
+use super::harness::wal_options;
+
+/// Write a few SSTs with overlapping key ranges, and some keys to the memtable.
+fn populate(storage: &MiniLsm) {
+    for round in 0..3 {
+        for i in 0..100 {
+            storage
+                .put(
+                    format!("key_{:03}", i).as_bytes(),
+                    format!("value_{}_{}", i, round).as_bytes(),
+                )
+                .unwrap();
+        }
+        storage.force_flush().unwrap();
+    }
+    storage.put(b"key_000", b"value").unwrap();
+}
+
+#[test]
+fn test_verify_clean_storage() {
+    let dir = tempdir().unwrap();
+    let storage = MiniLsm::open(&dir, wal_options()).unwrap();
+    populate(&storage);
+    let report = storage.verify(false).unwrap();
+    assert!(report.is_ok(), "{:?}", report.errors);
+    assert_eq!(report.ssts_checked, 3);
+    assert_eq!(report.blocks_checked, 0);
+    assert_eq!(report.wals_checked, 1);
+
+    let report = storage.verify(true).unwrap();
+    assert!(report.is_ok(), "{:?}", report.errors);
+    assert!(report.blocks_checked >= 3);
+    storage.force_full_compaction().unwrap();
+    assert!(storage.verify(true).unwrap().is_ok());
+}
+
+#[test]
+fn test_verify_reports_all_errors() {
+    let dir = tempdir().unwrap();
+    let storage = MiniLsm::open(&dir, wal_options()).unwrap();
+    populate(&storage);
+    let (l0_sstables, memtable_id) = {
+        let state = storage.inner.state.read();
+        (state.l0_sstables.clone(), state.memtable.id())
+    };
+
+    // a corrupted data block, a missing SST, and garbage at the end of a WAL
+    let path = dir.path().join(format!("{:05}.sst", l0_sstables[0]));
+    let mut data = std::fs::read(&path).unwrap();
+    data[0] ^= 0xff;
+    std::fs::write(&path, data).unwrap();
+    std::fs::remove_file(dir.path().join(format!("{:05}.sst", l0_sstables[1]))).unwrap();
+    storage.inner.sync().unwrap();
+    let wal = dir.path().join(format!("{:05}.wal", memtable_id));
+    std::fs::OpenOptions::new()
+        .append(true)
+        .open(&wal)
+        .unwrap()
+        .write_all(&[0, 0, 0, 100, 1, 2])
+        .unwrap();
+
+    // the block checksums are only checked in deep mode
+    let report = storage.verify(false).unwrap();
+    let locations = report
+        .errors
+        .iter()
+        .map(|error| error.location.clone())
+        .collect::<Vec<_>>();
+    assert_eq!(
+        locations,
+        vec![
+            VerifyLocation::Sst {
+                id: l0_sstables[1],
+                block: None
+            },
+            VerifyLocation::Wal(wal.clone()),
+        ]
+    );
+    assert!(report.errors[1].message.contains("offset"));
+
+    let report = storage.verify(true).unwrap();
+    assert_eq!(report.errors.len(), 3, "{:?}", report.errors);
+    assert_eq!(
+        report.errors[0].location,
+        VerifyLocation::Sst {
+            id: l0_sstables[0],
+            block: Some(0)
+        }
+    );
+    assert!(report.errors[0].to_string().contains("checksum"));
+}
+
+#[test]
+fn test_verify_overlapping_level() {
+    let dir = tempdir().unwrap();
+    let storage = MiniLsm::open(&dir, wal_options()).unwrap();
+    populate(&storage);
+    {
+        // move the L0 SSTs, whose key ranges overlap, to a level
+        let _state_lock = storage.inner.state_lock.lock();
+        let mut guard = storage.inner.state.write();
+        let mut snapshot = guard.as_ref().clone();
+        let l0_sstables = std::mem::take(&mut snapshot.l0_sstables);
+        snapshot.levels[0].1 = l0_sstables;
+        *guard = Arc::new(snapshot);
+    }
+    let report = storage.verify(false).unwrap();
+    assert_eq!(report.errors.len(), 2, "{:?}", report.errors);
+    assert!(report
+        .errors
+        .iter()
+        .all(|error| error.location == VerifyLocation::Level(1)));
+}
@@ -0,0 +1,124 @@
+use std::fs::OpenOptions;
+
+use bytes::Bytes;
+use crossbeam_skiplist::SkipMap;
+use tempfile::tempdir;
+
+use crate::key::{KeyBytes, KeySlice};
+use crate::wal::Wal;
+
+fn write_batches(path: &std::path::Path) -> (u64, u64) {
+    let wal = Wal::create(path).unwrap();
+    let first = wal
+        .put_batch(&[
+            (KeySlice::from_slice(b"a", 1), b"1".as_slice()),
+            (KeySlice::from_slice(b"b", 1), b"1".as_slice()),
+        ])
+        .unwrap();
+    let second = wal
+        .put_batch(&[
+            (KeySlice::from_slice(b"a", 2), b"2".as_slice()),
+            (KeySlice::from_slice(b"c", 2), b"".as_slice()),
+        ])
+        .unwrap();
+    wal.sync().unwrap();
+    (first, second)
+}
+
+fn get(map: &SkipMap<KeyBytes, Bytes>, key: &[u8], ts: u64) -> Option<Bytes> {
+    map.get(&KeyBytes::from_bytes_with_ts(
+        Bytes::copy_from_slice(key),
+        ts,
+    ))
+    .map(|e| e.value().clone())
+}
+
+#[test]
+fn test_wal_batch_roundtrip() {
+    let dir = tempdir().unwrap();
+    let path = dir.path().join("00000.wal");
+    write_batches(&path);
+    let map = SkipMap::new();
+    Wal::recover(&path, &map).unwrap();
+    assert_eq!(map.len(), 4);
+    assert_eq!(get(&map, b"a", 1), Some(Bytes::from_static(b"1")));
+    assert_eq!(get(&map, b"b", 1), Some(Bytes::from_static(b"1")));
+    assert_eq!(get(&map, b"a", 2), Some(Bytes::from_static(b"2")));
+    assert_eq!(get(&map, b"c", 2), Some(Bytes::new()));
+}
+
+#[test]
+fn test_wal_torn_batch_is_dropped() {
+    let dir = tempdir().unwrap();
+    let path = dir.path().join("00000.wal");
+    let (first, second) = write_batches(&path);
+    // cut the last batch in the middle, as if the process crashed while appending it
+    for len in [first + 1, first + 6, (first + second) / 2, second - 1] {
+        let torn = dir.path().join(format!("torn-{len}.wal"));
+        std::fs::copy(&path, &torn).unwrap();
+        OpenOptions::new()
+            .write(true)
+            .open(&torn)
+            .unwrap()
+            .set_len(len)
+            .unwrap();
+        let map = SkipMap::new();
+        let wal = Wal::recover(&torn, &map).unwrap();
+        assert_eq!(map.len(), 2, "torn at {len}");
+        assert_eq!(get(&map, b"a", 2), None);
+        assert_eq!(std::fs::metadata(&torn).unwrap().len(), first);
+        // the WAL stays usable after the torn record is truncated
+        wal.put_batch(&[(KeySlice::from_slice(b"d", 3), b"3".as_slice())])
+            .unwrap();
+        wal.sync().unwrap();
+        drop(wal);
+        let map = SkipMap::new();
+        Wal::recover(&torn, &map).unwrap();
+        assert_eq!(map.len(), 3);
+        assert_eq!(get(&map, b"d", 3), Some(Bytes::from_static(b"3")));
+    }
+}
+
+#[test]
+fn test_wal_corrupted_tail_batch_is_dropped() {
+    let dir = tempdir().unwrap();
+    let path = dir.path().join("00000.wal");
+    let (first, _) = write_batches(&path);
+    let mut data = std::fs::read(&path).unwrap();
+    let last = data.len() - 1;
+    data[last] ^= 0xff;
+    std::fs::write(&path, &data).unwrap();
+    let map = SkipMap::new();
+    Wal::recover(&path, &map).unwrap();
+    assert_eq!(map.len(), 2);
+    assert_eq!(std::fs::metadata(&path).unwrap().len(), first);
+}
+
+#[test]
+fn test_wal_corrupted_middle_batch_fails() {
+    let dir = tempdir().unwrap();
+    let path = dir.path().join("00000.wal");
+    write_batches(&path);
+    let mut data = std::fs::read(&path).unwrap();
+    data[10] ^= 0xff;
+    std::fs::write(&path, &data).unwrap();
+    let map = SkipMap::new();
+    assert!(Wal::recover(&path, &map).is_err());
+    assert!(map.is_empty());
+}
+
+#[test]
+fn test_wal_corrupted_middle_length_fails() {
+    let dir = tempdir().unwrap();
+    let path = dir.path().join("00000.wal");
+    write_batches(&path);
+    let mut data = std::fs::read(&path).unwrap();
+    // make the length of the first batch point past the end of the WAL
+    data[1] ^= 0xff;
+    std::fs::write(&path, &data).unwrap();
+    let map = SkipMap::new();
+    assert!(Wal::recover(&path, &map).is_err());
+    assert!(map.is_empty());
+    // the WAL is left untouched
+    assert_eq!(std::fs::read(&path).unwrap(), data);
+}
@@ -0,0 +1,126 @@
+use std::time::Duration;
+
+use tempfile::tempdir;
+
+use crate::{
+    compact::CompactionOptions,
+    lsm_storage::{LsmStorageOptions, MiniLsm, WriteBatchRecord, WriteOptions},
+};
+
+#[test]
+fn test_group_commit_concurrent_sync_writes() {
+    let dir = tempdir().unwrap();
+    let mut options = LsmStorageOptions::default_for_week2_test(CompactionOptions::NoCompaction);
+    options.enable_wal = true;
+    let storage = MiniLsm::open(&dir, options.clone()).unwrap();
+    let sync = WriteOptions {
+        sync: true,
+        ..Default::default()
+    };
+    std::thread::scope(|s| {
+        for t in 0..8 {
+            let storage = storage.clone();
+            let sync = sync.clone();
+            s.spawn(move || {
+                for i in 0..100 {
+                    storage
+                        .put_with_options(
+                            format!("key_{t}_{i}").as_bytes(),
+                            format!("value_{t}_{i}").as_bytes(),
+                            &sync,
+                        )
+                        .unwrap();
+                }
+            });
+        }
+    });
+    storage
+        .write_batch_with_options(
+            &[
+                WriteBatchRecord::Put(b"batch_1".as_slice(), b"1".as_slice()),
+                WriteBatchRecord::Del(b"key_0_0".as_slice()),
+            ],
+            &sync,
+        )
+        .unwrap();
+    let txn = storage.new_txn().unwrap();
+    txn.put(b"txn", b"1");
+    txn.commit_with_options(&sync).unwrap();
+    drop(txn);
+    // simulate a crash: do not close the engine
+    drop(storage);
+
+    let storage = MiniLsm::open(&dir, options).unwrap();
+    for t in 0..8 {
+        for i in 0..100 {
+            let value = storage.get(format!("key_{t}_{i}").as_bytes()).unwrap();
+            if t == 0 && i == 0 {
+                assert_eq!(value, None);
+            } else {
+                assert_eq!(value.unwrap(), format!("value_{t}_{i}").as_bytes());
+            }
+        }
+    }
+    assert_eq!(&storage.get(b"batch_1").unwrap().unwrap()[..], b"1");
+    assert_eq!(&storage.get(b"txn").unwrap().unwrap()[..], b"1");
+}
+
+#[test]
+fn test_sync_writes_published_in_commit_order() {
+    let dir = tempdir().unwrap();
+    let mut options = LsmStorageOptions::default_for_week2_test(CompactionOptions::NoCompaction);
+    options.enable_wal = true;
+    let storage = MiniLsm::open(&dir, options).unwrap();
+    let mut stream = storage.subscribe(0).unwrap();
+    std::thread::scope(|s| {
+        for t in 0..8 {
+            let storage = storage.clone();
+            // half of the writers do not sync, and must not overtake the synced writes before them
+            let options = WriteOptions {
+                sync: t % 2 == 0,
+                ..Default::default()
+            };
+            s.spawn(move || {
+                for i in 0..50 {
+                    let key = format!("key_{t}_{i}");
+                    storage
+                        .put_with_options(key.as_bytes(), b"v", &options)
+                        .unwrap();
+                    // the write is visible once it returns
+                    assert_eq!(&storage.get(key.as_bytes()).unwrap().unwrap()[..], b"v");
+                }
+            });
+        }
+    });
+    for ts in 1..=400 {
+        let batch = stream.next_timeout(Duration::from_secs(10)).unwrap();
+        assert_eq!(batch.commit_ts, ts);
+    }
+    assert_eq!(storage.latest_commit_ts(), 400);
+}
+
+#[test]
+fn test_disable_wal_write() {
+    let dir = tempdir().unwrap();
+    let mut options = LsmStorageOptions::default_for_week2_test(CompactionOptions::NoCompaction);
+    options.enable_wal = true;
+    let storage = MiniLsm::open(&dir, options.clone()).unwrap();
+    storage.put(b"1", b"v1").unwrap();
+    storage
+        .put_with_options(
+            b"2",
+            b"v2",
+            &WriteOptions {
+                disable_wal: true,
+                ..Default::default()
+            },
+        )
+        .unwrap();
+    assert_eq!(&storage.get(b"2").unwrap().unwrap()[..], b"v2");
+    storage.sync().unwrap();
+    drop(storage);
+
+    let storage = MiniLsm::open(&dir, options).unwrap();
+    assert_eq!(&storage.get(b"1").unwrap().unwrap()[..], b"v1");
+    assert_eq!(storage.get(b"2").unwrap(), None);
+}
@@ -0,0 +1,224 @@
+use std::path::{Path, PathBuf};
+
+use tempfile::{tempdir, TempDir};
+
+use crate::{
+    compact::CompactionOptions,
+    lsm_storage::{LsmStorageOptions, MiniLsm},
+    wal::{WalRecoveryMode, WalRecoveryReport},
+};
+
+/// Size of the WAL record of a single put with a 2-byte key and a 1-byte value.
+const RECORD_SIZE: usize = 4 + 4 + 8 + 2 + 2 + 2 + 1 + 4;
+
+fn options(mode: WalRecoveryMode) -> LsmStorageOptions {
+    let mut options = LsmStorageOptions::default_for_week2_test(CompactionOptions::NoCompaction);
+    options.enable_wal = true;
+    options.wal_recovery_mode = mode;
+    options
+}
+
+fn wal_files(dir: &Path) -> Vec<PathBuf> {
+    let mut files = std::fs::read_dir(dir)
+        .unwrap()
+        .map(|entry| entry.unwrap().path())
+        .filter(|path| path.extension().is_some_and(|ext| ext == "wal"))
+        .collect::<Vec<_>>();
+    files.sort();
+    files
+}
+
+/// Offset of a byte in the commit ts of the record of `a2`.
+const A2_BODY: usize = RECORD_SIZE + 15;
+/// Offset of a byte in the length prefix of the record of `a2`.
+const A2_LENGTH: usize = RECORD_SIZE + 1;
+
+/// Write `a1`, `a2`, `a3` to the first WAL and `b1` to the second one, then corrupt the byte at
+/// `corrupted_byte` of the first WAL.
+fn prepare_corrupted_db(corrupted_byte: usize) -> TempDir {
+    let dir = tempdir().unwrap();
+    let storage = MiniLsm::open(&dir, options(WalRecoveryMode::default())).unwrap();
+    for key in [b"a1", b"a2", b"a3"] {
+        storage.put(key, b"v").unwrap();
+    }
+    storage
+        .inner
+        .force_freeze_memtable(&storage.inner.state_lock.lock())
+        .unwrap();
+    storage.put(b"b1", b"v").unwrap();
+    storage.sync().unwrap();
+    drop(storage);
+
+    let wals = wal_files(dir.path());
+    assert_eq!(wals.len(), 2);
+    let mut data = std::fs::read(&wals[0]).unwrap();
+    assert_eq!(data.len(), RECORD_SIZE * 3);
+    data[corrupted_byte] ^= 0xff;
+    std::fs::write(&wals[0], &data).unwrap();
+    dir
+}
+
+fn copy_db(from: &Path) -> TempDir {
+    let dir = tempdir().unwrap();
+    for entry in std::fs::read_dir(from).unwrap() {
+        let entry = entry.unwrap();
+        std::fs::copy(entry.path(), dir.path().join(entry.file_name())).unwrap();
+    }
+    dir
+}
+
+fn check_keys(storage: &MiniLsm, expected: &[(&[u8], bool)]) {
+    for (key, exists) in expected {
+        assert_eq!(
+            storage.get(key).unwrap().is_some(),
+            *exists,
+            "{:?}",
+            String::from_utf8_lossy(key)
+        );
+    }
+}
+
+#[test]
+fn test_wal_recovery_absolute_consistency() {
+    let dir = prepare_corrupted_db(A2_BODY);
+    assert!(MiniLsm::open(&dir, options(WalRecoveryMode::AbsoluteConsistency)).is_err());
+    // a torn tail is not tolerated either
+    let dir = copy_db(dir.path());
+    let wals = wal_files(dir.path());
+    let mut data = std::fs::read(&wals[0]).unwrap();
+    data[A2_BODY] ^= 0xff;
+    data.truncate(data.len() - 1);
+    std::fs::write(&wals[0], &data).unwrap();
+    assert!(MiniLsm::open(&dir, options(WalRecoveryMode::AbsoluteConsistency)).is_err());
+}
+
+#[test]
+fn test_wal_recovery_tolerate_corrupted_tail() {
+    let dir = prepare_corrupted_db(A2_BODY);
+    // corruption in the middle of a WAL is not a torn write
+    assert!(MiniLsm::open(&dir, options(WalRecoveryMode::TolerateCorruptedTailRecords)).is_err());
+
+    let dir = copy_db(dir.path());
+    let wals = wal_files(dir.path());
+    let mut data = std::fs::read(&wals[0]).unwrap();
+    data[A2_BODY] ^= 0xff;
+    std::fs::write(&wals[0], &data).unwrap();
+    let len = std::fs::metadata(&wals[1]).unwrap().len();
+    std::fs::OpenOptions::new()
+        .write(true)
+        .open(&wals[1])
+        .unwrap()
+        .set_len(len - 3)
+        .unwrap();
+    let storage =
+        MiniLsm::open(&dir, options(WalRecoveryMode::TolerateCorruptedTailRecords)).unwrap();
+    check_keys(
+        &storage,
+        &[(b"a1", true), (b"a2", true), (b"a3", true), (b"b1", false)],
+    );
+    assert_eq!(
+        storage.wal_recovery_report(),
+        &WalRecoveryReport {
+            dropped_records: 1,
+            dropped_bytes: RECORD_SIZE as u64 - 3,
+            stopped_at: None,
+        }
+    );
+}
+
+#[test]
+fn test_wal_recovery_point_in_time() {
+    let dir = prepare_corrupted_db(A2_BODY);
+    let wals = wal_files(dir.path());
+    let storage = MiniLsm::open(&dir, options(WalRecoveryMode::PointInTimeRecovery)).unwrap();
+    check_keys(
+        &storage,
+        &[
+            (b"a1", true),
+            (b"a2", false),
+            (b"a3", false),
+            (b"b1", false),
+        ],
+    );
+    assert_eq!(
+        storage.wal_recovery_report(),
+        &WalRecoveryReport {
+            dropped_records: 3,
+            dropped_bytes: RECORD_SIZE as u64 * 3,
+            stopped_at: Some((wals[0].clone(), RECORD_SIZE as u64)),
+        }
+    );
+    // the truncated WALs are consistent, so later recoveries find nothing to drop
+    storage.put(b"c1", b"v").unwrap();
+    storage.sync().unwrap();
+    drop(storage);
+    let storage = MiniLsm::open(&dir, options(WalRecoveryMode::AbsoluteConsistency)).unwrap();
+    check_keys(&storage, &[(b"a1", true), (b"b1", false), (b"c1", true)]);
+    assert_eq!(storage.wal_recovery_report(), &WalRecoveryReport::default());
+}
+
+#[test]
+fn test_wal_recovery_skip_corrupted() {
+    let dir = prepare_corrupted_db(A2_BODY);
+    let storage = MiniLsm::open(&dir, options(WalRecoveryMode::SkipAnyCorruptedRecords)).unwrap();
+    check_keys(
+        &storage,
+        &[(b"a1", true), (b"a2", false), (b"a3", true), (b"b1", true)],
+    );
+    assert_eq!(
+        storage.wal_recovery_report(),
+        &WalRecoveryReport {
+            dropped_records: 1,
+            dropped_bytes: RECORD_SIZE as u64,
+            stopped_at: None,
+        }
+    );
+}
+
+#[test]
+fn test_wal_recovery_corrupted_length() {
+    let dir = prepare_corrupted_db(A2_LENGTH);
+    // the length of `a2` now points past the end of the WAL, which must not pass for a torn write
+    assert!(MiniLsm::open(&dir, options(WalRecoveryMode::AbsoluteConsistency)).is_err());
+    assert!(MiniLsm::open(&dir, options(WalRecoveryMode::TolerateCorruptedTailRecords)).is_err());
+    let wals = wal_files(dir.path());
+    assert_eq!(
+        std::fs::metadata(&wals[0]).unwrap().len(),
+        RECORD_SIZE as u64 * 3
+    );
+
+    let skip_dir = copy_db(dir.path());
+    let storage =
+        MiniLsm::open(&skip_dir, options(WalRecoveryMode::SkipAnyCorruptedRecords)).unwrap();
+    check_keys(
+        &storage,
+        &[(b"a1", true), (b"a2", false), (b"a3", true), (b"b1", true)],
+    );
+    assert_eq!(
+        storage.wal_recovery_report(),
+        &WalRecoveryReport {
+            dropped_records: 1,
+            dropped_bytes: RECORD_SIZE as u64,
+            stopped_at: None,
+        }
+    );
+
+    let storage = MiniLsm::open(&dir, options(WalRecoveryMode::PointInTimeRecovery)).unwrap();
+    check_keys(
+        &storage,
+        &[
+            (b"a1", true),
+            (b"a2", false),
+            (b"a3", false),
+            (b"b1", false),
+        ],
+    );
+    assert_eq!(
+        storage.wal_recovery_report(),
+        &WalRecoveryReport {
+            dropped_records: 3,
+            dropped_bytes: RECORD_SIZE as u64 * 3,
+            stopped_at: Some((wals[0].clone(), RECORD_SIZE as u64)),
+        }
+    );
+}
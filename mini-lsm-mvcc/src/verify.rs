@@ -0,0 +1,258 @@
+use std::fmt;
+use std::path::PathBuf;
+
+use anyhow::Result;
+use crossbeam_skiplist::SkipMap;
+
+use crate::block::BlockIterator;
+use crate::key::KeyBytes;
+use crate::lsm_storage::{LsmStorageInner, LsmStorageState};
+use crate::table::{FileObject, SsTable};
+use crate::wal::{Wal, WalRecoveryMode, WalRecoveryReport};
+
+/// Where `MiniLsm::verify` found a problem.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum VerifyLocation {
+    /// An SST, or one of its blocks.
+    Sst { id: usize, block: Option<usize> },
+    /// A level, or a tier for tiered compaction, identified by its id in `LsmStorageState::levels`.
+    Level(usize),
+    /// The WAL of a memtable.
+    Wal(PathBuf),
+}
+
+impl fmt::Display for VerifyLocation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Sst { id, block: None } => write!(f, "SST {}", id),
+            Self::Sst {
+                id,
+                block: Some(block),
+            } => write!(f, "SST {} block {}", id, block),
+            Self::Level(id) => write!(f, "level {}", id),
+            Self::Wal(path) => write!(f, "WAL {}", path.display()),
+        }
+    }
+}
+
+/// A problem found by `MiniLsm::verify`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct VerifyError {
+    pub location: VerifyLocation,
+    pub message: String,
+}
+
+impl fmt::Display for VerifyError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: {}", self.location, self.message)
+    }
+}
+
+/// What `MiniLsm::verify` checked, and all the problems it found.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct VerifyReport {
+    pub ssts_checked: usize,
+    /// Number of data blocks read, only in deep mode.
+    pub blocks_checked: usize,
+    pub wals_checked: usize,
+    pub errors: Vec<VerifyError>,
+}
+
+impl VerifyReport {
+    pub fn is_ok(&self) -> bool {
+        self.errors.is_empty()
+    }
+
+    fn error(&mut self, location: VerifyLocation, message: impl Into<String>) {
+        let error = VerifyError {
+            location,
+            message: message.into(),
+        };
+        println!("verify: {}", error);
+        self.errors.push(error);
+    }
+}
+
+impl LsmStorageInner {
+    pub(crate) fn verify(&self, deep: bool) -> Result<VerifyReport> {
+        let mut report = VerifyReport::default();
+        {
+            // compactions, which remove SSTs, wait until they are checked, while flushes only add
+            // SSTs that are not in the snapshot and may go on
+            let _compaction_lock = self.compaction_lock.write();
+            let snapshot = {
+                let _state_lock = self.state_lock.lock();
+                self.state.read().clone()
+            };
+            for id in snapshot
+                .l0_sstables
+                .iter()
+                .chain(snapshot.levels.iter().flat_map(|(_, ssts)| ssts))
+            {
+                self.verify_sst(&snapshot, *id, deep, &mut report);
+            }
+            Self::verify_levels(&snapshot, &mut report);
+        }
+        if self.options.enable_wal {
+            // writes go to the WAL buffers, which are flushed so that the files end with complete
+            // records
+            let _lck = self.mvcc().write_lock.lock();
+            let _state_lock = self.state_lock.lock();
+            let snapshot = self.state.read().clone();
+            for memtable in snapshot
+                .imm_memtables
+                .iter()
+                .chain(std::iter::once(&snapshot.memtable))
+            {
+                let path = self.path_of_wal(memtable.id());
+                report.wals_checked += 1;
+                let result = memtable.flush_wal_buffer().and_then(|_| {
+                    Wal::replay_with_mode(
+                        &path,
+                        &SkipMap::new(),
+                        WalRecoveryMode::AbsoluteConsistency,
+                        &mut WalRecoveryReport::default(),
+                    )
+                });
+                if let Err(e) = result {
+                    report.error(VerifyLocation::Wal(path), format!("{:#}", e));
+                }
+            }
+        }
+        Ok(report)
+    }
+
+    /// Reopen the SST `id` from disk, which checks the checksums of its block meta and bloom filter,
+    /// and check that its keys are sorted. In deep mode, read all its blocks.
+    fn verify_sst(
+        &self,
+        snapshot: &LsmStorageState,
+        id: usize,
+        deep: bool,
+        report: &mut VerifyReport,
+    ) {
+        report.ssts_checked += 1;
+        let location = VerifyLocation::Sst { id, block: None };
+        let Some(table) = snapshot.sstables.get(&id) else {
+            report.error(location, "not found in the state");
+            return;
+        };
+        let sst = match FileObject::open(&self.path_of_sst(id))
+            .and_then(|file| SsTable::open(id, None, file))
+        {
+            Ok(sst) => sst,
+            Err(e) => {
+                report.error(location, format!("failed to open: {:#}", e));
+                return;
+            }
+        };
+        if sst.table_size() != table.table_size() {
+            report.error(
+                location.clone(),
+                format!(
+                    "file has {} bytes, expected {}",
+                    sst.table_size(),
+                    table.table_size()
+                ),
+            );
+        }
+
+        let mut last_key: Option<KeyBytes> = None;
+        for (block_idx, meta) in sst.block_meta.iter().enumerate() {
+            let block_location = VerifyLocation::Sst {
+                id,
+                block: Some(block_idx),
+            };
+            if meta.first_key > meta.last_key {
+                report.error(
+                    block_location.clone(),
+                    format!(
+                        "first key {:?} is after last key {:?}",
+                        meta.first_key, meta.last_key
+                    ),
+                );
+            }
+            if let Some(last_key) = &last_key {
+                if *last_key >= meta.first_key {
+                    report.error(
+                        block_location.clone(),
+                        format!(
+                            "first key {:?} is not after {:?} in the previous block",
+                            meta.first_key, last_key
+                        ),
+                    );
+                }
+            }
+            last_key = Some(meta.last_key.clone());
+            if !deep {
+                continue;
+            }
+
+            report.blocks_checked += 1;
+            let block = match sst.read_block(block_idx) {
+                Ok(block) => block,
+                Err(e) => {
+                    report.error(block_location, format!("{:#}", e));
+                    continue;
+                }
+            };
+            let mut iter = BlockIterator::create_and_seek_to_first(block);
+            if !iter.is_valid() || iter.key() != meta.first_key.as_key_slice() {
+                report.error(
+                    block_location.clone(),
+                    format!(
+                        "first key does not match {:?} in the block meta",
+                        meta.first_key
+                    ),
+                );
+            }
+            let mut prev: Option<KeyBytes> = None;
+            while iter.is_valid() {
+                if let Some(prev) = &prev {
+                    if prev.as_key_slice() >= iter.key() {
+                        report.error(
+                            block_location.clone(),
+                            format!("key {:?} is not after {:?}", iter.key().to_key_vec(), prev),
+                        );
+                    }
+                }
+                prev = Some(iter.key().to_key_vec().into_key_bytes());
+                iter.next();
+            }
+            if prev.as_ref() != Some(&meta.last_key) {
+                report.error(
+                    block_location,
+                    format!(
+                        "last key does not match {:?} in the block meta",
+                        meta.last_key
+                    ),
+                );
+            }
+        }
+    }
+
+    /// Check that the SSTs within each level are ordered, and do not overlap. L0 SSTs may overlap.
+    fn verify_levels(snapshot: &LsmStorageState, report: &mut VerifyReport) {
+        for (level, ssts) in &snapshot.levels {
+            let ssts = ssts
+                .iter()
+                .filter_map(|id| snapshot.sstables.get(id))
+                .collect::<Vec<_>>();
+            for pair in ssts.windows(2) {
+                let (prev, next) = (pair[0], pair[1]);
+                if prev.last_key().key_ref() >= next.first_key().key_ref() {
+                    report.error(
+                        VerifyLocation::Level(*level),
+                        format!(
+                            "SST {} (last key {:?}) overlaps or is not ordered before SST {} (first key {:?})",
+                            prev.sst_id(),
+                            prev.last_key(),
+                            next.sst_id(),
+                            next.first_key()
+                        ),
+                    );
+                }
+            }
+        }
+    }
+}
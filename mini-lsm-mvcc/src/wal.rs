@@ -1,35 +1,129 @@
 use std::fs::{File, OpenOptions};
-use std::hash::Hasher;
-use std::io::{BufWriter, Read, Write};
-use std::path::Path;
+use std::io::{BufWriter, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
 use std::sync::Arc;
 
 use anyhow::{bail, Context, Result};
 use bytes::{Buf, BufMut, Bytes};
 use crossbeam_skiplist::SkipMap;
-use parking_lot::Mutex;
+use parking_lot::{Condvar, Mutex};
+use serde::{Deserialize, Serialize};
 
 use crate::key::{KeyBytes, KeySlice};
 
 pub struct Wal {
     file: Arc<Mutex<BufWriter<File>>>,
+    /// A second handle to the WAL file, so that `fsync` does not block writers appending to the
+    /// buffered writer.
+    sync_file: File,
+    /// Number of bytes appended to the WAL, updated while holding the `file` lock.
+    written: AtomicU64,
+    /// Group commit state, see `Wal::sync_to`.
+    sync_state: Mutex<WalSyncState>,
+    sync_cvar: Condvar,
+}
+
+/// How to handle incomplete or corrupted WAL records during recovery.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum WalRecoveryMode {
+    /// Fail on any incomplete or corrupted record.
+    AbsoluteConsistency,
+    /// Drop an incomplete or corrupted record at the end of a WAL, which is what a crash in the
+    /// middle of a write leaves behind, and truncate the WAL. Fail on corruption elsewhere, including
+    /// a corrupted record length, after which there is no telling whether the WAL ends.
+    #[default]
+    TolerateCorruptedTailRecords,
+    /// Stop at the first incomplete or corrupted record, and drop everything after it, including
+    /// all later WALs. The recovered state is always a consistent prefix of the committed writes.
+    PointInTimeRecovery,
+    /// Skip any corrupted record and keep going. After a corrupted record length, recovery resumes at
+    /// the next valid record. An incomplete record at the end of a WAL is dropped.
+    SkipAnyCorruptedRecords,
+}
+
+/// What was dropped when recovering WALs.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct WalRecoveryReport {
+    /// Number of records (batches) not applied to the memtables.
+    pub dropped_records: usize,
+    /// Number of bytes not applied to the memtables.
+    pub dropped_bytes: u64,
+    /// The WAL and offset where point-in-time recovery stopped, if any.
+    pub stopped_at: Option<(PathBuf, u64)>,
+}
+
+/// A batch of entries committed with the same timestamp, stored as one WAL record.
+#[derive(Debug, Clone)]
+pub struct WalBatch {
+    pub commit_ts: u64,
+    pub data: Vec<(Bytes, Bytes)>,
+}
+
+enum WalRecord {
+    /// A valid batch and the number of bytes its record occupies.
+    Batch(WalBatch, usize),
+    /// The buffer ends before the record does, i.e., the record was torn by a crash. As the length
+    /// prefix is checksummed on its own, this only happens to the last record.
+    Incomplete,
+    /// A complete record (of the given length) whose checksum does not match.
+    Corrupted(usize),
+    /// The header checksum does not match, so the length of the record is unknown.
+    CorruptedHeader,
+}
+
+#[derive(Default)]
+struct WalSyncState {
+    /// All bytes before this offset are durable.
+    synced: u64,
+    /// Whether some writer is currently flushing and syncing the file on behalf of the group.
+    syncing: bool,
 }
 
 impl Wal {
-    pub fn create(path: impl AsRef<Path>) -> Result<Self> {
+    fn from_file(file: File, written: u64) -> Result<Self> {
         Ok(Self {
-            file: Arc::new(Mutex::new(BufWriter::new(
-                OpenOptions::new()
-                    .read(true)
-                    .create_new(true)
-                    .write(true)
-                    .open(path)
-                    .context("failed to create WAL")?,
-            ))),
+            sync_file: file.try_clone()?,
+            file: Arc::new(Mutex::new(BufWriter::new(file))),
+            written: AtomicU64::new(written),
+            sync_state: Mutex::new(WalSyncState {
+                synced: written,
+                syncing: false,
+            }),
+            sync_cvar: Condvar::new(),
         })
     }
 
+    pub fn create(path: impl AsRef<Path>) -> Result<Self> {
+        Self::from_file(
+            OpenOptions::new()
+                .read(true)
+                .create_new(true)
+                .write(true)
+                .open(path)
+                .context("failed to create WAL")?,
+            0,
+        )
+    }
+
     pub fn recover(path: impl AsRef<Path>, skiplist: &SkipMap<KeyBytes, Bytes>) -> Result<Self> {
+        Self::recover_with_mode(
+            path,
+            skiplist,
+            WalRecoveryMode::default(),
+            &mut WalRecoveryReport::default(),
+        )
+    }
+
+    /// Recover the WAL into `skiplist`, handling bad records as specified by `mode`. Dropped records
+    /// are added to `report`, which should be shared across all WALs recovered when opening the
+    /// engine, in the order they were created.
+    pub fn recover_with_mode(
+        path: impl AsRef<Path>,
+        skiplist: &SkipMap<KeyBytes, Bytes>,
+        mode: WalRecoveryMode,
+        report: &mut WalRecoveryReport,
+    ) -> Result<Self> {
         let path = path.as_ref();
         let mut file = OpenOptions::new()
             .read(true)
@@ -38,57 +132,363 @@ impl Wal {
             .context("failed to recover from WAL")?;
         let mut buf = Vec::new();
         file.read_to_end(&mut buf)?;
-        let mut rbuf: &[u8] = buf.as_slice();
+        let offset = Self::replay(path, &buf, skiplist, mode, report)?;
+        if offset < buf.len() {
+            println!("truncating {} at offset {}", path.display(), offset);
+            file.set_len(offset as u64)?;
+            file.sync_all()?;
+        }
+        Self::from_file(file, offset as u64)
+    }
+
+    /// Replay the WAL into `skiplist` like `recover_with_mode`, but leave the WAL untouched. Used by
+    /// read-only storages.
+    pub fn replay_with_mode(
+        path: impl AsRef<Path>,
+        skiplist: &SkipMap<KeyBytes, Bytes>,
+        mode: WalRecoveryMode,
+        report: &mut WalRecoveryReport,
+    ) -> Result<()> {
+        let path = path.as_ref();
+        let buf = std::fs::read(path).context("failed to recover from WAL")?;
+        Self::replay(path, &buf, skiplist, mode, report)?;
+        Ok(())
+    }
+
+    /// Apply the records after `offset` in the WAL in `path` to `skiplist`, leaving a record that is
+    /// still being written for the next call. Returns the offset after the last record applied and
+    /// the largest commit ts applied. Used by secondary instances, which follow the WALs of a primary.
+    pub fn replay_from(
+        path: impl AsRef<Path>,
+        offset: u64,
+        skiplist: &SkipMap<KeyBytes, Bytes>,
+    ) -> Result<(u64, u64)> {
+        let path = path.as_ref();
+        let mut file = File::open(path)?;
+        file.seek(SeekFrom::Start(offset))?;
+        let mut buf = Vec::new();
+        file.read_to_end(&mut buf)?;
+        let mut pos = 0;
+        let mut max_ts = 0;
+        while pos < buf.len() {
+            match Self::decode_record(&buf[pos..]) {
+                WalRecord::Batch(batch, len) => {
+                    for (key, value) in batch.data {
+                        skiplist.insert(KeyBytes::from_bytes_with_ts(key, batch.commit_ts), value);
+                    }
+                    max_ts = max_ts.max(batch.commit_ts);
+                    pos += len;
+                }
+                WalRecord::Incomplete => break,
+                WalRecord::Corrupted(_) | WalRecord::CorruptedHeader => bail!(
+                    "checksum mismatch at offset {} of {}",
+                    offset + pos as u64,
+                    path.display()
+                ),
+            }
+        }
+        Ok((offset + pos as u64, max_ts))
+    }
+
+    /// Apply the records in `buf`, the content of the WAL in `path`, to `skiplist`. Returns the
+    /// offset where the records to keep end, after adding the ones dropped to `report`.
+    fn replay(
+        path: &Path,
+        buf: &[u8],
+        skiplist: &SkipMap<KeyBytes, Bytes>,
+        mode: WalRecoveryMode,
+        report: &mut WalRecoveryReport,
+    ) -> Result<usize> {
+        let mut offset = 0;
+        // a previous WAL stopped point-in-time recovery, so nothing in this WAL can be applied
+        let mut stopped = report.stopped_at.is_some();
+        while !stopped && offset < buf.len() {
+            let (batch, len) = match Self::decode_record(&buf[offset..]) {
+                WalRecord::Batch(batch, len) => (batch, len),
+                WalRecord::Incomplete if mode == WalRecoveryMode::AbsoluteConsistency => {
+                    bail!(
+                        "incomplete record at offset {} of {}",
+                        offset,
+                        path.display()
+                    )
+                }
+                WalRecord::Corrupted(_) | WalRecord::CorruptedHeader
+                    if mode == WalRecoveryMode::AbsoluteConsistency =>
+                {
+                    bail!(
+                        "checksum mismatch at offset {} of {}",
+                        offset,
+                        path.display()
+                    )
+                }
+                WalRecord::Corrupted(len)
+                    if mode == WalRecoveryMode::TolerateCorruptedTailRecords
+                        && offset + len < buf.len() =>
+                {
+                    bail!(
+                        "checksum mismatch at offset {} of {}",
+                        offset,
+                        path.display()
+                    )
+                }
+                // a torn write leaves a valid header behind, so this is corruption, and nothing
+                // tells whether more records follow
+                WalRecord::CorruptedHeader
+                    if mode == WalRecoveryMode::TolerateCorruptedTailRecords =>
+                {
+                    bail!(
+                        "header checksum mismatch at offset {} of {}",
+                        offset,
+                        path.display()
+                    )
+                }
+                WalRecord::Corrupted(len) if mode == WalRecoveryMode::SkipAnyCorruptedRecords => {
+                    println!(
+                        "skipping corrupted record at offset {} of {}",
+                        offset,
+                        path.display()
+                    );
+                    report.dropped_records += 1;
+                    report.dropped_bytes += len as u64;
+                    offset += len;
+                    continue;
+                }
+                WalRecord::CorruptedHeader if mode == WalRecoveryMode::SkipAnyCorruptedRecords => {
+                    // the length prefix cannot be trusted, so resume at the next valid record; if
+                    // there is none, the rest of the WAL is dropped like an incomplete record
+                    if let Some(len) = Self::find_next_record(&buf[offset..]) {
+                        println!(
+                            "skipping {} corrupted bytes at offset {} of {}",
+                            len,
+                            offset,
+                            path.display()
+                        );
+                        report.dropped_records += 1;
+                        report.dropped_bytes += len as u64;
+                        offset += len;
+                    } else {
+                        stopped = true;
+                    }
+                    continue;
+                }
+                WalRecord::Incomplete | WalRecord::Corrupted(_) | WalRecord::CorruptedHeader => {
+                    if mode == WalRecoveryMode::PointInTimeRecovery {
+                        report.stopped_at = Some((path.to_path_buf(), offset as u64));
+                    }
+                    stopped = true;
+                    continue;
+                }
+            };
+            // only apply a batch after the whole record has been validated
+            for (key, value) in batch.data {
+                skiplist.insert(KeyBytes::from_bytes_with_ts(key, batch.commit_ts), value);
+            }
+            offset += len;
+        }
+        if offset < buf.len() {
+            let dropped_records = Self::count_records(&buf[offset..]);
+            println!(
+                "{}: {} records ({} bytes) dropped from offset {}",
+                path.display(),
+                dropped_records,
+                buf.len() - offset,
+                offset
+            );
+            report.dropped_records += dropped_records;
+            report.dropped_bytes += (buf.len() - offset) as u64;
+        }
+        Ok(offset)
+    }
+
+    /// Read all batches in a WAL file without recovering it, failing on a corrupted record. Reading
+    /// stops at an incomplete record, which may be one still being appended.
+    pub fn read_batches(path: impl AsRef<Path>) -> Result<Vec<WalBatch>> {
+        Self::read_batches_inner(path.as_ref(), false)
+    }
+
+    /// Like `read_batches`, but skip the corrupted records and return the batches around them.
+    pub fn salvage_batches(path: impl AsRef<Path>) -> Result<Vec<WalBatch>> {
+        Self::read_batches_inner(path.as_ref(), true)
+    }
+
+    fn read_batches_inner(path: &Path, skip_corrupted: bool) -> Result<Vec<WalBatch>> {
+        let buf = std::fs::read(path)
+            .with_context(|| format!("failed to read WAL {}", path.display()))?;
+        let mut offset = 0;
+        let mut batches = Vec::new();
+        while offset < buf.len() {
+            match Self::decode_record(&buf[offset..]) {
+                WalRecord::Batch(batch, len) => {
+                    batches.push(batch);
+                    offset += len;
+                }
+                WalRecord::Corrupted(_) | WalRecord::CorruptedHeader if !skip_corrupted => {
+                    bail!("{}: corrupted record at offset {}", path.display(), offset)
+                }
+                WalRecord::Corrupted(len) => offset += len,
+                WalRecord::CorruptedHeader => match Self::find_next_record(&buf[offset..]) {
+                    Some(len) => offset += len,
+                    None => break,
+                },
+                WalRecord::Incomplete => break,
+            }
+        }
+        Ok(batches)
+    }
+
+    /// Count the records in `buf` by following the length prefixes, regardless of the record
+    /// checksums. The bytes skipped after a corrupted header count as one record, and so does an
+    /// incomplete record.
+    fn count_records(mut buf: &[u8]) -> usize {
+        let mut cnt = 0;
+        while buf.has_remaining() {
+            cnt += 1;
+            match Self::decode_record(buf) {
+                WalRecord::Batch(_, len) | WalRecord::Corrupted(len) => buf.advance(len),
+                WalRecord::CorruptedHeader => match Self::find_next_record(buf) {
+                    Some(len) => buf.advance(len),
+                    None => break,
+                },
+                WalRecord::Incomplete => break,
+            }
+        }
+        cnt
+    }
+
+    /// The offset of the first valid record in `buf` after its beginning, used to resume after a
+    /// record whose length prefix is corrupted.
+    fn find_next_record(buf: &[u8]) -> Option<usize> {
+        (1..buf.len()).find(|&pos| matches!(Self::decode_record(&buf[pos..]), WalRecord::Batch(..)))
+    }
+
+    /// Decode the record at the beginning of `buf`.
+    fn decode_record(mut buf: &[u8]) -> WalRecord {
+        if buf.remaining() < std::mem::size_of::<u32>() * 2 {
+            return WalRecord::Incomplete;
+        }
+        let raw = buf;
+        let batch_len = buf.get_u32() as usize;
+        if buf.get_u32() != crc32fast::hash(&raw[..4]) {
+            return WalRecord::CorruptedHeader;
+        }
+        let record_len = std::mem::size_of::<u32>() * 3 + batch_len;
+        if raw.len() < record_len {
+            return WalRecord::Incomplete;
+        }
+        let checksum = (&raw[record_len - 4..record_len]).get_u32();
+        if batch_len < std::mem::size_of::<u64>()
+            || checksum != crc32fast::hash(&raw[..record_len - 4])
+        {
+            return WalRecord::Corrupted(record_len);
+        }
+        let mut rbuf = &buf[..batch_len];
+        let commit_ts = rbuf.get_u64();
+        let mut data = Vec::new();
         while rbuf.has_remaining() {
-            let mut hasher = crc32fast::Hasher::new();
             let key_len = rbuf.get_u16() as usize;
-            hasher.write_u16(key_len as u16);
             let key = Bytes::copy_from_slice(&rbuf[..key_len]);
-            hasher.write(&key);
             rbuf.advance(key_len);
-            let ts = rbuf.get_u64();
-            hasher.write_u64(ts);
             let value_len = rbuf.get_u16() as usize;
-            hasher.write_u16(value_len as u16);
             let value = Bytes::copy_from_slice(&rbuf[..value_len]);
-            hasher.write(&value);
             rbuf.advance(value_len);
-            let checksum = rbuf.get_u32();
-            if hasher.finalize() != checksum {
-                bail!("checksum mismatch");
-            }
-            skiplist.insert(KeyBytes::from_bytes_with_ts(key, ts), value);
+            data.push((key, value));
         }
-        Ok(Self {
-            file: Arc::new(Mutex::new(BufWriter::new(file))),
-        })
+        WalRecord::Batch(WalBatch { commit_ts, data }, record_len)
     }
 
     pub fn put(&self, key: KeySlice, value: &[u8]) -> Result<()> {
-        let mut file = self.file.lock();
-        let mut buf: Vec<u8> =
-            Vec::with_capacity(key.raw_len() + value.len() + std::mem::size_of::<u16>());
-        let mut hasher = crc32fast::Hasher::new();
-        hasher.write_u16(key.key_len() as u16);
-        buf.put_u16(key.key_len() as u16);
-        hasher.write(key.key_ref());
-        buf.put_slice(key.key_ref());
-        hasher.write_u64(key.ts());
-        buf.put_u64(key.ts());
-        hasher.write_u16(value.len() as u16);
-        buf.put_u16(value.len() as u16);
-        buf.put_slice(value);
-        hasher.write(value);
+        self.put_batch(&[(key, value)])?;
+        Ok(())
+    }
+
+    /// Append a batch of entries sharing one commit timestamp to the WAL as a single record,
+    /// returning the offset right after the batch. The data is only buffered; pass the returned
+    /// offset to `sync_to` to make it durable.
+    ///
+    /// Record format:
+    /// `| batch_len (u32) | header_checksum (u32) | commit_ts (u64) | entries | checksum (u32) |`,
+    /// where each entry is `| key_len (u16) | key | value_len (u16) | value |`. The header checksum
+    /// covers `batch_len`, so that a corrupted length is not mistaken for a torn write, and the
+    /// checksum covers everything before it.
+    pub fn put_batch(&self, data: &[(KeySlice, &[u8])]) -> Result<u64> {
+        let Some((first_key, _)) = data.first() else {
+            return Ok(self.written.load(Ordering::SeqCst));
+        };
+        let commit_ts = first_key.ts();
+        assert!(
+            data.iter().all(|(key, _)| key.ts() == commit_ts),
+            "all entries in a WAL batch must share the same timestamp"
+        );
+        let mut buf: Vec<u8> = Vec::new();
+        buf.put_u32(0); // batch_len, filled in later
+        buf.put_u32(0); // header_checksum, filled in later
+        buf.put_u64(commit_ts);
+        for (key, value) in data {
+            buf.put_u16(key.key_len() as u16);
+            buf.put_slice(key.key_ref());
+            buf.put_u16(value.len() as u16);
+            buf.put_slice(value);
+        }
+        let batch_len = (buf.len() - std::mem::size_of::<u32>() * 2) as u32;
+        buf[..4].copy_from_slice(&batch_len.to_be_bytes());
+        let header_checksum = crc32fast::hash(&buf[..4]);
+        buf[4..8].copy_from_slice(&header_checksum.to_be_bytes());
         // add checksum: week 2 day 7
-        buf.put_u32(hasher.finalize());
+        let checksum = crc32fast::hash(&buf);
+        buf.put_u32(checksum);
+        let mut file = self.file.lock();
         file.write_all(&buf)?;
-        Ok(())
+        let written = self.written.load(Ordering::SeqCst) + buf.len() as u64;
+        self.written.store(written, Ordering::SeqCst);
+        Ok(written)
+    }
+
+    /// Make sure all bytes before `offset` are durable.
+    ///
+    /// Concurrent callers are grouped: one of them becomes the leader, flushes everything buffered
+    /// so far and issues a single `fsync`, while the others wait for it. Writers that appended
+    /// during the `fsync` are covered by the next leader.
+    pub fn sync_to(&self, offset: u64) -> Result<()> {
+        let mut state = self.sync_state.lock();
+        loop {
+            if state.synced >= offset {
+                return Ok(());
+            }
+            if !state.syncing {
+                break;
+            }
+            self.sync_cvar.wait(&mut state);
+        }
+        state.syncing = true;
+        drop(state);
+
+        let result = (|| {
+            let synced = {
+                let mut file = self.file.lock();
+                file.flush()?;
+                self.written.load(Ordering::SeqCst)
+            };
+            self.sync_file.sync_all()?;
+            Ok(synced)
+        })();
+
+        let mut state = self.sync_state.lock();
+        state.syncing = false;
+        if let Ok(synced) = result {
+            state.synced = state.synced.max(synced);
+        }
+        self.sync_cvar.notify_all();
+        result.map(|_| ())
     }
 
     pub fn sync(&self) -> Result<()> {
-        let mut file = self.file.lock();
-        file.flush()?;
-        file.get_mut().sync_all()?;
+        self.sync_to(self.written.load(Ordering::SeqCst))
+    }
+
+    /// Write the buffered records to the file without waiting for them to be durable, so that
+    /// they can be read by `read_batches`.
+    pub fn flush_buffer(&self) -> Result<()> {
+        self.file.lock().flush()?;
         Ok(())
     }
 }
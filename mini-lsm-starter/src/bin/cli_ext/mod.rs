@@ -0,0 +1,52 @@
+//! The options of the CLI for the features that only some of the crates implement. This crate
+//! implements none of them.
+
+use std::path::Path;
+
+use anyhow::Result;
+use clap::ValueEnum;
+
+use crate::wrapper::mini_lsm_wrapper::compact::CompactionOptions;
+use crate::wrapper::mini_lsm_wrapper::lsm_storage::LsmStorageOptions;
+use crate::{base_compaction_options, Args, BaseCompactionStrategy};
+
+#[derive(Debug, Clone, ValueEnum)]
+pub enum CompactionStrategy {
+    Simple,
+    Leveled,
+    Tiered,
+    None,
+}
+
+#[derive(clap::Args, Debug)]
+pub struct ExtArgs {}
+
+/// The commands that run instead of the REPL.
+#[derive(clap::Subcommand, Debug)]
+pub enum ExtCommand {}
+
+fn compaction_options(strategy: &CompactionStrategy) -> CompactionOptions {
+    base_compaction_options(match strategy {
+        CompactionStrategy::Simple => BaseCompactionStrategy::Simple,
+        CompactionStrategy::Leveled => BaseCompactionStrategy::Leveled,
+        CompactionStrategy::Tiered => BaseCompactionStrategy::Tiered,
+        CompactionStrategy::None => BaseCompactionStrategy::None,
+    })
+}
+
+/// The options to open the database with.
+pub fn storage_options(args: &Args) -> LsmStorageOptions {
+    LsmStorageOptions {
+        block_size: 4096,
+        target_sst_size: 2 << 20, // 2MB
+        num_memtable_limit: 3,
+        compaction_options: compaction_options(&args.compaction),
+        enable_wal: args.enable_wal,
+        serializable: args.serializable,
+    }
+}
+
+/// Run `command` on the database at `path`.
+pub fn run(command: ExtCommand, _path: &Path, _options: &LsmStorageOptions) -> Result<()> {
+    match command {}
+}
@@ -1,3 +1,4 @@
+mod simulator_ext;
 mod wrapper;
 use wrapper::mini_lsm_wrapper;
 
@@ -60,6 +61,8 @@ enum Args {
         #[clap(long, default_value = "32")]
         sst_size_mb: usize,
     },
+    #[command(flatten)]
+    Ext(simulator_ext::ExtArgs),
 }
 
 pub struct MockStorage {
@@ -110,12 +113,13 @@ impl MockStorage {
         id
     }
 
-    pub fn flush_sst_to_new_tier(&mut self) {
+    pub fn flush_sst_to_new_tier(&mut self) -> usize {
         let id = self.generate_sst_id();
         self.snapshot.levels.insert(0, (id, vec![id]));
         self.file_list.insert(id, id);
         self.total_flushes += 1;
         self.total_writes += 1;
+        id
     }
 
     pub fn remove(&mut self, files_to_remove: &[usize]) {
@@ -608,5 +612,6 @@ fn main() {
                 println!();
             }
         }
+        Args::Ext(args) => simulator_ext::run(args),
     }
 }
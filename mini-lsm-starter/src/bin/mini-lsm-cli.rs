@@ -1,3 +1,4 @@
+mod cli_ext;
 mod wrapper;
 
 use rustyline::DefaultEditor;
@@ -5,35 +6,66 @@ use wrapper::mini_lsm_wrapper;
 
 use anyhow::Result;
 use bytes::Bytes;
-use clap::{Parser, ValueEnum};
+use clap::Parser;
 use mini_lsm_wrapper::compact::{
     CompactionOptions, LeveledCompactionOptions, SimpleLeveledCompactionOptions,
     TieredCompactionOptions,
 };
 use mini_lsm_wrapper::iterators::StorageIterator;
-use mini_lsm_wrapper::lsm_storage::{LsmStorageOptions, MiniLsm};
+use mini_lsm_wrapper::lsm_storage::MiniLsm;
 use std::path::PathBuf;
 use std::sync::Arc;
 
-#[derive(Debug, Clone, ValueEnum)]
-enum CompactionStrategy {
+/// The compaction strategies every crate implements. `cli_ext::CompactionStrategy` lists the ones
+/// the CLI offers.
+#[derive(Debug, Clone, Copy)]
+enum BaseCompactionStrategy {
     Simple,
     Leveled,
     Tiered,
     None,
 }
 
+fn base_compaction_options(strategy: BaseCompactionStrategy) -> CompactionOptions {
+    match strategy {
+        BaseCompactionStrategy::None => CompactionOptions::NoCompaction,
+        BaseCompactionStrategy::Simple => {
+            CompactionOptions::Simple(SimpleLeveledCompactionOptions {
+                size_ratio_percent: 200,
+                level0_file_num_compaction_trigger: 2,
+                max_levels: 4,
+            })
+        }
+        BaseCompactionStrategy::Tiered => CompactionOptions::Tiered(TieredCompactionOptions {
+            num_tiers: 3,
+            max_size_amplification_percent: 200,
+            size_ratio: 1,
+            min_merge_width: 2,
+        }),
+        BaseCompactionStrategy::Leveled => CompactionOptions::Leveled(LeveledCompactionOptions {
+            level0_file_num_compaction_trigger: 2,
+            max_levels: 4,
+            base_level_size_mb: 128,
+            level_size_multiplier: 2,
+        }),
+    }
+}
+
 #[derive(Parser, Debug)]
 #[command(author, version, about, long_about = None)]
 struct Args {
     #[arg(long, default_value = "lsm.db")]
     path: PathBuf,
     #[arg(long, default_value = "leveled")]
-    compaction: CompactionStrategy,
+    compaction: cli_ext::CompactionStrategy,
     #[arg(long)]
     enable_wal: bool,
     #[arg(long)]
     serializable: bool,
+    #[command(flatten)]
+    ext: cli_ext::ExtArgs,
+    #[command(subcommand)]
+    command: Option<cli_ext::ExtCommand>,
 }
 
 struct ReplHandler {
@@ -311,40 +343,11 @@ impl ReplBuilder {
 
 fn main() -> Result<()> {
     let args = Args::parse();
-    let lsm = MiniLsm::open(
-        args.path,
-        LsmStorageOptions {
-            block_size: 4096,
-            target_sst_size: 2 << 20, // 2MB
-            num_memtable_limit: 3,
-            compaction_options: match args.compaction {
-                CompactionStrategy::None => CompactionOptions::NoCompaction,
-                CompactionStrategy::Simple => {
-                    CompactionOptions::Simple(SimpleLeveledCompactionOptions {
-                        size_ratio_percent: 200,
-                        level0_file_num_compaction_trigger: 2,
-                        max_levels: 4,
-                    })
-                }
-                CompactionStrategy::Tiered => CompactionOptions::Tiered(TieredCompactionOptions {
-                    num_tiers: 3,
-                    max_size_amplification_percent: 200,
-                    size_ratio: 1,
-                    min_merge_width: 2,
-                }),
-                CompactionStrategy::Leveled => {
-                    CompactionOptions::Leveled(LeveledCompactionOptions {
-                        level0_file_num_compaction_trigger: 2,
-                        max_levels: 4,
-                        base_level_size_mb: 128,
-                        level_size_multiplier: 2,
-                    })
-                }
-            },
-            enable_wal: args.enable_wal,
-            serializable: args.serializable,
-        },
-    )?;
+    let options = cli_ext::storage_options(&args);
+    if let Some(command) = args.command {
+        return cli_ext::run(command, &args.path, &options);
+    }
+    let lsm = MiniLsm::open(args.path, options)?;
 
     let repl = ReplBuilder::new()
         .app_name("mini-lsm-cli")
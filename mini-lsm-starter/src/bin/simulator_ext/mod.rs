@@ -0,0 +1,9 @@
+//! The compaction strategies that only some of the crates implement. This crate implements none of
+//! them.
+
+#[derive(clap::Subcommand, Debug)]
+pub enum ExtArgs {}
+
+pub fn run(args: ExtArgs) {
+    match args {}
+}
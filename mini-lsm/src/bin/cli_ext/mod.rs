@@ -0,0 +1 @@
+../../../../mini-lsm-starter/src/bin/cli_ext/mod.rs
\ No newline at end of file
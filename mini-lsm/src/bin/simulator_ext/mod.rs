@@ -0,0 +1 @@
+../../../../mini-lsm-starter/src/bin/simulator_ext/mod.rs
\ No newline at end of file
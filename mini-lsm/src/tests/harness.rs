@@ -13,7 +13,8 @@ use crate::{
     },
     iterators::{merge_iterator::MergeIterator, StorageIterator},
     key::{KeySlice, TS_ENABLED},
-    lsm_storage::{BlockCache, LsmStorageInner, LsmStorageState, MiniLsm},
+    lsm_storage::{BlockCache, LsmStorageInner, LsmStorageOptions, LsmStorageState, MiniLsm},
+    mem_table::MemTable,
     table::{SsTable, SsTableBuilder, SsTableIterator},
 };
 
@@ -210,6 +211,113 @@ pub fn generate_sst_with_ts(
     builder.build(id, block_cache, path.as_ref()).unwrap()
 }
 
+/// A state with the `l0_sstables`, the `levels` and the `ssts`, and an empty memtable.
+#[allow(dead_code)]
+pub fn mock_state(
+    l0_sstables: Vec<usize>,
+    levels: Vec<(usize, Vec<usize>)>,
+    ssts: Vec<Arc<SsTable>>,
+) -> LsmStorageState {
+    LsmStorageState {
+        memtable: Arc::new(MemTable::create(0)),
+        imm_memtables: Vec::new(),
+        l0_sstables,
+        levels,
+        sstables: ssts.into_iter().map(|sst| (sst.sst_id(), sst)).collect(),
+    }
+}
+
+/// Build an SST with a key for each of `keys`, at timestamp `ts`, where one out of `tombstone_every`
+/// keys is deleted.
+#[allow(dead_code)]
+pub fn build_sst(
+    dir: &Path,
+    id: usize,
+    prefix: &str,
+    keys: usize,
+    ts: u64,
+    tombstone_every: Option<usize>,
+) -> Arc<SsTable> {
+    let mut builder = SsTableBuilder::new(4096);
+    for i in 0..keys {
+        let key = format!("{}_{:04}", prefix, i);
+        let value = match tombstone_every {
+            Some(every) if i % every == 0 => &b""[..],
+            _ => &b"value"[..],
+        };
+        builder.add(
+            KeySlice::for_testing_from_slice_with_ts(key.as_bytes(), ts),
+            value,
+        );
+    }
+    Arc::new(
+        builder
+            .build(id, None, dir.join(format!("{:05}.sst", id)))
+            .unwrap(),
+    )
+}
+
+/// The leveled compaction of the tests, with `max_levels` levels.
+#[allow(dead_code)]
+pub fn leveled_options(max_levels: usize) -> CompactionOptions {
+    CompactionOptions::Leveled(LeveledCompactionOptions {
+        level_size_multiplier: 2,
+        level0_file_num_compaction_trigger: 2,
+        max_levels,
+        base_level_size_mb: 1,
+    })
+}
+
+/// The tiered compaction of the tests.
+#[allow(dead_code)]
+pub fn tiered_options() -> CompactionOptions {
+    CompactionOptions::Tiered(TieredCompactionOptions {
+        num_tiers: 3,
+        max_size_amplification_percent: 200,
+        size_ratio: 1,
+        min_merge_width: 2,
+    })
+}
+
+/// The simple leveled compaction of the tests.
+#[allow(dead_code)]
+pub fn simple_options() -> CompactionOptions {
+    CompactionOptions::Simple(SimpleLeveledCompactionOptions {
+        size_ratio_percent: 200,
+        level0_file_num_compaction_trigger: 2,
+        max_levels: 3,
+    })
+}
+
+/// The options of the tests that write the WAL, without compaction.
+#[allow(dead_code)]
+pub fn wal_options() -> LsmStorageOptions {
+    wal_options_with(CompactionOptions::NoCompaction)
+}
+
+/// The options of the tests that write the WAL, with `compaction_options`.
+#[allow(dead_code)]
+pub fn wal_options_with(compaction_options: CompactionOptions) -> LsmStorageOptions {
+    let mut options = LsmStorageOptions::default_for_week2_test(compaction_options);
+    options.enable_wal = true;
+    options
+}
+
+/// All the key-value pairs visible in `storage`, in key order.
+#[allow(dead_code)]
+pub fn scan_all(storage: &MiniLsm) -> Vec<(Bytes, Bytes)> {
+    let mut iter = storage.scan(Bound::Unbounded, Bound::Unbounded).unwrap();
+    let mut result = Vec::new();
+    while iter.is_valid() {
+        result.push((
+            Bytes::copy_from_slice(iter.key()),
+            Bytes::copy_from_slice(iter.value()),
+        ));
+        iter.next().unwrap();
+    }
+    result
+}
+
 pub fn sync(storage: &LsmStorageInner) {
     storage
         .force_freeze_memtable(&storage.state_lock.lock())
@@ -217,6 +325,35 @@ pub fn sync(storage: &LsmStorageInner) {
     storage.force_flush_next_imm_memtable().unwrap();
 }
 
+/// Write the keys in `ranges` to an L0 SST each, and return their ids from the oldest.
+#[allow(dead_code)]
+pub fn flush_ranges(
+    storage: &Arc<LsmStorageInner>,
+    ranges: &[std::ops::Range<usize>],
+) -> Vec<usize> {
+    for range in ranges {
+        for i in range.clone() {
+            storage
+                .put(format!("key_{:05}", i).as_bytes(), b"value")
+                .unwrap();
+        }
+        sync(storage);
+    }
+    let mut l0_sstables = storage.state.read().l0_sstables.clone();
+    l0_sstables.reverse();
+    l0_sstables
+}
+
+/// Check that the keys written by `flush_ranges` in `keys` are all present, or all absent.
+#[allow(dead_code)]
+pub fn check_keys(storage: &Arc<LsmStorageInner>, keys: std::ops::Range<usize>, exists: bool) {
+    for i in keys {
+        let value = storage.get(format!("key_{:05}", i).as_bytes()).unwrap();
+        let expected = exists.then(|| Bytes::from_static(b"value"));
+        assert_eq!(value, expected, "key_{:05}", i);
+    }
+}
+
 pub fn compaction_bench(storage: Arc<MiniLsm>) {
     let mut key_map = BTreeMap::<usize, usize>::new();
     let gen_key = |i| format!("{:010}", i); // 10B
@@ -306,7 +443,6 @@ pub fn check_compaction_ratio(storage: Arc<MiniLsm>) {
         .num_active_iterators();
     let num_memtables = storage.inner.state.read().imm_memtables.len() + 1;
     match compaction_options {
-        CompactionOptions::NoCompaction => unreachable!(),
         CompactionOptions::Simple(SimpleLeveledCompactionOptions {
             size_ratio_percent,
             level0_file_num_compaction_trigger,
@@ -405,6 +541,7 @@ pub fn check_compaction_ratio(storage: Arc<MiniLsm>) {
                 "we found {num_iters} iterators in your implementation, (num_memtables={num_memtables}, num_tiers={num_tiers}) did you use concat iterators?"
             );
         }
+        _ => unreachable!(),
     }
 }
 